@@ -0,0 +1,219 @@
+//! A client for a cluster of nodes, retrying failed requests and moving off nodes that fail.
+//!
+//! Any node of a cluster serves any key, handing requests for partitions it doesn't own to the
+//! node that does. The client sends its requests to one node at a time: when a request can't reach
+//! it, or the node can't reach the owner of the key because leadership moved or the owner is down,
+//! the node is set aside for the health check interval and the request is retried on the next one,
+//! which routes it with its own view of the cluster. A node that was set aside is used again once
+//! the interval has passed, and its first request is the check that it recovered.
+//!
+//! Every request of the protocol can be repeated without changing its outcome, so they are all
+//! retried, though a retried delete may report a missing key if the first attempt removed it.
+
+use std::io::{self, ErrorKind};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use flowdb::FlowDbError;
+use crate::{Client, ClientError, KeyValue, Pool};
+
+/// How many times a request is tried, and how long to wait between tries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times a request is sent, counting the first; at least 1.
+    pub attempts: u32,
+    /// The wait before the first retry, doubled before each retry after it.
+    pub initial_backoff: Duration,
+    /// The longest wait between two tries.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: u32, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        Self { attempts: attempts.max(1), initial_backoff, max_backoff }
+    }
+
+    /// A policy that sends each request once.
+    pub fn never() -> Self {
+        Self::new(1, Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Returns how long to wait before the given retry, counting from 0.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff.saturating_mul(1 << retry.min(31)).min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three tries, waiting 10ms and then 20ms, up to a second.
+    fn default() -> Self {
+        Self::new(3, Duration::from_millis(10), Duration::from_secs(1))
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    pool: Pool,
+    /// Until when the node is set aside after failing a request.
+    down_until: Mutex<Option<Instant>>,
+}
+
+/// A thread-safe client for a cluster, with a pool of connections to each node.
+#[derive(Debug)]
+pub struct ClusterClient {
+    nodes: Vec<Node>,
+    retry: RetryPolicy,
+    health_check_interval: Duration,
+    /// The node requests go to while it is healthy.
+    current: AtomicUsize,
+}
+
+impl ClusterClient {
+    /// Creates a client for the nodes at the addresses, keeping up to 4 idle connections to each.
+    /// No connection is opened until a request is sent.
+    pub fn new<A: ToSocketAddrs>(nodes: impl IntoIterator<Item = A>) -> io::Result<Self> {
+        Self::with_pool_size(nodes, 4)
+    }
+
+    /// Creates a client for the nodes at the addresses, keeping up to `max_idle` idle connections
+    /// to each.
+    pub fn with_pool_size<A: ToSocketAddrs>(nodes: impl IntoIterator<Item = A>, max_idle: usize) -> io::Result<Self> {
+        let nodes = nodes.into_iter().map(|address| Ok(Node { pool: Pool::new(address, max_idle)?, down_until: Mutex::new(None) })).collect::<io::Result<Vec<_>>>()?;
+        if nodes.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "a cluster client needs at least one node"));
+        }
+        Ok(Self { nodes, retry: RetryPolicy::default(), health_check_interval: Duration::from_secs(5), current: AtomicUsize::new(0) })
+    }
+
+    /// Sets how failed requests are retried.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets how long a node that failed a request is set aside before it is tried again.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Sets the timeout of every node's connections, as with `Client::with_timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.nodes = self.nodes.into_iter().map(|node| Node { pool: node.pool.with_timeout(timeout), ..node }).collect();
+        self
+    }
+
+    /// Returns the key's value, or None if the key is missing.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, ClientError> {
+        self.run(|client| client.get(key.as_ref()))
+    }
+
+    /// Stores the value under the key.
+    pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), ClientError> {
+        self.run(|client| client.put(key.as_ref(), value.as_ref()))
+    }
+
+    /// Removes the key, returning whether it existed.
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<bool, ClientError> {
+        self.run(|client| client.delete(key.as_ref()))
+    }
+
+    /// Returns the entries whose key starts with the prefix, or at most `limit` of them if given.
+    pub fn scan(&self, prefix: impl AsRef<[u8]>, limit: Option<u32>) -> Result<Vec<KeyValue>, ClientError> {
+        self.run(|client| client.scan(prefix.as_ref(), limit))
+    }
+
+    /// Runs the request on the current node, retrying it on the next healthy one as the policy allows.
+    fn run<T>(&self, request: impl Fn(&mut Client) -> Result<T, ClientError>) -> Result<T, ClientError> {
+        let mut retry = 0;
+        loop {
+            let index = self.pick();
+            let node = &self.nodes[index];
+            let error = match node.pool.get().and_then(|mut client| request(&mut client)) {
+                Err(e) if is_retryable(&e) => e,
+                result => return result,
+            };
+            *node.down_until.lock().unwrap() = Some(Instant::now() + self.health_check_interval);
+            retry += 1;
+            if retry >= self.retry.attempts {
+                return Err(error);
+            }
+            thread::sleep(self.retry.backoff(retry - 1));
+        }
+    }
+
+    /// Returns the current node if it is healthy, or else makes the next healthy node current. If
+    /// every node is set aside, the one set aside the longest is tried.
+    fn pick(&self) -> usize {
+        let now = Instant::now();
+        let current = self.current.load(Ordering::Relaxed);
+        let down_until = |index: usize| *self.nodes[index].down_until.lock().unwrap();
+        let index = (0..self.nodes.len())
+            .map(|offset| (current + offset) % self.nodes.len())
+            .find(|&index| down_until(index).is_none_or(|until| until <= now))
+            .unwrap_or_else(|| (0..self.nodes.len()).min_by_key(|&index| down_until(index)).expect("cluster has nodes"));
+        if index != current {
+            self.current.store(index, Ordering::Relaxed);
+        }
+        index
+    }
+}
+
+/// Returns whether another node might serve the request that failed with the error.
+fn is_retryable(error: &ClientError) -> bool {
+    match error {
+        ClientError::Io(_) => true,
+        ClientError::Server(message) => *message == FlowDbError::Unavailable.to_string() || *message == FlowDbError::ShuttingDown.to_string(),
+        ClientError::UnexpectedResponse => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use flowdb::StorageServer;
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(10), Duration::from_millis(50));
+        let backoffs: Vec<_> = (0..4).map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(backoffs, [10, 20, 40, 50].map(Duration::from_millis));
+        assert_eq!(RetryPolicy::new(0, Duration::ZERO, Duration::ZERO).attempts, 1);
+    }
+
+    #[test]
+    fn test_cluster_client() {
+        // The first node is gone, so requests move to the second.
+        let gone = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let server = Arc::clone(&server);
+                    thread::spawn(move || server.serve_client(stream.unwrap()));
+                }
+            });
+        }
+
+        let client = ClusterClient::new([gone, address]).unwrap().with_timeout(Duration::from_secs(5)).with_health_check_interval(Duration::from_millis(100));
+        client.put("key", "value").unwrap();
+        assert_eq!(server.get("key"), Ok("value".to_owned()));
+        assert_eq!(client.current.load(Ordering::Relaxed), 1);
+        assert_eq!(client.get("key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(client.scan("", None).unwrap().len(), 1);
+        assert!(client.delete("key").unwrap());
+        assert_eq!(client.nodes[1].pool.idle(), 1);
+
+        // Without retries, a request to a node that is down fails.
+        let lonely = ClusterClient::new([gone]).unwrap().with_retry_policy(RetryPolicy::never());
+        assert!(matches!(lonely.get("key"), Err(ClientError::Io(_))));
+        assert!(ClusterClient::new(Vec::<&str>::new()).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! A client for FlowDB servers, speaking the binary protocol of `flowdb::protocol` over TCP.
+//!
+//! A client holds one connection and sends one request at a time on it. When the connection
+//! breaks, the client reconnects: a request that fails on a connection the client had used before
+//! is retried once on a new one. Every request is safe to repeat, though a retried delete may
+//! report a missing key if the first attempt removed it before the connection broke.
+
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use flowdb::protocol::{Request, Response};
+
+mod cluster;
+mod pipeline;
+mod pool;
+
+pub use cluster::{ClusterClient, RetryPolicy};
+pub use pipeline::Pipeline;
+pub use pool::{Pool, PooledClient};
+
+/// A key and its value, as returned by a scan.
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server couldn't be reached, the connection failed, or it timed out.
+    Io(io::Error),
+    /// The server failed the request, with its message.
+    Server(String),
+    /// The server answered with a response that doesn't belong to the request.
+    UnexpectedResponse,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Server(message) => write!(f, "server error: {}", message),
+            ClientError::UnexpectedResponse => write!(f, "unexpected response from the server"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+/// Connects to the server at the address, trying each address it resolves to in turn.
+pub fn connect(address: impl ToSocketAddrs) -> Result<Client, ClientError> {
+    Client::connect(address)
+}
+
+/// A connection to a FlowDB server.
+#[derive(Debug)]
+pub struct Client {
+    addresses: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    stream: Option<TcpStream>,
+}
+
+impl Client {
+    /// Connects to the server at the address, trying each address it resolves to in turn.
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self, ClientError> {
+        Self::connect_with(address.to_socket_addrs()?.collect(), None)
+    }
+
+    /// Connects to the first of the addresses that accepts, with the timeout if given.
+    pub(crate) fn connect_with(addresses: Vec<SocketAddr>, timeout: Option<Duration>) -> Result<Self, ClientError> {
+        let mut client = Self { addresses, timeout, stream: None };
+        client.stream = Some(client.open()?);
+        Ok(client)
+    }
+
+    /// Returns whether the client's connection is still open, without waiting: false if it broke,
+    /// or if the server closed it or sent something no request asked for.
+    pub(crate) fn is_alive(&self) -> bool {
+        let Some(stream) = &self.stream else { return false };
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let alive = matches!(stream.peek(&mut [0]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+        stream.set_nonblocking(false).is_ok() && alive
+    }
+
+    /// Sets how long connecting, sending a request and waiting for its response may each take
+    /// before the request fails. Requests that time out aren't retried.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        if let Some(stream) = &self.stream {
+            if stream.set_read_timeout(self.timeout).and_then(|()| stream.set_write_timeout(self.timeout)).is_err() {
+                self.stream = None;
+            }
+        }
+        self
+    }
+
+    /// Returns the key's value, or None if the key is missing.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, ClientError> {
+        match self.call(&Request::Get { key: key.as_ref().to_vec() })? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Stores the value under the key.
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), ClientError> {
+        match self.call(&Request::Put { key: key.as_ref().to_vec(), value: value.as_ref().to_vec() })? {
+            Response::Stored => Ok(()),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Removes the key, returning whether it existed.
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<bool, ClientError> {
+        match self.call(&Request::Delete { key: key.as_ref().to_vec() })? {
+            Response::Deleted(existed) => Ok(existed),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Returns the entries whose key starts with the prefix, or at most `limit` of them if given.
+    pub fn scan(&mut self, prefix: impl AsRef<[u8]>, limit: Option<u32>) -> Result<Vec<KeyValue>, ClientError> {
+        self.scan_stream(prefix, limit)?.collect()
+    }
+
+    /// Returns an iterator over the entries whose key starts with the prefix, or at most `limit` of
+    /// them if given, reading them from the server a chunk at a time as the iterator advances.
+    ///
+    /// The connection carries the scan until the iterator ends; dropping it early closes the
+    /// connection, and the next request opens a new one.
+    pub fn scan_stream(&mut self, prefix: impl AsRef<[u8]>, limit: Option<u32>) -> Result<ScanStream<'_>, ClientError> {
+        // A limit of 0 means no limit on the wire, so asking for no entries is answered here.
+        if limit == Some(0) {
+            return Ok(ScanStream { client: self, entries: Vec::new().into_iter(), done: true });
+        }
+        let (entries, done) = match self.call(&Request::Scan { prefix: prefix.as_ref().to_vec(), limit: limit.unwrap_or(0) })? {
+            Response::Entries(entries) => (entries, true),
+            Response::MoreEntries(entries) => (entries, false),
+            _ => return Err(ClientError::UnexpectedResponse),
+        };
+        Ok(ScanStream { client: self, entries: entries.into_iter(), done })
+    }
+
+    /// Starts a pipeline of requests that are sent together, without waiting for each response.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
+    /// Sends the request and reads its response, reconnecting if there is no connection or the one
+    /// the client had broke.
+    fn call(&mut self, request: &Request) -> Result<Response, ClientError> {
+        let frame = request.encode();
+        loop {
+            let reused = self.stream.is_some();
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => self.stream.insert(self.open()?),
+            };
+            let response = stream.write_all(&frame).and_then(|()| Response::read_from(stream));
+            match response {
+                Ok(Response::Error(message)) => return Err(ClientError::Server(message)),
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    // The connection may be partway through a frame, so it can't be used again.
+                    self.stream = None;
+                    if !reused || matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::InvalidData) {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+    }
+
+    fn open(&self) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing");
+        for address in &self.addresses {
+            let stream = match self.timeout {
+                Some(timeout) => TcpStream::connect_timeout(address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(self.timeout)?;
+                    stream.set_write_timeout(self.timeout)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// The entries of a scan, read from the server a chunk at a time. See `Client::scan_stream`.
+#[derive(Debug)]
+pub struct ScanStream<'a> {
+    client: &'a mut Client,
+    entries: std::vec::IntoIter<KeyValue>,
+    /// Whether the server sent the last chunk, or the scan failed.
+    done: bool,
+}
+
+impl Iterator for ScanStream<'_> {
+    type Item = Result<KeyValue, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            let stream = self.client.stream.as_mut()?;
+            let (entries, done) = match Response::read_from(stream) {
+                Ok(Response::MoreEntries(entries)) => (entries, false),
+                Ok(Response::Entries(entries)) => (entries, true),
+                result => {
+                    self.done = true;
+                    self.client.stream = None;
+                    return Some(Err(match result {
+                        Ok(Response::Error(message)) => ClientError::Server(message),
+                        Ok(_) => ClientError::UnexpectedResponse,
+                        Err(e) => e.into(),
+                    }));
+                }
+            };
+            self.entries = entries.into_iter();
+            self.done = done;
+        }
+    }
+}
+
+impl Drop for ScanStream<'_> {
+    fn drop(&mut self) {
+        // The rest of the scan is still on its way, so the connection can't carry another request.
+        if !self.done {
+            self.client.stream = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use flowdb::StorageServer;
+
+    #[test]
+    fn test_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                // The first connection is dropped, so the client has to reconnect.
+                for (i, stream) in listener.incoming().enumerate() {
+                    let stream = stream.unwrap();
+                    if i > 0 {
+                        let server = Arc::clone(&server);
+                        thread::spawn(move || server.serve_client(stream));
+                    }
+                }
+            });
+        }
+
+        let mut client = connect(address).unwrap().with_timeout(Duration::from_secs(5));
+        client.put("user:1", "alice").unwrap();
+        client.put("user:2", [0, 255]).unwrap();
+        assert_eq!(server.get("user:1"), Ok("alice".to_owned()));
+        assert_eq!(client.get("user:1").unwrap(), Some(b"alice".to_vec()));
+        assert_eq!(client.get("missing").unwrap(), None);
+
+        let mut entries = client.scan("user:", None).unwrap();
+        entries.sort();
+        assert_eq!(entries, vec![(b"user:1".to_vec(), b"alice".to_vec()), (b"user:2".to_vec(), vec![0, 255])]);
+        assert_eq!(client.scan("", Some(1)).unwrap().len(), 1);
+        assert!(client.scan("", Some(0)).unwrap().is_empty());
+
+        // A scan larger than a chunk is streamed, and abandoning it doesn't break the next request.
+        for i in 0..1000 {
+            client.put(format!("item:{:04}", i), "value").unwrap();
+        }
+        assert_eq!(client.scan_stream("item:", None).unwrap().count(), 1000);
+        assert_eq!(client.scan_stream("item:", None).unwrap().take(3).count(), 3);
+        assert_eq!(client.get("item:0001").unwrap(), Some(b"value".to_vec()));
+
+        assert!(client.delete("user:1").unwrap());
+        assert!(!client.delete("user:1").unwrap());
+    }
+
+    #[test]
+    fn test_client_timeout() {
+        // A server that accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || listener.incoming().collect::<Vec<_>>());
+
+        let mut client = connect(address).unwrap().with_timeout(Duration::from_millis(50));
+        match client.get("key") {
+            Err(ClientError::Io(e)) => assert!(matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(connect("127.0.0.1:1").is_err());
+    }
+}
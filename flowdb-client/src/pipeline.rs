@@ -0,0 +1,147 @@
+//! Sending a batch of requests without waiting for each response, to save round trips.
+
+use std::io::{Read, Write};
+use flowdb::protocol::{Request, Response};
+use crate::{Client, ClientError};
+
+/// The most requests sent ahead of the responses read, so that neither side blocks writing while
+/// the other is also blocked writing.
+const WINDOW: usize = 32;
+
+/// Requests queued on a client to be sent together by `execute`. See `Client::pipeline`.
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    /// The queued requests, with None for scans of no entries, which are answered without asking.
+    requests: Vec<Option<Request>>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub(crate) fn new(client: &'a mut Client) -> Self {
+        Self { client, requests: Vec::new() }
+    }
+
+    /// Queues a get, answered by `Value` or `NotFound`.
+    pub fn get(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.requests.push(Some(Request::Get { key: key.as_ref().to_vec() }));
+        self
+    }
+
+    /// Queues a put, answered by `Stored`.
+    pub fn put(mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Self {
+        self.requests.push(Some(Request::Put { key: key.as_ref().to_vec(), value: value.as_ref().to_vec() }));
+        self
+    }
+
+    /// Queues a delete, answered by `Deleted`.
+    pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.requests.push(Some(Request::Delete { key: key.as_ref().to_vec() }));
+        self
+    }
+
+    /// Queues a scan of the entries whose key starts with the prefix, or at most `limit` of them if
+    /// given, answered by `Entries` with all of them.
+    pub fn scan(mut self, prefix: impl AsRef<[u8]>, limit: Option<u32>) -> Self {
+        // A limit of 0 means no limit on the wire, so asking for no entries is answered locally.
+        self.requests.push((limit != Some(0)).then(|| Request::Scan { prefix: prefix.as_ref().to_vec(), limit: limit.unwrap_or(0) }));
+        self
+    }
+
+    /// Returns the number of queued requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the queued requests and returns their responses in the same order. A request the
+    /// server failed is answered by `Error` in its place.
+    ///
+    /// The whole pipeline fails if the connection does, in which case some of its requests may
+    /// have been applied; it isn't retried, unlike single requests.
+    pub fn execute(self) -> Result<Vec<Response>, ClientError> {
+        let Pipeline { client, requests } = self;
+        let sent: Vec<&Request> = requests.iter().flatten().collect();
+        let stream = match &mut client.stream {
+            Some(stream) => stream,
+            None => client.stream.insert(client.open()?),
+        };
+        let mut responses = Vec::with_capacity(sent.len());
+        let result = (|| {
+            let mut written = 0;
+            for window in sent.chunks(WINDOW) {
+                stream.write_all(&window.iter().flat_map(|request| request.encode()).collect::<Vec<_>>())?;
+                written += window.len();
+                while written - responses.len() > WINDOW {
+                    responses.push(read_response(stream)?);
+                }
+            }
+            while responses.len() < written {
+                responses.push(read_response(stream)?);
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            client.stream = None;
+            return Err(e);
+        }
+        let mut responses = responses.into_iter();
+        let responses = requests.iter().map(|request| match request {
+            Some(_) => responses.next().expect("every sent request is answered"),
+            None => Response::Entries(Vec::new()),
+        });
+        Ok(responses.collect())
+    }
+}
+
+/// Reads the response to one request, gathering the chunks of a scan into one `Entries`.
+fn read_response(stream: &mut impl Read) -> Result<Response, ClientError> {
+    let mut gathered = Vec::new();
+    loop {
+        match Response::read_from(stream)? {
+            Response::MoreEntries(entries) => gathered.extend(entries),
+            Response::Entries(entries) if !gathered.is_empty() => {
+                gathered.extend(entries);
+                return Ok(Response::Entries(gathered));
+            }
+            response if gathered.is_empty() => return Ok(response),
+            _ => return Err(ClientError::UnexpectedResponse),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use flowdb::protocol::Response;
+    use flowdb::StorageServer;
+    use crate::connect;
+
+    #[test]
+    fn test_pipeline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        thread::spawn(move || server.serve_client(listener.accept().unwrap().0));
+        let mut client = connect(address).unwrap();
+
+        // More requests than fit in a window, including a scan streamed in chunks.
+        let mut pipeline = client.pipeline();
+        for i in 0..300 {
+            pipeline = pipeline.put(format!("key{:03}", i), "value");
+        }
+        let responses = pipeline.scan("key", None).get("key007").get("missing").delete("key007").scan("key", Some(0)).execute().unwrap();
+        assert_eq!(responses.len(), 305);
+        assert!(responses[..300].iter().all(|response| *response == Response::Stored));
+        assert!(matches!(&responses[300], Response::Entries(entries) if entries.len() == 300));
+        assert_eq!(responses[301..], [Response::Value(b"value".to_vec()), Response::NotFound, Response::Deleted(true), Response::Entries(Vec::new())]);
+
+        // The connection is ready for more requests afterwards.
+        assert_eq!(client.get("key008").unwrap(), Some(b"value".to_vec()));
+        assert!(client.pipeline().execute().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+//! A pool of connections to one server, so threads sharing a node don't each open their own.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::{Client, ClientError};
+
+/// Connections to one server, opened as they are needed and kept open between requests.
+///
+/// Idle connections are checked before being handed out again, and ones the server closed are
+/// dropped, so a checked-out client starts with a live connection unless the server just went away.
+#[derive(Debug)]
+pub struct Pool {
+    addresses: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    max_idle: usize,
+    idle: Mutex<Vec<Client>>,
+}
+
+impl Pool {
+    /// Creates a pool for the server at the address that keeps up to `max_idle` connections open
+    /// while they aren't in use. No connection is opened until one is needed.
+    pub fn new(address: impl ToSocketAddrs, max_idle: usize) -> io::Result<Self> {
+        Ok(Self { addresses: address.to_socket_addrs()?.collect(), timeout: None, max_idle, idle: Mutex::new(Vec::new()) })
+    }
+
+    /// Sets the timeout of the pool's connections, as with `Client::with_timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self.idle.get_mut().unwrap().clear();
+        self
+    }
+
+    /// Returns an idle connection that is still open, or a new one if there is none.
+    pub fn get(&self) -> Result<PooledClient<'_>, ClientError> {
+        loop {
+            let Some(client) = self.idle.lock().unwrap().pop() else { break };
+            if client.is_alive() {
+                return Ok(PooledClient { pool: self, client: Some(client) });
+            }
+        }
+        let client = Client::connect_with(self.addresses.clone(), self.timeout)?;
+        Ok(PooledClient { pool: self, client: Some(client) })
+    }
+
+    /// Returns the number of connections open and waiting to be used.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/// A connection checked out of a pool, returned to it when dropped unless the connection broke.
+#[derive(Debug)]
+pub struct PooledClient<'a> {
+    pool: &'a Pool,
+    client: Option<Client>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("client is returned on drop")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("client is returned on drop")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else { return };
+        let mut idle = self.pool.idle.lock().unwrap();
+        if client.stream.is_some() && idle.len() < self.pool.max_idle {
+            idle.push(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use flowdb::StorageServer;
+
+    #[test]
+    fn test_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (accepted, connections) = mpsc::channel();
+        thread::spawn(move || {
+            let server = Arc::new(StorageServer::new(4, 2));
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                accepted.send(stream.try_clone().unwrap()).unwrap();
+                let server = Arc::clone(&server);
+                thread::spawn(move || server.serve_client(stream));
+            }
+        });
+        let pool = Pool::new(address, 1).unwrap().with_timeout(Duration::from_secs(5));
+
+        // Connections are reused, and only as many as `max_idle` are kept.
+        {
+            let (mut first, mut second) = (pool.get().unwrap(), pool.get().unwrap());
+            first.put("key", "value").unwrap();
+            assert_eq!(second.get("key").unwrap(), Some(b"value".to_vec()));
+        }
+        assert_eq!(pool.idle(), 1);
+        pool.get().unwrap().get("key").unwrap();
+        let server_sides: Vec<_> = connections.try_iter().collect();
+        assert_eq!(server_sides.len(), 2);
+
+        // An idle connection the server closed is replaced by a new one.
+        server_sides.iter().for_each(|stream| stream.shutdown(Shutdown::Both).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.get().unwrap().get("key").unwrap(), Some(b"value".to_vec()));
+        assert!(connections.try_recv().is_ok());
+        assert_eq!(pool.idle(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+//! Finds and repairs replicas that have diverged from their partition's leader.
+//!
+//! Each copy of a partition is summarised by a Merkle tree: keys are hashed into a fixed number of
+//! buckets, each leaf digests the entries of one bucket, and each inner node digests its two
+//! children. Comparing a replica's tree with the leader's from the root down only descends into
+//! subtrees whose digests differ, so the buckets holding divergent keys are found without comparing
+//! the keys themselves. Only those buckets are then copied from the leader.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::warn;
+use crate::backend::PartitionData;
+use crate::entry::Entry;
+use crate::leadership::Leadership;
+use crate::rebalance::Routing;
+use crate::replication;
+use crate::ring;
+
+/// The number of levels below the root, so trees have 2^DEPTH buckets.
+const DEPTH: u32 = 10;
+
+/// A Merkle tree over the entries of one copy of a partition.
+pub(crate) struct MerkleTree {
+    /// The nodes in breadth-first order: the root first, and the leaves last.
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Builds the tree of the partition data. Entries are digested whole, so copies only match if
+    /// their values, versions, expiry and tombstones all do.
+    pub(crate) fn build(data: &PartitionData) -> Self {
+        let leaves = 1 << DEPTH;
+        let mut nodes = vec![0u64; 2 * leaves - 1];
+        for (key, entry) in data.iter() {
+            // Digests are added up, so the order the engine returns entries in doesn't matter.
+            let leaf = leaves - 1 + bucket(&key);
+            nodes[leaf] = nodes[leaf].wrapping_add(digest(&key, &entry));
+        }
+        for node in (0..leaves - 1).rev() {
+            let mut hasher = DefaultHasher::new();
+            (nodes[2 * node + 1], nodes[2 * node + 2]).hash(&mut hasher);
+            nodes[node] = hasher.finish();
+        }
+        Self { nodes }
+    }
+
+    /// Returns the buckets whose entries differ between the two trees.
+    pub(crate) fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        let leaves = 1 << DEPTH;
+        let mut divergent = Vec::new();
+        let mut pending = vec![0];
+        while let Some(node) = pending.pop() {
+            if self.nodes[node] == other.nodes[node] {
+                continue;
+            }
+            if node >= leaves - 1 {
+                divergent.push(node - (leaves - 1));
+            } else {
+                pending.extend([2 * node + 2, 2 * node + 1]);
+            }
+        }
+        divergent
+    }
+}
+
+/// Returns the Merkle tree bucket of the key.
+fn bucket(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    ring::hash_key(key, &mut hasher);
+    hasher.finish() as usize % (1 << DEPTH)
+}
+
+fn digest(key: &[u8], entry: &Entry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    ring::hash_key(key, &mut hasher);
+    bincode::serialize(entry).unwrap_or_default().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Makes the buckets of the replica identical to the leader's, returning how many keys were
+/// rewritten or removed.
+fn repair(leader: &PartitionData, replica: &mut PartitionData, buckets: &[usize]) -> usize {
+    let in_buckets = |key: &[u8]| buckets.contains(&bucket(key));
+    let stale: Vec<Vec<u8>> = replica.iter().map(|(key, _)| key.into_owned()).filter(|key| in_buckets(key) && leader.get(key).is_none()).collect();
+    let changed: Vec<(Vec<u8>, Entry)> = leader
+        .iter()
+        .filter(|(key, entry)| in_buckets(key) && replica.get(key).as_deref() != Some(&**entry))
+        .map(|(key, entry)| (key.into_owned(), entry.into_owned()))
+        .collect();
+    let repaired = stale.len() + changed.len();
+    for key in stale {
+        replica.remove(&key);
+    }
+    replica.extend(changed);
+    repaired
+}
+
+/// Compares every replica with its partition's leader and repairs the ones that diverged,
+/// returning how many keys were repaired.
+///
+/// Each partition's leader is read-locked while its replicas are checked, so writes to it wait,
+/// but reads and other partitions carry on. Replicas that are down or whose lock is poisoned are
+/// skipped.
+pub(crate) fn repair_replicas(leadership: &Leadership) -> usize {
+    let mut repaired = 0;
+    for partition in 0..leadership.partition_count() {
+        let copies = leadership.copies(partition);
+        let Ok(leader) = copies[0].read() else {
+            continue;
+        };
+        let leader_tree = MerkleTree::build(&leader.data);
+        for (index, copy) in copies.iter().enumerate().skip(1) {
+            let Ok(mut replica) = copy.write() else {
+                continue;
+            };
+            // A replica that is down catches up from its hints once it is back.
+            if replica.down {
+                continue;
+            }
+            let buckets = leader_tree.diff(&MerkleTree::build(&replica.data));
+            // The leader is locked, so the replica now holds every write it made.
+            replication::record_caught_up(&mut replica);
+            if buckets.is_empty() {
+                continue;
+            }
+            let keys = repair(&leader.data, &mut replica.data, &buckets);
+            warn!("Repaired {} keys in {} buckets of partition {} replica {}", keys, buckets.len(), partition, index);
+            repaired += keys;
+        }
+    }
+    repaired
+}
+
+/// A background thread that periodically repairs the replicas of a StorageServer that diverged
+/// from their leaders.
+///
+/// The thread is stopped when the service is stopped or dropped.
+pub struct AntiEntropy {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AntiEntropy {
+    pub(crate) fn spawn(routing: Arc<Routing>, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Acquire) {
+                repair_replicas(&routing.current().leadership);
+                thread::park_timeout(interval);
+            }
+        });
+        Self { stop, handle: Some(handle) }
+    }
+
+    /// Stops the anti-entropy thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for AntiEntropy {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+
+    #[test]
+    fn test_merkle_diff() {
+        let mut leader = PartitionData::new(Backend::default());
+        let mut replica = PartitionData::new(Backend::default());
+        for i in 0..100 {
+            let entry = Entry::new(format!("value{}", i).into_bytes());
+            leader.insert(format!("key{}", i).into_bytes(), entry.clone());
+            replica.insert(format!("key{}", i).into_bytes(), entry);
+        }
+        assert_eq!(MerkleTree::build(&leader).diff(&MerkleTree::build(&replica)), Vec::<usize>::new());
+
+        replica.remove(b"key7");
+        replica.insert(b"key8".to_vec(), Entry::new(b"stale".to_vec()));
+        replica.insert(b"extra".to_vec(), Entry::new(b"extra".to_vec()));
+        let mut buckets = MerkleTree::build(&leader).diff(&MerkleTree::build(&replica));
+        let mut expected = vec![bucket(b"key7"), bucket(b"key8"), bucket(b"extra")];
+        buckets.sort();
+        expected.sort();
+        expected.dedup();
+        assert_eq!(buckets, expected);
+        assert_eq!(repair(&leader, &mut replica, &buckets), 3);
+        assert_eq!(MerkleTree::build(&leader).diff(&MerkleTree::build(&replica)), Vec::<usize>::new());
+    }
+}
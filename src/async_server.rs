@@ -0,0 +1,262 @@
+//! A tokio server core for the binary protocol.
+//!
+//! Each connection is a task on the runtime rather than a thread, so idle clients cost a few
+//! kilobytes instead of a stack each. The StorageServer itself stays synchronous: its async
+//! operations run the sync ones on tokio's blocking pool, so a write waiting on a key lock or the
+//! transaction log never stalls the tasks serving other connections. The sync API is unchanged
+//! and remains the one embedders call directly.
+
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio_rustls::TlsAcceptor;
+use crate::error::FlowDbError;
+use crate::protocol::{frame_buffer, Request, Response, ScanChunks, MAX_PENDING_RESPONSES};
+use crate::scan::{Cursor, Page};
+use crate::storage_server::StorageServer;
+use crate::tls::TlsConfig;
+use crate::transaction_log::Lsn;
+
+impl StorageServer {
+    /// Reads the key's value without blocking the runtime; see `get_bytes`.
+    pub async fn get_async(self: &Arc<Self>, key: impl AsRef<[u8]>) -> Result<Vec<u8>, FlowDbError> {
+        let key = key.as_ref().to_vec();
+        self.run_blocking(move |server| server.get_bytes(key)).await
+    }
+
+    /// Stores the value without blocking the runtime; see `put`.
+    pub async fn put_async(self: &Arc<Self>, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Lsn>, FlowDbError> {
+        let (key, value) = (key.as_ref().to_vec(), value.as_ref().to_vec());
+        self.run_blocking(move |server| server.put(key, value)).await
+    }
+
+    /// Deletes the key without blocking the runtime, returning whether it existed; see `delete`.
+    pub async fn delete_async(self: &Arc<Self>, key: impl AsRef<[u8]>) -> Result<bool, FlowDbError> {
+        let key = key.as_ref().to_vec();
+        self.run_blocking(move |server| server.delete(key)).await
+    }
+
+    /// Reads a page of the entries whose key starts with the prefix without blocking the runtime;
+    /// see `scan_prefix_page`.
+    pub async fn scan_prefix_page_async(self: &Arc<Self>, prefix: impl AsRef<[u8]>, cursor: Option<Cursor>, limit: usize) -> Result<Page, FlowDbError> {
+        let prefix = prefix.as_ref().to_vec();
+        self.run_blocking(move |server| Ok(server.scan_prefix_page(prefix, cursor.as_ref(), limit))).await
+    }
+
+    pub(crate) async fn run_blocking<T, F>(self: &Arc<Self>, operation: F) -> Result<T, FlowDbError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&StorageServer) -> Result<T, FlowDbError> + Send + 'static,
+    {
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || operation(&server)).await.map_err(|e| FlowDbError::Io(e.to_string()))?
+    }
+
+    /// Serves binary protocol requests from the client until it disconnects, as `serve_client`
+    /// does on a thread.
+    pub async fn serve_client_async<S: AsyncRead + AsyncWrite + Unpin>(self: &Arc<Self>, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut out = Vec::new();
+        while let Some(request) = read_request(&mut reader).await? {
+            let response = match request {
+                Request::Get { key } => Response::to_get(self.get_async(key).await),
+                Request::Put { key, value } => Response::to_put(self.put_async(key, value).await),
+                Request::Delete { key } => Response::to_delete(self.delete_async(key).await),
+                Request::Scan { prefix, limit } => {
+                    // The responses to earlier requests go out before the scan's chunks.
+                    reader.get_mut().write_all(&out).await?;
+                    out.clear();
+                    let limit = if limit == 0 { usize::MAX } else { limit as usize };
+                    match self.stream_scan_async(&prefix, limit, reader.get_mut()).await? {
+                        Ok(last) => Response::Entries(last),
+                        Err(e) => Response::Error(e.to_string()),
+                    }
+                }
+            };
+            out.extend_from_slice(&response.encode());
+            // Responses to pipelined requests are sent together once every buffered request is handled.
+            if reader.buffer().is_empty() || out.len() >= MAX_PENDING_RESPONSES {
+                reader.get_mut().write_all(&out).await?;
+                reader.get_mut().flush().await?;
+                out.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the entries of a scan as MORE_ENTRIES chunks and returns the last chunk, as
+    /// `stream_scan` does.
+    async fn stream_scan_async(
+        self: &Arc<Self>,
+        prefix: &[u8],
+        limit: usize,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<Result<Vec<(Vec<u8>, Vec<u8>)>, FlowDbError>> {
+        let mut chunks = ScanChunks::default();
+        let mut cursor = None;
+        loop {
+            let wanted = chunks.wanted(limit);
+            if wanted == 0 {
+                return Ok(Ok(chunks.finish()));
+            }
+            let (page, next) = match self.scan_prefix_page_async(prefix, cursor.take(), wanted).await {
+                Ok(page) => page,
+                Err(e) => return Ok(Err(e)),
+            };
+            for (key, value) in page {
+                if let Some(full) = chunks.push(key, value) {
+                    stream.write_all(&Response::MoreEntries(full).encode()).await?;
+                }
+            }
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(Ok(chunks.finish())),
+            }
+        }
+    }
+}
+
+/// Reads the next request, or None if the client closed the connection before sending one.
+async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Request>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut body = frame_buffer(len)?;
+    stream.read_exact(&mut body).await?;
+    Request::decode(&body).map(Some).ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid request"))
+}
+
+/// Accepts clients on the address and serves each as a task, over TLS if configured, until
+/// accepting fails. Must be run on a tokio runtime.
+pub async fn listen(server: Arc<StorageServer>, address: impl ToSocketAddrs, tls: Option<TlsConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Listening on {}", listener.local_addr()?);
+    serve(server, listener, tls.as_ref()).await
+}
+
+/// Accepts clients on the listener and serves each as a task, over TLS if configured, until
+/// accepting fails.
+pub async fn serve(server: Arc<StorageServer>, listener: TcpListener, tls: Option<&TlsConfig>) -> io::Result<()> {
+    let acceptor = tls.map(TlsConfig::acceptor).transpose()?.map(TlsAcceptor::from);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        let (server, acceptor) = (Arc::clone(&server), acceptor.clone());
+        tokio::spawn(async move {
+            let served = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => server.serve_client_async(stream).await,
+                    Err(e) => {
+                        error!("TLS handshake with protocol client failed: {}", e);
+                        return;
+                    }
+                },
+                None => server.serve_client_async(stream).await,
+            };
+            if let Err(e) = served {
+                error!("Error serving protocol client: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    async fn call(stream: &mut TcpStream, request: Request) -> Response {
+        stream.write_all(&request.encode()).await.unwrap();
+        read_response(stream).await
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> Response {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut body = frame_buffer(len).unwrap();
+        stream.read_exact(&mut body).await.unwrap();
+        Response::decode(&body).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_async_operations() {
+        let server = Arc::new(StorageServer::new(4, 2));
+        server.put_async("alice", "1").await.unwrap();
+        assert_eq!(server.get_async("alice").await, Ok(b"1".to_vec()));
+        assert_eq!(server.get_bytes("alice"), Ok(b"1".to_vec()));
+        assert_eq!(server.delete_async("alice").await, Ok(true));
+        assert_eq!(server.delete_async("alice").await, Ok(false));
+        assert_eq!(server.get_async("alice").await, Err(FlowDbError::NotFound));
+
+        server.multi_put(&[("user:1", "a"), ("user:2", "b"), ("other", "c")]).unwrap();
+        let (mut page, next) = server.scan_prefix_page_async("user:", None, 10).await.unwrap();
+        page.sort();
+        assert_eq!(page, vec![(b"user:1".to_vec(), b"a".to_vec()), (b"user:2".to_vec(), b"b".to_vec())]);
+        assert!(next.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_async_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(8, 2));
+        tokio::spawn(serve(Arc::clone(&server), listener, None));
+
+        // Far more clients than worker threads are served at once.
+        let clients: Vec<_> = (0..200)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(address).await.unwrap();
+                    let key = format!("client:{:03}", i).into_bytes();
+                    for round in 0..5 {
+                        let value = format!("{}", round).into_bytes();
+                        assert_eq!(call(&mut stream, Request::Put { key: key.clone(), value: value.clone() }).await, Response::Stored);
+                        assert_eq!(call(&mut stream, Request::Get { key: key.clone() }).await, Response::Value(value));
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.unwrap();
+        }
+        assert_eq!(server.get_bytes("client:123"), Ok(b"4".to_vec()));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert_eq!(call(&mut stream, Request::Get { key: b"missing".to_vec() }).await, Response::NotFound);
+        assert_eq!(call(&mut stream, Request::Delete { key: b"client:000".to_vec() }).await, Response::Deleted(true));
+
+        // Pipelined requests are answered in order, and a large scan is streamed in chunks.
+        let mut pipelined = Vec::new();
+        for i in 0..3 {
+            pipelined.extend_from_slice(&Request::Get { key: format!("client:{:03}", i + 1).into_bytes() }.encode());
+        }
+        stream.write_all(&pipelined).await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(read_response(&mut stream).await, Response::Value(b"4".to_vec()));
+        }
+        let items: Vec<String> = (0..600).map(|i| format!("item:{:03}", i)).collect();
+        server.multi_put(&items.iter().map(|key| (key.as_str(), [0; 8])).collect::<Vec<_>>()).unwrap();
+        stream.write_all(&Request::Scan { prefix: b"item:".to_vec(), limit: 0 }.encode()).await.unwrap();
+        let (mut chunks, mut keys) = (0, 0);
+        loop {
+            match read_response(&mut stream).await {
+                Response::MoreEntries(entries) => {
+                    chunks += 1;
+                    keys += entries.len();
+                }
+                Response::Entries(entries) => {
+                    keys += entries.len();
+                    break;
+                }
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+        assert!(chunks >= 2);
+        assert_eq!(keys, 600);
+    }
+}
@@ -0,0 +1,287 @@
+use std::borrow::Cow;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use arc_swap::ArcSwapOption;
+use crate::engine::{EngineIter, StorageEngine};
+use crate::entry::Entry;
+use crate::lsm::CompactionStats;
+
+/// The data structure used to store the entries of each partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Unordered storage backed by a hash map. Fastest for point lookups.
+    #[default]
+    Hash,
+    /// Key-ordered storage backed by a B-tree map, which makes range queries cheap.
+    Ordered,
+}
+
+/// The entries of a built-in map at one point in time.
+///
+/// The maps are persistent: cloning one is cheap and shares its entries with the original until
+/// either changes. Every change to a partition's map publishes a clone, which reads use without
+/// locking the partition.
+#[derive(Debug, Clone)]
+pub(crate) enum MapVersion {
+    Hash(imbl::HashMap<Vec<u8>, Entry>),
+    Ordered(imbl::OrdMap<Vec<u8>, Entry>),
+}
+
+impl MapVersion {
+    /// Returns the entry for the key unless it is missing or has expired.
+    pub(crate) fn get_live(&self, key: &[u8]) -> Option<&Entry> {
+        match self {
+            MapVersion::Hash(map) => map.get(key),
+            MapVersion::Ordered(map) => map.get(key),
+        }
+        .filter(|entry| entry.is_live())
+    }
+}
+
+impl StorageEngine for MapVersion {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        match self {
+            MapVersion::Hash(map) => map.get(key).map(Cow::Borrowed),
+            MapVersion::Ordered(map) => map.get(key).map(Cow::Borrowed),
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        match self {
+            MapVersion::Hash(map) => map.insert(key, entry),
+            MapVersion::Ordered(map) => map.insert(key, entry),
+        };
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        match self {
+            MapVersion::Hash(map) => map.remove(key),
+            MapVersion::Ordered(map) => map.remove(key),
+        }
+    }
+
+    fn scan(&self) -> EngineIter<'_> {
+        match self {
+            MapVersion::Hash(map) => Box::new(map.iter().map(|(key, entry)| (Cow::Borrowed(key), Cow::Borrowed(entry)))),
+            MapVersion::Ordered(map) => Box::new(map.iter().map(|(key, entry)| (Cow::Borrowed(key), Cow::Borrowed(entry)))),
+        }
+    }
+
+    fn range(&self, range: (Bound<&[u8]>, Bound<&[u8]>)) -> EngineIter<'_> {
+        match self {
+            MapVersion::Ordered(map) => Box::new(map.range::<_, [u8]>(range).map(|(key, entry)| (Cow::Borrowed(key), Cow::Borrowed(entry)))),
+            MapVersion::Hash(_) => {
+                let mut entries: Vec<_> = self.scan().filter(|(key, _)| RangeBounds::<[u8]>::contains(&range, key.as_slice())).collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                Box::new(entries.into_iter())
+            }
+        }
+    }
+}
+
+/// Where a partition's entries are kept.
+#[derive(Debug)]
+enum Store {
+    Map(MapVersion),
+    Engine(Box<dyn StorageEngine>),
+}
+
+/// The entries of a single partition, stored in a StorageEngine.
+///
+/// The memory the entries take is counted here for the built-in maps, which don't count it
+/// themselves, and asked of the engine otherwise.
+#[derive(Debug)]
+pub(crate) struct PartitionData {
+    store: Store,
+    /// The version of a built-in map that reads without the partition lock see, kept up to date
+    /// with every change. Always empty for engines.
+    published: Arc<ArcSwapOption<MapVersion>>,
+    /// The memory counted for the entries of a built-in map.
+    counted: Option<usize>,
+    /// The server-wide total that changes to the memory taken are added to, if there is one.
+    usage: Option<Arc<AtomicUsize>>,
+}
+
+impl PartitionData {
+    pub(crate) fn new(backend: Backend) -> Self {
+        let map = match backend {
+            Backend::Hash => MapVersion::Hash(imbl::HashMap::new()),
+            Backend::Ordered => MapVersion::Ordered(imbl::OrdMap::new()),
+        };
+        let published = Arc::new(ArcSwapOption::from_pointee(map.clone()));
+        Self { store: Store::Map(map), published, counted: Some(0), usage: None }
+    }
+
+    pub(crate) fn with_engine(engine: Box<dyn StorageEngine>) -> Self {
+        Self { store: Store::Engine(engine), published: Arc::new(ArcSwapOption::empty()), counted: None, usage: None }
+    }
+
+    fn engine(&self) -> &dyn StorageEngine {
+        match &self.store {
+            Store::Map(map) => map,
+            Store::Engine(engine) => &**engine,
+        }
+    }
+
+    fn engine_mut(&mut self) -> &mut dyn StorageEngine {
+        match &mut self.store {
+            Store::Map(map) => map,
+            Store::Engine(engine) => &mut **engine,
+        }
+    }
+
+    /// Returns where the versions of a built-in map are published. It stays with the partition
+    /// the data belongs to, even when the entries are swapped with another's.
+    pub(crate) fn published(&self) -> Arc<ArcSwapOption<MapVersion>> {
+        Arc::clone(&self.published)
+    }
+
+    /// Publishes the map as it is now, if it is a built-in one.
+    fn publish(&self) {
+        if let Store::Map(map) = &self.store {
+            self.published.store(Some(Arc::new(map.clone())));
+        }
+    }
+
+    /// Exchanges the entries of two partitions, each keeping where it publishes them, as when a
+    /// replica takes over from its leader.
+    pub(crate) fn swap(&mut self, other: &mut PartitionData) {
+        let (before, other_before) = (self.memory_bytes(), other.memory_bytes());
+        std::mem::swap(&mut self.store, &mut other.store);
+        std::mem::swap(&mut self.counted, &mut other.counted);
+        self.account(before);
+        other.account(other_before);
+        self.publish();
+        other.publish();
+    }
+
+    /// Returns about how many bytes of memory the entries take: their keys, stored values and
+    /// `ENTRY_OVERHEAD` each. Engines that don't count it are taken to hold nothing in memory.
+    pub(crate) fn memory_bytes(&self) -> usize {
+        self.counted.or_else(|| self.engine().memory_bytes()).unwrap_or(0)
+    }
+
+    /// Adds the memory the entries take to the total, and every change to it from now on.
+    pub(crate) fn track_memory(&mut self, usage: Arc<AtomicUsize>) {
+        usage.fetch_add(self.memory_bytes(), Ordering::Relaxed);
+        if let Some(previous) = self.usage.replace(usage) {
+            previous.fetch_sub(self.memory_bytes(), Ordering::Relaxed);
+        }
+    }
+
+    /// Adds the change in memory since it was `before` to the tracked total.
+    fn account(&self, before: usize) {
+        if let Some(usage) = &self.usage {
+            let after = self.memory_bytes();
+            match after >= before {
+                true => usage.fetch_add(after - before, Ordering::Relaxed),
+                false => usage.fetch_sub(before - after, Ordering::Relaxed),
+            };
+        }
+    }
+
+    /// Returns the entry for the key. Entries read from disk are returned owned.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        self.engine().get(key)
+    }
+
+    /// Returns the entry for the key unless it is missing or has expired.
+    pub(crate) fn get_live(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        self.get(key).filter(|entry| entry.is_live())
+    }
+
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Entry) {
+        self.insert_unpublished(key, value);
+        self.publish();
+    }
+
+    fn insert_unpublished(&mut self, key: Vec<u8>, value: Entry) {
+        let before = self.memory_bytes();
+        if self.counted.is_some() {
+            let previous = self.engine().get(&key).map_or(0, |entry| key.len() + entry.footprint());
+            self.counted = self.counted.map(|counted| counted + key.len() + value.footprint() - previous);
+        }
+        self.engine_mut().put(key, value);
+        self.account(before);
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let before = self.memory_bytes();
+        let removed = self.engine_mut().delete(key);
+        if let (Some(counted), Some(entry)) = (&mut self.counted, &removed) {
+            *counted -= key.len() + entry.footprint();
+        }
+        self.account(before);
+        self.publish();
+        removed
+    }
+
+    /// Inserts every entry, publishing the map once they are all in.
+    pub(crate) fn extend(&mut self, entries: impl IntoIterator<Item = (Vec<u8>, Entry)>) {
+        for (key, entry) in entries {
+            self.insert_unpublished(key, entry);
+        }
+        self.publish();
+    }
+
+    /// Writes any entries still held in memory to disk. Does nothing for the in-memory backends.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.engine_mut().flush()
+    }
+
+    pub(crate) fn compaction_stats(&self) -> Option<CompactionStats> {
+        self.engine().compaction_stats()
+    }
+
+    pub(crate) fn set_compaction_paused(&self, paused: bool) {
+        self.engine().set_compaction_paused(paused);
+    }
+
+    /// Returns the number of entries that have not expired.
+    pub(crate) fn live_len(&self) -> usize {
+        self.iter().filter(|(_, entry)| entry.is_live()).count()
+    }
+
+    /// Removes every entry that has expired as of `now`, returning the removed keys.
+    pub(crate) fn remove_expired(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        let before = self.memory_bytes();
+        let expired = self.engine_mut().remove_expired(now);
+        // The engine drops expired entries without counting them, so what is left is counted again.
+        if self.counted.is_some() && !expired.is_empty() {
+            self.counted = Some(self.iter().map(|(key, entry)| key.len() + entry.footprint()).sum());
+            self.publish();
+        }
+        self.account(before);
+        expired
+    }
+
+    /// Returns every entry of the partition, in the engine's order.
+    pub(crate) fn iter(&self) -> EngineIter<'_> {
+        self.engine().scan()
+    }
+
+    /// Returns the stored values of up to `limit` live entries whose keys fall within the range,
+    /// sorted by key, with the codec each value is in.
+    pub(crate) fn range(&self, range: (Bound<&[u8]>, Bound<&[u8]>), limit: usize) -> Vec<(Vec<u8>, Arc<[u8]>, u8)> {
+        self.engine()
+            .range(range)
+            .filter(|(_, entry)| entry.is_live() && !entry.is_collection())
+            .take(limit)
+            .map(|(key, entry)| {
+                let entry = entry.into_owned();
+                (key.into_owned(), entry.value, entry.codec)
+            })
+            .collect()
+    }
+}
+
+impl Drop for PartitionData {
+    fn drop(&mut self) {
+        if let Some(usage) = &self.usage {
+            usage.fetch_sub(self.memory_bytes(), Ordering::Relaxed);
+        }
+    }
+}
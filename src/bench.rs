@@ -0,0 +1,426 @@
+//! A load generator for comparing server settings, run by the binary's `bench` subcommand.
+//!
+//! A benchmark first writes every one of its keys once, then has each thread read or write keys
+//! picked uniformly at random until the duration is up, timing every operation. The server is
+//! either embedded, built from a configuration file so its settings can be compared, or a node
+//! reached over the binary protocol. Values are random bytes, so they don't shrink when compressed.
+//!
+//! The keys, named `bench-<n>`, are left in the server afterwards, so benchmark a server whose
+//! data can be overwritten.
+
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::error::FlowDbError;
+use crate::protocol::{Request, Response};
+use crate::storage_server::StorageServer;
+
+/// How many sub-buckets each power of two of nanoseconds is split into, bounding the error of a
+/// reported latency to 1/16 of it.
+const SUB_BUCKETS: u64 = 16;
+const LATENCY_BUCKETS: usize = 64 * SUB_BUCKETS as usize;
+
+/// The workload of a benchmark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchOptions {
+    /// The size of every value written, in bytes.
+    pub value_size: usize,
+    /// How many distinct keys are read and written.
+    pub keys: usize,
+    /// The share of operations that are reads, from 0 to 1; the rest are writes.
+    pub read_ratio: f64,
+    /// How many threads send operations at once.
+    pub threads: usize,
+    /// How long operations are sent for, not counting writing the keys beforehand.
+    pub duration: Duration,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self { value_size: 100, keys: 100_000, read_ratio: 0.9, threads: 4, duration: Duration::from_secs(10) }
+    }
+}
+
+/// The server a benchmark sends its operations to.
+#[derive(Clone)]
+pub enum BenchTarget {
+    /// A server in this process.
+    Embedded(Arc<StorageServer>),
+    /// A node's binary protocol address. Each thread opens its own plaintext connection.
+    Remote(String),
+}
+
+/// The options of the `bench` subcommand.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BenchArgs {
+    pub options: BenchOptions,
+    /// The configuration file the embedded server is built from, if not the default one.
+    pub config: Option<PathBuf>,
+    /// The node to benchmark instead of an embedded server.
+    pub remote: Option<String>,
+}
+
+impl BenchArgs {
+    /// Parses the arguments that follow `bench`: `--value-size`, `--keys`, `--read-ratio`,
+    /// `--threads` and `--duration` in seconds for the workload, and `--config` or `--remote`
+    /// for the server. Returns why they are invalid if they are.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = BenchArgs::default();
+        let mut args = args.into_iter();
+        while let Some(name) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", name))?;
+            let options = &mut parsed.options;
+            match name.as_str() {
+                "--value-size" => options.value_size = number(&name, &value)?,
+                "--keys" => options.keys = positive(&name, &value)?,
+                "--read-ratio" => {
+                    options.read_ratio = value.parse().ok().filter(|ratio| (0.0..=1.0).contains(ratio)).ok_or_else(|| format!("{} must be between 0 and 1", name))?
+                }
+                "--threads" => options.threads = positive(&name, &value)?,
+                "--duration" => options.duration = Duration::from_secs(positive(&name, &value)? as u64),
+                "--config" => parsed.config = Some(value.into()),
+                "--remote" => parsed.remote = Some(value),
+                _ => return Err(format!("{} is not an option", name)),
+            }
+        }
+        if parsed.config.is_some() && parsed.remote.is_some() {
+            return Err("--config and --remote can't be used together".to_owned());
+        }
+        Ok(parsed)
+    }
+}
+
+fn number(name: &str, value: &str) -> Result<usize, String> {
+    value.replace('_', "").parse().map_err(|_| format!("{} must be a number", name))
+}
+
+fn positive(name: &str, value: &str) -> Result<usize, String> {
+    number(name, value).ok().filter(|&value| value > 0).ok_or_else(|| format!("{} must be a positive number", name))
+}
+
+/// The counts and latencies a benchmark measured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchReport {
+    pub reads: u64,
+    pub writes: u64,
+    /// How many operations the server failed, which are counted among the reads and writes.
+    pub errors: u64,
+    /// How long operations were sent for.
+    pub elapsed: Duration,
+    latencies: Latencies,
+}
+
+impl BenchReport {
+    /// Returns how many operations were completed per second.
+    pub fn throughput(&self) -> f64 {
+        (self.reads + self.writes) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Returns the latency that the given share of operations, from 0 to 1, completed within,
+    /// rounded up to within 1/16 of it.
+    pub fn latency(&self, percentile: f64) -> Duration {
+        self.latencies.percentile(percentile)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} operations in {:.2?}: {:.0} ops/s ({} reads, {} writes, {} errors)",
+            self.reads + self.writes,
+            self.elapsed,
+            self.throughput(),
+            self.reads,
+            self.writes,
+            self.errors
+        )?;
+        write!(
+            f,
+            "latency: p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+            self.latency(0.5),
+            self.latency(0.9),
+            self.latency(0.99),
+            self.latency(0.999),
+            self.latency(1.0)
+        )
+    }
+}
+
+/// A histogram of latencies in nanoseconds. Each power of two is split into `SUB_BUCKETS`
+/// buckets, so a few kilobytes hold any number of operations with bounded error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Latencies {
+    buckets: Vec<u64>,
+    count: u64,
+}
+
+impl Latencies {
+    fn new() -> Self {
+        Self { buckets: vec![0; LATENCY_BUCKETS], count: 0 }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket(nanos)] += 1;
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &Latencies) {
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+    }
+
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_max(index));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// Returns the bucket of a latency: exact below `SUB_BUCKETS` nanoseconds, and above that the
+/// power of two it falls in and which of its sub-buckets.
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+    let shift = 63 - nanos.leading_zeros() - SUB_BUCKETS.trailing_zeros();
+    ((shift as u64 + 1) * SUB_BUCKETS + ((nanos >> shift) - SUB_BUCKETS)) as usize
+}
+
+/// Returns the largest latency that falls in the bucket.
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let low = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    low.saturating_add((1 << shift) - 1)
+}
+
+/// One thread's way of sending operations to the target.
+enum Connection<'a> {
+    Embedded(&'a StorageServer),
+    Remote(TcpStream),
+}
+
+impl Connection<'_> {
+    fn open(target: &BenchTarget) -> io::Result<Connection<'_>> {
+        Ok(match target {
+            BenchTarget::Embedded(server) => Connection::Embedded(server),
+            BenchTarget::Remote(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Connection::Remote(stream)
+            }
+        })
+    }
+
+    /// Reads the key, returning whether the server served it. A missing key is served.
+    fn get(&mut self, key: &[u8]) -> io::Result<bool> {
+        match self {
+            Connection::Embedded(server) => Ok(matches!(server.get_shared(key), Ok(_) | Err(FlowDbError::NotFound))),
+            Connection::Remote(stream) => Ok(matches!(call(stream, Request::Get { key: key.to_vec() })?, Response::Value(_) | Response::NotFound)),
+        }
+    }
+
+    /// Writes the key, returning whether the server stored it.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<bool> {
+        match self {
+            Connection::Embedded(server) => Ok(server.put(key, value).is_ok()),
+            Connection::Remote(stream) => Ok(call(stream, Request::Put { key: key.to_vec(), value: value.to_vec() })? == Response::Stored),
+        }
+    }
+}
+
+fn call(stream: &mut TcpStream, request: Request) -> io::Result<Response> {
+    stream.write_all(&request.encode())?;
+    Response::read_from(stream)
+}
+
+/// The counts and latencies of one thread.
+struct ThreadReport {
+    reads: u64,
+    writes: u64,
+    errors: u64,
+    latencies: Latencies,
+}
+
+/// Writes every key of the workload, then sends it to the target and measures it. Fails if a
+/// connection to a remote target can't be opened or breaks; operations a server fails are
+/// counted as errors instead.
+pub fn run(target: &BenchTarget, options: &BenchOptions) -> io::Result<BenchReport> {
+    if options.keys == 0 || options.threads == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "a benchmark needs keys and threads"));
+    }
+    let threads = options.threads;
+
+    // Each thread writes its share of the keys, so reads find them.
+    thread::scope(|scope| {
+        let writers: Vec<_> = (0..threads)
+            .map(|index| {
+                scope.spawn(move || -> io::Result<()> {
+                    let mut connection = Connection::open(target)?;
+                    let mut random = seed(index);
+                    let value = random_value(&mut random, options.value_size);
+                    for key in (index..options.keys).step_by(threads) {
+                        connection.put(&key_name(key), &value)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        writers.into_iter().try_for_each(|writer| writer.join().unwrap_or_else(|_| Err(Error::other("benchmark thread panicked"))))
+    })?;
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let reports = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|index| scope.spawn(move || measure(target, options, index, deadline)))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap_or_else(|_| Err(Error::other("benchmark thread panicked")))).collect::<io::Result<Vec<_>>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let mut report = BenchReport { reads: 0, writes: 0, errors: 0, elapsed, latencies: Latencies::new() };
+    for thread in reports {
+        report.reads += thread.reads;
+        report.writes += thread.writes;
+        report.errors += thread.errors;
+        report.latencies.merge(&thread.latencies);
+    }
+    Ok(report)
+}
+
+/// Sends one thread's operations until the deadline.
+fn measure(target: &BenchTarget, options: &BenchOptions, index: usize, deadline: Instant) -> io::Result<ThreadReport> {
+    let mut connection = Connection::open(target)?;
+    let mut random = seed(index + options.threads);
+    let value = random_value(&mut random, options.value_size);
+    let mut report = ThreadReport { reads: 0, writes: 0, errors: 0, latencies: Latencies::new() };
+    loop {
+        let started = Instant::now();
+        if started >= deadline {
+            return Ok(report);
+        }
+        let key = key_name(next_random(&mut random) as usize % options.keys);
+        let read = unit_random(&mut random) < options.read_ratio;
+        let served = match read {
+            true => connection.get(&key)?,
+            false => connection.put(&key, &value)?,
+        };
+        report.latencies.record(started.elapsed());
+        match read {
+            true => report.reads += 1,
+            false => report.writes += 1,
+        }
+        report.errors += u64::from(!served);
+    }
+}
+
+fn key_name(key: usize) -> Vec<u8> {
+    format!("bench-{}", key).into_bytes()
+}
+
+fn random_value(random: &mut u64, size: usize) -> Vec<u8> {
+    (0..size).map(|_| next_random(random) as u8).collect()
+}
+
+/// Returns a distinct, nonzero starting state for each thread's random numbers.
+fn seed(index: usize) -> u64 {
+    (index as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+/// Returns the next number of a xorshift64* sequence.
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Returns a random number from 0 up to, but not including, 1.
+fn unit_random(state: &mut u64) -> f64 {
+    (next_random(state) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_bench() {
+        let options = BenchOptions { value_size: 64, keys: 200, read_ratio: 0.5, threads: 2, duration: Duration::from_millis(200) };
+        let server = Arc::new(StorageServer::new(4, 1));
+        let report = run(&BenchTarget::Embedded(Arc::clone(&server)), &options).unwrap();
+        assert!(report.reads > 0 && report.writes > 0, "{}", report);
+        assert_eq!(report.errors, 0);
+        assert_eq!(server.len(), 200);
+        assert!(report.latency(0.5) <= report.latency(0.99) && report.latency(0.99) <= report.latency(1.0));
+        assert!(report.to_string().contains("ops/s"));
+
+        // A node is benchmarked over the binary protocol, with a connection per thread.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let remote = Arc::new(StorageServer::new(4, 1));
+        {
+            let remote = Arc::clone(&remote);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let remote = Arc::clone(&remote);
+                    thread::spawn(move || remote.serve_client(stream.unwrap()));
+                }
+            });
+        }
+        let report = run(&BenchTarget::Remote(address), &BenchOptions { read_ratio: 1.0, ..options.clone() }).unwrap();
+        assert!(report.reads > 0 && report.writes == 0 && report.errors == 0, "{}", report);
+        assert_eq!(remote.len(), 200);
+        assert!(run(&BenchTarget::Remote("127.0.0.1:1".to_owned()), &options).is_err());
+    }
+
+    #[test]
+    fn test_latencies() {
+        for nanos in [0, 1, 15, 16, 17, 31, 32, 1000, 123_456_789, u64::MAX] {
+            let bucket = bucket(nanos);
+            assert!(bucket_max(bucket) >= nanos && (bucket == 0 || bucket_max(bucket - 1) < nanos), "{} ns", nanos);
+            assert!(bucket_max(bucket) - nanos <= nanos / SUB_BUCKETS, "{} ns", nanos);
+        }
+        let mut latencies = Latencies::new();
+        for micros in 1..=100 {
+            latencies.record(Duration::from_micros(micros));
+        }
+        let p50 = latencies.percentile(0.5);
+        assert!(p50 >= Duration::from_micros(50) && p50 <= Duration::from_micros(54), "{:?}", p50);
+        assert!(latencies.percentile(1.0) >= Duration::from_micros(100));
+        assert_eq!(Latencies::new().percentile(0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_bench_args() {
+        let args = |args: &str| BenchArgs::parse(args.split_whitespace().map(str::to_owned));
+        let parsed = args("--keys 1_000 --read-ratio 0.5 --threads 8 --duration 30 --remote 10.0.0.1:7070").unwrap();
+        let expected = BenchOptions { keys: 1000, read_ratio: 0.5, threads: 8, duration: Duration::from_secs(30), ..BenchOptions::default() };
+        assert_eq!(parsed, BenchArgs { options: expected, config: None, remote: Some("10.0.0.1:7070".to_owned()) });
+        assert_eq!(args("").unwrap(), BenchArgs::default());
+        assert_eq!(args("--read-ratio 2"), Err("--read-ratio must be between 0 and 1".to_owned()));
+        assert_eq!(args("--threads 0"), Err("--threads must be a positive number".to_owned()));
+        assert_eq!(args("--keys"), Err("--keys needs a value".to_owned()));
+        assert_eq!(args("--verbose 1"), Err("--verbose is not an option".to_owned()));
+        assert_eq!(args("--config a.toml --remote b:1"), Err("--config and --remote can't be used together".to_owned()));
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::ring;
+
+/// A probabilistic set of keys that answers "definitely absent" or "possibly present".
+///
+/// Used to skip reading an SSTable from disk when the key being looked up is not in it.
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized to hold `expected_items` keys with the given false-positive rate.
+    pub(crate) fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let expected_items = expected_items.max(1) as f64;
+        let false_positive_rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-expected_items * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / expected_items) * ln2).round().max(1.0) as u32;
+        Self { bits: vec![0; num_bits.div_ceil(64) as usize], num_bits, num_hashes }
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        for bit in bit_positions(key, self.num_bits, self.num_hashes) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if the key was never inserted; true means it probably was.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        bit_positions(key, self.num_bits, self.num_hashes).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// Derives a key's bit positions in a filter of `num_bits` bits from two hashes (Kirsch-Mitzenmacher double hashing).
+fn bit_positions(key: &[u8], num_bits: u64, num_hashes: u32) -> impl Iterator<Item = u64> {
+    let mut hasher = DefaultHasher::new();
+    ring::hash_key(key, &mut hasher);
+    let first = hasher.finish();
+    first.hash(&mut hasher);
+    let second = hasher.finish() | 1;
+    (0..num_hashes as u64).map(move |i| first.wrapping_add(i.wrapping_mul(second)) % num_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        assert!((0..1000).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+        let false_positives = (0..10_000).filter(|i| filter.may_contain(format!("other{}", i).as_bytes())).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
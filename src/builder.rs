@@ -0,0 +1,351 @@
+//! Configuring a StorageServer in one place, with the combination of settings checked before the
+//! server is created.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::info;
+use crate::backend::Backend;
+use crate::codec::{Codec, NoCompression, SharedCodec, Snappy};
+use crate::encoding::Encoding;
+use crate::error::FlowDbError;
+use crate::eviction::{EvictionPolicy, MemoryPolicy};
+use crate::lsm::LsmOptions;
+use crate::replication::ReplicationMode;
+use crate::ring::Partitioner;
+use crate::storage_server::{StorageServer, MIN_COMPRESS_SIZE};
+use crate::transaction_log::{SyncPolicy, TransactionLog};
+
+/// The size at which the transaction log of a built server moves on to a new segment, by default.
+const WAL_SEGMENT_SIZE: u64 = 10 << 20;
+/// How many transaction log segments a built server keeps, by default.
+const WAL_MAX_FILES: u32 = 10;
+const WAL_READ_BUFFER_SIZE: usize = 8192;
+
+/// Where a built server keeps its partitions' entries.
+#[derive(Debug, Clone, PartialEq)]
+enum Storage {
+    Memory(Backend),
+    Lsm(PathBuf, LsmOptions),
+    MemoryBudget(PathBuf, usize),
+    DataDir(PathBuf),
+}
+
+/// Settings for a StorageServer, created with `StorageServer::builder`.
+///
+/// By default a server has 16 partitions of 3 copies each, held in memory with Snappy-compressed values,
+/// and no transaction log. Each storage setting (`backend`, `lsm`, `memory_budget` and `data_dir`)
+/// replaces the one set before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageServerBuilder {
+    partitions: usize,
+    replicas: usize,
+    virtual_nodes: Option<usize>,
+    partitioner: Partitioner,
+    storage: Storage,
+    codec: SharedCodec,
+    min_compress_size: usize,
+    default_ttl: Option<Duration>,
+    encoding: Encoding,
+    wal: Option<PathBuf>,
+    wal_segment_size: u64,
+    wal_max_files: u32,
+    sync_policy: Option<SyncPolicy>,
+    replication_mode: ReplicationMode,
+    read_quorum: usize,
+    eviction: Option<(usize, EvictionPolicy)>,
+    memory_limit: Option<(usize, MemoryPolicy)>,
+    read_cache: Option<usize>,
+    tombstone_grace: Option<Duration>,
+    hint_ttl: Option<Duration>,
+}
+
+impl Default for StorageServerBuilder {
+    fn default() -> Self {
+        Self {
+            partitions: 16,
+            replicas: 3,
+            virtual_nodes: None,
+            partitioner: Partitioner::default(),
+            storage: Storage::Memory(Backend::default()),
+            codec: SharedCodec(Arc::new(Snappy)),
+            min_compress_size: MIN_COMPRESS_SIZE,
+            default_ttl: None,
+            encoding: Encoding::default(),
+            wal: None,
+            wal_segment_size: WAL_SEGMENT_SIZE,
+            wal_max_files: WAL_MAX_FILES,
+            sync_policy: None,
+            replication_mode: ReplicationMode::default(),
+            read_quorum: 1,
+            eviction: None,
+            memory_limit: None,
+            read_cache: None,
+            tombstone_grace: None,
+            hint_ttl: None,
+        }
+    }
+}
+
+impl StorageServerBuilder {
+    /// Sets the number of partitions.
+    pub fn partitions(mut self, partitions: usize) -> Self {
+        self.partitions = partitions;
+        self
+    }
+
+    /// Sets how many copies each partition has, counting the primary.
+    pub fn replicas(mut self, replicas: usize) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// Sets how many points each partition has on the hash ring, as with `StorageServer::with_virtual_nodes`.
+    pub fn virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        self.virtual_nodes = Some(virtual_nodes);
+        self
+    }
+
+    /// Sets the hash function that routes keys to partitions, as with `StorageServer::with_partitioner`.
+    pub fn partitioner(mut self, partitioner: Partitioner) -> Self {
+        self.partitioner = partitioner;
+        self
+    }
+
+    /// Keeps the entries in memory in the given structure.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.storage = Storage::Memory(backend);
+        self
+    }
+
+    /// Keeps the entries in LSM trees under the directory, as with `StorageServer::with_lsm`.
+    pub fn lsm(mut self, path: impl Into<PathBuf>, options: LsmOptions) -> Self {
+        self.storage = Storage::Lsm(path.into(), options);
+        self
+    }
+
+    /// Keeps at most `budget` bytes of entries in memory and spills the rest to files in the
+    /// directory, as with `StorageServer::with_memory_budget`.
+    pub fn memory_budget(mut self, path: impl Into<PathBuf>, budget: usize) -> Self {
+        self.storage = Storage::MemoryBudget(path.into(), budget);
+        self
+    }
+
+    /// Keeps the entries in memory and persists them to the directory, as with `StorageServer::open`.
+    pub fn data_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.storage = Storage::DataDir(path.into());
+        self
+    }
+
+    /// Sets whether values are compressed, with Snappy if they are.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.codec = match compression {
+            true => SharedCodec(Arc::new(Snappy)),
+            false => SharedCodec(Arc::new(NoCompression)),
+        };
+        self
+    }
+
+    /// Sets the codec values are compressed with, as with `StorageServer::with_codec`.
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.codec = SharedCodec(codec);
+        self
+    }
+
+    /// Sets the size in bytes below which values are stored uncompressed, as with
+    /// `StorageServer::with_min_compress_size`.
+    pub fn min_compress_size(mut self, bytes: usize) -> Self {
+        self.min_compress_size = bytes;
+        self
+    }
+
+    /// Sets the TTL applied to values put without one.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the serialization format used by `put_typed` and `get_typed`.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Logs every mutation to a transaction log in the directory. The records already there are
+    /// replayed when the server is built, so it starts with the writes logged before it last stopped.
+    pub fn wal(mut self, path: impl Into<PathBuf>) -> Self {
+        self.wal = Some(path.into());
+        self
+    }
+
+    /// Sets the size at which the transaction log moves on to a new segment, 10 MiB by default, and
+    /// how many segments it keeps, 10 by default.
+    pub fn wal_rotation(mut self, segment_size: u64, max_files: u32) -> Self {
+        self.wal_segment_size = segment_size;
+        self.wal_max_files = max_files;
+        self
+    }
+
+    /// Sets when the transaction log is synced to disk. Needs a `wal`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = Some(policy);
+        self
+    }
+
+    /// Sets how many copies each write updates before it returns, as with `StorageServer::with_replication_mode`.
+    pub fn replication_mode(mut self, mode: ReplicationMode) -> Self {
+        self.replication_mode = mode;
+        self
+    }
+
+    /// Sets how many copies `get` reads, at most the number of replicas, as with `StorageServer::with_read_quorum`.
+    pub fn read_quorum(mut self, copies: usize) -> Self {
+        self.read_quorum = copies;
+        self
+    }
+
+    /// Caps the size of each partition, as with `StorageServer::with_eviction`. Can't be combined
+    /// with a `memory_budget`, which already bounds the memory used.
+    pub fn eviction(mut self, max_bytes_per_partition: usize, policy: EvictionPolicy) -> Self {
+        self.eviction = Some((max_bytes_per_partition, policy));
+        self
+    }
+
+    /// Limits the memory the partitions take, as with `StorageServer::with_memory_limit`. Evicting to
+    /// stay under the limit can't be combined with a `memory_budget`, like `eviction`.
+    pub fn memory_limit(mut self, max_bytes: usize, policy: MemoryPolicy) -> Self {
+        self.memory_limit = Some((max_bytes, policy));
+        self
+    }
+
+    /// Caches up to `max_bytes` of recently read values decompressed, as with `StorageServer::with_read_cache`.
+    pub fn read_cache(mut self, max_bytes: usize) -> Self {
+        self.read_cache = Some(max_bytes);
+        self
+    }
+
+    /// Makes deletes leave tombstones for `grace`, as with `StorageServer::with_tombstones`.
+    pub fn tombstones(mut self, grace: Duration) -> Self {
+        self.tombstone_grace = Some(grace);
+        self
+    }
+
+    /// Keeps writes a replica misses as hints for up to `ttl`, as with `StorageServer::with_hinted_handoff`.
+    pub fn hinted_handoff(mut self, ttl: Duration) -> Self {
+        self.hint_ttl = Some(ttl);
+        self
+    }
+
+    /// Checks the settings and creates the server, opening its files and replaying its transaction
+    /// log. Fails with `InvalidArgument` if a count or size is 0, the read quorum is above the number of
+    /// replicas, a sync policy is set without a `wal`, or eviction, or a memory limit that evicts, is
+    /// combined with a `memory_budget`, and with `Io` if a file can't be opened or the log replayed.
+    pub fn build(self) -> Result<StorageServer, FlowDbError> {
+        let invalid = self.partitions == 0
+            || self.replicas == 0
+            || self.virtual_nodes == Some(0)
+            || self.wal_segment_size == 0
+            || self.wal_max_files == 0
+            || !(1..=self.replicas).contains(&self.read_quorum)
+            || (self.sync_policy.is_some() && self.wal.is_none())
+            || matches!(self.memory_limit, Some((0, _)))
+            || ((self.eviction.is_some() || matches!(self.memory_limit, Some((_, MemoryPolicy::Evict)))) && matches!(self.storage, Storage::MemoryBudget(..)));
+        if invalid {
+            return Err(FlowDbError::InvalidArgument);
+        }
+
+        let (partitions, replicas) = (self.partitions, self.replicas);
+        let mut server = match &self.storage {
+            Storage::Memory(backend) => StorageServer::with_backend(partitions, replicas, *backend),
+            Storage::Lsm(path, options) => StorageServer::with_lsm(path, partitions, replicas, *options)?,
+            Storage::MemoryBudget(path, budget) => StorageServer::with_memory_budget(path, partitions, replicas, *budget)?,
+            Storage::DataDir(_) => StorageServer::new(partitions, replicas),
+        };
+        // Keys are routed by the ring, so it is settled before any are loaded.
+        server = server.with_partitioner(self.partitioner);
+        if let Some(virtual_nodes) = self.virtual_nodes {
+            server = server.with_virtual_nodes(virtual_nodes);
+        }
+        server = server
+            .with_value_options(self.codec.0, self.default_ttl)?
+            .with_min_compress_size(self.min_compress_size)
+            .with_encoding(self.encoding);
+        if let Storage::DataDir(path) = &self.storage {
+            server = server.with_data_dir(path)?;
+        }
+        if let Some(ttl) = self.hint_ttl {
+            server = server.with_hinted_handoff(ttl);
+        }
+        server = server.with_replication_mode(self.replication_mode).with_read_quorum(self.read_quorum);
+        if let Some((max_bytes, policy)) = self.eviction {
+            server = server.with_eviction(max_bytes, policy);
+        }
+        if let Some((max_bytes, policy)) = self.memory_limit {
+            server = server.with_memory_limit(max_bytes, policy);
+        }
+        if let Some(grace) = self.tombstone_grace {
+            server = server.with_tombstones(grace);
+        }
+        if let Some(max_bytes) = self.read_cache {
+            server = server.with_read_cache(max_bytes);
+        }
+
+        let Some(wal) = &self.wal else {
+            return Ok(server);
+        };
+        let replayed = server.recover(wal)?;
+        info!("Replayed {} transaction log records from {}", replayed, wal.display());
+        let path = wal.to_str().ok_or(FlowDbError::InvalidArgument)?;
+        let mut log = TransactionLog::new(path, self.wal_segment_size, self.wal_max_files, WAL_READ_BUFFER_SIZE, Box::new(|data| data.to_vec()))?;
+        if let Some(policy) = self.sync_policy {
+            log = log.with_sync_policy(policy)?;
+        }
+        Ok(server.with_transaction_log(Arc::new(Mutex::new(log))))
+    }
+}
+
+impl StorageServer {
+    /// Starts configuring a server, to be created with `StorageServerBuilder::build`.
+    pub fn builder() -> StorageServerBuilder {
+        StorageServerBuilder::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_builder() {
+        let dir = "logs/test_builder";
+        let _ = fs::remove_dir_all(dir);
+        let builder = || StorageServer::builder().partitions(4).replicas(2).virtual_nodes(16).data_dir(format!("{}/data", dir)).wal(format!("{}/wal", dir));
+        {
+            let server = builder().sync_policy(SyncPolicy::Always).build().unwrap();
+            assert_eq!((server.partition_count(), server.topology().leadership.copies(0).len()), (4, 2));
+            server.put("flushed", "value1").unwrap();
+            server.flush().unwrap();
+            server.put("logged", "value2").unwrap();
+        }
+
+        // A rebuilt server reloads the flushed data and replays the log on top of it.
+        let server = builder().build().unwrap();
+        assert_eq!(server.get("flushed"), Ok("value1".to_owned()));
+        assert_eq!(server.get("logged"), Ok("value2".to_owned()));
+        let ttl = StorageServer::builder().replicas(1).compression(false).default_ttl(Duration::from_secs(60)).build().unwrap();
+        ttl.put("key", "value").unwrap();
+        assert!(ttl.get_partition(b"key").read().unwrap().data.get(b"key").unwrap().expires_at.is_some());
+
+        // Settings that don't go together are rejected.
+        let invalid = [
+            StorageServer::builder().partitions(0),
+            StorageServer::builder().replicas(2).read_quorum(3),
+            StorageServer::builder().sync_policy(SyncPolicy::Always),
+            StorageServer::builder().memory_budget(format!("{}/spill", dir), 1 << 20).eviction(1 << 10, EvictionPolicy::default()),
+        ];
+        for builder in invalid {
+            assert_eq!(builder.build().err(), Some(FlowDbError::InvalidArgument));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::{self, BufRead, ErrorKind, Write};
+use serde::{Deserialize, Serialize};
+
+/// The file format used by `StorageServer::import` and `StorageServer::export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    /// A sequence of records, each a big-endian u32 key length, the key bytes, a big-endian u32
+    /// value length, and the value bytes. Supports arbitrary binary keys and values.
+    #[default]
+    Binary,
+    /// One JSON object per line, `{"key": "...", "value": "..."}`. Keys and values must be valid UTF-8.
+    Ndjson,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: String,
+}
+
+/// Reads the next key-value record, returning None at a clean end of input.
+pub(crate) fn read_record(reader: &mut impl BufRead, format: DumpFormat) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match format {
+        DumpFormat::Binary => {
+            let Some(key) = read_field(reader, true)? else {
+                return Ok(None);
+            };
+            let value = read_field(reader, false)?.unwrap_or_default();
+            Ok(Some((key, value)))
+        }
+        DumpFormat::Ndjson => {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                // Tolerate blank lines, e.g. a trailing newline at the end of the file.
+                if !line.trim().is_empty() {
+                    break;
+                }
+            }
+            let record: JsonRecord = serde_json::from_str(&line).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            Ok(Some((record.key.into_bytes(), record.value.into_bytes())))
+        }
+    }
+}
+
+/// Reads a length-prefixed field. If `allow_eof` is set, end of input before the length yields None.
+fn read_field(reader: &mut impl BufRead, allow_eof: bool) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    if allow_eof && reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len)?;
+    let mut field = vec![0u8; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut field)?;
+    Ok(Some(field))
+}
+
+/// Writes a single key-value record, e.g. to produce a file for `StorageServer::import`.
+pub fn write_record(writer: &mut impl Write, format: DumpFormat, key: &[u8], value: &[u8]) -> io::Result<()> {
+    match format {
+        DumpFormat::Binary => {
+            writer.write_all(&(key.len() as u32).to_be_bytes())?;
+            writer.write_all(key)?;
+            writer.write_all(&(value.len() as u32).to_be_bytes())?;
+            writer.write_all(value)
+        }
+        DumpFormat::Ndjson => {
+            let utf8 = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+            serde_json::to_writer(&mut *writer, &JsonRecord { key: utf8(key)?, value: utf8(value)? })?;
+            writer.write_all(b"\n")
+        }
+    }
+}
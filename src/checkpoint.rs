@@ -0,0 +1,67 @@
+//! Periodic checkpoints, which persist a server's changed partitions and mark the point in its
+//! transaction log that they cover, so recovery replays only the log's tail.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::{error, info};
+use crate::storage_server::StorageServer;
+use crate::transaction_log::Lsn;
+
+/// What a checkpoint wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointReport {
+    /// How many partitions had changed since the last checkpoint and were written.
+    pub partitions: usize,
+    /// The LSN of the checkpoint record, or None if the server has no transaction log. For a
+    /// `SharedTransactionLog` it is the record's sequence number.
+    pub lsn: Option<Lsn>,
+}
+
+/// A background thread that periodically checkpoints a StorageServer.
+///
+/// Failed checkpoints are logged and retried at the next interval. The thread is stopped when the
+/// checkpointer is stopped or dropped.
+pub struct Checkpointer {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Checkpointer {
+    pub(crate) fn spawn(server: Arc<StorageServer>, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || loop {
+            thread::park_timeout(interval);
+            if thread_stop.load(Ordering::Acquire) {
+                break;
+            }
+            match server.checkpoint() {
+                Ok(report) if report.partitions > 0 => info!("Checkpointed {} partitions at {:?}", report.partitions, report.lsn),
+                Ok(_) => {}
+                Err(e) => error!("Checkpoint failed: {}", e),
+            }
+        });
+        Self { stop, handle: Some(handle) }
+    }
+
+    /// Stops the checkpointer thread and waits for it to exit. A checkpoint under way is finished.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
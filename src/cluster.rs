@@ -0,0 +1,209 @@
+//! Spreading a server's partitions over several nodes.
+//!
+//! Every node runs a StorageServer with the same number of partitions, virtual nodes and partitioner, so all
+//! of them route a key to the same partition index. A node marks the partitions that other nodes own
+//! as remote with `StorageServer::with_remote_partition`, and `get`, `put` and `delete` of their
+//! keys are sent to the owner, which serves them from its local partition with `serve_node`.
+//!
+//! Each message is a big-endian u32 length followed by a bincode-encoded request or response. A
+//! connection carries one request at a time, answered in order.
+
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::error::FlowDbError;
+use crate::replication::ReplicationReport;
+use crate::storage_server::StorageServer;
+
+/// The largest message accepted, so a corrupt length can't make a node allocate without bound.
+const MAX_MESSAGE_LEN: u32 = 64 << 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Get(Vec<u8>),
+    Put { key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration> },
+    Delete(Vec<u8>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    Value(Result<Vec<u8>, FlowDbError>),
+    Put(Result<ReplicationReport, FlowDbError>),
+    Delete(Result<bool, FlowDbError>),
+}
+
+/// A node that owns some of a server's partitions, reached over TCP.
+///
+/// One connection is kept open and reused for every request, and reopened when the node closed it.
+#[derive(Debug)]
+pub struct RemoteNode {
+    address: SocketAddr,
+    connection: Mutex<Option<TcpStream>>,
+}
+
+impl RemoteNode {
+    /// Creates a handle to the node listening at the address. No connection is made until the
+    /// first request.
+    pub fn new(address: SocketAddr) -> Self {
+        Self { address, connection: Mutex::new(None) }
+    }
+
+    /// Returns the address of the node.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        match self.call(&Request::Get(key.to_vec())) {
+            Ok(Response::Value(value)) => value,
+            _ => Err(FlowDbError::Unavailable),
+        }
+    }
+
+    pub(crate) fn put(&self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<ReplicationReport, FlowDbError> {
+        match self.call(&Request::Put { key: key.to_vec(), value: value.to_vec(), ttl }) {
+            Ok(Response::Put(report)) => report,
+            _ => Err(FlowDbError::Unavailable),
+        }
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> Result<bool, FlowDbError> {
+        match self.call(&Request::Delete(key.to_vec())) {
+            Ok(Response::Delete(existed)) => existed,
+            _ => Err(FlowDbError::Unavailable),
+        }
+    }
+
+    fn call(&self, request: &Request) -> io::Result<Response> {
+        let mut connection = self.connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = match connection.take() {
+            // The node may have closed an idle connection, so a failure on one is retried once on a
+            // new connection. A request the node did apply is then applied again, which leaves the
+            // same value, though a repeated delete reports the key as missing.
+            Some(mut stream) => exchange(&mut stream, request).map(|response| (stream, response)).or_else(|_| self.connect_and_exchange(request)),
+            None => self.connect_and_exchange(request),
+        };
+        match result {
+            Ok((stream, response)) => {
+                *connection = Some(stream);
+                Ok(response)
+            }
+            Err(e) => {
+                warn!("Request to node {} failed: {}", self.address, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn connect_and_exchange(&self, request: &Request) -> io::Result<(TcpStream, Response)> {
+        let mut stream = TcpStream::connect(self.address)?;
+        stream.set_nodelay(true)?;
+        let response = exchange(&mut stream, request)?;
+        Ok((stream, response))
+    }
+}
+
+fn exchange(stream: &mut TcpStream, request: &Request) -> io::Result<Response> {
+    write_message(stream, request)?;
+    read_message(stream)?.ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "node closed the connection"))
+}
+
+fn write_message(stream: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    let payload = bincode::serialize(message).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Reads the next message, or None if the peer closed the connection before starting one.
+fn read_message<T: DeserializeOwned>(stream: &mut impl Read) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("message of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    bincode::deserialize(&payload).map(Some).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+impl StorageServer {
+    /// Serves the requests other nodes send for the partitions this node owns, until the
+    /// connection is closed or fails.
+    pub fn serve_node<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        while let Some(request) = read_message(&mut stream)? {
+            let response = match request {
+                Request::Get(key) => Response::Value(self.get_bytes(&key)),
+                Request::Put { key, value, ttl } => Response::Put(self.put_value(&key, &value, ttl).map(|(_, report)| report)),
+                Request::Delete(key) => Response::Delete(self.delete(&key)),
+            };
+            write_message(&mut stream, &response)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_remote_partitions() {
+        let owner = Arc::new(StorageServer::new(4, 2));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        {
+            let owner = Arc::clone(&owner);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let owner = Arc::clone(&owner);
+                    thread::spawn(move || owner.serve_node(stream.unwrap()));
+                }
+            });
+        }
+
+        // This node keeps partition 0 and leaves the others to the owner.
+        let node = Arc::new(RemoteNode::new(address));
+        let server = (1..4).fold(StorageServer::new(4, 2), |server, partition| server.with_remote_partition(partition, Arc::clone(&node)));
+        let keys: Vec<_> = (0..40).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            server.put(key, format!("value of {}", key)).unwrap();
+        }
+        for key in &keys {
+            let local = server.partition_index(key.as_bytes()) == 0;
+            assert_eq!(server.get(key), Ok(format!("value of {}", key)));
+            assert_eq!(server.get_partition(key.as_bytes()).read().unwrap().data.get_live(key.as_bytes()).is_some(), local);
+            assert_eq!(owner.get(key).is_ok(), !local);
+        }
+
+        let remote_key = keys.iter().find(|key| server.partition_index(key.as_bytes()) != 0).unwrap();
+        assert_eq!(server.delete(remote_key), Ok(true));
+        assert_eq!(server.get(remote_key), Err(FlowDbError::NotFound));
+        assert_eq!(owner.get(remote_key), Err(FlowDbError::NotFound));
+        server.put_with_ttl(remote_key, "short-lived", Duration::from_millis(20)).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(server.get(remote_key), Err(FlowDbError::NotFound));
+    }
+
+    #[test]
+    fn test_unreachable_node() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = StorageServer::new(1, 1).with_remote_partition(0, Arc::new(RemoteNode::new(address)));
+        assert_eq!(server.get("key"), Err(FlowDbError::Unavailable));
+        assert!(server.put("key", "value").is_err());
+        assert!(server.delete("key").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! Group apply of concurrent writes, so a burst of small writes to one partition pays for a single
+//! lock acquisition and log record instead of one each.
+//!
+//! Writes are queued by the partition they target. Whoever queues a write while no batch of its
+//! queue is being applied becomes the leader: it takes every write queued so far, applies them
+//! together, and hands each writer its result. Writes queued meanwhile wait and form the next batch,
+//! so a lone write is applied at once and batches only grow while writes contend.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// How many queues a coalescer has. Partitions share a queue when there are more of them.
+pub(crate) const WRITE_QUEUES: usize = 64;
+
+pub(crate) struct WriteCoalescer<W, R> {
+    queues: Box<[Queue<W, R>]>,
+}
+
+struct Queue<W, R> {
+    state: Mutex<QueueState<W, R>>,
+    /// Notified whenever a batch has been applied.
+    applied: Condvar,
+}
+
+struct QueueState<W, R> {
+    /// Whether a leader is applying a batch.
+    applying: bool,
+    next_ticket: u64,
+    /// The writes waiting for the next batch, with their tickets.
+    pending: Vec<(u64, W)>,
+    /// The result of each applied write until its writer collects it, or None if the leader
+    /// applying it panicked.
+    results: HashMap<u64, Option<R>>,
+}
+
+impl<W, R> Queue<W, R> {
+    fn lock(&self) -> MutexGuard<'_, QueueState<W, R>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Publishes the results of a leader's batch when dropped, even if applying it panicked.
+struct Leading<'a, W, R> {
+    queue: &'a Queue<W, R>,
+    tickets: Vec<u64>,
+    results: Vec<R>,
+}
+
+impl<W, R> Drop for Leading<'_, W, R> {
+    fn drop(&mut self) {
+        let mut state = self.queue.lock();
+        let mut results = std::mem::take(&mut self.results).into_iter();
+        for &ticket in &self.tickets {
+            state.results.insert(ticket, results.next());
+        }
+        state.applying = false;
+        drop(state);
+        self.queue.applied.notify_all();
+    }
+}
+
+impl<W, R> WriteCoalescer<W, R> {
+    pub(crate) fn new(queues: usize) -> Self {
+        let queues = (0..queues.max(1))
+            .map(|_| Queue {
+                state: Mutex::new(QueueState { applying: false, next_ticket: 0, pending: Vec::new(), results: HashMap::new() }),
+                applied: Condvar::new(),
+            })
+            .collect();
+        Self { queues }
+    }
+
+    /// Queues the write on the queue of `partition` and returns its result once it has been applied,
+    /// either by this caller with `apply` or by the leader of the batch it joined.
+    ///
+    /// `apply` gets the writes of a batch in the order they were queued and returns their results in
+    /// the same order. Returns None if the batch's leader panicked before returning the write's result.
+    pub(crate) fn submit(&self, partition: usize, write: W, apply: impl FnOnce(Vec<W>) -> Vec<R>) -> Option<R> {
+        let queue = &self.queues[partition % self.queues.len()];
+        let mut state = queue.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, write));
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
+            }
+            if !state.applying {
+                break;
+            }
+            state = queue.applied.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+
+        // No batch is being applied, so the write is still pending and this caller leads the next one.
+        let (tickets, writes) = state.pending.drain(..).unzip();
+        state.applying = true;
+        drop(state);
+        let mut leading = Leading { queue, tickets, results: Vec::new() };
+        leading.results = apply(writes);
+        drop(leading);
+        queue.lock().results.remove(&ticket).flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_write_coalescer() {
+        let coalescer: Arc<WriteCoalescer<usize, usize>> = Arc::new(WriteCoalescer::new(2));
+        assert_eq!(coalescer.submit(0, 1, |writes| writes.iter().map(|write| write * 10).collect()), Some(10));
+
+        // Writes queued while a batch is applied are applied together as the next batch.
+        let batches = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (coalescer, batches) = (Arc::clone(&coalescer), Arc::clone(&batches));
+                thread::spawn(move || {
+                    coalescer.submit(0, i, |writes| {
+                        batches.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        writes.iter().map(|write| write * 10).collect()
+                    })
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(results, (0..8).map(|i| Some(i * 10)).collect::<Vec<_>>());
+        assert!(batches.load(Ordering::SeqCst) < 8);
+
+        // A leader that panics fails its batch without leaving the queue stuck.
+        let panicking = Arc::clone(&coalescer);
+        assert!(thread::spawn(move || panicking.submit(1, 1, |_| panic!("apply failed"))).join().is_err());
+        assert_eq!(coalescer.submit(1, 2, |writes| writes), Some(2));
+        assert_eq!(coalescer.submit(1, 3, |_| Vec::new()), None);
+    }
+}
@@ -0,0 +1,571 @@
+//! The compression formats values are stored in.
+//!
+//! Every entry records the id of the codec its value was compressed with, so a server whose codec
+//! is changed keeps reading the values stored before the change.
+
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use snap::raw::Decoder as SnapDecoder;
+use snap::raw::Encoder as SnapEncoder;
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use crate::error::FlowDbError;
+
+/// The id of `NoCompression`, recorded for values stored as they are.
+pub const NO_COMPRESSION_ID: u8 = 0;
+/// The id of `Snappy`.
+pub const SNAPPY_ID: u8 = 1;
+/// The id of `Zstd`.
+pub const ZSTD_ID: u8 = 2;
+/// The id of `Lz4`.
+pub const LZ4_ID: u8 = 3;
+
+/// A compression format for stored values.
+///
+/// The id is stored with every value the codec compresses and picks the codec that decompresses it,
+/// so it must stay the same for as long as any such value is kept. Codecs with the same id are
+/// treated as the same format. Ids up to `LZ4_ID` are taken by the built-in codecs.
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// Returns the id recorded with the values the codec compresses.
+    fn id(&self) -> u8;
+
+    /// Compresses the value.
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError>;
+
+    /// Recovers a value compressed by `compress`, failing with `CorruptValue` if the bytes aren't
+    /// a valid compressed value.
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError>;
+
+    /// Returns the length of the value the bytes decompress to, if it can be read without
+    /// decompressing them.
+    fn decompressed_len(&self, _data: &[u8]) -> Option<usize> {
+        None
+    }
+
+    /// Trains the codec on sample values, so it compresses values like them better from now on.
+    /// Fails with `Unsupported` for codecs that can't be trained.
+    fn train(&self, _samples: &[Vec<u8>]) -> Result<(), FlowDbError> {
+        Err(FlowDbError::Unsupported)
+    }
+
+    /// Keeps whatever the codec needs to read its values, such as trained dictionaries, in the
+    /// directory from now on, and reads back what was kept there before, so values it compressed
+    /// stay readable after a restart. Does nothing for codecs that need nothing but the bytes.
+    fn store_in(&self, _dir: &Path) -> Result<(), FlowDbError> {
+        Ok(())
+    }
+}
+
+/// A codec held by settings, compared by id so the settings can still be compared.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedCodec(pub(crate) Arc<dyn Codec>);
+
+impl PartialEq for SharedCodec {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id() == other.0.id()
+    }
+}
+
+impl Eq for SharedCodec {}
+
+/// Stores values as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl Codec for NoCompression {
+    fn id(&self) -> u8 {
+        NO_COMPRESSION_ID
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        Ok(data.to_vec())
+    }
+
+    fn decompressed_len(&self, data: &[u8]) -> Option<usize> {
+        Some(data.len())
+    }
+}
+
+/// Compresses values with Snappy's raw format, which is fast and what servers use by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Snappy;
+
+impl Codec for Snappy {
+    fn id(&self) -> u8 {
+        SNAPPY_ID
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        SnapEncoder::new().compress_vec(data).map_err(|e| {
+            log::error!("Value of {} bytes can't be compressed: {}", data.len(), e);
+            FlowDbError::InvalidArgument
+        })
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        SnapDecoder::new().decompress_vec(data).map_err(|_| FlowDbError::CorruptValue)
+    }
+
+    fn decompressed_len(&self, data: &[u8]) -> Option<usize> {
+        snap::raw::decompress_len(data).ok()
+    }
+}
+
+/// Compresses values with LZ4's block format, prefixed with their length. It compresses less than
+/// zstd but decompresses faster than any other codec here.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+impl Codec for Lz4 {
+    fn id(&self) -> u8 {
+        LZ4_ID
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        Ok(lz4_flex::compress_prepend_size(data))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        lz4_flex::decompress_size_prepended(data).map_err(|_| FlowDbError::CorruptValue)
+    }
+
+    fn decompressed_len(&self, data: &[u8]) -> Option<usize> {
+        Some(u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize)
+    }
+}
+
+/// The zstd level servers compress at unless told otherwise, which is zstd's own default.
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// The size in bytes dictionaries are trained to, which suits values of up to a few kilobytes.
+pub const DICTIONARY_SIZE: usize = 16 * 1024;
+
+/// The start of the names of the files `Zstd` stores its dictionaries in, each followed by a
+/// sequence number so they are reloaded in the order they were adopted.
+const DICTIONARY_FILE_PREFIX: &str = "zstd-dictionary-";
+
+/// A trained zstd dictionary, prepared for compressing and decompressing.
+struct Dictionary {
+    id: u32,
+    bytes: Vec<u8>,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+/// Compresses values with zstd, optionally with a dictionary trained on samples of the values.
+///
+/// Small values that share their structure, such as JSON documents of one kind, barely compress on
+/// their own; with a dictionary that holds what they have in common they compress several times
+/// better. Every frame records the id of the dictionary it was compressed with, and the codec keeps
+/// every dictionary it has used, so values compressed before a dictionary was trained or replaced
+/// stay readable. Once a server stores the codec in its directory, each dictionary is written there
+/// before any value is compressed with it and reloaded when the server is reopened.
+pub struct Zstd {
+    level: i32,
+    /// Every dictionary the codec has used, the one new values are compressed with last.
+    dictionaries: RwLock<Vec<Arc<Dictionary>>>,
+    /// The values kept as samples until there are this many to train a dictionary on, if the
+    /// codec trains itself.
+    samples: Mutex<(Vec<Vec<u8>>, usize)>,
+    sampling: AtomicBool,
+    /// The directory dictionaries are written to, held while one is adopted.
+    dir: Mutex<Option<PathBuf>>,
+}
+
+impl Zstd {
+    /// Compresses at the zstd level, such as `ZSTD_LEVEL`, without a dictionary.
+    pub fn new(level: i32) -> Self {
+        Self {
+            level,
+            dictionaries: RwLock::new(Vec::new()),
+            samples: Mutex::new((Vec::new(), 0)),
+            sampling: AtomicBool::new(false),
+            dir: Mutex::new(None),
+        }
+    }
+
+    /// Compresses with the dictionary, as returned by `dictionary`, failing with `InvalidArgument`
+    /// if it isn't a trained zstd dictionary.
+    pub fn with_dictionary(level: i32, dictionary: Vec<u8>) -> Result<Self, FlowDbError> {
+        let codec = Self::new(level);
+        codec.use_dictionary(dictionary)?;
+        Ok(codec)
+    }
+
+    /// Keeps the first `samples` values compressed as samples, then trains a dictionary on them
+    /// and compresses with it from then on. Values compressed before are compressed without one.
+    pub fn trained(level: i32, samples: usize) -> Self {
+        let codec = Self::new(level);
+        *codec.samples.lock().unwrap_or_else(PoisonError::into_inner) = (Vec::with_capacity(samples), samples);
+        codec.sampling.store(samples > 0, Ordering::Relaxed);
+        codec
+    }
+
+    /// Returns the dictionary new values are compressed with, if there is one, so it can be stored
+    /// and passed to `with_dictionary` later.
+    pub fn dictionary(&self) -> Option<Vec<u8>> {
+        self.current().map(|dictionary| dictionary.bytes.clone())
+    }
+
+    /// Compresses new values with the dictionary from now on, keeping the ones used before. It is
+    /// written to the codec's directory first, if it has one.
+    fn use_dictionary(&self, bytes: Vec<u8>) -> Result<(), FlowDbError> {
+        let id = dictionary_id(&bytes)?;
+        let dir = self.dir.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(dir) = &*dir {
+            write_dictionary(dir, &bytes)?;
+        }
+        self.adopt(id, bytes);
+        Ok(())
+    }
+
+    fn adopt(&self, id: u32, bytes: Vec<u8>) {
+        let dictionary = Dictionary { id, encoder: EncoderDictionary::copy(&bytes, self.level), decoder: DecoderDictionary::copy(&bytes), bytes };
+        let mut dictionaries = self.dictionaries.write().unwrap_or_else(PoisonError::into_inner);
+        dictionaries.retain(|known| known.id != id);
+        dictionaries.push(Arc::new(dictionary));
+    }
+
+    fn current(&self) -> Option<Arc<Dictionary>> {
+        self.dictionaries.read().unwrap_or_else(PoisonError::into_inner).last().cloned()
+    }
+
+    /// Keeps the value as a sample, training a dictionary once there are enough. If one can't be
+    /// trained, `train` logs why and the codec carries on without one.
+    fn sample(&self, data: &[u8]) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        if !self.sampling.load(Ordering::Relaxed) {
+            return;
+        }
+        samples.0.push(data.to_vec());
+        if samples.0.len() < samples.1 {
+            return;
+        }
+        self.sampling.store(false, Ordering::Relaxed);
+        let taken = std::mem::take(&mut samples.0);
+        drop(samples);
+        let _ = self.train(&taken);
+    }
+}
+
+fn dictionary_id(bytes: &[u8]) -> Result<u32, FlowDbError> {
+    Ok(zstd::zstd_safe::get_dict_id_from_dict(bytes).ok_or(FlowDbError::InvalidArgument)?.get())
+}
+
+/// Returns the dictionary files in the directory with their sequence numbers, in order.
+fn dictionary_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>, FlowDbError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let sequence = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_prefix(DICTIONARY_FILE_PREFIX)?.strip_suffix(".bin")?.parse().ok());
+        if let Some(sequence) = sequence {
+            files.push((sequence, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Writes the dictionary to the directory after those already there, replacing nothing and
+/// syncing it before it is used.
+fn write_dictionary(dir: &Path, bytes: &[u8]) -> Result<(), FlowDbError> {
+    let sequence = dictionary_files(dir)?.last().map_or(0, |(sequence, _)| sequence + 1);
+    let path = dir.join(format!("{}{:06}.bin", DICTIONARY_FILE_PREFIX, sequence));
+    let temp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)?;
+    Ok(())
+}
+
+impl fmt::Debug for Zstd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dictionaries: Vec<u32> = self.dictionaries.read().unwrap_or_else(PoisonError::into_inner).iter().map(|dictionary| dictionary.id).collect();
+        f.debug_struct("Zstd").field("level", &self.level).field("dictionaries", &dictionaries).finish()
+    }
+}
+
+impl Codec for Zstd {
+    fn id(&self) -> u8 {
+        ZSTD_ID
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        if self.sampling.load(Ordering::Relaxed) {
+            self.sample(data);
+        }
+        let compressed = match self.current() {
+            Some(dictionary) => Compressor::with_prepared_dictionary(&dictionary.encoder).and_then(|mut compressor| compressor.compress(data)),
+            None => zstd::bulk::compress(data, self.level),
+        };
+        compressed.map_err(|e| {
+            log::error!("Value of {} bytes can't be compressed: {}", data.len(), e);
+            FlowDbError::InvalidArgument
+        })
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+        let len = self.decompressed_len(data).ok_or(FlowDbError::CorruptValue)?;
+        let decompressed = match zstd::zstd_safe::get_dict_id_from_frame(data) {
+            Some(id) => {
+                let dictionaries = self.dictionaries.read().unwrap_or_else(PoisonError::into_inner);
+                let Some(dictionary) = dictionaries.iter().find(|dictionary| dictionary.id == id.get()) else {
+                    log::error!("Stored value is compressed with unknown zstd dictionary {}", id);
+                    return Err(FlowDbError::CorruptValue);
+                };
+                Decompressor::with_prepared_dictionary(&dictionary.decoder).and_then(|mut decompressor| decompressor.decompress(data, len))
+            }
+            None => zstd::bulk::decompress(data, len),
+        };
+        decompressed.map_err(|_| FlowDbError::CorruptValue)
+    }
+
+    fn decompressed_len(&self, data: &[u8]) -> Option<usize> {
+        zstd::zstd_safe::get_frame_content_size(data).ok().flatten().map(|len| len as usize)
+    }
+
+    fn train(&self, samples: &[Vec<u8>]) -> Result<(), FlowDbError> {
+        let dictionary = zstd::dict::from_samples(samples, DICTIONARY_SIZE).map_err(|e| {
+            log::error!("zstd dictionary can't be trained on {} samples: {}", samples.len(), e);
+            FlowDbError::InvalidArgument
+        })?;
+        self.use_dictionary(dictionary)
+    }
+
+    /// Reloads the dictionaries stored in the directory, then stores the ones the codec had that
+    /// aren't there yet, so the one it compressed with stays the one it compresses with. A codec
+    /// that trains itself stops sampling if a dictionary was reloaded, since it was trained before.
+    fn store_in(&self, dir: &Path) -> Result<(), FlowDbError> {
+        let mut stored_dir = self.dir.lock().unwrap_or_else(PoisonError::into_inner);
+        fs::create_dir_all(dir)?;
+        let earlier: Vec<(u32, Vec<u8>)> = self.dictionaries.read().unwrap_or_else(PoisonError::into_inner).iter().map(|dictionary| (dictionary.id, dictionary.bytes.clone())).collect();
+        let mut stored = Vec::new();
+        for (_, path) in dictionary_files(dir)? {
+            let bytes = fs::read(&path)?;
+            let id = dictionary_id(&bytes).inspect_err(|_| log::error!("{} is not a zstd dictionary", path.display()))?;
+            stored.push(id);
+            self.adopt(id, bytes);
+        }
+        if !stored.is_empty() {
+            self.sampling.store(false, Ordering::Relaxed);
+        }
+        for (id, bytes) in earlier {
+            if !stored.contains(&id) {
+                write_dictionary(dir, &bytes)?;
+            }
+            self.adopt(id, bytes);
+        }
+        *stored_dir = Some(dir.to_owned());
+        Ok(())
+    }
+}
+
+/// A built-in codec, as named in a server's settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Values are stored as they are.
+    None,
+    #[default]
+    Snappy,
+    Lz4,
+    /// Values are compressed with zstd at the level, from 1, the fastest, to 22.
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Returns the codec that compresses values this way.
+    pub fn codec(self) -> Arc<dyn Codec> {
+        match self {
+            Compression::None => Arc::new(NoCompression),
+            Compression::Snappy => Arc::new(Snappy),
+            Compression::Lz4 => Arc::new(Lz4),
+            Compression::Zstd(level) => Arc::new(Zstd::new(level)),
+        }
+    }
+}
+
+/// The codec a server compresses new values with, and every codec it knows how to read values
+/// from: the built-in ones and any it was set to before.
+#[derive(Debug, Clone)]
+pub(crate) struct Codecs {
+    current: Arc<dyn Codec>,
+    known: Vec<Arc<dyn Codec>>,
+}
+
+impl Codecs {
+    pub(crate) fn new(current: Arc<dyn Codec>) -> Self {
+        let mut codecs = Self { current: Arc::new(NoCompression), known: vec![Arc::new(NoCompression), Arc::new(Snappy), Arc::new(Zstd::new(ZSTD_LEVEL)), Arc::new(Lz4)] };
+        codecs.set(current);
+        codecs
+    }
+
+    /// Compresses new values with the codec from now on, still reading values stored with the
+    /// codecs used before. A codec replaces a known one with the same id.
+    pub(crate) fn set(&mut self, codec: Arc<dyn Codec>) {
+        self.known.retain(|known| known.id() != codec.id());
+        self.known.push(Arc::clone(&codec));
+        self.current = codec;
+    }
+
+    /// Returns the codec new values are compressed with.
+    pub(crate) fn current(&self) -> &Arc<dyn Codec> {
+        &self.current
+    }
+
+    /// Keeps what every known codec needs to read its values in the directory, as `Codec::store_in`.
+    pub(crate) fn store_in(&self, dir: &Path) -> Result<(), FlowDbError> {
+        self.known.iter().try_for_each(|codec| codec.store_in(dir))
+    }
+
+    /// Converts a value into the bytes stored in an entry and the id of the codec they are in. Values
+    /// smaller than `min_size` are stored as they are.
+    pub(crate) fn encode(&self, data: &[u8], min_size: usize) -> Result<(Vec<u8>, u8), FlowDbError> {
+        if data.len() < min_size {
+            return Ok((data.to_vec(), NO_COMPRESSION_ID));
+        }
+        Ok((self.current.compress(data)?, self.current.id()))
+    }
+
+    /// Recovers the original value from the bytes stored in an entry with the codec.
+    pub(crate) fn decode(&self, stored: &[u8], codec: u8) -> Result<Vec<u8>, FlowDbError> {
+        match codec {
+            NO_COMPRESSION_ID => Ok(stored.to_vec()),
+            codec => self.get(codec)?.decompress(stored),
+        }
+    }
+
+    /// Returns the length of the original value without decoding it, or the stored length if it
+    /// can't be determined.
+    pub(crate) fn decoded_len(&self, stored: &[u8], codec: u8) -> usize {
+        self.get(codec).ok().and_then(|codec| codec.decompressed_len(stored)).unwrap_or(stored.len())
+    }
+
+    fn get(&self, id: u8) -> Result<&Arc<dyn Codec>, FlowDbError> {
+        self.known.iter().find(|codec| codec.id() == id).ok_or_else(|| {
+            log::error!("Stored value is compressed with unknown codec {}", id);
+            FlowDbError::CorruptValue
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Reversed;
+
+    impl Codec for Reversed {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+            Ok(data.iter().rev().copied().collect())
+        }
+
+        fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, FlowDbError> {
+            self.compress(data)
+        }
+    }
+
+    #[test]
+    fn test_snappy() {
+        let data = b"hello world";
+        let compressed = Snappy.compress(data).unwrap();
+        assert_ne!(compressed, data);
+        assert_eq!(Snappy.decompress(&compressed).unwrap(), data);
+        assert_eq!(Snappy.decompressed_len(&compressed), Some(data.len()));
+        assert_eq!(Snappy.decompress(&[0xff; 4]), Err(FlowDbError::CorruptValue));
+    }
+
+    /// A small JSON document like many others.
+    fn document(i: usize) -> Vec<u8> {
+        format!(r#"{{"id":{},"name":"user{}","email":"user{}@example.com","active":{},"roles":["reader","writer"],"created_at":"2024-01-{:02}T10:00:00Z"}}"#, i, i, i, i.is_multiple_of(2), i % 28 + 1).into_bytes()
+    }
+
+    #[test]
+    fn test_zstd() {
+        let plain = Zstd::new(ZSTD_LEVEL);
+        let data = document(0);
+        let compressed = plain.compress(&data).unwrap();
+        assert_eq!(plain.decompress(&compressed).unwrap(), data);
+        assert_eq!(plain.decompressed_len(&compressed), Some(data.len()));
+        assert_eq!(plain.decompress(&[0xff; 4]), Err(FlowDbError::CorruptValue));
+        assert_eq!(Zstd::with_dictionary(ZSTD_LEVEL, b"not a dictionary".to_vec()).unwrap_err(), FlowDbError::InvalidArgument);
+
+        // A codec that trains itself compresses with its dictionary once it has enough samples.
+        let trained = Zstd::trained(ZSTD_LEVEL, 500);
+        let before = trained.compress(&document(1)).unwrap();
+        for i in 2..501 {
+            trained.compress(&document(i)).unwrap();
+        }
+        let dictionary = trained.dictionary().unwrap();
+        let after = trained.compress(&document(1000)).unwrap();
+        assert!(after.len() * 2 < plain.compress(&document(1000)).unwrap().len());
+        assert_eq!(trained.decompress(&after).unwrap(), document(1000));
+        assert_eq!(trained.decompress(&before).unwrap(), document(1));
+
+        // Values compressed with an earlier dictionary stay readable after training a new one.
+        let samples: Vec<Vec<u8>> = (0..500).map(|i| document(i).repeat(2)).collect();
+        trained.train(&samples).unwrap();
+        assert_ne!(trained.dictionary(), Some(dictionary.clone()));
+        assert_eq!(trained.decompress(&after).unwrap(), document(1000));
+
+        // A dictionary that was stored reads the values compressed with it, and only it does.
+        assert_eq!(Zstd::with_dictionary(ZSTD_LEVEL, dictionary).unwrap().decompress(&after).unwrap(), document(1000));
+        assert_eq!(plain.decompress(&after), Err(FlowDbError::CorruptValue));
+        assert_eq!(Snappy.train(&samples), Err(FlowDbError::Unsupported));
+    }
+
+    #[test]
+    fn test_lz4() {
+        let data = b"value value value value value value value value".repeat(4);
+        let compressed = Lz4.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(Lz4.decompress(&compressed).unwrap(), data);
+        assert_eq!(Lz4.decompressed_len(&compressed), Some(data.len()));
+        assert_eq!(Lz4.decompress(&[0xff; 4]), Err(FlowDbError::CorruptValue));
+        assert_eq!(Lz4.decompressed_len(&[0; 2]), None);
+        assert_eq!(Lz4.decompress(&Lz4.compress(b"").unwrap()).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_codecs() {
+        let mut codecs = Codecs::new(Arc::new(Snappy));
+        let data = b"value value value value";
+        let (snappy, id) = codecs.encode(data, 0).unwrap();
+        assert_eq!(id, SNAPPY_ID);
+        assert_eq!(codecs.encode(data, data.len() + 1).unwrap(), (data.to_vec(), NO_COMPRESSION_ID));
+
+        // Values stored before a codec change stay readable, whichever codec wrote them.
+        codecs.set(Arc::new(Reversed));
+        let (reversed, id) = codecs.encode(data, 0).unwrap();
+        assert_eq!((reversed.first(), id), (data.last(), 200));
+        assert_eq!(codecs.decode(&snappy, SNAPPY_ID).unwrap(), data);
+        assert_eq!(codecs.decode(&reversed, 200).unwrap(), data);
+        assert_eq!(codecs.decoded_len(&snappy, SNAPPY_ID), data.len());
+        assert_eq!(codecs.decoded_len(&reversed, 200), reversed.len());
+        assert_eq!(codecs.decode(&snappy, ZSTD_ID), Err(FlowDbError::CorruptValue));
+
+        // Every built-in codec can be named in the settings, and each reads back what it wrote.
+        for compression in [Compression::None, Compression::Snappy, Compression::Lz4, Compression::Zstd(19)] {
+            let codec = compression.codec();
+            codecs.set(Arc::clone(&codec));
+            let (stored, id) = codecs.encode(data, 0).unwrap();
+            assert_eq!(id, codec.id());
+            assert_eq!(Codecs::new(Arc::new(Snappy)).decode(&stored, id).unwrap(), data);
+        }
+        assert_eq!(SharedCodec(Arc::new(Snappy)), SharedCodec(Arc::new(Snappy)));
+        assert_ne!(SharedCodec(Arc::new(Snappy)), SharedCodec(Arc::new(Reversed)));
+    }
+}
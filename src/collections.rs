@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use serde::{Deserialize, Serialize};
+use crate::entry::Entry;
+use crate::error::FlowDbError;
+use crate::storage_server::StorageServer;
+use crate::transaction_log::LogRecord;
+
+/// A structured value stored under a single key, operated on server-side by the list, set, and hash commands.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Collection {
+    List(VecDeque<Vec<u8>>),
+    Set(BTreeSet<Vec<u8>>),
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+}
+
+impl Collection {
+    /// Returns the total length of the collection's elements.
+    pub(crate) fn byte_len(&self) -> usize {
+        match self {
+            Collection::List(list) => list.iter().map(Vec::len).sum(),
+            Collection::Set(set) => set.iter().map(Vec::len).sum(),
+            Collection::Hash(hash) => hash.iter().map(|(field, value)| field.len() + value.len()).sum(),
+        }
+    }
+}
+
+/// A command that changes a collection, as written to the transaction log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CollectionOp {
+    /// Prepends the values to a list, one at a time.
+    ListPush { values: Vec<Vec<u8>> },
+    /// Adds the members to a set.
+    SetAdd { members: Vec<Vec<u8>> },
+    /// Sets a field of a hash.
+    HashSet { field: Vec<u8>, value: Vec<u8> },
+}
+
+impl CollectionOp {
+    /// Returns the empty collection the command creates when the key is missing.
+    fn create(&self) -> Collection {
+        match self {
+            CollectionOp::ListPush { .. } => Collection::List(VecDeque::new()),
+            CollectionOp::SetAdd { .. } => Collection::Set(BTreeSet::new()),
+            CollectionOp::HashSet { .. } => Collection::Hash(BTreeMap::new()),
+        }
+    }
+
+    /// Runs the command on the collection, returning the list's new length, how many set members
+    /// were new, or 1 if the hash field was new and 0 otherwise.
+    fn apply(&self, collection: &mut Collection) -> Result<usize, FlowDbError> {
+        match (self, collection) {
+            (CollectionOp::ListPush { values }, Collection::List(list)) => {
+                for value in values {
+                    list.push_front(value.clone());
+                }
+                Ok(list.len())
+            }
+            (CollectionOp::SetAdd { members }, Collection::Set(set)) => Ok(members.iter().filter(|member| set.insert(member.to_vec())).count()),
+            (CollectionOp::HashSet { field, value }, Collection::Hash(hash)) => Ok(usize::from(hash.insert(field.clone(), value.clone()).is_none())),
+            _ => Err(FlowDbError::InvalidValue),
+        }
+    }
+}
+
+/// Redis-style list, set, and hash operations.
+///
+/// Collections are kept as structured values inside the partition, so a mutation only touches the
+/// affected elements instead of decoding and re-encoding the whole collection. Using a collection
+/// command on a key that holds a different type (including a plain value) returns an error. Each
+/// mutation is logged as the command that made it, so it is replayed on recovery.
+impl StorageServer {
+    /// Prepends the values to the list stored at the key, creating it if needed, and returns the new length.
+    ///
+    /// Values are pushed one at a time, so the last value ends up at the head of the list.
+    pub fn lpush(&self, key: impl AsRef<[u8]>, values: &[&[u8]]) -> Result<usize, FlowDbError> {
+        let values = values.iter().map(|value| value.to_vec()).collect();
+        self.modify_collection(key.as_ref(), &CollectionOp::ListPush { values }, true)
+    }
+
+    /// Returns the elements of the list between `start` and `stop` inclusive.
+    ///
+    /// Negative indexes count from the end of the list, so `lrange(key, 0, -1)` returns every element.
+    pub fn lrange(&self, key: impl AsRef<[u8]>, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, FlowDbError> {
+        self.read_collection(key.as_ref(), |collection| match collection {
+            None => Ok(Vec::new()),
+            Some(Collection::List(list)) => {
+                let len = list.len() as i64;
+                let resolve = |index: i64| if index < 0 { len + index } else { index };
+                let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+                if start > stop {
+                    return Ok(Vec::new());
+                }
+                Ok(list.range(start as usize..=stop as usize).cloned().collect())
+            }
+            Some(_) => Err(FlowDbError::InvalidValue),
+        })
+    }
+
+    /// Adds the members to the set stored at the key, creating it if needed, and returns how many were new.
+    pub fn sadd(&self, key: impl AsRef<[u8]>, members: &[&[u8]]) -> Result<usize, FlowDbError> {
+        let members = members.iter().map(|member| member.to_vec()).collect();
+        self.modify_collection(key.as_ref(), &CollectionOp::SetAdd { members }, true)
+    }
+
+    /// Returns every member of the set stored at the key, in ascending order.
+    pub fn smembers(&self, key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>, FlowDbError> {
+        self.read_collection(key.as_ref(), |collection| match collection {
+            None => Ok(Vec::new()),
+            Some(Collection::Set(set)) => Ok(set.iter().cloned().collect()),
+            Some(_) => Err(FlowDbError::InvalidValue),
+        })
+    }
+
+    /// Sets the field of the hash stored at the key, creating it if needed, and returns whether the field is new.
+    pub fn hset(&self, key: impl AsRef<[u8]>, field: &[u8], value: &[u8]) -> Result<bool, FlowDbError> {
+        let op = CollectionOp::HashSet { field: field.to_vec(), value: value.to_vec() };
+        self.modify_collection(key.as_ref(), &op, true).map(|new| new == 1)
+    }
+
+    /// Returns the value of the field of the hash stored at the key, if present.
+    pub fn hget(&self, key: impl AsRef<[u8]>, field: &[u8]) -> Result<Option<Vec<u8>>, FlowDbError> {
+        self.read_collection(key.as_ref(), |collection| match collection {
+            None => Ok(None),
+            Some(Collection::Hash(hash)) => Ok(hash.get(field).cloned()),
+            Some(_) => Err(FlowDbError::InvalidValue),
+        })
+    }
+
+    /// Runs `f` on the collection stored at the key (None if missing) under the partition read lock.
+    fn read_collection<T>(&self, key: &[u8], f: impl FnOnce(Option<&Collection>) -> Result<T, FlowDbError>) -> Result<T, FlowDbError> {
+        let _routing = self.enter();
+        let partition = self.get_partition(key);
+        let partition_guard = partition.read()?;
+        match partition_guard.data.get_live(key) {
+            None => f(None),
+            Some(entry) => f(Some(entry.collection.as_ref().ok_or(FlowDbError::InvalidValue)?)),
+        }
+    }
+
+    /// Runs the command on a copy of the collection stored at the key under the key lock, creating
+    /// an empty one if the key is missing, then stores the result on the primary and replica
+    /// partitions. The command is logged first if `log` is set; replay doesn't log it again.
+    pub(crate) fn modify_collection(&self, key: &[u8], op: &CollectionOp, log: bool) -> Result<usize, FlowDbError> {
+        let _routing = self.enter();
+        let _key_lock = self.key_locks.lock(key);
+        let partition = self.get_partition(key);
+        let mut entry = match self.live_entry(&partition, key)? {
+            Some(existing) if existing.collection.is_none() => return Err(FlowDbError::InvalidValue),
+            Some(existing) => existing,
+            None => Entry::with_collection(op.create()),
+        };
+        let result = op.apply(entry.collection.as_mut().ok_or(FlowDbError::InvalidValue)?)?;
+        if log {
+            self.log_write(&partition, &LogRecord::Collection { key: key.to_vec(), op: op.clone() })?;
+        }
+        let mut partition_guard = partition.write()?;
+        self.store_entry(&mut partition_guard, key, entry);
+        Ok(result)
+    }
+}
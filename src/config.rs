@@ -0,0 +1,473 @@
+//! The settings the server binary starts with, read from a TOML file.
+//!
+//! | table       | key                       | value                                              | default            |
+//! |-------------|---------------------------|----------------------------------------------------|--------------------|
+//! | `[server]`  | `address`                 | where binary protocol clients connect              | `127.0.0.1:7070`   |
+//! |             | `http_address`            | where the HTTP API listens                         | `127.0.0.1:7080`   |
+//! |             | `resp_address`            | where Redis clients connect                        | `127.0.0.1:6379`   |
+//! |             | `memcached_address`       | where memcached clients connect                    | `127.0.0.1:11211`  |
+//! |             | `grpc_address`            | where gRPC clients connect                         | `127.0.0.1:7090`   |
+//! | `[storage]` | `partitions`, `replicas`  | the number of partitions and copies of each        | 16 and 3           |
+//! |             | `compression`             | `"snappy"`, `"lz4"`, `"zstd"` or `"none"`          | `"snappy"`         |
+//! |             | `zstd_level`              | the level `"zstd"` compresses at, from 1 to 22     | 3                  |
+//! |             | `min_compress_size`       | the smallest value in bytes that is compressed     | 64                 |
+//! |             | `data_dir`                | where partitions are persisted, if anywhere        | none, in memory    |
+//! |             | `partitioner`             | how keys are hashed, `"xxhash64"` or `"siphash"`   | `"xxhash64"`       |
+//! |             | `default_ttl_secs`        | the TTL of values put without one                  | none               |
+//! |             | `read_cache_bytes`        | the size of the cache of decompressed values       | none, uncached     |
+//! | `[wal]`     | `dir`                     | the transaction log's directory                    | `logs/wal`         |
+//! |             | `enabled`                 | whether mutations are logged                       | `true`             |
+//! |             | `segment_size`            | the size in bytes at which a new segment starts    | 10 MiB             |
+//! |             | `max_files`               | how many segments are kept                         | 10                 |
+//! |             | `sync`                    | `"always"`, `"never"`, or an interval in ms        | `"never"`          |
+//! |             | `checkpoint_interval_ms`  | how often the `data_dir` is checkpointed           | none               |
+//! | `[limits]`  | `max_bytes_per_partition` | the size past which a partition evicts keys        | none               |
+//! |             | `eviction`                | which keys go first, `"lru"` or `"fifo"`           | `"lru"`            |
+//! |             | `max_memory_bytes`        | the memory past which writes get `memory_policy`   | none               |
+//! |             | `memory_policy`           | `"reject"` writes or `"evict"` keys                | `"reject"`         |
+//! | `[tls]`     | `cert`, `key`             | the PEM certificate chain and key to serve TLS     | none, plaintext    |
+//! |             | `client_ca`               | the CA client certificates must be signed by       | none               |
+//!
+//! Only the part of TOML these settings need is read: tables, comments, and keys set to basic
+//! strings, integers or booleans. Unknown keys are rejected, so a misspelled setting isn't
+//! silently ignored.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::builder::StorageServerBuilder;
+use crate::codec::{Compression, ZSTD_LEVEL};
+use crate::eviction::{EvictionPolicy, MemoryPolicy};
+use crate::ring::Partitioner;
+use crate::storage_server::{StorageServer, MIN_COMPRESS_SIZE};
+use crate::tls::TlsConfig;
+use crate::transaction_log::SyncPolicy;
+
+/// A server's settings. See the module documentation for the file they are read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub address: String,
+    pub http_address: String,
+    pub resp_address: String,
+    pub memcached_address: String,
+    pub grpc_address: String,
+    pub partitions: usize,
+    pub replicas: usize,
+    pub compression: Compression,
+    pub min_compress_size: usize,
+    pub data_dir: Option<PathBuf>,
+    pub partitioner: Partitioner,
+    pub default_ttl: Option<Duration>,
+    pub read_cache_bytes: Option<usize>,
+    pub wal_dir: Option<PathBuf>,
+    pub wal_segment_size: u64,
+    pub wal_max_files: u32,
+    pub sync_policy: SyncPolicy,
+    pub checkpoint_interval: Option<Duration>,
+    pub max_bytes_per_partition: Option<usize>,
+    pub eviction_policy: EvictionPolicy,
+    pub max_memory_bytes: Option<usize>,
+    pub memory_policy: MemoryPolicy,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:7070".to_owned(),
+            http_address: "127.0.0.1:7080".to_owned(),
+            resp_address: "127.0.0.1:6379".to_owned(),
+            memcached_address: "127.0.0.1:11211".to_owned(),
+            grpc_address: "127.0.0.1:7090".to_owned(),
+            partitions: 16,
+            replicas: 3,
+            compression: Compression::default(),
+            min_compress_size: MIN_COMPRESS_SIZE,
+            data_dir: None,
+            partitioner: Partitioner::default(),
+            default_ttl: None,
+            read_cache_bytes: None,
+            wal_dir: Some(PathBuf::from("logs/wal")),
+            wal_segment_size: 10 << 20,
+            wal_max_files: 10,
+            sync_policy: SyncPolicy::default(),
+            checkpoint_interval: None,
+            max_bytes_per_partition: None,
+            eviction_policy: EvictionPolicy::default(),
+            max_memory_bytes: None,
+            memory_policy: MemoryPolicy::default(),
+            tls: None,
+        }
+    }
+}
+
+/// A value in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl Config {
+    /// Reads the settings from the file, with defaults for the ones it leaves out. Fails with
+    /// `InvalidData` and the line at fault if the file can't be parsed or holds an invalid setting.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::parse(&fs::read_to_string(path)?).map_err(|message| io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), message)))
+    }
+
+    /// Parses the settings from the text of a file, returning why it is invalid, and where, if it is.
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Config::default();
+        let (mut cert, mut key, mut client_ca) = (None, None, None);
+        let mut wal_enabled = true;
+        let mut zstd_level = None;
+        let mut seen = Vec::new();
+        for (line, name, value) in parse_settings(text)? {
+            if seen.contains(&name) {
+                return Err(format!("line {}: {} is set twice", line, name));
+            }
+            let applied = match name.as_str() {
+                "server.address" => string(value).map(|value| config.address = value),
+                "server.http_address" => string(value).map(|value| config.http_address = value),
+                "server.resp_address" => string(value).map(|value| config.resp_address = value),
+                "server.memcached_address" => string(value).map(|value| config.memcached_address = value),
+                "server.grpc_address" => string(value).map(|value| config.grpc_address = value),
+                "storage.partitions" => integer(value).map(|value| config.partitions = value),
+                "storage.replicas" => integer(value).map(|value| config.replicas = value),
+                "storage.compression" => compression(value).map(|value| config.compression = value),
+                "storage.zstd_level" => integer(value).and_then(zstd_level_in_range).map(|value| zstd_level = Some(value)),
+                "storage.min_compress_size" => integer(value).map(|value| config.min_compress_size = value),
+                "storage.data_dir" => string(value).map(|value| config.data_dir = Some(value.into())),
+                "storage.partitioner" => partitioner(value).map(|value| config.partitioner = value),
+                "storage.default_ttl_secs" => integer(value).map(|value| config.default_ttl = Some(Duration::from_secs(value))),
+                "storage.read_cache_bytes" => integer(value).map(|value| config.read_cache_bytes = Some(value)),
+                "wal.dir" => string(value).map(|value| config.wal_dir = Some(value.into())),
+                "wal.enabled" => boolean(value).map(|value| wal_enabled = value),
+                "wal.segment_size" => integer(value).map(|value| config.wal_segment_size = value),
+                "wal.max_files" => integer(value).map(|value| config.wal_max_files = value),
+                "wal.sync" => sync_policy(value).map(|value| config.sync_policy = value),
+                "wal.checkpoint_interval_ms" => interval(value).map(|value| config.checkpoint_interval = Some(value)),
+                "limits.max_bytes_per_partition" => integer(value).map(|value| config.max_bytes_per_partition = Some(value)),
+                "limits.eviction" => eviction_policy(value).map(|value| config.eviction_policy = value),
+                "limits.max_memory_bytes" => integer(value).map(|value| config.max_memory_bytes = Some(value)),
+                "limits.memory_policy" => memory_policy(value).map(|value| config.memory_policy = value),
+                "tls.cert" => string(value).map(|value| cert = Some(value)),
+                "tls.key" => string(value).map(|value| key = Some(value)),
+                "tls.client_ca" => string(value).map(|value| client_ca = Some(value)),
+                _ => Err("is not a setting".to_owned()),
+            };
+            applied.map_err(|message| format!("line {}: {} {}", line, name, message))?;
+            seen.push(name);
+        }
+        if !wal_enabled {
+            config.wal_dir = None;
+        }
+        match (&mut config.compression, zstd_level) {
+            (Compression::Zstd(level), Some(zstd_level)) => *level = zstd_level,
+            (_, Some(_)) => return Err("storage.zstd_level needs storage.compression = \"zstd\"".to_owned()),
+            (_, None) => {}
+        }
+        if config.checkpoint_interval.is_some() && config.data_dir.is_none() {
+            return Err("wal.checkpoint_interval_ms needs storage.data_dir".to_owned());
+        }
+        config.tls = match (cert, key, client_ca) {
+            (Some(cert), Some(key), client_ca) => {
+                let tls = TlsConfig::new(cert, key);
+                Some(match client_ca {
+                    Some(client_ca) => tls.with_client_ca(client_ca),
+                    None => tls,
+                })
+            }
+            (None, None, None) => None,
+            _ => return Err("tls needs both cert and key".to_owned()),
+        };
+        Ok(config)
+    }
+
+    /// Returns a builder for the server the settings describe.
+    pub fn builder(&self) -> StorageServerBuilder {
+        let mut builder = StorageServer::builder().partitions(self.partitions).replicas(self.replicas).codec(self.compression.codec()).min_compress_size(self.min_compress_size)
+            .partitioner(self.partitioner);
+        if let Some(dir) = &self.data_dir {
+            builder = builder.data_dir(dir);
+        }
+        if let Some(ttl) = self.default_ttl {
+            builder = builder.default_ttl(ttl);
+        }
+        if let Some(max_bytes) = self.read_cache_bytes {
+            builder = builder.read_cache(max_bytes);
+        }
+        if let Some(dir) = &self.wal_dir {
+            builder = builder.wal(dir).wal_rotation(self.wal_segment_size, self.wal_max_files).sync_policy(self.sync_policy);
+        }
+        if let Some(max_bytes) = self.max_bytes_per_partition {
+            builder = builder.eviction(max_bytes, self.eviction_policy);
+        }
+        if let Some(max_bytes) = self.max_memory_bytes {
+            builder = builder.memory_limit(max_bytes, self.memory_policy);
+        }
+        builder
+    }
+}
+
+fn string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value),
+        _ => Err("must be a string".to_owned()),
+    }
+}
+
+fn integer<T: TryFrom<i64>>(value: Value) -> Result<T, String> {
+    match value {
+        Value::Integer(value) => T::try_from(value).map_err(|_| "is out of range".to_owned()),
+        _ => Err("must be an integer".to_owned()),
+    }
+}
+
+fn boolean(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(value) => Ok(value),
+        _ => Err("must be true or false".to_owned()),
+    }
+}
+
+fn compression(value: Value) -> Result<Compression, String> {
+    match value {
+        Value::String(codec) if codec == "snappy" => Ok(Compression::Snappy),
+        Value::String(codec) if codec == "lz4" => Ok(Compression::Lz4),
+        Value::String(codec) if codec == "zstd" => Ok(Compression::Zstd(ZSTD_LEVEL)),
+        Value::String(codec) if codec == "none" => Ok(Compression::None),
+        _ => Err("must be \"snappy\", \"lz4\", \"zstd\" or \"none\"".to_owned()),
+    }
+}
+
+fn zstd_level_in_range(level: i32) -> Result<i32, String> {
+    match level {
+        1..=22 => Ok(level),
+        _ => Err("must be from 1 to 22".to_owned()),
+    }
+}
+
+fn sync_policy(value: Value) -> Result<SyncPolicy, String> {
+    match value {
+        Value::String(policy) if policy == "always" => Ok(SyncPolicy::Always),
+        Value::String(policy) if policy == "never" => Ok(SyncPolicy::Never),
+        Value::Integer(millis) if millis > 0 => Ok(SyncPolicy::EveryN(Duration::from_millis(millis as u64))),
+        _ => Err("must be \"always\", \"never\" or a positive number of milliseconds".to_owned()),
+    }
+}
+
+fn interval(value: Value) -> Result<Duration, String> {
+    match value {
+        Value::Integer(millis) if millis > 0 => Ok(Duration::from_millis(millis as u64)),
+        _ => Err("must be a positive number of milliseconds".to_owned()),
+    }
+}
+
+fn partitioner(value: Value) -> Result<Partitioner, String> {
+    match value {
+        Value::String(partitioner) if partitioner == "xxhash64" => Ok(Partitioner::XxHash64),
+        Value::String(partitioner) if partitioner == "siphash" => Ok(Partitioner::SipHash),
+        _ => Err("must be \"xxhash64\" or \"siphash\"".to_owned()),
+    }
+}
+
+fn eviction_policy(value: Value) -> Result<EvictionPolicy, String> {
+    match value {
+        Value::String(policy) if policy == "lru" => Ok(EvictionPolicy::Lru),
+        Value::String(policy) if policy == "fifo" => Ok(EvictionPolicy::Fifo),
+        _ => Err("must be \"lru\" or \"fifo\"".to_owned()),
+    }
+}
+
+fn memory_policy(value: Value) -> Result<MemoryPolicy, String> {
+    match value {
+        Value::String(policy) if policy == "reject" => Ok(MemoryPolicy::Reject),
+        Value::String(policy) if policy == "evict" => Ok(MemoryPolicy::Evict),
+        _ => Err("must be \"reject\" or \"evict\"".to_owned()),
+    }
+}
+
+/// Returns every setting in the text as its line number, its name qualified by its table as
+/// `table.key`, and its value, or where and why the text is invalid.
+fn parse_settings(text: &str) -> Result<Vec<(usize, String, Value)>, String> {
+    let mut settings = Vec::new();
+    let mut table = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let error = |message: &str| format!("line {}: {}", number, message);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let (name, rest) = header.split_once(']').ok_or_else(|| error("table header is not closed"))?;
+            if !is_bare_key(name.trim()) || !is_comment(rest) {
+                return Err(error("invalid table header"));
+            }
+            table = name.trim().to_owned();
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error("expected key = value"))?;
+        if !is_bare_key(key.trim()) {
+            return Err(error("invalid key"));
+        }
+        let (value, rest) = parse_value(value.trim_start()).map_err(|message| error(&message))?;
+        if !is_comment(rest) {
+            return Err(error("unexpected text after the value"));
+        }
+        let name = if table.is_empty() { key.trim().to_owned() } else { format!("{}.{}", table, key.trim()) };
+        settings.push((number, name, value));
+    }
+    Ok(settings)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-')
+}
+
+/// Returns whether the text left on a line is blank or a comment.
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// Parses the value at the start of the text, returning it and the text after it.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(quoted) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &quoted[index + 1..])),
+                '\\' => value.push(match chars.next() {
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    _ => return Err("invalid escape in string".to_owned()),
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err("string is not closed".to_owned());
+    }
+    let end = text.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    let value = match token {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::Integer(token.replace('_', "").parse().map_err(|_| format!("invalid value {}", token))?),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let text = r#"
+            # Settings for one node.
+            [server]
+            address = "0.0.0.0:7070"   # every interface
+            http_address = "0.0.0.0:7080"
+            grpc_address = "0.0.0.0:7090"
+
+            [storage]
+            partitions = 32
+            replicas = 2
+            compression = "zstd"
+            zstd_level = 9
+            min_compress_size = 128
+            data_dir = "/var/lib/flowdb"
+            partitioner = "siphash"
+            default_ttl_secs = 3_600
+            read_cache_bytes = 1_048_576
+
+            [wal]
+            dir = "/var/log/flowdb \"wal\""
+            segment_size = 1_048_576
+            sync = 100
+            checkpoint_interval_ms = 30_000
+
+            [limits]
+            max_bytes_per_partition = 65536
+            eviction = "fifo"
+            max_memory_bytes = 1_073_741_824
+            memory_policy = "evict"
+
+            [tls]
+            cert = "server.pem"
+            key = "server-key.pem"
+        "#;
+        let config = Config::parse(text).unwrap();
+        let expected = Config {
+            address: "0.0.0.0:7070".to_owned(),
+            http_address: "0.0.0.0:7080".to_owned(),
+            grpc_address: "0.0.0.0:7090".to_owned(),
+            partitions: 32,
+            replicas: 2,
+            compression: Compression::Zstd(9),
+            min_compress_size: 128,
+            data_dir: Some(PathBuf::from("/var/lib/flowdb")),
+            partitioner: Partitioner::SipHash,
+            default_ttl: Some(Duration::from_secs(3600)),
+            read_cache_bytes: Some(1 << 20),
+            wal_dir: Some(PathBuf::from("/var/log/flowdb \"wal\"")),
+            wal_segment_size: 1 << 20,
+            sync_policy: SyncPolicy::EveryN(Duration::from_millis(100)),
+            checkpoint_interval: Some(Duration::from_secs(30)),
+            max_bytes_per_partition: Some(65536),
+            eviction_policy: EvictionPolicy::Fifo,
+            max_memory_bytes: Some(1 << 30),
+            memory_policy: MemoryPolicy::Evict,
+            tls: Some(TlsConfig::new("server.pem", "server-key.pem")),
+            ..Config::default()
+        };
+        assert_eq!(config, expected);
+        assert_eq!(Config::parse("[wal]\nenabled = false\n").unwrap().wal_dir, None);
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert_eq!(Config::parse("[storage]\ncompression = \"zstd\"\n").unwrap().compression, Compression::Zstd(ZSTD_LEVEL));
+        assert_eq!(Config::parse("[storage]\ncompression = \"lz4\"\n").unwrap().compression, Compression::Lz4);
+
+        // Invalid files are reported with the line at fault.
+        let invalid = [
+            ("[storage]\npartitons = 4", "line 2: storage.partitons is not a setting"),
+            ("[storage]\n\npartitions = \"4\"", "line 3: storage.partitions must be an integer"),
+            ("[storage]\nreplicas = -1", "line 2: storage.replicas is out of range"),
+            ("[storage]\npartitioner = \"fnv\"", "line 2: storage.partitioner must be \"xxhash64\" or \"siphash\""),
+            ("[storage]\ncompression = true", "line 2: storage.compression must be \"snappy\", \"lz4\", \"zstd\" or \"none\""),
+            ("[storage]\ncompression = \"zstd\"\nzstd_level = 23", "line 3: storage.zstd_level must be from 1 to 22"),
+            ("[storage]\nzstd_level = 5", "storage.zstd_level needs storage.compression = \"zstd\""),
+            ("[wal]\nsync = \"sometimes\"", "line 2: wal.sync must be \"always\", \"never\" or a positive number of milliseconds"),
+            ("[server]\naddress = \"a\"\naddress = \"b\"", "line 3: server.address is set twice"),
+            ("[server\naddress = \"a\"", "line 1: table header is not closed"),
+            ("[server]\naddress = \"a", "line 2: string is not closed"),
+            ("[server]\naddress = \"a\" b", "line 2: unexpected text after the value"),
+            ("[tls]\ncert = \"server.pem\"", "tls needs both cert and key"),
+            ("[wal]\ncheckpoint_interval_ms = 1000", "wal.checkpoint_interval_ms needs storage.data_dir"),
+        ];
+        for (text, message) in invalid {
+            assert_eq!(Config::parse(text), Err(message.to_owned()));
+        }
+
+        // A file's settings build the server they describe.
+        let dir = "logs/test_config";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = format!("{}/flowdb.toml", dir);
+        fs::write(&path, format!("[storage]\npartitions = 4\nreplicas = 1\ncompression = \"lz4\"\n[wal]\ndir = \"{}/wal\"\nmax_files = 2\n", dir)).unwrap();
+        let server = Config::from_file(&path).unwrap().builder().build().unwrap();
+        assert_eq!(server.partition_count(), 4);
+        server.put("key", "value").unwrap();
+        drop(server);
+        assert_eq!(Config::from_file(&path).unwrap().builder().build().unwrap().get("key"), Ok("value".to_owned()));
+        fs::write(&path, "partitions = 4\n").unwrap();
+        assert_eq!(Config::from_file(&path).unwrap_err().to_string(), format!("{}: line 1: partitions is not a setting", path));
+    }
+}
@@ -0,0 +1,31 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::FlowDbError;
+
+/// The serialization format used to store typed values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Human-readable JSON, handy when values are inspected with other tools.
+    #[default]
+    Json,
+    /// Compact binary encoding via bincode.
+    Bincode,
+}
+
+impl Encoding {
+    /// Serializes the value into bytes using this encoding.
+    pub(crate) fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FlowDbError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|_| FlowDbError::InvalidValue),
+            Encoding::Bincode => bincode::serialize(value).map_err(|_| FlowDbError::InvalidValue),
+        }
+    }
+
+    /// Deserializes a value from bytes produced by `serialize` with the same encoding.
+    pub(crate) fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, FlowDbError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|_| FlowDbError::InvalidValue),
+            Encoding::Bincode => bincode::deserialize(data).map_err(|_| FlowDbError::InvalidValue),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::time::SystemTime;
+use crate::entry::Entry;
+use crate::lsm::CompactionStats;
+
+/// An iterator over the keys and entries of a StorageEngine.
+pub type EngineIter<'a> = Box<dyn Iterator<Item = (Cow<'a, Vec<u8>>, Cow<'a, Entry>)> + 'a>;
+
+/// The storage for the entries of a single partition or replica.
+///
+/// StorageServer handles partitioning, replication, expiry, and encoding on top of an engine, so an
+/// implementation only needs to map keys to entries. Entries are opaque to engines: they should be
+/// stored and returned unchanged. Engines that read from disk return owned entries; in-memory
+/// engines can return borrowed ones.
+pub trait StorageEngine: Debug + Send + Sync {
+    /// Returns the entry stored for the key, including entries that have expired.
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>>;
+
+    /// Stores the entry for the key, replacing any previous entry.
+    fn put(&mut self, key: Vec<u8>, entry: Entry);
+
+    /// Removes the key, returning the entry it had.
+    fn delete(&mut self, key: &[u8]) -> Option<Entry>;
+
+    /// Returns every key and entry, in any order.
+    fn scan(&self) -> EngineIter<'_>;
+
+    /// Writes any entries still held in memory to durable storage.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the counters of the engine's background compaction, if it compacts at all.
+    fn compaction_stats(&self) -> Option<CompactionStats> {
+        None
+    }
+
+    /// Pauses or resumes background compaction. Does nothing for engines that do not compact.
+    fn set_compaction_paused(&self, _paused: bool) {}
+
+    /// Returns about how many bytes of memory the engine holds entries in, counting each entry's
+    /// key, its stored value and a fixed overhead, or None if the engine doesn't keep count.
+    /// Engines that keep entries on disk only count the ones held in memory.
+    fn memory_bytes(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the keys and entries whose keys fall within the range, in key order.
+    ///
+    /// The default implementation scans and sorts every entry; ordered engines should override it.
+    fn range(&self, range: (Bound<&[u8]>, Bound<&[u8]>)) -> EngineIter<'_> {
+        let mut entries: Vec<_> = self.scan().filter(|(key, _)| RangeBounds::<[u8]>::contains(&range, key.as_slice())).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Box::new(entries.into_iter())
+    }
+
+    /// Removes every entry that has expired as of `now`, returning the removed keys.
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        let expired: Vec<_> = self.scan().filter(|(_, entry)| entry.is_expired(now)).map(|(key, _)| key.into_owned()).collect();
+        for key in &expired {
+            self.delete(key);
+        }
+        expired
+    }
+}
+
+impl StorageEngine for HashMap<Vec<u8>, Entry> {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        HashMap::get(self, key).map(Cow::Borrowed)
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        self.insert(key, entry);
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        self.remove(key)
+    }
+
+    fn scan(&self) -> EngineIter<'_> {
+        Box::new(self.iter().map(|(key, entry)| (Cow::Borrowed(key), Cow::Borrowed(entry))))
+    }
+
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        retain_live(now, |retain| self.retain(retain))
+    }
+}
+
+impl StorageEngine for BTreeMap<Vec<u8>, Entry> {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        BTreeMap::get(self, key).map(Cow::Borrowed)
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        self.insert(key, entry);
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        self.remove(key)
+    }
+
+    fn scan(&self) -> EngineIter<'_> {
+        Box::new(self.iter().map(|(key, entry)| (Cow::Borrowed(key), Cow::Borrowed(entry))))
+    }
+
+    fn range(&self, range: (Bound<&[u8]>, Bound<&[u8]>)) -> EngineIter<'_> {
+        Box::new(BTreeMap::range::<[u8], _>(self, range).map(|(key, entry)| (Cow::Borrowed(key), Cow::Borrowed(entry))))
+    }
+
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        retain_live(now, |retain| self.retain(retain))
+    }
+}
+
+/// Runs a map's `retain` with a predicate that drops entries expired as of `now`, returning their keys.
+fn retain_live(now: SystemTime, retain: impl FnOnce(&mut dyn FnMut(&Vec<u8>, &mut Entry) -> bool)) -> Vec<Vec<u8>> {
+    let mut expired = Vec::new();
+    retain(&mut |key, entry| {
+        if entry.is_expired(now) {
+            expired.push(key.clone());
+            false
+        } else {
+            true
+        }
+    });
+    expired
+}
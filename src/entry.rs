@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::codec::NO_COMPRESSION_ID;
+use crate::collections::Collection;
+
+/// The number of bytes counted for an entry on top of its key and value when estimating memory usage.
+pub(crate) const ENTRY_OVERHEAD: usize = 64;
+
+/// Metadata describing the current version of a stored value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueMeta {
+    /// A number that increases with every write to the server, so later writes have higher versions.
+    pub version: u64,
+    /// When the key was first written since it was last missing.
+    pub created_at: SystemTime,
+    /// When the key was last written.
+    pub modified_at: SystemTime,
+}
+
+/// A value stored in a partition, together with the bookkeeping needed to serve it.
+///
+/// Entries are opaque outside the crate; StorageEngine implementations store them as they are.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The stored value bytes, shared by the copies of the entry on every replica and handed out
+    /// without copying when they aren't compressed.
+    pub(crate) value: Arc<[u8]>,
+    /// The time after which the entry is considered missing, if it was written with a TTL.
+    pub(crate) expires_at: Option<SystemTime>,
+    /// The version, creation time, and modification time of the value.
+    pub(crate) meta: ValueMeta,
+    /// The structured value, if the key holds a list, set, or hash rather than plain bytes.
+    /// `value` is empty for collections.
+    pub(crate) collection: Option<Collection>,
+    /// The CRC32 of the decoded value, checked on reads to catch corrupted or wrongly decompressed
+    /// bytes. None for collections and for entries built directly from stored bytes.
+    pub(crate) checksum: Option<u32>,
+    /// Whether the entry records a delete rather than a value. Tombstones are never live and are
+    /// purged once they expire.
+    pub(crate) tombstone: bool,
+    /// The id of the codec `value` is compressed with, or `NO_COMPRESSION_ID` if it holds the value
+    /// itself, as values smaller than the server's `min_compress_size` do.
+    pub(crate) codec: u8,
+}
+
+impl Entry {
+    /// Creates an entry that never expires.
+    pub(crate) fn new(value: Vec<u8>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            value: value.into(),
+            expires_at: None,
+            meta: ValueMeta { version: 0, created_at: now, modified_at: now },
+            collection: None,
+            checksum: None,
+            tombstone: false,
+            codec: NO_COMPRESSION_ID,
+        }
+    }
+
+    /// Creates a tombstone recording a delete, which expires once `grace` has elapsed.
+    pub(crate) fn tombstone(grace: Duration) -> Self {
+        Self { tombstone: true, ..Self::with_ttl(Vec::new(), grace) }
+    }
+
+    /// Replaces the stored bytes of the entry with those of a new value, checksumming the decoded value.
+    pub(crate) fn with_value(self, value: Vec<u8>, codec: u8, decoded: &[u8]) -> Self {
+        Self { value: value.into(), codec, checksum: Some(crc32fast::hash(decoded)), ..self }
+    }
+
+    /// Returns whether the decoded value matches the checksum, if the entry has one.
+    pub(crate) fn verify(&self, decoded: &[u8]) -> bool {
+        self.checksum.is_none_or(|checksum| checksum == crc32fast::hash(decoded))
+    }
+
+    /// Creates an entry holding a collection that never expires.
+    pub(crate) fn with_collection(collection: Collection) -> Self {
+        Self { collection: Some(collection), ..Self::new(Vec::new()) }
+    }
+
+    /// Creates an entry that expires once `ttl` has elapsed.
+    pub(crate) fn with_ttl(value: Vec<u8>, ttl: Duration) -> Self {
+        Self { expires_at: Some(SystemTime::now() + ttl), ..Self::new(value) }
+    }
+
+    /// Returns whether the entry has expired as of `now`.
+    pub(crate) fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns whether the entry holds a list, set, or hash rather than plain bytes.
+    pub(crate) fn is_collection(&self) -> bool {
+        self.collection.is_some()
+    }
+
+    /// Returns an estimate of the memory the entry occupies, excluding its key.
+    pub(crate) fn footprint(&self) -> usize {
+        self.value.len() + self.collection.as_ref().map_or(0, Collection::byte_len) + ENTRY_OVERHEAD
+    }
+
+    /// Returns whether the entry records a delete.
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    /// Returns whether the entry holds a value that has not expired yet.
+    pub(crate) fn is_live(&self) -> bool {
+        !self.tombstone && !self.is_expired(SystemTime::now())
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+use serde::{Deserialize, Serialize};
+
+/// The ways an operation on a StorageServer can fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlowDbError {
+    /// The key is missing or has expired.
+    NotFound,
+    /// The stored value does not match its checksum or can't be decompressed, so it was corrupted
+    /// in memory or on disk.
+    CorruptValue,
+    /// The value is intact but isn't what was asked for: the key holds a collection, or the value
+    /// isn't valid UTF-8 or can't be serialized or deserialized.
+    InvalidValue,
+    /// The key's partition is owned by another node, which couldn't be reached.
+    Unavailable,
+    /// Reading or writing the transaction log, a data file or a dump failed, with the error's message.
+    Io(String),
+    /// A lock was poisoned by a thread that panicked while holding it, so the data it guards may be
+    /// half-updated.
+    LockPoisoned,
+    /// A write reached fewer copies than its consistency level asked for, though the copies that
+    /// applied it keep it.
+    ReplicaFailure { required: usize, applied: usize },
+    /// An argument is out of range or names something that doesn't exist, or a name is already taken.
+    InvalidArgument,
+    /// The server isn't set up for the operation, such as a merge without a merge operator.
+    Unsupported,
+    /// The server is shutting down and no longer accepts writes.
+    ShuttingDown,
+    /// The server is over its memory limit, so it rejects writes that would store more until
+    /// deletes or expiry bring it back under.
+    Backpressure,
+    /// A key a transaction read was written before the transaction committed, so none of its
+    /// writes were applied.
+    Conflict,
+}
+
+impl fmt::Display for FlowDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlowDbError::NotFound => write!(f, "key not found"),
+            FlowDbError::CorruptValue => write!(f, "stored value is corrupt"),
+            FlowDbError::InvalidValue => write!(f, "value has the wrong type or format"),
+            FlowDbError::Unavailable => write!(f, "node owning the key is unavailable"),
+            FlowDbError::Io(message) => write!(f, "I/O error: {}", message),
+            FlowDbError::LockPoisoned => write!(f, "lock is poisoned"),
+            FlowDbError::ReplicaFailure { required, applied } => write!(f, "write reached {} of the {} copies required", applied, required),
+            FlowDbError::InvalidArgument => write!(f, "invalid argument"),
+            FlowDbError::Unsupported => write!(f, "operation is not supported by this server"),
+            FlowDbError::ShuttingDown => write!(f, "server is shutting down"),
+            FlowDbError::Backpressure => write!(f, "server is over its memory limit"),
+            FlowDbError::Conflict => write!(f, "transaction conflicts with a concurrent write"),
+        }
+    }
+}
+
+impl std::error::Error for FlowDbError {}
+
+impl From<io::Error> for FlowDbError {
+    fn from(error: io::Error) -> Self {
+        FlowDbError::Io(error.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for FlowDbError {
+    fn from(_: PoisonError<T>) -> Self {
+        FlowDbError::LockPoisoned
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// How a size-capped partition chooses which keys to drop once it is over its cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Drops the keys that were read or written least recently.
+    #[default]
+    Lru,
+    /// Drops the keys that were written least recently, ignoring reads.
+    Fifo,
+}
+
+/// What a server over its memory limit does about writes that store more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryPolicy {
+    /// Fails them with `Backpressure`, leaving room to be made by deletes and expiry.
+    #[default]
+    Reject,
+    /// Applies them, then evicts keys of the written partition until the server is estimated to
+    /// be back under its limit.
+    Evict,
+}
+
+/// A server-wide limit on the memory its partitions and replicas take.
+#[derive(Debug)]
+pub(crate) struct MemoryLimit {
+    pub(crate) max_bytes: usize,
+    pub(crate) policy: MemoryPolicy,
+    /// The memory every copy of every partition takes, kept up to date by the copies themselves.
+    pub(crate) usage: Arc<AtomicUsize>,
+}
+
+impl MemoryLimit {
+    pub(crate) fn new(max_bytes: usize, policy: MemoryPolicy) -> Self {
+        Self { max_bytes, policy, usage: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Returns how many bytes the server is over its limit by, 0 if it is within it.
+    pub(crate) fn excess(&self) -> usize {
+        self.usage.load(Ordering::Relaxed).saturating_sub(self.max_bytes)
+    }
+}
+
+/// Tracks the size and age of every key in one partition for size-capped eviction.
+#[derive(Debug)]
+pub(crate) struct EvictionTracker {
+    policy: EvictionPolicy,
+    max_bytes: usize,
+    state: Mutex<TrackerState>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    bytes: usize,
+    clock: u64,
+    /// Keys ordered by the tick of their last use, oldest first.
+    order: BTreeMap<u64, Vec<u8>>,
+    /// The tick of each key's last use and the size it was accounted with.
+    keys: HashMap<Vec<u8>, (u64, usize)>,
+}
+
+impl TrackerState {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((tick, size)) = self.keys.remove(key) {
+            self.order.remove(&tick);
+            self.bytes -= size;
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], size: usize) {
+        self.clock += 1;
+        self.order.insert(self.clock, key.to_vec());
+        self.keys.insert(key.to_vec(), (self.clock, size));
+        self.bytes += size;
+    }
+}
+
+impl EvictionTracker {
+    pub(crate) fn new(max_bytes: usize, policy: EvictionPolicy) -> Self {
+        Self { policy, max_bytes, state: Mutex::new(TrackerState::default()) }
+    }
+
+    /// Records that the key was written with a value of the given size, returning the keys that must
+    /// be evicted to bring the partition back under its cap. The written key itself is never returned.
+    pub(crate) fn record_write(&self, key: &[u8], size: usize) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.remove(key);
+        state.insert(key, size);
+        let mut victims = Vec::new();
+        while state.bytes > self.max_bytes {
+            let Some((&tick, oldest)) = state.order.iter().next() else { break };
+            if oldest == key {
+                break;
+            }
+            let oldest = oldest.clone();
+            state.order.remove(&tick);
+            let (_, size) = state.keys.remove(&oldest).unwrap_or_default();
+            state.bytes -= size;
+            victims.push(oldest);
+        }
+        victims
+    }
+
+    /// Stops tracking the key that would be evicted first other than `key` and returns it with the
+    /// size it was recorded with, or None if there is no other key.
+    pub(crate) fn pop_oldest(&self, key: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let oldest = state.order.values().take(2).find(|oldest| oldest.as_slice() != key)?.clone();
+        let (_, size) = state.keys.get(&oldest).copied().unwrap_or_default();
+        state.remove(&oldest);
+        Some((oldest, size))
+    }
+
+    /// Records that the key was read, which makes it the most recently used key under the LRU policy.
+    pub(crate) fn record_read(&self, key: &[u8]) {
+        if self.policy != EvictionPolicy::Lru {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&(_, size)) = state.keys.get(key) {
+            state.remove(key);
+            state.insert(key, size);
+        }
+    }
+
+    /// Records that the key was removed.
+    pub(crate) fn record_remove(&self, key: &[u8]) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eviction_tracker_lru() {
+        let tracker = EvictionTracker::new(300, EvictionPolicy::Lru);
+        assert!(tracker.record_write(b"a", 100).is_empty());
+        assert!(tracker.record_write(b"b", 100).is_empty());
+        assert!(tracker.record_write(b"c", 100).is_empty());
+        tracker.record_read(b"a");
+        assert_eq!(tracker.record_write(b"d", 100), vec![b"b".to_vec()]);
+        tracker.record_remove(b"c");
+        assert!(tracker.record_write(b"e", 100).is_empty());
+        assert_eq!(tracker.pop_oldest(b"a"), Some((b"d".to_vec(), 100)));
+        assert_eq!(tracker.pop_oldest(b"e"), Some((b"a".to_vec(), 100)));
+        assert_eq!(tracker.pop_oldest(b"e"), None);
+    }
+
+    #[test]
+    fn test_eviction_tracker_fifo() {
+        let tracker = EvictionTracker::new(200, EvictionPolicy::Fifo);
+        tracker.record_write(b"a", 100);
+        tracker.record_write(b"b", 100);
+        tracker.record_read(b"a");
+        assert_eq!(tracker.record_write(b"c", 100), vec![b"a".to_vec()]);
+        // A value larger than the cap evicts everything else but is kept itself.
+        assert_eq!(tracker.record_write(b"big", 500), vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+}
@@ -0,0 +1,228 @@
+//! A gRPC frontend, for clients generated from `proto/flowdb.proto` in any language.
+//!
+//! Each call maps onto one StorageServer operation, run through its async facade: Get onto
+//! `get_bytes`, Put onto `put`, Delete onto `delete`, BatchWrite onto `multi_put` followed by the
+//! deletes, and Scan onto pages of `scan_prefix_page`, with each entry streamed to the client as
+//! the page holding it is read. A missing key is answered with NOT_FOUND; the other errors map
+//! onto the nearest status code, with the error's message.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use log::{error, info};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::Stream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use crate::error::FlowDbError;
+use crate::storage_server::StorageServer;
+use crate::tls::TlsConfig;
+use proto::flow_db_server::{FlowDb, FlowDbServer};
+use proto::{
+    BatchWriteRequest, BatchWriteResponse, DeleteRequest, DeleteResponse, Entry, GetRequest, GetResponse, PutRequest, PutResponse,
+    ScanRequest,
+};
+
+/// The messages, client and server generated from `proto/flowdb.proto`.
+pub mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("flowdb.v1");
+}
+
+/// How many entries of a scan are read at a time, and so at most buffered for a slow client.
+const SCAN_PAGE_ENTRIES: usize = 256;
+/// How many TLS connections can finish their handshake before tonic takes them up.
+const PENDING_CONNECTIONS: usize = 64;
+
+/// Serves the gRPC service from a StorageServer.
+#[derive(Clone)]
+pub struct GrpcService {
+    server: Arc<StorageServer>,
+}
+
+impl GrpcService {
+    pub fn new(server: Arc<StorageServer>) -> Self {
+        Self { server }
+    }
+}
+
+fn status(error: FlowDbError) -> Status {
+    let message = error.to_string();
+    match error {
+        FlowDbError::NotFound => Status::not_found(message),
+        FlowDbError::InvalidArgument => Status::invalid_argument(message),
+        FlowDbError::InvalidValue => Status::failed_precondition(message),
+        FlowDbError::Conflict => Status::aborted(message),
+        FlowDbError::Unsupported => Status::unimplemented(message),
+        FlowDbError::Backpressure => Status::resource_exhausted(message),
+        FlowDbError::CorruptValue => Status::data_loss(message),
+        FlowDbError::Io(_) | FlowDbError::LockPoisoned => Status::internal(message),
+        FlowDbError::Unavailable | FlowDbError::ReplicaFailure { .. } | FlowDbError::ShuttingDown => Status::unavailable(message),
+    }
+}
+
+#[tonic::async_trait]
+impl FlowDb for GrpcService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let value = self.server.get_async(request.into_inner().key).await.map_err(status)?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        self.server.put_async(key, value).await.map_err(status)?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let existed = self.server.delete_async(request.into_inner().key).await.map_err(status)?;
+        Ok(Response::new(DeleteResponse { existed }))
+    }
+
+    async fn batch_write(&self, request: Request<BatchWriteRequest>) -> Result<Response<BatchWriteResponse>, Status> {
+        let BatchWriteRequest { puts, deletes } = request.into_inner();
+        let puts: Vec<_> = puts.into_iter().map(|entry| (entry.key, entry.value)).collect();
+        self.server
+            .run_blocking(move |server| {
+                server.multi_put(&puts)?;
+                deletes.iter().try_for_each(|key| server.delete(key).map(|_| ()))
+            })
+            .await
+            .map_err(status)?;
+        Ok(Response::new(BatchWriteResponse {}))
+    }
+
+    type ScanStream = Pin<Box<dyn Stream<Item = Result<Entry, Status>> + Send>>;
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { prefix, limit } = request.into_inner();
+        let mut remaining = if limit == 0 { usize::MAX } else { limit as usize };
+        let (sender, receiver) = mpsc::channel(SCAN_PAGE_ENTRIES);
+        let server = Arc::clone(&self.server);
+        tokio::spawn(async move {
+            let mut cursor = None;
+            while remaining > 0 {
+                let (page, next) = match server.scan_prefix_page_async(&prefix, cursor.take(), remaining.min(SCAN_PAGE_ENTRIES)).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        let _ = sender.send(Err(status(e))).await;
+                        return;
+                    }
+                };
+                remaining -= page.len();
+                for (key, value) in page {
+                    // The client went away, so nothing more is read.
+                    if sender.send(Ok(Entry { key, value })).await.is_err() {
+                        return;
+                    }
+                }
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => return,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+/// Serves gRPC clients on the address, over TLS if configured, until accepting fails. Must be run
+/// on a tokio runtime.
+pub async fn listen(server: Arc<StorageServer>, address: impl ToSocketAddrs, tls: Option<TlsConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Listening for gRPC clients on {}", listener.local_addr()?);
+    serve(server, listener, tls.as_ref()).await
+}
+
+/// Serves gRPC clients on the listener, over TLS if configured, until accepting fails.
+pub async fn serve(server: Arc<StorageServer>, listener: TcpListener, tls: Option<&TlsConfig>) -> io::Result<()> {
+    let router = Server::builder().add_service(FlowDbServer::new(GrpcService::new(server)));
+    let served = match tls {
+        Some(tls) => {
+            // Handshakes run as tasks of their own, so a slow client doesn't hold up accepting others.
+            let acceptor = TlsAcceptor::from(tls.acceptor()?);
+            let (sender, receiver) = mpsc::channel(PENDING_CONNECTIONS);
+            tokio::spawn(async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            let _ = sender.send(Err(e)).await;
+                            return;
+                        }
+                    };
+                    let (acceptor, sender) = (acceptor.clone(), sender.clone());
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => {
+                                let _ = sender.send(Ok(stream)).await;
+                            }
+                            Err(e) => error!("TLS handshake with gRPC client failed: {}", e),
+                        }
+                    });
+                }
+            });
+            router.serve_with_incoming(ReceiverStream::new(receiver)).await
+        }
+        None => router.serve_with_incoming(TcpListenerStream::new(listener)).await,
+    };
+    served.map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+    use proto::flow_db_client::FlowDbClient;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_grpc() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        tokio::spawn(serve(Arc::clone(&server), listener, None));
+        let mut client = FlowDbClient::connect(format!("http://{}", address)).await.unwrap();
+
+        client.put(PutRequest { key: b"user:1".to_vec(), value: b"alice".to_vec() }).await.unwrap();
+        let response = client.get(GetRequest { key: b"user:1".to_vec() }).await.unwrap();
+        assert_eq!(response.into_inner().value, b"alice");
+        assert_eq!(server.get_bytes("user:1"), Ok(b"alice".to_vec()));
+        let missing = client.get(GetRequest { key: b"missing".to_vec() }).await.unwrap_err();
+        assert_eq!(missing.code(), Code::NotFound);
+
+        let puts = (0..600).map(|i| Entry { key: format!("item:{:03}", i).into_bytes(), value: vec![0; 8] }).collect();
+        let deletes = vec![b"user:1".to_vec(), b"item:000".to_vec()];
+        client.batch_write(BatchWriteRequest { puts, deletes }).await.unwrap();
+        assert_eq!(server.get_bytes("user:1"), Err(FlowDbError::NotFound));
+        assert_eq!(server.get_bytes("item:599"), Ok(vec![0; 8]));
+
+        let response = client.delete(DeleteRequest { key: b"item:001".to_vec() }).await.unwrap();
+        assert!(response.into_inner().existed);
+        let response = client.delete(DeleteRequest { key: b"item:001".to_vec() }).await.unwrap();
+        assert!(!response.into_inner().existed);
+
+        // A scan spanning several pages is streamed in full, or up to its limit.
+        let stored: Vec<Vec<u8>> = (2..600).map(|i| format!("item:{:03}", i).into_bytes()).collect();
+        for limit in [0, 300] {
+            let mut stream = client.scan(ScanRequest { prefix: b"item:".to_vec(), limit }).await.unwrap().into_inner();
+            let mut keys = Vec::new();
+            while let Some(entry) = stream.message().await.unwrap() {
+                assert_eq!(entry.value, vec![0; 8]);
+                keys.push(entry.key);
+            }
+            keys.sort();
+            if limit == 0 {
+                assert_eq!(keys, stored);
+            } else {
+                // Which keys a limited scan returns depends on the partitions' order, but each is
+                // returned once.
+                assert_eq!(keys.len(), 300);
+                assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                assert!(keys.iter().all(|key| stored.binary_search(key).is_ok()));
+            }
+        }
+    }
+}
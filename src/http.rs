@@ -0,0 +1,370 @@
+//! A JSON-over-HTTP/1.1 interface to a StorageServer, for scripts, health checks and curl.
+//!
+//! | method and path              | body                     | response                                    |
+//! |------------------------------|--------------------------|---------------------------------------------|
+//! | `GET /v1/keys/{key}`         |                          | 200 with the entry, or 404                  |
+//! | `PUT /v1/keys/{key}`         | the value, as raw bytes  | 200 `{"key": ...}`                          |
+//! | `DELETE /v1/keys/{key}`      |                          | 200 `{"deleted": true}`, or 404             |
+//! | `POST /v1/batch`             | `{"puts": [entry], "deletes": [key]}` | 200 `{"put": n, "deleted": n}` |
+//! | `GET /v1/scan?prefix=&limit=`|                          | 200 `{"entries": [entry]}`                  |
+//! | `GET /v1/health`             |                          | 200 `{"status": "ok"}`                      |
+//! | `GET /v1/watch?prefix=`      |                          | 101, then a WebSocket feed of changes       |
+//!
+//! An entry is `{"key": ..., "value": ...}` with the value as a string, or, for values that aren't
+//! valid UTF-8, `{"key": ..., "value_bytes": [...]}` with the value's bytes; batches accept either
+//! form. Keys that aren't valid UTF-8 are likewise given as `"key_bytes"`, and are percent-encoded
+//! in paths. Keys in paths and query parameters are percent-decoded. Errors are reported as
+//! `{"error": message}` with a 4xx or 5xx status. Connections are kept alive unless the client asks
+//! to close them; request bodies need a `Content-Length`. The watch endpoint only accepts WebSocket
+//! upgrades and is described in `websocket`.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::error::FlowDbError;
+use crate::storage_server::StorageServer;
+use crate::tls::{self, TlsConfig};
+
+/// The largest request body accepted.
+const MAX_BODY_LEN: usize = 64 << 20;
+/// The most header lines read for a request.
+const MAX_HEADERS: usize = 100;
+
+/// A key and its value, as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JsonEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_bytes: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_bytes: Option<Vec<u8>>,
+}
+
+impl JsonEntry {
+    pub(crate) fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        let (value, value_bytes) = split_utf8(value);
+        Self { value, value_bytes, ..Self::key(key) }
+    }
+
+    /// Returns an entry with only a key, such as the one a delete reports.
+    pub(crate) fn key(key: Vec<u8>) -> Self {
+        let (key, key_bytes) = split_utf8(key);
+        Self { key, key_bytes, value: None, value_bytes: None }
+    }
+
+    /// Returns the key and the value, or None if either is missing.
+    fn into_pair(self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let key = self.key.map(String::into_bytes).or(self.key_bytes)?;
+        Some((key, self.value.map(String::into_bytes).or(self.value_bytes)?))
+    }
+}
+
+/// Returns the bytes as a string if they are valid UTF-8, and as they are otherwise.
+fn split_utf8(bytes: Vec<u8>) -> (Option<String>, Option<Vec<u8>>) {
+    match String::from_utf8(bytes) {
+        Ok(string) => (Some(string), None),
+        Err(e) => (None, Some(e.into_bytes())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Batch {
+    #[serde(default)]
+    puts: Vec<JsonEntry>,
+    #[serde(default)]
+    deletes: Vec<String>,
+}
+
+/// A request as read off the connection.
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+    close: bool,
+    /// The `Sec-WebSocket-Key` of a request to upgrade to a WebSocket.
+    websocket_key: Option<String>,
+}
+
+/// A status and JSON body to send back.
+type HttpResponse = (u16, serde_json::Value);
+
+impl StorageServer {
+    /// Serves HTTP requests on the connection until the client closes it or asks to, or the
+    /// connection fails or carries a request that can't be parsed.
+    pub fn serve_http<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        loop {
+            let request = match read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    write_response(reader.get_mut(), (400, json!({ "error": e.to_string() })), true)?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if let ("GET", "/v1/watch", Some(key)) = (request.method.as_str(), request.path.as_str(), &request.websocket_key) {
+                let prefix = request.query.iter().find(|(name, _)| name == "prefix").map_or("", |(_, value)| value.as_str());
+                return self.serve_watch(reader.get_mut(), key, prefix);
+            }
+            let response = self.route(&request);
+            write_response(reader.get_mut(), response, request.close)?;
+            if request.close {
+                return Ok(());
+            }
+        }
+    }
+
+    fn route(&self, request: &HttpRequest) -> HttpResponse {
+        let path = request.path.strip_prefix("/v1/");
+        match (request.method.as_str(), path) {
+            (method, Some(path)) if path.starts_with("keys/") => {
+                let Some(key) = percent_decode_bytes(&path["keys/".len()..]).filter(|key| !key.is_empty()) else {
+                    return error(400, "invalid key");
+                };
+                match method {
+                    "GET" => match self.get_bytes(&key) {
+                        Ok(value) => (200, json!(JsonEntry::new(key, value))),
+                        Err(e) => error(status_of(&e), &e.to_string()),
+                    },
+                    "PUT" => match self.put(&key, &request.body) {
+                        Ok(_) => (200, json!(JsonEntry::key(key))),
+                        Err(e) => error(status_of(&e), &e.to_string()),
+                    },
+                    "DELETE" => match self.delete(&key) {
+                        Ok(true) => (200, json!({ "deleted": true })),
+                        Ok(false) => error(404, &FlowDbError::NotFound.to_string()),
+                        Err(e) => error(status_of(&e), &e.to_string()),
+                    },
+                    _ => error(405, "method not allowed"),
+                }
+            }
+            ("POST", Some("batch")) => self.batch(&request.body),
+            ("GET", Some("scan")) => {
+                let param = |name: &str| request.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+                let limit = match param("limit").map(str::parse::<usize>) {
+                    None => usize::MAX,
+                    Some(Ok(limit)) => limit,
+                    Some(Err(_)) => return error(400, "invalid limit"),
+                };
+                let entries: Vec<_> = self.scan_prefix(param("prefix").unwrap_or("")).take(limit).map(|(key, value)| JsonEntry::new(key, value)).collect();
+                (200, json!({ "entries": entries }))
+            }
+            ("GET", Some("health")) => (200, json!({ "status": "ok" })),
+            ("GET", Some("watch")) => error(400, "expected a WebSocket upgrade"),
+            (_, Some("batch" | "scan" | "health" | "watch")) => error(405, "method not allowed"),
+            _ => error(404, "no such endpoint"),
+        }
+    }
+
+    fn batch(&self, body: &[u8]) -> HttpResponse {
+        let batch: Batch = match serde_json::from_slice(body) {
+            Ok(batch) => batch,
+            Err(e) => return error(400, &format!("invalid batch: {}", e)),
+        };
+        let mut puts = Vec::with_capacity(batch.puts.len());
+        for entry in batch.puts {
+            let key = entry.key.clone();
+            match entry.into_pair() {
+                Some(pair) => puts.push(pair),
+                None => return error(400, &format!("no key or value for {}", key.unwrap_or_default())),
+            }
+        }
+        if let Err(e) = self.multi_put(&puts) {
+            return error(status_of(&e), &e.to_string());
+        }
+        let mut deleted = 0;
+        for key in &batch.deletes {
+            match self.delete(key) {
+                Ok(existed) => deleted += usize::from(existed),
+                Err(e) => return error(status_of(&e), &e.to_string()),
+            }
+        }
+        (200, json!({ "put": puts.len(), "deleted": deleted }))
+    }
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    (status, json!({ "error": message }))
+}
+
+fn status_of(error: &FlowDbError) -> u16 {
+    match error {
+        FlowDbError::NotFound => 404,
+        FlowDbError::InvalidArgument => 400,
+        FlowDbError::InvalidValue => 422,
+        FlowDbError::Conflict => 409,
+        FlowDbError::CorruptValue | FlowDbError::Io(_) | FlowDbError::LockPoisoned | FlowDbError::Unsupported => 500,
+        FlowDbError::Unavailable | FlowDbError::ReplicaFailure { .. } | FlowDbError::ShuttingDown | FlowDbError::Backpressure => 503,
+    }
+}
+
+/// Reads the next request, or None if the client closed the connection before sending one.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<HttpRequest>> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_owned());
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let (method, version) = (method.to_owned(), version.to_owned());
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("malformed query"))?;
+    let path = path.to_owned();
+
+    let mut content_length = 0;
+    let mut close = version == "HTTP/1.0";
+    let (mut upgrade, mut websocket_key) = (false, None);
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed in the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            if content_length > MAX_BODY_LEN {
+                return Err(invalid("request body is too large"));
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            let websocket_key = websocket_key.filter(|_| upgrade);
+            return Ok(Some(HttpRequest { method, path, query, body, close, websocket_key }));
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.parse().map_err(|_| invalid("invalid content length"))?,
+            "connection" => close = value.eq_ignore_ascii_case("close"),
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => websocket_key = Some(value.to_owned()),
+            "transfer-encoding" => return Err(invalid("chunked bodies are not supported")),
+            _ => {}
+        }
+    }
+    Err(invalid("too many headers"))
+}
+
+fn write_response(stream: &mut impl Write, (status, body): HttpResponse, close: bool) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        422 => "Unprocessable Entity",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    let connection = if close { "close" } else { "keep-alive" };
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}", status, reason, body.len(), connection, body)?;
+    stream.flush()
+}
+
+/// Decodes `%XX` escapes and `+` as a space, returning None for invalid escapes or UTF-8.
+fn percent_decode(encoded: &str) -> Option<String> {
+    String::from_utf8(percent_decode_bytes(encoded)?).ok()
+}
+
+fn percent_decode_bytes(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b'+' => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    Some(bytes)
+}
+
+/// Accepts HTTP clients on the address and serves each on its own thread, over TLS if
+/// configured, until accepting fails.
+pub fn listen(server: Arc<StorageServer>, address: impl ToSocketAddrs, tls: Option<&TlsConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving HTTP on {}", listener.local_addr()?);
+    tls::serve(listener, tls, "HTTP", move |connection| server.serve_http(connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+
+    /// Sends the request on the connection and returns the status and JSON body of the response.
+    fn call(reader: &mut BufReader<TcpStream>, method: &str, target: &str, body: &str) -> (u16, serde_json::Value) {
+        write!(reader.get_mut(), "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let status = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            match line.trim_end().split_once(": ") {
+                Some(("Content-Length", len)) => content_length = len.parse().unwrap(),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_http(listener.accept().unwrap().0));
+        }
+        let mut reader = BufReader::new(TcpStream::connect(address).unwrap());
+
+        assert_eq!(call(&mut reader, "PUT", "/v1/keys/user%3A1", "alice"), (200, json!({ "key": "user:1" })));
+        assert_eq!(call(&mut reader, "GET", "/v1/keys/user:1", ""), (200, json!({ "key": "user:1", "value": "alice" })));
+        assert_eq!(call(&mut reader, "GET", "/v1/keys/missing", "").0, 404);
+
+        let batch = r#"{"puts": [{"key": "user:2", "value": "bob"}, {"key": "raw", "value_bytes": [0, 255]}], "deletes": ["user:1", "missing"]}"#;
+        assert_eq!(call(&mut reader, "POST", "/v1/batch", batch), (200, json!({ "put": 2, "deleted": 1 })));
+        assert_eq!(server.get_bytes("raw"), Ok(vec![0, 255]));
+        assert_eq!(call(&mut reader, "GET", "/v1/keys/raw", ""), (200, json!({ "key": "raw", "value_bytes": [0, 255] })));
+        assert_eq!(call(&mut reader, "POST", "/v1/batch", "not json").0, 400);
+
+        assert_eq!(call(&mut reader, "GET", "/v1/scan?prefix=user%3A", ""), (200, json!({ "entries": [{ "key": "user:2", "value": "bob" }] })));
+        assert_eq!(call(&mut reader, "GET", "/v1/scan?limit=1", "").1["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(call(&mut reader, "GET", "/v1/scan?limit=many", "").0, 400);
+
+        assert_eq!(call(&mut reader, "DELETE", "/v1/keys/raw", ""), (200, json!({ "deleted": true })));
+        assert_eq!(call(&mut reader, "DELETE", "/v1/keys/raw", "").0, 404);
+        assert_eq!(call(&mut reader, "POST", "/v1/keys/raw", "").0, 405);
+        assert_eq!(call(&mut reader, "GET", "/v2/keys/raw", "").0, 404);
+        assert_eq!(call(&mut reader, "GET", "/v1/health", ""), (200, json!({ "status": "ok" })));
+    }
+}
@@ -0,0 +1,72 @@
+//! Striped locks that keep writes to the same key from interleaving without making writes to other
+//! keys of its partition wait.
+//!
+//! Each key hashes to one of a fixed number of stripes, so unrelated keys only share a lock when
+//! they land on the same stripe. The locks guard no data of their own, and a stripe poisoned by a
+//! panicking writer is simply locked again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use crate::ring;
+
+/// How many stripes a server's key locks have.
+pub(crate) const KEY_LOCK_STRIPES: usize = 1024;
+
+pub(crate) struct KeyLocks {
+    stripes: Box<[Mutex<()>]>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new(stripes: usize) -> Self {
+        Self { stripes: (0..stripes.max(1)).map(|_| Mutex::new(())).collect() }
+    }
+
+    /// Locks the key's stripe until the guard is dropped.
+    pub(crate) fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe(key)].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the stripes of every key, in ascending order so that callers locking overlapping sets
+    /// of keys can't deadlock.
+    pub(crate) fn lock_all<K: AsRef<[u8]>>(&self, keys: impl IntoIterator<Item = K>) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys.into_iter().map(|key| self.stripe(key.as_ref())).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes.into_iter().map(|stripe| self.stripes[stripe].lock().unwrap_or_else(PoisonError::into_inner)).collect()
+    }
+
+    /// Locks every stripe, in ascending order, which waits for every write in progress to finish
+    /// and holds off new ones.
+    pub(crate) fn lock_every(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.stripes.iter().map(|stripe| stripe.lock().unwrap_or_else(PoisonError::into_inner)).collect()
+    }
+
+    fn stripe(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        ring::hash_key(key, &mut hasher);
+        hasher.finish() as usize % self.stripes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_locks() {
+        let locks = KeyLocks::new(KEY_LOCK_STRIPES);
+        let keys: Vec<_> = (0..100).map(|i| format!("key{}", i)).collect();
+        let spread: std::collections::HashSet<_> = keys.iter().map(|key| locks.stripe(key.as_bytes())).collect();
+        assert!(spread.len() > 90, "{} stripes used", spread.len());
+
+        // Locking a key twice in one set takes its stripe once, and the stripes are free again afterwards.
+        let guards = locks.lock_all(["key1", "key2", "key1"]);
+        assert_eq!(guards.len(), 2);
+        assert!(locks.stripes[locks.stripe(b"key1")].try_lock().is_err());
+        drop(guards);
+        drop(locks.lock(b"key1"));
+        assert_eq!(locks.lock_every().len(), KEY_LOCK_STRIPES);
+        assert_eq!(KeyLocks::new(0).lock_all(keys.iter()).len(), 1);
+    }
+}
@@ -0,0 +1,179 @@
+//! Which copy of each partition leads it, and failing over to a replica when the leader becomes
+//! unavailable.
+//!
+//! Every partition has a fixed set of copies, and reads and writes are routed to the first of them,
+//! the leader's slot. Each copy's data is identified by the replica it started in. When the leader's
+//! lock is poisoned, a healthy replica is promoted by moving its data into the leader's slot, so
+//! routing reaches the new leader straight away, and the failed copy is rebuilt from the new
+//! leader and carries on as a replica. Every failover starts a new term for the partition.
+//!
+//! The leader's slot also keeps where the latest version of its built-in map is published, so reads
+//! of the leader can skip its lock; a failover publishes the new leader's entries there.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use arc_swap::ArcSwapOption;
+use log::warn;
+use crate::backend::{MapVersion, PartitionData};
+use crate::rebalance::Routing;
+use crate::replication::{self, ReplicationQueue};
+use crate::storage_server::Partition;
+
+/// The copy of a partition that currently leads it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leader {
+    /// The partition's index.
+    pub partition: usize,
+    /// The replica whose data leads the partition; 0 until the first failover.
+    pub replica: usize,
+    /// How many times the partition has failed over.
+    pub term: u64,
+}
+
+/// The copies of every partition and who leads each.
+pub(crate) struct Leadership {
+    copies: Vec<Vec<Arc<RwLock<Partition>>>>,
+    /// The published map of each partition's leader slot.
+    published: Vec<Arc<ArcSwapOption<MapVersion>>>,
+    terms: Vec<Mutex<Term>>,
+}
+
+#[derive(Clone)]
+struct Term {
+    /// The replica whose data each copy holds, by copy.
+    replicas: Vec<usize>,
+    term: u64,
+}
+
+impl Leadership {
+    pub(crate) fn new(copies: Vec<Vec<Arc<RwLock<Partition>>>>) -> Self {
+        let terms = copies.iter().map(|copies| Mutex::new(Term { replicas: (0..copies.len()).collect(), term: 0 })).collect();
+        let published = copies.iter().map(|copies| copies[0].read().unwrap_or_else(PoisonError::into_inner).data.published()).collect();
+        Self { copies, published, terms }
+    }
+
+    /// Returns the leadership of a resized server with the given copies, keeping the leaders and
+    /// terms of the partitions it shares with this one.
+    pub(crate) fn resized(&self, copies: Vec<Vec<Arc<RwLock<Partition>>>>) -> Self {
+        let mut leadership = Self::new(copies);
+        for (term, previous) in leadership.terms.iter_mut().zip(&self.terms) {
+            *term.get_mut().unwrap_or_else(PoisonError::into_inner) = previous.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        }
+        leadership
+    }
+
+    pub(crate) fn partition_count(&self) -> usize {
+        self.copies.len()
+    }
+
+    /// Returns every copy of the partition, the leader first.
+    pub(crate) fn copies(&self, partition: usize) -> &[Arc<RwLock<Partition>>] {
+        &self.copies[partition]
+    }
+
+    /// Returns the latest version of the leader's entries, readable without its lock, or None if
+    /// the partition is stored in an engine rather than a built-in map.
+    pub(crate) fn published(&self, partition: usize) -> Option<Arc<MapVersion>> {
+        self.published[partition].load_full()
+    }
+
+    pub(crate) fn leaders(&self) -> Vec<Leader> {
+        (0..self.copies.len()).map(|partition| self.leader(partition)).collect()
+    }
+
+    fn leader(&self, partition: usize) -> Leader {
+        let term = self.terms[partition].lock().unwrap_or_else(PoisonError::into_inner);
+        Leader { partition, replica: term.replicas[0], term: term.term }
+    }
+
+    /// Fails over every partition whose leader is unavailable, returning the newly elected leaders.
+    /// Replica updates still queued are applied first, so the promoted replica is up to date.
+    pub(crate) fn check(&self, queue: Option<&ReplicationQueue>) -> Vec<Leader> {
+        let mut elected = Vec::new();
+        for partition in 0..self.copies.len() {
+            if !self.copies[partition][0].is_poisoned() {
+                continue;
+            }
+            if let Some(queue) = queue {
+                queue.wait_until_empty();
+            }
+            elected.extend(self.fail_over(partition));
+        }
+        elected
+    }
+
+    fn fail_over(&self, partition: usize) -> Option<Leader> {
+        let copies = &self.copies[partition];
+        let mut term = self.terms[partition].lock().unwrap_or_else(PoisonError::into_inner);
+        // Lock the leader before the replica, in the same order writers do.
+        let mut leader = copies[0].write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some((candidate, mut replica)) = (1..copies.len()).find_map(|copy| Some((copy, copies[copy].write().ok().filter(|replica| !replica.down)?))) else {
+            warn!("Partition {} has no healthy replica to fail over to", partition);
+            return None;
+        };
+        leader.data.swap(&mut replica.data);
+        // The failed copy may be half-updated, so it is rebuilt from the new leader.
+        resync(&leader.data, &mut replica.data);
+        replication::record_caught_up(&mut replica);
+        drop(replica);
+        drop(leader);
+        copies[0].clear_poison();
+        term.replicas.swap(0, candidate);
+        term.term += 1;
+        warn!("Partition {} failed over to replica {} in term {}", partition, term.replicas[0], term.term);
+        Some(Leader { partition, replica: term.replicas[0], term: term.term })
+    }
+}
+
+/// Makes the data of a replica identical to the leader's.
+fn resync(leader: &PartitionData, replica: &mut PartitionData) {
+    let stale: Vec<Vec<u8>> = replica.iter().map(|(key, _)| key.into_owned()).filter(|key| leader.get(key).is_none()).collect();
+    for key in stale {
+        replica.remove(&key);
+    }
+    replica.extend(leader.iter().map(|(key, entry)| (key.into_owned(), entry.into_owned())));
+}
+
+/// A background thread that periodically checks the leader of every partition of a StorageServer,
+/// failing over the ones that became unavailable.
+///
+/// The thread is stopped when the checker is stopped or dropped.
+pub struct HealthChecker {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HealthChecker {
+    pub(crate) fn spawn(routing: Arc<Routing>, queue: Option<Arc<ReplicationQueue>>, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Acquire) {
+                routing.current().leadership.check(queue.as_deref());
+                thread::park_timeout(interval);
+            }
+        });
+        Self { stop, handle: Some(handle) }
+    }
+
+    /// Stops the checker thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,77 @@
+pub mod anti_entropy;
+pub mod archive;
+pub mod async_server;
+pub mod backend;
+pub mod bench;
+mod bloom;
+pub mod builder;
+pub mod bulk;
+pub mod checkpoint;
+pub mod cluster;
+pub mod codec;
+mod coalescer;
+mod collections;
+pub mod config;
+pub mod encoding;
+pub mod engine;
+pub mod entry;
+pub mod error;
+pub mod eviction;
+pub mod grpc;
+pub mod http;
+mod key_locks;
+pub mod leadership;
+pub mod lsm;
+pub mod memcached;
+pub mod mvcc;
+pub mod namespace;
+mod persistence;
+pub mod placement;
+pub mod protocol;
+mod read_cache;
+mod read_repair;
+mod rebalance;
+pub mod remote_cluster;
+pub mod replication;
+pub mod resp;
+pub mod ring;
+pub mod scan;
+pub mod shared_log;
+pub mod shipping;
+mod spill;
+pub mod stats;
+pub mod storage_server;
+pub mod transaction_log;
+pub mod tls;
+pub mod ttl;
+pub mod txn;
+pub mod watch;
+mod websocket;
+
+pub use anti_entropy::AntiEntropy;
+pub use backend::Backend;
+pub use builder::StorageServerBuilder;
+pub use bulk::DumpFormat;
+pub use checkpoint::{CheckpointReport, Checkpointer};
+pub use cluster::RemoteNode;
+pub use codec::{Codec, Compression, Lz4, NoCompression, Snappy, Zstd};
+pub use collections::CollectionOp;
+pub use config::Config;
+pub use encoding::Encoding;
+pub use engine::StorageEngine;
+pub use entry::{Entry, ValueMeta};
+pub use error::FlowDbError;
+pub use eviction::{EvictionPolicy, MemoryPolicy};
+pub use leadership::{HealthChecker, Leader};
+pub use lsm::{CompactionStats, LsmOptions};
+pub use mvcc::SnapshotView;
+pub use namespace::NamespaceOptions;
+pub use placement::{NodeLabels, PlacementPolicy};
+pub use remote_cluster::{ClusterFollower, ConflictPolicy, RemoteCluster};
+pub use replication::{Consistency, HintedHandoff, ReadPreference, ReplicaError, ReplicationMode, ReplicationReport};
+pub use ring::{HashRing, Partitioner};
+pub use stats::{CacheMetrics, PartitionStats, ReplicaLag, ServerStats};
+pub use shared_log::SharedTransactionLog;
+pub use shipping::{LogFollower, LogShipper};
+pub use storage_server::{MergeFn, StorageServer};
+pub use transaction_log::{LogMetrics, Lsn};
@@ -0,0 +1,798 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+#[cfg(not(feature = "mmap"))]
+use std::io::{Seek, SeekFrom};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+#[cfg(not(feature = "mmap"))]
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::bloom::BloomFilter;
+use crate::engine::{EngineIter, StorageEngine};
+use crate::entry::{Entry, ENTRY_OVERHEAD};
+
+/// Sorted entries that have not been written to an SSTable yet. None marks a deleted key.
+type Memtable = BTreeMap<Vec<u8>, Option<Entry>>;
+
+/// Sends work to the background flush thread.
+type FlushQueue = Sender<FlushTask>;
+
+enum FlushTask {
+    /// Writes the memtable to the SSTable with the given id.
+    Write(u64, Arc<Memtable>),
+    /// Acknowledges once every memtable queued before it has been written.
+    Sync(Sender<()>),
+}
+
+/// An entry yielded by one of the sorted sources merged by `LsmIter`.
+type Item<'a> = (Cow<'a, Vec<u8>>, Option<Cow<'a, Entry>>);
+
+/// A key and entry read from an SSTable, with the number of bytes the record took. None marks a
+/// deleted key.
+type Record = (Vec<u8>, Option<Entry>, u64);
+
+/// How many records each block of an SSTable holds; the index keeps the first key of every block.
+const BLOCK_RECORDS: usize = 16;
+
+/// Settings for the LSM trees of a server created with `StorageServer::with_lsm`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LsmOptions {
+    pub(crate) memtable_size: usize,
+    pub(crate) bloom_false_positive_rate: f64,
+    pub(crate) compaction_threshold: usize,
+    pub(crate) compaction_interval: Duration,
+}
+
+/// Counters describing the work done by background compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionStats {
+    /// How many compactions have completed.
+    pub compactions: u64,
+    /// How many SSTables have been merged away.
+    pub tables_merged: u64,
+    /// How many superseded versions and tombstones were dropped.
+    pub entries_dropped: u64,
+    /// How many bytes of merged SSTables were written.
+    pub bytes_written: u64,
+}
+
+impl CompactionStats {
+    /// Adds the counters of another tree to these.
+    pub(crate) fn add(&mut self, other: CompactionStats) {
+        self.compactions += other.compactions;
+        self.tables_merged += other.tables_merged;
+        self.entries_dropped += other.entries_dropped;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LsmOptions {
+    /// Creates options with 4 MiB memtables, bloom filters with a 1% false-positive rate, and
+    /// compaction once four SSTables have accumulated, checked at least every second.
+    pub fn new() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            bloom_false_positive_rate: 0.01,
+            compaction_threshold: 4,
+            compaction_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets how many bytes of recent writes each tree holds in memory before flushing them to an SSTable.
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Sets the false-positive rate of the per-SSTable bloom filters; lower rates use more memory.
+    pub fn bloom_false_positive_rate(mut self, rate: f64) -> Self {
+        self.bloom_false_positive_rate = rate;
+        self
+    }
+
+    /// Sets how many SSTables a tree accumulates before they are merged into one.
+    pub fn compaction_threshold(mut self, tables: usize) -> Self {
+        self.compaction_threshold = tables.max(2);
+        self
+    }
+
+    /// Sets how often the compaction thread checks whether a tree needs compacting, in addition to
+    /// checking after every flush.
+    pub fn compaction_interval(mut self, interval: Duration) -> Self {
+        self.compaction_interval = interval;
+        self
+    }
+}
+
+/// A log-structured merge tree storing the entries of a single partition.
+///
+/// Writes go to an in-memory memtable. Once the memtable grows past its size limit it becomes
+/// immutable and is handed to a background thread, which writes it to a sorted SSTable file and
+/// then drops it from memory. Reads check the memtable, then the immutable memtables, then the
+/// SSTables, newest first, so only the memtables and the SSTable indexes are held in memory. Each
+/// SSTable has a bloom filter, so lookups of missing keys rarely touch the disk.
+///
+/// Deletes are recorded as tombstones that shadow older versions of the key. A background thread
+/// compacts the tree once enough SSTables accumulate, merging them into one and dropping tombstones
+/// and superseded versions.
+#[derive(Debug)]
+pub(crate) struct LsmTree {
+    dir: PathBuf,
+    memtable: Memtable,
+    memtable_bytes: usize,
+    options: LsmOptions,
+    next_id: u64,
+    levels: Arc<RwLock<Levels>>,
+    flusher: Option<(FlushQueue, JoinHandle<()>)>,
+    compactor: Option<JoinHandle<()>>,
+    compaction: Arc<CompactionState>,
+}
+
+/// The memtables waiting to be flushed and the SSTables already on disk, both oldest first.
+#[derive(Debug, Default)]
+struct Levels {
+    immutable: Vec<Arc<Memtable>>,
+    sstables: Vec<Arc<SsTable>>,
+}
+
+/// The state shared between a tree and its compaction thread.
+#[derive(Debug, Default)]
+struct CompactionState {
+    stop: AtomicBool,
+    paused: AtomicBool,
+    compactions: AtomicU64,
+    tables_merged: AtomicU64,
+    entries_dropped: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl LsmTree {
+    /// Opens the tree stored in the directory, loading any SSTables written by a previous run.
+    pub(crate) fn open(dir: &Path, options: LsmOptions) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("sst") => {
+                    if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()) {
+                        ids.push(id);
+                    }
+                }
+                // An interrupted flush leaves a partial file behind; its memtable is recovered from the transaction log.
+                Some("tmp") => fs::remove_file(&path)?,
+                _ => {}
+            }
+        }
+        ids.sort_unstable();
+        let sstables = ids
+            .iter()
+            .map(|&id| SsTable::open(sstable_path(dir, id), options.bloom_false_positive_rate).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        let levels = Arc::new(RwLock::new(Levels { immutable: Vec::new(), sstables }));
+
+        let compaction = Arc::new(CompactionState::default());
+        let compactor = {
+            let dir = dir.to_owned();
+            let levels = Arc::clone(&levels);
+            let compaction = Arc::clone(&compaction);
+            thread::spawn(move || {
+                while !compaction.stop.load(Ordering::Acquire) {
+                    if !compaction.paused.load(Ordering::Acquire) {
+                        if let Err(e) = compact(&levels, &compaction, options) {
+                            log::error!("Failed to compact SSTables in {}: {}", dir.display(), e);
+                        }
+                    }
+                    thread::park_timeout(options.compaction_interval);
+                }
+            })
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let thread_dir = dir.to_owned();
+        let thread_levels = Arc::clone(&levels);
+        let compactor_thread = compactor.thread().clone();
+        let handle = thread::spawn(move || {
+            for task in receiver {
+                let (id, memtable) = match task {
+                    FlushTask::Write(id, memtable) => (id, memtable),
+                    FlushTask::Sync(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                match SsTable::write(sstable_path(&thread_dir, id), memtable_records(&memtable), options.bloom_false_positive_rate) {
+                    Ok(sstable) => {
+                        let mut levels = thread_levels.write().unwrap_or_else(PoisonError::into_inner);
+                        levels.immutable.retain(|immutable| !Arc::ptr_eq(immutable, &memtable));
+                        levels.sstables.push(Arc::new(sstable));
+                        drop(levels);
+                        compactor_thread.unpark();
+                    }
+                    // Keep serving the memtable from memory; it is retried when the tree is dropped.
+                    Err(e) => log::error!("Failed to flush memtable to SSTable {}: {}", id, e),
+                }
+            }
+        });
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            memtable: Memtable::new(),
+            memtable_bytes: 0,
+            options,
+            next_id: ids.last().map_or(0, |id| id + 1),
+            levels,
+            flusher: Some((sender, handle)),
+            compactor: Some(compactor),
+            compaction,
+        })
+    }
+
+    /// Pauses or resumes background compaction. A compaction already in progress is finished.
+    pub(crate) fn set_compaction_paused(&self, paused: bool) {
+        self.compaction.paused.store(paused, Ordering::Release);
+        if let Some(compactor) = &self.compactor {
+            compactor.thread().unpark();
+        }
+    }
+
+    pub(crate) fn compaction_stats(&self) -> CompactionStats {
+        CompactionStats {
+            compactions: self.compaction.compactions.load(Ordering::Relaxed),
+            tables_merged: self.compaction.tables_merged.load(Ordering::Relaxed),
+            entries_dropped: self.compaction.entries_dropped.load(Ordering::Relaxed),
+            bytes_written: self.compaction.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        if let Some(entry) = self.memtable.get(key) {
+            return entry.as_ref().map(Cow::Borrowed);
+        }
+        let levels = self.levels.read().unwrap_or_else(PoisonError::into_inner);
+        for immutable in levels.immutable.iter().rev() {
+            if let Some(entry) = immutable.get(key) {
+                return entry.clone().map(Cow::Owned);
+            }
+        }
+        for sstable in levels.sstables.iter().rev() {
+            match sstable.get(key) {
+                Ok(Some(entry)) => return entry.map(Cow::Owned),
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("Failed to read {}: {}", sstable.path.display(), e);
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    pub(crate) fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        self.write(key, Some(entry));
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let removed = self.get(key).map(Cow::into_owned);
+        if removed.is_some() {
+            self.write(key.to_vec(), None);
+        }
+        removed
+    }
+
+    /// Returns every live key and entry in key order, merging the memtables and SSTables.
+    pub(crate) fn iter(&self) -> LsmIter<'_> {
+        let levels = self.levels.read().unwrap_or_else(PoisonError::into_inner);
+        let mut sources: Vec<Box<dyn Iterator<Item = Item<'_>> + '_>> = Vec::new();
+        sources.push(Box::new(
+            self.memtable.iter().map(|(key, entry)| (Cow::Borrowed(key), entry.as_ref().map(Cow::Borrowed))),
+        ));
+        for immutable in levels.immutable.iter().rev() {
+            let entries: Vec<_> = immutable.iter().map(|(key, entry)| (key.clone(), entry.clone())).collect();
+            sources.push(Box::new(entries.into_iter().map(|(key, entry)| (Cow::Owned(key), entry.map(Cow::Owned)))));
+        }
+        for sstable in levels.sstables.iter().rev() {
+            match sstable.iter() {
+                Ok(records) => sources.push(Box::new(records.map(|(key, entry)| (Cow::Owned(key), entry.map(Cow::Owned))))),
+                Err(e) => log::error!("Failed to read {}: {}", sstable.path.display(), e),
+            }
+        }
+        LsmIter { sources: sources.into_iter().map(Iterator::peekable).collect() }
+    }
+
+    fn write(&mut self, key: Vec<u8>, entry: Option<Entry>) {
+        self.memtable_bytes += key.len() + entry.as_ref().map_or(ENTRY_OVERHEAD, Entry::footprint);
+        self.memtable.insert(key, entry);
+        if self.memtable_bytes >= self.options.memtable_size {
+            self.rotate_memtable();
+        }
+    }
+
+    /// Freezes the memtable and queues it to be written to an SSTable in the background.
+    fn rotate_memtable(&mut self) {
+        let memtable = Arc::new(std::mem::take(&mut self.memtable));
+        self.memtable_bytes = 0;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.levels.write().unwrap_or_else(PoisonError::into_inner).immutable.push(Arc::clone(&memtable));
+        if let Some((sender, _)) = &self.flusher {
+            let _ = sender.send(FlushTask::Write(id, memtable));
+        }
+    }
+
+    /// Writes the memtable to an SSTable and waits until every queued memtable is on disk.
+    pub(crate) fn flush(&mut self) {
+        if !self.memtable.is_empty() {
+            self.rotate_memtable();
+        }
+        if let Some((sender, _)) = &self.flusher {
+            let (done, wait) = mpsc::channel();
+            if sender.send(FlushTask::Sync(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+}
+
+impl StorageEngine for LsmTree {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        LsmTree::get(self, key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        self.insert(key, entry);
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        self.remove(key)
+    }
+
+    /// Yields entries in key order, reading the SSTables from disk as it goes.
+    fn scan(&self) -> EngineIter<'_> {
+        Box::new(self.iter())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        LsmTree::flush(self);
+        Ok(())
+    }
+
+    fn compaction_stats(&self) -> Option<CompactionStats> {
+        Some(LsmTree::compaction_stats(self))
+    }
+
+    fn set_compaction_paused(&self, paused: bool) {
+        LsmTree::set_compaction_paused(self, paused);
+    }
+
+    /// Counts the writes in the memtable, not the ones frozen and waiting to be flushed.
+    fn memory_bytes(&self) -> Option<usize> {
+        Some(self.memtable_bytes)
+    }
+
+    fn range(&self, range: (Bound<&[u8]>, Bound<&[u8]>)) -> EngineIter<'_> {
+        let (lower, upper) = (range.0.map(<[u8]>::to_vec), range.1.map(<[u8]>::to_vec));
+        Box::new(self.iter().skip_while(move |(key, _)| precedes(&lower, key)).take_while(move |(key, _)| !exceeds(&upper, key)))
+    }
+}
+
+/// Returns whether the key sorts before the lower bound.
+fn precedes(lower: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match lower {
+        Bound::Included(lower) => key < lower.as_slice(),
+        Bound::Excluded(lower) => key <= lower.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// Returns whether the key sorts after the upper bound, so no later key can fall within the range.
+fn exceeds(upper: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match upper {
+        Bound::Included(upper) => key > upper.as_slice(),
+        Bound::Excluded(upper) => key >= upper.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+impl Drop for LsmTree {
+    /// Stops compaction, waits for pending flushes, and writes the remaining memtables to disk.
+    fn drop(&mut self) {
+        if let Some(compactor) = self.compactor.take() {
+            self.compaction.stop.store(true, Ordering::Release);
+            compactor.thread().unpark();
+            let _ = compactor.join();
+        }
+        if let Some((sender, handle)) = self.flusher.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+        if !self.memtable.is_empty() {
+            let memtable = Arc::new(std::mem::take(&mut self.memtable));
+            self.levels.write().unwrap_or_else(PoisonError::into_inner).immutable.push(memtable);
+        }
+        let immutable = std::mem::take(&mut self.levels.write().unwrap_or_else(PoisonError::into_inner).immutable);
+        for memtable in immutable {
+            let id = self.next_id;
+            self.next_id += 1;
+            if let Err(e) = SsTable::write(sstable_path(&self.dir, id), memtable_records(&memtable), self.options.bloom_false_positive_rate) {
+                log::error!("Failed to flush memtable to SSTable {}: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Yields the newest version of every key across the merged sources, skipping deleted keys.
+///
+/// Sources are ordered newest first, so when several hold the same key the first one wins.
+pub(crate) struct LsmIter<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = Item<'a>> + 'a>>>,
+}
+
+impl<'a> Iterator for LsmIter<'a> {
+    type Item = (Cow<'a, Vec<u8>>, Cow<'a, Entry>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self
+                .sources
+                .iter_mut()
+                .filter_map(|source| source.peek().map(|(key, _)| key.as_slice()))
+                .min()?
+                .to_owned();
+            let mut newest = None;
+            for source in self.sources.iter_mut() {
+                if source.peek().is_some_and(|(candidate, _)| **candidate == key) {
+                    let item = source.next();
+                    newest = newest.or(item);
+                }
+            }
+            if let Some((key, Some(entry))) = newest {
+                return Some((key, entry));
+            }
+        }
+    }
+}
+
+fn sstable_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.sst", id))
+}
+
+fn memtable_records(memtable: &Memtable) -> impl Iterator<Item = (&Vec<u8>, Option<&Entry>)> {
+    memtable.iter().map(|(key, entry)| (key, entry.as_ref()))
+}
+
+/// Merges every SSTable of the tree into one once there are at least `compaction_threshold` of them.
+///
+/// The merged table keeps only the newest version of each key and drops tombstones, which is safe
+/// because the inputs include the oldest table. It takes the id of the newest input, so tables
+/// flushed meanwhile still shadow it. The swap happens under the levels lock, so readers see either
+/// the inputs or the merged table. The inputs are deleted afterwards; a crash before that can briefly
+/// resurrect keys whose tombstones were dropped.
+fn compact(levels: &RwLock<Levels>, state: &CompactionState, options: LsmOptions) -> io::Result<()> {
+    let inputs = levels.read().unwrap_or_else(PoisonError::into_inner).sstables.clone();
+    if inputs.len() < options.compaction_threshold {
+        return Ok(());
+    }
+    let sources: Vec<Box<dyn Iterator<Item = Item<'_>>>> = inputs
+        .iter()
+        .rev()
+        .map(|sstable| -> io::Result<Box<dyn Iterator<Item = Item<'_>>>> {
+            Ok(Box::new(sstable.iter()?.map(|(key, entry)| (Cow::Owned(key), entry.map(Cow::Owned)))))
+        })
+        .collect::<io::Result<_>>()?;
+    let merged = LsmIter { sources: sources.into_iter().map(Iterator::peekable).collect() };
+    let newest = inputs[inputs.len() - 1].path.clone();
+    let records: Vec<_> = merged.collect();
+    let input_records: usize = inputs.iter().map(|sstable| sstable.records).sum();
+    let output = if records.is_empty() {
+        None
+    } else {
+        let records = records.iter().map(|(key, entry)| (key.as_ref(), Some(entry.as_ref())));
+        Some(SsTable::write_unpublished(&newest, records, options.bloom_false_positive_rate)?)
+    };
+
+    let mut levels_guard = levels.write().unwrap_or_else(PoisonError::into_inner);
+    let bytes_written = output.as_ref().map_or(0, |sstable| sstable.len);
+    let merged = match output {
+        Some(mut sstable) => {
+            sstable.publish(newest)?;
+            vec![Arc::new(sstable)]
+        }
+        // Every key was deleted; the inputs are simply removed, including the newest one.
+        None => Vec::new(),
+    };
+    levels_guard.sstables.splice(..inputs.len(), merged.iter().cloned());
+    drop(levels_guard);
+
+    for sstable in &inputs {
+        if !merged.iter().any(|merged| merged.path == sstable.path) {
+            fs::remove_file(&sstable.path)?;
+        }
+    }
+    state.compactions.fetch_add(1, Ordering::Relaxed);
+    state.tables_merged.fetch_add(inputs.len() as u64, Ordering::Relaxed);
+    state.entries_dropped.fetch_add((input_records - records.len()) as u64, Ordering::Relaxed);
+    state.bytes_written.fetch_add(bytes_written, Ordering::Relaxed);
+    Ok(())
+}
+
+/// An immutable file of sorted records, each a big-endian u32 length followed by a bincode-encoded key and entry.
+///
+/// The file is split into blocks of `BLOCK_RECORDS` records. Only the first key and offset of each
+/// block are kept in memory, along with a bloom filter of every key; a lookup checks the filter
+/// and then reads the single block that could contain the key. With the `mmap` feature the block is
+/// decoded straight from a memory map of the file, so it is served from the page cache without
+/// being copied through a read buffer.
+#[derive(Debug)]
+struct SsTable {
+    path: PathBuf,
+    #[cfg(not(feature = "mmap"))]
+    file: Mutex<File>,
+    #[cfg(feature = "mmap")]
+    map: memmap2::Mmap,
+    index: Vec<(Vec<u8>, u64)>,
+    filter: BloomFilter,
+    len: u64,
+    records: usize,
+}
+
+impl SsTable {
+    /// Writes the sorted records to a new SSTable at the path, replacing it atomically once complete.
+    fn write<'a>(path: PathBuf, records: impl Iterator<Item = (&'a Vec<u8>, Option<&'a Entry>)>, false_positive_rate: f64) -> io::Result<Self> {
+        let mut sstable = Self::write_unpublished(&path, records, false_positive_rate)?;
+        sstable.publish(path)?;
+        Ok(sstable)
+    }
+
+    /// Writes the sorted records to a temporary file next to the path and opens it, without
+    /// replacing the file at the path yet.
+    fn write_unpublished<'a>(path: &Path, records: impl Iterator<Item = (&'a Vec<u8>, Option<&'a Entry>)>, false_positive_rate: f64) -> io::Result<Self> {
+        let temp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for record in records {
+            let data = bincode::serialize(&record).map_err(io::Error::other)?;
+            writer.write_all(&(data.len() as u32).to_be_bytes())?;
+            writer.write_all(&data)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Self::open(temp_path, false_positive_rate)
+    }
+
+    /// Moves a table written by `write_unpublished` to its final path, replacing any table there.
+    fn publish(&mut self, path: PathBuf) -> io::Result<()> {
+        fs::rename(&self.path, &path)?;
+        self.path = path;
+        Ok(())
+    }
+
+    /// Opens an existing SSTable, reading it once to rebuild the block index and bloom filter.
+    fn open(path: PathBuf, false_positive_rate: f64) -> io::Result<Self> {
+        let mut keys = Vec::new();
+        let mut index = Vec::new();
+        let mut offset = 0;
+        let mut records = Records::new(File::open(&path)?);
+        while let Some((key, _, len)) = records.next_record()? {
+            if keys.len() % BLOCK_RECORDS == 0 {
+                index.push((key.clone(), offset));
+            }
+            keys.push(key);
+            offset += len;
+        }
+        let mut filter = BloomFilter::new(keys.len(), false_positive_rate);
+        for key in &keys {
+            filter.insert(key);
+        }
+        #[cfg(not(feature = "mmap"))]
+        let source = Mutex::new(File::open(&path)?);
+        // SAFETY: SSTables are never modified once written; they are only replaced by renaming new files over them.
+        #[cfg(feature = "mmap")]
+        let source = unsafe { memmap2::Mmap::map(&File::open(&path)?)? };
+        Ok(Self {
+            path,
+            #[cfg(not(feature = "mmap"))]
+            file: source,
+            #[cfg(feature = "mmap")]
+            map: source,
+            index,
+            filter,
+            len: offset,
+            records: keys.len(),
+        })
+    }
+
+    /// Looks the key up, returning Some(None) if the table records it as deleted.
+    fn get(&self, key: &[u8]) -> io::Result<Option<Option<Entry>>> {
+        if !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self.index.binary_search_by(|(first, _)| first.as_slice().cmp(key)) {
+            Ok(block) => block,
+            Err(0) => return Ok(None),
+            Err(next) => next - 1,
+        };
+        let start = self.index[block].1;
+        let end = self.index.get(block + 1).map_or(self.len, |(_, offset)| *offset);
+        self.with_block(start, end, |block| {
+            let mut records = Records::new(block);
+            while let Some((candidate, entry, _)) = records.next_record()? {
+                if candidate == key {
+                    return Ok(Some(entry));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// Runs `f` on the bytes of the file between the offsets.
+    #[cfg(not(feature = "mmap"))]
+    fn with_block<T>(&self, start: u64, end: u64, f: impl FnOnce(&[u8]) -> io::Result<T>) -> io::Result<T> {
+        let mut data = vec![0; (end - start) as usize];
+        {
+            let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut data)?;
+        }
+        f(&data)
+    }
+
+    /// Runs `f` on the bytes of the file between the offsets.
+    #[cfg(feature = "mmap")]
+    fn with_block<T>(&self, start: u64, end: u64, f: impl FnOnce(&[u8]) -> io::Result<T>) -> io::Result<T> {
+        let block = self.map.get(start as usize..end as usize).ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        f(block)
+    }
+
+    /// Returns the records of the table in key order, streamed from a separate file handle.
+    fn iter(&self) -> io::Result<impl Iterator<Item = (Vec<u8>, Option<Entry>)>> {
+        let mut records = Records::new(File::open(&self.path)?);
+        let path = self.path.clone();
+        Ok(std::iter::from_fn(move || match records.next_record() {
+            Ok(record) => record.map(|(key, entry, _)| (key, entry)),
+            Err(e) => {
+                log::error!("Failed to read {}: {}", path.display(), e);
+                None
+            }
+        }))
+    }
+}
+
+/// Reads length-prefixed records from an SSTable.
+struct Records<R> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> Records<R> {
+    fn new(reader: R) -> Self {
+        Self { reader: BufReader::new(reader) }
+    }
+
+    /// Returns the next key and entry along with the number of bytes the record took, or None at the end.
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+        let (key, entry) = bincode::deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some((key, entry, 4 + len as u64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: &str) -> Entry {
+        Entry::new(value.as_bytes().to_vec())
+    }
+
+    fn sstable_count(tree: &LsmTree) -> usize {
+        tree.levels.read().unwrap().sstables.len()
+    }
+
+    #[test]
+    fn test_lsm_get_and_remove() {
+        let dir = Path::new("logs/test_lsm_get_and_remove");
+        let _ = fs::remove_dir_all(dir);
+        let mut tree = LsmTree::open(dir, LsmOptions::new()).unwrap();
+        tree.insert(b"key1".to_vec(), entry("value1"));
+        tree.insert(b"key2".to_vec(), entry("value2"));
+        assert_eq!(tree.get(b"key1").map(|entry| entry.value.to_vec()), Some(b"value1".to_vec()));
+        assert_eq!(tree.remove(b"key1").map(|entry| entry.value.to_vec()), Some(b"value1".to_vec()));
+        assert!(tree.get(b"key1").is_none());
+        assert!(tree.remove(b"key1").is_none());
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.into_owned()).collect();
+        assert_eq!(keys, vec![b"key2".to_vec()]);
+    }
+
+    #[test]
+    fn test_lsm_flush_and_reopen() {
+        let dir = Path::new("logs/test_lsm_flush_and_reopen");
+        let _ = fs::remove_dir_all(dir);
+        {
+            // A tiny memtable makes every few writes produce a new SSTable, and compaction is kept
+            // from merging them.
+            let mut tree = LsmTree::open(dir, LsmOptions::new().memtable_size(256).compaction_threshold(usize::MAX)).unwrap();
+            for i in 0..100 {
+                tree.insert(format!("key{:03}", i).into_bytes(), entry(&format!("value{}", i)));
+            }
+            tree.remove(b"key050");
+            tree.insert(b"key010".to_vec(), entry("updated"));
+        }
+        let tree = LsmTree::open(dir, LsmOptions::new().memtable_size(256).compaction_threshold(usize::MAX)).unwrap();
+        assert!(sstable_count(&tree) > 1);
+        assert_eq!(tree.get(b"key000").map(|entry| entry.value.to_vec()), Some(b"value0".to_vec()));
+        assert_eq!(tree.get(b"key010").map(|entry| entry.value.to_vec()), Some(b"updated".to_vec()));
+        assert_eq!(tree.get(b"key099").map(|entry| entry.value.to_vec()), Some(b"value99".to_vec()));
+        assert!(tree.get(b"key050").is_none());
+        assert!(tree.get(b"missing").is_none());
+
+        // Iteration merges every SSTable in key order, keeping the newest version of each key.
+        let entries: Vec<_> = tree.iter().map(|(key, entry)| (key.into_owned(), entry.into_owned().value)).collect();
+        assert_eq!(entries.len(), 99);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(entries.contains(&(b"key010".to_vec(), b"updated".to_vec().into())));
+    }
+
+    #[test]
+    fn test_lsm_compaction() {
+        let dir = Path::new("logs/test_lsm_compaction");
+        let _ = fs::remove_dir_all(dir);
+        let options = LsmOptions::new().memtable_size(256).compaction_threshold(2).compaction_interval(Duration::from_millis(10));
+        let mut tree = LsmTree::open(dir, options).unwrap();
+        tree.set_compaction_paused(true);
+        for i in 0..50 {
+            tree.insert(format!("key{:02}", i).into_bytes(), entry("old"));
+        }
+        for i in 0..25 {
+            tree.remove(format!("key{:02}", i).as_bytes());
+        }
+        tree.insert(b"key30".to_vec(), entry("new"));
+        tree.flush();
+        thread::sleep(Duration::from_millis(50));
+        assert!(sstable_count(&tree) > 2);
+        assert_eq!(tree.compaction_stats().compactions, 0);
+
+        tree.set_compaction_paused(false);
+        for _ in 0..500 {
+            if sstable_count(&tree) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sstable_count(&tree), 1);
+        let stats = tree.compaction_stats();
+        assert!(stats.compactions >= 1);
+        assert!(stats.entries_dropped > 0);
+        assert_eq!(tree.levels.read().unwrap().sstables[0].records, 25);
+
+        assert!(tree.get(b"key00").is_none());
+        assert_eq!(tree.get(b"key30").map(|entry| entry.value.to_vec()), Some(b"new".to_vec()));
+        assert_eq!(tree.get(b"key31").map(|entry| entry.value.to_vec()), Some(b"old".to_vec()));
+        assert_eq!(tree.iter().count(), 25);
+        drop(tree);
+
+        // Only the merged table is left on disk.
+        let tree = LsmTree::open(dir, options).unwrap();
+        assert_eq!(sstable_count(&tree), 1);
+        assert_eq!(tree.iter().count(), 25);
+    }
+}
@@ -1,228 +1,132 @@
-mod transaction_log;
-
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
-use std::net::TcpListener;
-use std::sync::{Arc, Mutex, RwLock};
+use std::env;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use log::error;
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use snap::raw::Encoder as SnapEncoder;
-use snap::raw::Decoder as SnapDecoder;
-use crate::transaction_log::{handle_client, TransactionLog};
-
-struct StorageServer {
-    partitions: Vec<Arc<RwLock<Partition>>>,
-    replicas: usize,
-}
-
-#[derive(Debug)]
-struct Partition {
-    data: HashMap<String, Vec<u8>>,
-    replicas: Vec<Arc<RwLock<Partition>>>,
-}
-
-impl StorageServer {
-    fn new(num_partitions: usize, num_replicas: usize) -> Self {
-        let mut partitions = Vec::with_capacity(num_partitions);
-        for _ in 0..num_partitions {
-            let mut replicas = Vec::with_capacity(num_replicas);
-            for _ in 0..num_replicas {
-                replicas.push(Arc::new(RwLock::new(Partition {
-                    data: HashMap::new(),
-                    replicas: Vec::with_capacity(num_replicas),
-                })));
-            }
-            partitions.push(Arc::clone(&replicas[0]));
-            for replica in replicas.iter() {
-                let mut replica_guard = match replica.write() {
-                    Ok(guard) => guard,
-                    Err(poisoned) => poisoned.into_inner(),
-                };
-                replica_guard.replicas = replicas.clone();
+use std::time::Duration;
+use log::{error, info};
+use flowdb::{async_server, bench, grpc, http, memcached, resp};
+use flowdb::bench::{BenchArgs, BenchTarget};
+use flowdb::tls::TlsConfig;
+use flowdb::{Config, StorageServer};
+
+/// The configuration file read at startup unless another is given as the first argument. Without
+/// one, the server starts with the defaults described in `flowdb::config`. A first argument of
+/// `bench` benchmarks a server instead, as described in `flowdb::bench`.
+const DEFAULT_CONFIG: &str = "flowdb.toml";
+/// How often the main thread checks whether it has been asked to stop.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long shutdown waits for requests the binary protocol's clients have under way.
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set by the signal handler when SIGINT or SIGTERM arrives.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let config = match args.next() {
+        Some(command) if command == "bench" => return run_bench(args),
+        path => load_config(path.map(PathBuf::from))?,
+    };
+
+    // Replay the writes logged before the last shutdown, then log new ones to the same directory.
+    let server = Arc::new(build_server(&config)?);
+    let checkpointer = config.checkpoint_interval.map(|interval| server.start_checkpointer(interval));
+    spawn_listener("HTTP", http::listen, &server, config.http_address, config.tls.clone());
+    spawn_listener("RESP", resp::listen, &server, config.resp_address, config.tls.clone());
+    spawn_listener("memcached", memcached::listen, &server, config.memcached_address, config.tls.clone());
+    // The binary protocol and gRPC are served by tasks on a tokio runtime rather than a thread per client.
+    let runtime = tokio::runtime::Runtime::new()?;
+    {
+        let grpc = grpc::listen(Arc::clone(&server), config.grpc_address, config.tls.clone());
+        runtime.spawn(async move {
+            if let Err(e) = grpc.await {
+                error!("gRPC listener stopped: {}", e);
             }
-        }
-        Self { partitions, replicas: num_replicas }
-    }
-
-    fn get_partition(&self, key: &str) -> Arc<RwLock<Partition>> {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let partition_index = hasher.finish() as usize % self.partitions.len();
-        self.partitions[partition_index].clone()
+        });
     }
+    let listener = runtime.spawn(async_server::listen(Arc::clone(&server), config.address, config.tls));
 
-    /// Returns the value associated with the given key, or an error if the key is not found.
-    fn get(&self, key: &str) -> Result<String, ()> {
-        // Determine which partition the key belongs to.
-        let partition = self.get_partition(key);
-
-        // Acquire a read lock on the partition to ensure exclusive access.
-        let partition_guard = partition.read().unwrap();
-
-        // Look up the key in the partition data.
-        let compressed_data = match partition_guard.data.get(key) {
-            Some(data) => data,
-            None => return Err(()),
-        };
-        let data = decompress(compressed_data).ok_or(())?;
-        let value = String::from_utf8(data).map_err(|_| ())?;
-        Ok(value)
+    // Run until stopped by a signal or until the binary protocol's listener fails.
+    install_signal_handlers();
+    while !SHUTDOWN_REQUESTED.load(Ordering::Acquire) && !listener.is_finished() {
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
     }
-
-    /// Inserts a key-value pair into the partition and its replicas.
-    fn put(&self, key: &str, value: &str) -> Result<(), ()> {
-        let data = compress(value.as_bytes());
-
-        // Determine which partition the key belongs to.
-        let partition = self.get_partition(key);
-
-        // Acquire a lock on the partition to ensure exclusive access.
-        let mut partition_guard = partition.write().unwrap();
-
-        // Insert the key-value pair into the primary partition.
-        partition_guard.data.insert(key.to_owned(), data.to_owned());
-
-        // Insert the key-value pair into the replica partitions.
-        for replica in partition_guard.replicas.iter().skip(1) {
-            let mut replica_guard = replica.write().unwrap();
-            replica_guard.data.insert(key.to_owned(), data.to_owned());
-        }
-
-        // Return success.
-        Ok(())
+    let result = match listener.is_finished() {
+        true => runtime.block_on(listener).unwrap_or_else(|_| Err(io::Error::other("listener panicked"))),
+        false => Ok(()),
+    };
+    info!("Shutting down");
+    runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.stop();
     }
+    server.shutdown().map_err(|e| io::Error::other(format!("server didn't shut down cleanly: {}", e)))?;
+    result
 }
 
-fn compress(data: &[u8]) -> Vec<u8> {
-    SnapEncoder::new().compress_vec(data).unwrap()
+/// Reads the configuration file, or the default one if there is one, or else uses the defaults.
+fn load_config(path: Option<PathBuf>) -> io::Result<Config> {
+    match path {
+        Some(path) => Config::from_file(path),
+        None if Path::new(DEFAULT_CONFIG).exists() => Config::from_file(DEFAULT_CONFIG),
+        None => Ok(Config::default()),
+    }
 }
 
-fn decompress(data: &[u8]) -> Option<Vec<u8>> {
-    SnapDecoder::new().decompress_vec(data).ok()
+fn build_server(config: &Config) -> io::Result<StorageServer> {
+    config.builder().build().map_err(|e| io::Error::other(format!("server can't be started: {}", e)))
 }
 
-fn main() -> std::io::Result<()> {
-    let log = Arc::new(Mutex::new(TransactionLog::new("logs/transactions.log", 1024 * 1024 * 10, 10, 0.5, 8192, Box::new(|data| data.to_vec()))?));
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-    builder.set_private_key_file("key.pem", SslFiletype::PEM)?;
-    builder.set_certificate_chain_file("cert.pem")?;
-    let acceptor = builder.build();
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-    for stream in listener.incoming() {
-        let log = log.clone();
-        let acceptor = acceptor.clone();
-        thread::spawn(move || {
-            match acceptor.accept(stream.unwrap()) {
-                Ok(stream) => {
-                    if let Err(e) = handle_client(stream, log) {
-                        error!("Error handling client: {}", e);
-                    }
-                }
-                Err(e) => {
-                    error!("TLS accept error: {}", e);
-                }
-            }
-        });
+/// Benchmarks the server the arguments after `bench` describe and prints the report.
+fn run_bench(args: impl Iterator<Item = String>) -> io::Result<()> {
+    let args = BenchArgs::parse(args).map_err(|message| io::Error::new(ErrorKind::InvalidInput, message))?;
+    let target = match args.remote {
+        Some(address) => BenchTarget::Remote(address),
+        None => BenchTarget::Embedded(Arc::new(build_server(&load_config(args.config)?)?)),
+    };
+    let report = bench::run(&target, &args.options)?;
+    println!("{}", report);
+    if let BenchTarget::Embedded(server) = target {
+        server.shutdown().map_err(|e| io::Error::other(format!("server didn't shut down cleanly: {}", e)))?;
     }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Runs a frontend's listener on its own thread, logging why it stopped if it does.
+fn spawn_listener(name: &'static str, listen: fn(Arc<StorageServer>, String, Option<&TlsConfig>) -> io::Result<()>, server: &Arc<StorageServer>, address: String, tls: Option<TlsConfig>) {
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        if let Err(e) = listen(server, address, tls.as_ref()) {
+            error!("{} listener stopped: {}", name, e);
+        }
+    });
+}
 
-    #[test]
-    fn test_new_storage_server() {
-        let num_partitions = 3;
-        let num_replicas = 2;
-        let storage_server = StorageServer::new(num_partitions, num_replicas);
-        assert_eq!(storage_server.partitions.len(), num_partitions);
-        assert_eq!(storage_server.replicas, num_replicas);
-    }
+/// Makes SIGINT and SIGTERM request a shutdown instead of killing the process.
+#[cfg(unix)]
+fn install_signal_handlers() {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
 
-    #[test]
-    fn test_get_partition() {
-        let num_partitions = 3;
-        let num_replicas = 2;
-        let storage_server = StorageServer::new(num_partitions, num_replicas);
-        let partition = storage_server.get_partition("test_key");
-        assert!(partition.read().unwrap().data.is_empty());
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
     }
 
-    #[test]
-    fn test_get() {
-        let num_partitions = 3;
-        let num_replicas = 2;
-        let storage_server = StorageServer::new(num_partitions, num_replicas);
-        let key = "test_key";
-        let value = "test_value";
-        let compressed_value = compress(value.as_bytes());
-        let partition = storage_server.get_partition(key);
-        let mut partition_guard = partition.write().unwrap();
-        partition_guard.data.insert(key.to_owned(), compressed_value.to_owned());
-        drop(partition_guard); // Release the lock early.
-        let result = storage_server.get(key);
-        assert_eq!(result, Ok(value.to_owned()));
+    // Only an atomic store happens in the handler, which is safe to do while interrupted.
+    extern "C" fn request_shutdown(_: i32) {
+        SHUTDOWN_REQUESTED.store(true, Ordering::Release);
     }
 
-    #[test]
-    fn test_put_replicas() {
-        let num_partitions = 4;
-        let num_replicas = 2;
-        let storage_server = StorageServer::new(num_partitions, num_replicas);
-        let key = "test_key";
-        let value = "test_value";
-        let compressed_value = compress(value.as_bytes());
-        let result = storage_server.put(key, value);
-        assert_eq!(result, Ok(()));
-        for i in 1..num_replicas {
-            let partition = storage_server.get_partition(key);
-            let replica = &partition.write().unwrap().replicas[i];
-            let replica_guard = replica.read().unwrap();
-            assert_eq!(replica_guard.data.get(key), Some(&compressed_value));
+    for signum in [SIGINT, SIGTERM] {
+        // SAFETY: the handler only touches an atomic, and the previous handler returned isn't used.
+        unsafe {
+            signal(signum, request_shutdown);
         }
     }
+}
 
-    #[test]
-    fn test_put_existing_key() {
-        let num_partitions = 4;
-        let num_replicas = 2;
-        let storage_server = StorageServer::new(num_partitions, num_replicas);
-        let key = "test_key";
-        let value1 = "test_value1";
-        let value2 = "test_value2";
-        let result = storage_server.put(key, value1);
-        assert_eq!(result, Ok(()));
-        let result = storage_server.put(key, value2);
-        assert_eq!(result, Ok(()));
-        let result = storage_server.get(key);
-        assert_eq!(result, Ok(value2.to_owned()));
-    }
-
-    #[test]
-    fn test_get_nonexistent_key() {
-        let num_partitions = 4;
-        let num_replicas = 2;
-        let storage_server = StorageServer::new(num_partitions, num_replicas);
-        let key = "test_key".to_string();
-        let result = storage_server.get(&key.clone());
-        assert_eq!(result, Err(()));
-    }
-
-    #[test]
-    fn test_compress() {
-        let data = b"hello world";
-        let compressed_data = compress(data);
-        assert_ne!(data.as_slice(), compressed_data);
-    }
-
-    #[test]
-    fn test_decompress() {
-        let data = b"hello world";
-        let compressed_data = compress(data);
-        let decompressed_data = decompress(&compressed_data).unwrap();
-        assert_eq!(data, &decompressed_data[..]);
-    }
-}
\ No newline at end of file
+/// Signals aren't handled elsewhere, so the server stops only if its listener fails.
+#[cfg(not(unix))]
+fn install_signal_handlers() {}
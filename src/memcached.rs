@@ -0,0 +1,216 @@
+//! The memcached text protocol, so applications using a memcached client can store their items in
+//! a StorageServer.
+//!
+//! Supported commands are `get <key>*`, `set`, `add` and `replace` as
+//! `<command> <key> <flags> <exptime> <bytes> [noreply]` followed by the data block,
+//! `delete <key> [noreply]`, `version` and `quit`. Commands may be pipelined.
+//!
+//! An exptime of 0 never expires, up to 30 days is a number of seconds from now, larger values are
+//! a Unix time, and negative ones expire the item at once, as in memcached. Items are stored with
+//! their flags as a four-byte big-endian prefix of the value, so keys used over memcached should
+//! only be read over memcached; values too short to hold the prefix are reported as misses.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::info;
+use crate::storage_server::StorageServer;
+use crate::tls::{self, TlsConfig};
+
+/// The longest key memcached accepts.
+const MAX_KEY_LEN: usize = 250;
+/// The largest data block accepted.
+const MAX_VALUE_LEN: usize = 64 << 20;
+/// Exptimes above this many seconds are Unix times rather than offsets from now.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// How a storage command treats a key that is already present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Store {
+    Set,
+    /// Store only if the key is missing.
+    Add,
+    /// Store only if the key is present.
+    Replace,
+}
+
+impl StorageServer {
+    /// Serves memcached commands on the connection until the client closes it or sends `quit`, or
+    /// the connection fails.
+    pub fn serve_memcached<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut out = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            let command = str::from_utf8(&line).unwrap_or("").trim_end_matches(['\r', '\n']).to_owned();
+            let args: Vec<&str> = command.split(' ').filter(|arg| !arg.is_empty()).collect();
+            match args.first().copied() {
+                None => out.extend_from_slice(b"ERROR\r\n"),
+                Some("quit") => return Ok(()),
+                Some("get") if args.len() > 1 => self.get_items(&args[1..], &mut out),
+                Some(name @ ("set" | "add" | "replace")) => {
+                    let store = match name {
+                        "set" => Store::Set,
+                        "add" => Store::Add,
+                        _ => Store::Replace,
+                    };
+                    let reply = self.store_item(store, &args[1..], &mut reader)?;
+                    if args.last() != Some(&"noreply") {
+                        out.extend_from_slice(reply.as_bytes());
+                    }
+                }
+                Some("delete") if (2..=4).contains(&args.len()) => {
+                    let reply = match self.delete(args[1]) {
+                        Ok(true) => "DELETED\r\n",
+                        Ok(false) => "NOT_FOUND\r\n",
+                        Err(_) => "SERVER_ERROR delete failed\r\n",
+                    };
+                    if args.last() != Some(&"noreply") {
+                        out.extend_from_slice(reply.as_bytes());
+                    }
+                }
+                Some("version") => out.extend_from_slice(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).as_bytes()),
+                Some("get" | "delete") => out.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n"),
+                Some(_) => out.extend_from_slice(b"ERROR\r\n"),
+            }
+            // Replies to pipelined commands are sent together once every buffered command is handled.
+            if reader.buffer().is_empty() {
+                reader.get_mut().write_all(&out)?;
+                reader.get_mut().flush()?;
+                out.clear();
+            }
+        }
+    }
+
+    fn get_items(&self, keys: &[&str], out: &mut Vec<u8>) {
+        for &key in keys {
+            let Ok(item) = self.get_bytes(key) else { continue };
+            let Some((flags, data)) = item.split_first_chunk::<4>() else { continue };
+            out.extend_from_slice(format!("VALUE {} {} {}\r\n", key, u32::from_be_bytes(*flags), data.len()).as_bytes());
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"END\r\n");
+    }
+
+    /// Reads the data block of a storage command and stores it, returning the reply line.
+    fn store_item(&self, store: Store, args: &[&str], reader: &mut impl BufRead) -> io::Result<&'static str> {
+        let args = match args {
+            [key, flags, exptime, bytes] | [key, flags, exptime, bytes, "noreply"] => (key, flags.parse::<u32>(), exptime.parse::<i64>(), bytes.parse::<usize>()),
+            _ => return Ok("CLIENT_ERROR bad command line format\r\n"),
+        };
+        let (key, Ok(flags), Ok(exptime), Ok(bytes)) = args else {
+            return Ok("CLIENT_ERROR bad command line format\r\n");
+        };
+        if bytes > MAX_VALUE_LEN {
+            // Skip the data block so the next command can be read.
+            io::copy(&mut reader.take(bytes as u64 + 2), &mut io::sink())?;
+            return Ok("SERVER_ERROR object too large for cache\r\n");
+        }
+        let mut data = vec![0; bytes + 2];
+        reader.read_exact(&mut data)?;
+        if !data.ends_with(b"\r\n") {
+            return Err(io::Error::new(ErrorKind::InvalidData, "data block is not terminated"));
+        }
+        if key.len() > MAX_KEY_LEN || key.bytes().any(|byte| byte.is_ascii_control()) {
+            return Ok("CLIENT_ERROR bad command line format\r\n");
+        }
+
+        let mut item = Vec::with_capacity(4 + bytes);
+        item.extend_from_slice(&flags.to_be_bytes());
+        item.extend_from_slice(&data[..bytes]);
+        let ttl = expiry(exptime, SystemTime::now());
+        let stored = match store {
+            Store::Set => self.put_value(key.as_bytes(), &item, ttl).map(|_| true),
+            Store::Add => self.put_if_present(key.as_bytes(), &item, ttl, false),
+            Store::Replace => self.put_if_present(key.as_bytes(), &item, ttl, true),
+        };
+        Ok(match stored {
+            Ok(true) => "STORED\r\n",
+            Ok(false) => "NOT_STORED\r\n",
+            Err(_) => "SERVER_ERROR write failed\r\n",
+        })
+    }
+}
+
+/// Returns the TTL an exptime stands for as of `now`, or None if the item never expires.
+fn expiry(exptime: i64, now: SystemTime) -> Option<Duration> {
+    match exptime {
+        0 => None,
+        ..0 => Some(Duration::ZERO),
+        1..=MAX_RELATIVE_EXPTIME => Some(Duration::from_secs(exptime as u64)),
+        _ => Some((UNIX_EPOCH + Duration::from_secs(exptime as u64)).duration_since(now).unwrap_or_default()),
+    }
+}
+
+/// Accepts memcached clients on the address and serves each on its own thread, over TLS if
+/// configured, until accepting fails.
+pub fn listen(server: Arc<StorageServer>, address: impl ToSocketAddrs, tls: Option<&TlsConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving memcached on {}", listener.local_addr()?);
+    tls::serve(listener, tls, "memcached", move |connection| server.serve_memcached(connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn test_expiry() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(expiry(0, now), None);
+        assert_eq!(expiry(-1, now), Some(Duration::ZERO));
+        assert_eq!(expiry(60, now), Some(Duration::from_secs(60)));
+        assert_eq!(expiry(1_000_000_100, now), Some(Duration::from_secs(100)));
+        assert_eq!(expiry(999_999_000, now), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_memcached() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_memcached(listener.accept().unwrap().0));
+        }
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut call = |request: &[u8], expected: &[u8]| {
+            stream.write_all(request).unwrap();
+            let mut reply = vec![0; expected.len()];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(String::from_utf8_lossy(&reply), String::from_utf8_lossy(expected));
+        };
+
+        call(b"set user:1 42 0 5\r\nalice\r\n", b"STORED\r\n");
+        call(b"get user:1 missing\r\n", b"VALUE user:1 42 5\r\nalice\r\nEND\r\n");
+        call(b"add user:1 0 0 3\r\nbob\r\n", b"NOT_STORED\r\n");
+        call(b"replace user:2 0 0 3\r\nbob\r\n", b"NOT_STORED\r\n");
+        call(b"add user:2 7 0 3\r\nbob\r\n", b"STORED\r\n");
+        call(b"replace user:1 1 0 6\r\nalicia\r\n", b"STORED\r\n");
+
+        // Pipelined commands are answered in order, and noreply commands aren't answered at all.
+        call(
+            b"set raw 0 0 2 noreply\r\n\x00\xff\r\nget user:1 user:2 raw\r\ndelete user:2\r\ndelete user:2\r\n",
+            b"VALUE user:1 1 6\r\nalicia\r\nVALUE user:2 7 3\r\nbob\r\nVALUE raw 0 2\r\n\x00\xff\r\nEND\r\nDELETED\r\nNOT_FOUND\r\n",
+        );
+
+        // A negative exptime expires the item at once, and a positive one keeps it for now.
+        call(b"set temp 0 -1 1\r\nx\r\nget temp\r\n", b"STORED\r\nEND\r\n");
+        call(b"set temp 0 60 1\r\nx\r\nget temp\r\n", b"STORED\r\nVALUE temp 0 1\r\nx\r\nEND\r\n");
+        assert!(server.get_partition(b"temp").read().unwrap().data.get(b"temp").unwrap().expires_at.is_some());
+
+        call(b"set key flags 0 1\r\n", b"CLIENT_ERROR bad command line format\r\n");
+        call(b"incr counter 1\r\n", b"ERROR\r\n");
+        call(b"version\r\n", format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).as_bytes());
+        call(b"get user:1\r\n", b"VALUE user:1 1 6\r\nalicia\r\nEND\r\n");
+    }
+}
@@ -0,0 +1,159 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+use crate::entry::Entry;
+use crate::error::FlowDbError;
+use crate::storage_server::StorageServer;
+
+/// A read-only view of a StorageServer pinned to the version current when it was taken.
+///
+/// Reads through the view see every write up to that version and none after it, across all
+/// partitions, while writers carry on unblocked. The server keeps the versions a view may need for
+/// as long as it is alive, so views should not be held longer than necessary.
+///
+/// Expiry is judged at read time, and keys removed by the TTL sweeper or by eviction disappear from
+/// views too.
+pub struct SnapshotView<'a> {
+    server: &'a StorageServer,
+    version: u64,
+}
+
+impl<'a> SnapshotView<'a> {
+    pub(crate) fn new(server: &'a StorageServer, version: u64) -> Self {
+        Self { server, version }
+    }
+
+    /// Returns the version the view is pinned to.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the value the key had as of the view's version, as a UTF-8 string.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<String, FlowDbError> {
+        String::from_utf8(self.get_bytes(key)?).map_err(|_| FlowDbError::InvalidValue)
+    }
+
+    /// Returns the raw bytes of the value the key had as of the view's version.
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Vec<u8>, FlowDbError> {
+        let key = key.as_ref();
+        let partition = self.server.get_partition(key);
+        let partition_guard = partition.read()?;
+        let entry = partition_guard.history.state_at(key, self.version, partition_guard.data.get(key));
+        match entry.filter(|entry| entry.is_live()) {
+            Some(entry) => self.server.decode_entry(&entry),
+            None => Err(FlowDbError::NotFound),
+        }
+    }
+
+    /// Returns every key-value pair as of the view's version, one partition at a time.
+    ///
+    /// Each partition is read-locked only while its pairs are copied out. Keys holding collections,
+    /// values that can't be decoded and partitions whose lock is poisoned are skipped.
+    pub fn scan(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        (0..self.server.partition_count()).flat_map(move |index| {
+            let partition = self.server.partition(index);
+            let Ok(partition_guard) = partition.read() else {
+                return Vec::new();
+            };
+            let current = partition_guard.data.iter().map(|(key, entry)| (key, Some(entry)));
+            let removed = partition_guard.history.keys().filter(|key| partition_guard.data.get(key).is_none()).map(|key| (Cow::Borrowed(key), None));
+            let pairs: Vec<_> = current
+                .chain(removed)
+                .filter_map(|(key, entry)| {
+                    let entry = partition_guard.history.state_at(&key, self.version, entry)?;
+                    let value = self.server.decode_entry(&entry).ok().filter(|_| entry.is_live())?;
+                    Some((key.into_owned(), value))
+                })
+                .collect();
+            pairs
+        })
+    }
+}
+
+impl Drop for SnapshotView<'_> {
+    fn drop(&mut self) {
+        self.server.unpin(self.version);
+    }
+}
+
+/// The earlier states of keys overwritten while snapshot views are pinned, kept per partition.
+///
+/// Each key maps to the states it was replaced from, with the version of the write that replaced
+/// them, oldest first. None records that the key was missing.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    superseded: HashMap<Vec<u8>, Vec<(u64, Option<Entry>)>>,
+}
+
+impl History {
+    /// Records that the write at `version` replaced the key's previous state.
+    pub(crate) fn record(&mut self, key: &[u8], version: u64, previous: Option<Entry>) {
+        self.superseded.entry(key.to_vec()).or_default().push((version, previous));
+    }
+
+    /// Returns the key's state as of `version`, given its current state.
+    ///
+    /// That is the state replaced by the first write after `version`, or the current state if there
+    /// has been no such write.
+    pub(crate) fn state_at<'a>(&'a self, key: &[u8], version: u64, current: Option<Cow<'a, Entry>>) -> Option<Cow<'a, Entry>> {
+        let replaced = self.superseded.get(key).and_then(|states| states.iter().find(|(replaced_at, _)| *replaced_at > version));
+        match replaced {
+            Some((_, state)) => state.as_ref().map(Cow::Borrowed),
+            None => current,
+        }
+    }
+
+    /// Returns the keys with recorded states.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.superseded.keys()
+    }
+
+    /// Drops the states no view can read anymore: those replaced at or before the oldest pinned
+    /// version, or all of them if no view is pinned.
+    pub(crate) fn prune(&mut self, oldest_pinned: Option<u64>) {
+        match oldest_pinned {
+            Some(oldest) => self.superseded.retain(|_, states| {
+                states.retain(|(replaced_at, _)| *replaced_at > oldest);
+                !states.is_empty()
+            }),
+            None => self.superseded.clear(),
+        }
+    }
+}
+
+/// The versions snapshot views are pinned to, with how many views are pinned to each.
+#[derive(Debug, Default)]
+pub(crate) struct Pins {
+    versions: RwLock<BTreeMap<u64, usize>>,
+}
+
+impl Pins {
+    /// Pins a view to the current version and returns it.
+    ///
+    /// The version is read under the lock, so a writer that sees no pins has already taken its
+    /// version and that write is visible to any view pinned afterwards.
+    pub(crate) fn pin(&self, version: &AtomicU64) -> u64 {
+        let mut versions = self.versions.write().unwrap_or_else(PoisonError::into_inner);
+        let current = version.load(Ordering::SeqCst);
+        *versions.entry(current).or_default() += 1;
+        current
+    }
+
+    /// Releases a pin, returning the oldest version still pinned.
+    pub(crate) fn unpin(&self, version: u64) -> Option<u64> {
+        let mut versions = self.versions.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = versions.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                versions.remove(&version);
+            }
+        }
+        versions.keys().next().copied()
+    }
+
+    /// Returns whether any view is pinned, in which case overwritten states must be kept.
+    pub(crate) fn any(&self) -> bool {
+        !self.versions.read().unwrap_or_else(PoisonError::into_inner).is_empty()
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::backend::Backend;
+use crate::codec::{Codec, NoCompression, SharedCodec, Snappy, Zstd, ZSTD_LEVEL};
+
+/// Settings for a namespace created with `StorageServer::create_namespace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceOptions {
+    pub(crate) num_partitions: usize,
+    pub(crate) num_replicas: usize,
+    pub(crate) backend: Backend,
+    pub(crate) codec: SharedCodec,
+    pub(crate) default_ttl: Option<Duration>,
+}
+
+impl NamespaceOptions {
+    /// Creates options for a namespace with its own partitions, Snappy-compressed values, and no default TTL.
+    pub fn new(num_partitions: usize, num_replicas: usize) -> Self {
+        Self { num_partitions, num_replicas, backend: Backend::default(), codec: SharedCodec(Arc::new(Snappy)), default_ttl: None }
+    }
+
+    /// Sets the backend used by the namespace's partitions.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Sets whether values in the namespace are compressed, with Snappy if they are.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.codec = match compression {
+            true => SharedCodec(Arc::new(Snappy)),
+            false => SharedCodec(Arc::new(NoCompression)),
+        };
+        self
+    }
+
+    /// Sets the codec values in the namespace are compressed with.
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.codec = SharedCodec(codec);
+        self
+    }
+
+    /// Compresses values in the namespace with zstd and a dictionary trained on the first `samples`
+    /// values put into it, which suits many small values of one kind, such as JSON documents. The
+    /// dictionary is kept with the namespace, in the server's directory if it has one, and reloaded
+    /// when the namespace is created again; `StorageServer::train_dictionary` trains a new one.
+    pub fn dictionary_compression(mut self, samples: usize) -> Self {
+        self.codec = SharedCodec(Arc::new(Zstd::trained(ZSTD_LEVEL, samples)));
+        self
+    }
+
+    /// Sets the TTL applied to values put into the namespace without an explicit TTL.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::backend::PartitionData;
+use crate::entry::Entry;
+
+/// Returns the path of the file holding the data of the partition with the given index.
+pub(crate) fn partition_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("partition-{}.bin", index))
+}
+
+/// Writes every entry of the partition to its file, replacing the previous contents atomically.
+///
+/// The entries are first written to a temporary file, which is then renamed over the old one, so a
+/// crash during a flush leaves the previous flush intact.
+pub(crate) fn save_partition(path: &Path, data: &PartitionData) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let entries: Vec<_> = data.iter().filter(|(_, entry)| entry.is_live()).collect();
+    bincode::serialize_into(&mut writer, &entries).map_err(io::Error::other)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Reads the entries previously written by `save_partition`.
+pub(crate) fn load_partition(path: &Path) -> io::Result<Vec<(Vec<u8>, Entry)>> {
+    let reader = BufReader::new(File::open(path)?);
+    bincode::deserialize_from(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a snapshot file holding the entries of every given partition, replacing any previous
+/// snapshot at the path atomically. Returns how many entries were written.
+///
+/// The file is a u64 entry count followed by that many bincode-encoded keys and entries.
+pub(crate) fn save_snapshot<'a>(path: &Path, partitions: impl Iterator<Item = &'a PartitionData> + Clone) -> io::Result<usize> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let count = partitions.clone().map(|data| data.iter().filter(|(_, entry)| entry.is_live()).count()).sum::<usize>();
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    writer.write_all(&(count as u64).to_be_bytes())?;
+    for data in partitions {
+        for record in data.iter().filter(|(_, entry)| entry.is_live()) {
+            bincode::serialize_into(&mut writer, &record).map_err(io::Error::other)?;
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(count)
+}
+
+/// Streams the entries of a snapshot written by `save_snapshot` to `apply`, returning how many there were.
+pub(crate) fn load_snapshot(path: &Path, mut apply: impl FnMut(Vec<u8>, Entry)) -> io::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut count = [0; 8];
+    reader.read_exact(&mut count)?;
+    let count = u64::from_be_bytes(count) as usize;
+    for _ in 0..count {
+        let (key, entry) = bincode::deserialize_from(&mut reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        apply(key, entry);
+    }
+    Ok(count)
+}
+
+/// Removes the files of partitions past the first `partitions`, left behind by a server with more
+/// partitions; their keys now live in the files of the partitions that remain.
+pub(crate) fn remove_stale_partitions(dir: &Path, partitions: usize) -> io::Result<()> {
+    for file in partition_files(dir)? {
+        let in_use = (0..partitions).any(|index| partition_path(dir, index) == file);
+        if !in_use {
+            fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the files in the directory that hold partition data, in no particular order.
+pub(crate) fn partition_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_partition = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            name.strip_prefix("partition-").and_then(|rest| rest.strip_suffix(".bin")).is_some_and(|index| index.parse::<usize>().is_ok())
+        });
+        if is_partition {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,169 @@
+//! Assigning the copies of each partition to labeled nodes, so that losing a node, a rack or a zone
+//! takes down as few copies of any one partition as possible.
+//!
+//! Copies are placed one partition at a time: each copy goes to the node that shares the fewest
+//! failure domains with the partition's copies placed so far, and among those to the node holding
+//! the fewest copies overall. Domains are kept distinct as long as there are enough of them, and
+//! reused as evenly as possible otherwise.
+
+use std::sync::PoisonError;
+use crate::error::FlowDbError;
+use crate::storage_server::StorageServer;
+
+/// Where a node runs: its name and the zone and rack it is in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeLabels {
+    pub name: String,
+    pub zone: String,
+    /// The rack within the zone; racks of different zones are distinct even with the same name.
+    pub rack: String,
+}
+
+impl NodeLabels {
+    pub fn new(name: impl Into<String>, zone: impl Into<String>, rack: impl Into<String>) -> Self {
+        Self { name: name.into(), zone: zone.into(), rack: rack.into() }
+    }
+}
+
+/// The widest failure domain the copies of a partition are spread over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlacementPolicy {
+    /// Every copy on a different node.
+    #[default]
+    Nodes,
+    /// Every copy in a different rack, then on a different node.
+    Racks,
+    /// Every copy in a different zone, then in a different rack, then on a different node.
+    Zones,
+}
+
+/// The nodes of a server and the node each copy of each partition is placed on.
+#[derive(Debug)]
+pub(crate) struct Placement {
+    pub(crate) nodes: Vec<NodeLabels>,
+    policy: PlacementPolicy,
+    /// The node of each copy of each partition, by index into `nodes`, the leader's slot first.
+    pub(crate) copies: Vec<Vec<usize>>,
+}
+
+impl Placement {
+    pub(crate) fn new(nodes: Vec<NodeLabels>, policy: PlacementPolicy, partitions: usize, replicas: usize) -> Self {
+        let mut placement = Self { nodes, policy, copies: Vec::new() };
+        placement.place(partitions, replicas);
+        placement
+    }
+
+    /// Places the copies of the given number of partitions. Partitions are placed in order, so the
+    /// ones a server already had keep their nodes when partitions are added or removed.
+    pub(crate) fn place(&mut self, partitions: usize, replicas: usize) {
+        let mut load = vec![0usize; self.nodes.len()];
+        self.copies = (0..partitions)
+            .map(|partition| {
+                let mut chosen: Vec<usize> = Vec::with_capacity(replicas);
+                for _ in 0..replicas {
+                    let node = (0..self.nodes.len())
+                        .min_by_key(|&node| (self.shared_domains(node, &chosen), load[node], (node + self.nodes.len() - partition % self.nodes.len()) % self.nodes.len()))
+                        .expect("placement has nodes");
+                    load[node] += 1;
+                    chosen.push(node);
+                }
+                chosen
+            })
+            .collect();
+    }
+
+    /// Returns how many of the chosen nodes share each failure domain with the node, widest first.
+    fn shared_domains(&self, node: usize, chosen: &[usize]) -> (usize, usize, usize) {
+        let labels = &self.nodes[node];
+        let count = |same: &dyn Fn(&NodeLabels) -> bool| chosen.iter().filter(|&&other| same(&self.nodes[other])).count();
+        let zones = count(&|other| other.zone == labels.zone);
+        let racks = count(&|other| other.zone == labels.zone && other.rack == labels.rack);
+        let nodes = count(&|other| other.name == labels.name);
+        match self.policy {
+            PlacementPolicy::Nodes => (0, 0, nodes),
+            PlacementPolicy::Racks => (0, racks, nodes),
+            PlacementPolicy::Zones => (zones, racks, nodes),
+        }
+    }
+
+    fn node(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+}
+
+impl StorageServer {
+    /// Returns the node each copy of the partition is placed on, the leader's slot first, or None
+    /// if the server has no placement or no such partition.
+    pub fn replica_nodes(&self, partition: usize) -> Option<Vec<NodeLabels>> {
+        let placement = self.placement.as_ref()?.read().unwrap_or_else(PoisonError::into_inner);
+        let copies = placement.copies.get(partition)?;
+        Some(copies.iter().map(|&node| placement.nodes[node].clone()).collect())
+    }
+
+    /// Marks every replica placed on the node down, as with `mark_replica_down`, and returns how
+    /// many were. Copies in a partition's leader slot keep serving, since a leader can't be marked
+    /// down. Fails if the server has no node of that name.
+    pub fn mark_node_down(&self, name: &str) -> Result<usize, FlowDbError> {
+        let replicas = self.replicas_on(name)?;
+        for &(partition, replica) in &replicas {
+            self.mark_replica_down(partition, replica)?;
+        }
+        Ok(replicas.len())
+    }
+
+    /// Marks every replica placed on the node up, as with `mark_replica_up`, and returns how many
+    /// hints were replayed to them.
+    pub fn mark_node_up(&self, name: &str) -> Result<usize, FlowDbError> {
+        self.replicas_on(name)?.into_iter().map(|(partition, replica)| self.mark_replica_up(partition, replica)).sum()
+    }
+
+    /// Returns the partition and replica index of every replica placed on the node.
+    fn replicas_on(&self, name: &str) -> Result<Vec<(usize, usize)>, FlowDbError> {
+        let placement = self.placement.as_ref().ok_or(FlowDbError::Unsupported)?.read().unwrap_or_else(PoisonError::into_inner);
+        let node = placement.node(name).ok_or(FlowDbError::InvalidArgument)?;
+        let replicas = placement.copies.iter().enumerate().flat_map(|(partition, copies)| copies.iter().enumerate().skip(1).filter(move |&(_, &on)| on == node).map(move |(replica, _)| (partition, replica)));
+        Ok(replicas.collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn nodes() -> Vec<NodeLabels> {
+        let mut nodes = Vec::new();
+        for zone in ["us-east", "us-west", "eu"] {
+            for rack in ["r1", "r2"] {
+                nodes.push(NodeLabels::new(format!("{}-{}", zone, rack), zone, rack));
+            }
+        }
+        nodes
+    }
+
+    #[test]
+    fn test_placement() {
+        let placement = Placement::new(nodes(), PlacementPolicy::Zones, 4, 3);
+        for copies in &placement.copies {
+            let zones: HashSet<_> = copies.iter().map(|&node| &placement.nodes[node].zone).collect();
+            assert_eq!(zones.len(), 3);
+        }
+        let mut load = vec![0; 6];
+        placement.copies.iter().flatten().for_each(|&node| load[node] += 1);
+        assert_eq!(load, vec![2; 6]);
+
+        // With fewer racks than copies, racks are reused but nodes are still distinct.
+        let placement = Placement::new(nodes()[..2].to_vec(), PlacementPolicy::Racks, 2, 3);
+        assert!(placement.copies.iter().all(|copies| copies[0] != copies[1]));
+
+        // Losing a node takes down the replicas placed on it, and the partitions keep serving.
+        let storage_server = StorageServer::new(4, 3).with_placement(nodes(), PlacementPolicy::Zones);
+        storage_server.put("key", "value").unwrap();
+        let on_node = |name: &str| (0..4).flat_map(|partition| storage_server.replica_nodes(partition).unwrap().into_iter().skip(1)).filter(|node| node.name == name).count();
+        assert_eq!(storage_server.mark_node_down("eu-r1"), Ok(on_node("eu-r1")));
+        assert_eq!(storage_server.get("key"), Ok("value".to_owned()));
+        assert!(storage_server.mark_node_up("eu-r1").is_ok());
+        assert!(storage_server.mark_node_down("unknown").is_err());
+        assert_eq!(storage_server.replica_nodes(4), None);
+    }
+}
@@ -0,0 +1,460 @@
+//! The binary protocol clients use to talk to a FlowDB server over TCP.
+//!
+//! Every message, in either direction, is a frame: a big-endian u32 length followed by that many
+//! bytes of body. A connection carries any number of requests, each answered by one response in
+//! the order the requests were sent. Keys, prefixes and values are byte strings and error messages
+//! are UTF-8; each is written as a field: a big-endian u32 length followed by the bytes.
+//!
+//! A request body is an op byte followed by its fields:
+//!
+//! | op | request | fields                                             |
+//! |----|---------|----------------------------------------------------|
+//! | 1  | GET     | key                                                |
+//! | 2  | PUT     | key, value                                         |
+//! | 3  | DELETE  | key                                                |
+//! | 4  | SCAN    | prefix, then the most entries to return as a u32 (0 for no limit) |
+//!
+//! A response body is a status byte followed by its fields:
+//!
+//! | status | response  | fields                                                  |
+//! |--------|-----------|---------------------------------------------------------|
+//! | 0      | VALUE     | value, answering GET                                    |
+//! | 1      | NOT_FOUND | none, answering a GET of a missing key                  |
+//! | 2      | STORED    | none, answering PUT                                     |
+//! | 3      | DELETED   | one byte, 1 if the key existed, answering DELETE        |
+//! | 4      | ENTRIES   | a u32 count then each entry's key and value, answering SCAN |
+//! | 5      | ERROR     | a message                                               |
+//! | 6      | MORE_ENTRIES | as ENTRIES, for a chunk of a SCAN's entries with more to follow |
+//!
+//! A SCAN is answered by any number of MORE_ENTRIES chunks followed by one ENTRIES holding the
+//! last of them, so the server never holds more than a chunk of a large scan in memory. A client
+//! that stops reading holds the server back from reading further, by TCP flow control.
+//!
+//! Clients may pipeline requests, sending more before the responses to earlier ones arrive; the
+//! server reads them in order and answers each in turn. A frame longer than 64 MiB or one that
+//! doesn't decode ends the connection.
+
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use log::info;
+use crate::error::FlowDbError;
+use crate::storage_server::StorageServer;
+use crate::tls::{self, TlsConfig};
+
+/// The longest frame accepted, so a corrupt length can't make a peer allocate without bound.
+pub const MAX_FRAME_LEN: u32 = 64 << 20;
+
+const OP_GET: u8 = 1;
+const OP_PUT: u8 = 2;
+const OP_DELETE: u8 = 3;
+const OP_SCAN: u8 = 4;
+
+const STATUS_VALUE: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_STORED: u8 = 2;
+const STATUS_DELETED: u8 = 3;
+const STATUS_ENTRIES: u8 = 4;
+const STATUS_ERROR: u8 = 5;
+const STATUS_MORE_ENTRIES: u8 = 6;
+
+/// The size of responses to pipelined requests past which they are sent without waiting for the rest.
+pub(crate) const MAX_PENDING_RESPONSES: usize = 64 << 10;
+/// The most entries a chunk of a scan holds.
+const SCAN_CHUNK_ENTRIES: usize = 256;
+/// The size of keys and values past which a chunk of a scan is sent even if it has fewer entries.
+const SCAN_CHUNK_BYTES: usize = 1 << 20;
+
+/// A request sent by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get { key: Vec<u8> },
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    /// The entries whose key starts with the prefix, at most `limit` of them unless it is 0.
+    Scan { prefix: Vec<u8>, limit: u32 },
+}
+
+/// A server's answer to a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Value(Vec<u8>),
+    NotFound,
+    Stored,
+    /// Whether the deleted key existed.
+    Deleted(bool),
+    /// The last entries answering a scan.
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    Error(String),
+    /// A chunk of the entries answering a scan, with more responses to follow.
+    MoreEntries(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+impl Request {
+    /// Encodes the request as a frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Request::Get { key } => {
+                body.push(OP_GET);
+                push_field(&mut body, key);
+            }
+            Request::Put { key, value } => {
+                body.push(OP_PUT);
+                push_field(&mut body, key);
+                push_field(&mut body, value);
+            }
+            Request::Delete { key } => {
+                body.push(OP_DELETE);
+                push_field(&mut body, key);
+            }
+            Request::Scan { prefix, limit } => {
+                body.push(OP_SCAN);
+                push_field(&mut body, prefix);
+                body.extend_from_slice(&limit.to_be_bytes());
+            }
+        }
+        frame(body)
+    }
+
+    /// Reads the next request, or None if the client closed the connection before sending one.
+    pub fn read_from(stream: &mut impl Read) -> io::Result<Option<Self>> {
+        let Some(body) = read_frame(stream)? else {
+            return Ok(None);
+        };
+        Self::decode(&body).map(Some).ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid request"))
+    }
+
+    /// Decodes the body of a request frame, returning None if it isn't a valid request.
+    pub(crate) fn decode(body: &[u8]) -> Option<Self> {
+        let (&op, mut rest) = body.split_first()?;
+        let request = match op {
+            OP_GET => Request::Get { key: take_field(&mut rest)?.to_vec() },
+            OP_PUT => Request::Put { key: take_field(&mut rest)?.to_vec(), value: take_field(&mut rest)?.to_vec() },
+            OP_DELETE => Request::Delete { key: take_field(&mut rest)?.to_vec() },
+            OP_SCAN => Request::Scan { prefix: take_field(&mut rest)?.to_vec(), limit: take_u32(&mut rest)? },
+            _ => return None,
+        };
+        rest.is_empty().then_some(request)
+    }
+}
+
+impl Response {
+    /// Answers a GET with the value read, or the reason it couldn't be.
+    pub(crate) fn to_get(result: Result<Vec<u8>, FlowDbError>) -> Self {
+        match result {
+            Ok(value) => Response::Value(value),
+            Err(FlowDbError::NotFound) => Response::NotFound,
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+
+    /// Answers a PUT with whether it was stored.
+    pub(crate) fn to_put<T>(result: Result<T, FlowDbError>) -> Self {
+        match result {
+            Ok(_) => Response::Stored,
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+
+    /// Answers a DELETE with whether the key existed.
+    pub(crate) fn to_delete(result: Result<bool, FlowDbError>) -> Self {
+        match result {
+            Ok(existed) => Response::Deleted(existed),
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+
+    /// Encodes the response as a frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Response::Value(value) => {
+                body.push(STATUS_VALUE);
+                push_field(&mut body, value);
+            }
+            Response::NotFound => body.push(STATUS_NOT_FOUND),
+            Response::Stored => body.push(STATUS_STORED),
+            Response::Deleted(existed) => body.extend_from_slice(&[STATUS_DELETED, u8::from(*existed)]),
+            Response::Entries(entries) | Response::MoreEntries(entries) => {
+                body.push(if matches!(self, Response::Entries(_)) { STATUS_ENTRIES } else { STATUS_MORE_ENTRIES });
+                body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (key, value) in entries {
+                    push_field(&mut body, key);
+                    push_field(&mut body, value);
+                }
+            }
+            Response::Error(message) => {
+                body.push(STATUS_ERROR);
+                push_field(&mut body, message.as_bytes());
+            }
+        }
+        frame(body)
+    }
+
+    /// Reads the next response, failing if the server closed the connection instead.
+    pub fn read_from(stream: &mut impl Read) -> io::Result<Self> {
+        let body = read_frame(stream)?.ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "server closed the connection"))?;
+        Self::decode(&body).ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid response"))
+    }
+
+    pub(crate) fn decode(body: &[u8]) -> Option<Self> {
+        let (&status, mut rest) = body.split_first()?;
+        let response = match status {
+            STATUS_VALUE => Response::Value(take_field(&mut rest)?.to_vec()),
+            STATUS_NOT_FOUND => Response::NotFound,
+            STATUS_STORED => Response::Stored,
+            STATUS_DELETED => {
+                let (&existed, after) = rest.split_first()?;
+                rest = after;
+                Response::Deleted(existed == 1)
+            }
+            STATUS_ENTRIES | STATUS_MORE_ENTRIES => {
+                let count = take_u32(&mut rest)?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push((take_field(&mut rest)?.to_vec(), take_field(&mut rest)?.to_vec()));
+                }
+                if status == STATUS_ENTRIES { Response::Entries(entries) } else { Response::MoreEntries(entries) }
+            }
+            STATUS_ERROR => Response::Error(take_string(&mut rest)?),
+            _ => return None,
+        };
+        rest.is_empty().then_some(response)
+    }
+}
+
+fn frame(body: Vec<u8>) -> Vec<u8> {
+    let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&body);
+    frame
+}
+
+fn push_field(body: &mut Vec<u8>, field: &[u8]) {
+    body.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    body.extend_from_slice(field);
+}
+
+fn take_u32(rest: &mut &[u8]) -> Option<u32> {
+    let (bytes, after) = rest.split_at_checked(4)?;
+    *rest = after;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn take_field<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u32(rest)? as usize;
+    let (field, after) = rest.split_at_checked(len)?;
+    *rest = after;
+    Some(field)
+}
+
+fn take_string(rest: &mut &[u8]) -> Option<String> {
+    String::from_utf8(take_field(rest)?.to_vec()).ok()
+}
+
+/// Reads the body of the next frame, or None if the peer closed the connection before starting one.
+fn read_frame(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut body = frame_buffer(len)?;
+    stream.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Returns a buffer for the body of a frame with the big-endian length, failing if it is longer
+/// than `MAX_FRAME_LEN`.
+pub(crate) fn frame_buffer(len: [u8; 4]) -> io::Result<Vec<u8>> {
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    Ok(vec![0; len as usize])
+}
+
+/// Splits the entries answering a scan into chunks of at most `SCAN_CHUNK_ENTRIES` entries, or
+/// of about `SCAN_CHUNK_BYTES` of keys and values.
+#[derive(Debug, Default)]
+pub(crate) struct ScanChunks {
+    chunk: Vec<(Vec<u8>, Vec<u8>)>,
+    chunk_bytes: usize,
+    sent: usize,
+}
+
+impl ScanChunks {
+    /// Returns how many entries to read next, at most a chunk's worth, for the scan to return at
+    /// most `limit` of them.
+    pub(crate) fn wanted(&self, limit: usize) -> usize {
+        (limit - self.sent - self.chunk.len()).min(SCAN_CHUNK_ENTRIES)
+    }
+
+    /// Adds the entry, returning the chunk before it if that one is full and should be sent.
+    pub(crate) fn push(&mut self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut full = None;
+        if self.chunk.len() == SCAN_CHUNK_ENTRIES || self.chunk_bytes >= SCAN_CHUNK_BYTES {
+            self.sent += self.chunk.len();
+            self.chunk_bytes = 0;
+            full = Some(std::mem::take(&mut self.chunk));
+        }
+        self.chunk_bytes += key.len() + value.len();
+        self.chunk.push((key, value));
+        full
+    }
+
+    /// Returns the last chunk, for the ENTRIES response that ends the scan.
+    pub(crate) fn finish(self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.chunk
+    }
+}
+
+impl StorageServer {
+    /// Serves a client speaking the binary protocol until it closes the connection, or the
+    /// connection fails or carries an invalid frame.
+    pub fn serve_client<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut out = Vec::new();
+        while let Some(request) = Request::read_from(&mut reader)? {
+            let response = match request {
+                Request::Get { key } => Response::to_get(self.get_bytes(&key)),
+                Request::Put { key, value } => Response::to_put(self.put(&key, value)),
+                Request::Delete { key } => Response::to_delete(self.delete(&key)),
+                Request::Scan { prefix, limit } => {
+                    // The responses to earlier requests go out before the scan's chunks.
+                    reader.get_mut().write_all(&out)?;
+                    out.clear();
+                    let limit = if limit == 0 { usize::MAX } else { limit as usize };
+                    Response::Entries(self.stream_scan(&prefix, limit, reader.get_mut())?)
+                }
+            };
+            out.extend_from_slice(&response.encode());
+            // Responses to pipelined requests are sent together once every buffered request is handled.
+            if reader.buffer().is_empty() || out.len() >= MAX_PENDING_RESPONSES {
+                reader.get_mut().write_all(&out)?;
+                reader.get_mut().flush()?;
+                out.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the entries of a scan as MORE_ENTRIES chunks, a page at a time, and returns the last
+    /// chunk for the ENTRIES response that ends the scan.
+    fn stream_scan(&self, prefix: &[u8], limit: usize, stream: &mut impl Write) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut chunks = ScanChunks::default();
+        let mut cursor = None;
+        loop {
+            let wanted = chunks.wanted(limit);
+            if wanted == 0 {
+                return Ok(chunks.finish());
+            }
+            let (page, next) = self.scan_prefix_page(prefix, cursor.as_ref(), wanted);
+            for (key, value) in page {
+                if let Some(full) = chunks.push(key, value) {
+                    stream.write_all(&Response::MoreEntries(full).encode())?;
+                }
+            }
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(chunks.finish()),
+            }
+        }
+    }
+}
+
+/// Accepts clients on the address and serves each on its own thread, over TLS if
+/// configured, until accepting fails. `async_server::listen` serves them as tasks instead.
+pub fn listen(server: Arc<StorageServer>, address: impl ToSocketAddrs, tls: Option<&TlsConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Listening on {}", listener.local_addr()?);
+    tls::serve(listener, tls, "protocol", move |connection| server.serve_client(connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn test_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_client(listener.accept().unwrap().0));
+        }
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut call = |request: Request| {
+            stream.write_all(&request.encode()).unwrap();
+            Response::read_from(&mut stream).unwrap()
+        };
+        assert_eq!(call(Request::Put { key: b"user:1".to_vec(), value: b"alice".to_vec() }), Response::Stored);
+        assert_eq!(call(Request::Put { key: b"user:2".to_vec(), value: b"bob".to_vec() }), Response::Stored);
+        assert_eq!(call(Request::Put { key: b"other".to_vec(), value: vec![0, 255] }), Response::Stored);
+        assert_eq!(call(Request::Get { key: b"user:1".to_vec() }), Response::Value(b"alice".to_vec()));
+        assert_eq!(call(Request::Get { key: b"missing".to_vec() }), Response::NotFound);
+        assert_eq!(call(Request::Delete { key: b"other".to_vec() }), Response::Deleted(true));
+        assert_eq!(call(Request::Delete { key: b"other".to_vec() }), Response::Deleted(false));
+        let Response::Entries(mut entries) = call(Request::Scan { prefix: b"user:".to_vec(), limit: 0 }) else {
+            panic!("expected entries");
+        };
+        entries.sort();
+        assert_eq!(entries, vec![(b"user:1".to_vec(), b"alice".to_vec()), (b"user:2".to_vec(), b"bob".to_vec())]);
+        assert!(matches!(call(Request::Scan { prefix: Vec::new(), limit: 1 }), Response::Entries(entries) if entries.len() == 1));
+
+        // A large scan is streamed in chunks, ending with the ENTRIES response.
+        let items: Vec<String> = (0..600).map(|i| format!("item:{:03}", i)).collect();
+        server.multi_put(&items.iter().map(|key| (key.as_str(), [0; 8])).collect::<Vec<_>>()).unwrap();
+        for (limit, expected) in [(0, 600), (300, 300)] {
+            stream.write_all(&Request::Scan { prefix: b"item:".to_vec(), limit }.encode()).unwrap();
+            let (mut chunks, mut keys) = (0, Vec::new());
+            loop {
+                match Response::read_from(&mut stream).unwrap() {
+                    Response::MoreEntries(entries) => {
+                        assert!(entries.len() <= SCAN_CHUNK_ENTRIES);
+                        chunks += 1;
+                        keys.extend(entries.into_iter().map(|(key, _)| key));
+                    }
+                    Response::Entries(entries) => {
+                        keys.extend(entries.into_iter().map(|(key, _)| key));
+                        break;
+                    }
+                    other => panic!("unexpected response {:?}", other),
+                }
+            }
+            assert!(chunks >= 1);
+            keys.sort();
+            keys.dedup();
+            assert_eq!(keys.len(), expected);
+        }
+
+        // Pipelined requests are answered in order.
+        let pipelined: Vec<u8> = [
+            Request::Put { key: b"user:3".to_vec(), value: b"carol".to_vec() },
+            Request::Scan { prefix: b"user:3".to_vec(), limit: 0 },
+            Request::Get { key: b"user:3".to_vec() },
+            Request::Delete { key: b"user:3".to_vec() },
+        ]
+        .iter()
+        .flat_map(Request::encode)
+        .collect();
+        stream.write_all(&pipelined).unwrap();
+        let responses: Vec<_> = (0..4).map(|_| Response::read_from(&mut stream).unwrap()).collect();
+        let carol = b"carol".to_vec();
+        assert_eq!(responses, vec![Response::Stored, Response::Entries(vec![(b"user:3".to_vec(), carol.clone())]), Response::Value(carol), Response::Deleted(true)]);
+
+        // Every response survives a round trip, and a malformed request ends the connection.
+        let chunk = Response::MoreEntries(vec![(b"key".to_vec(), b"value".to_vec())]);
+        for response in [Response::Deleted(true), Response::Error("failed".to_owned()), Response::Entries(Vec::new()), chunk] {
+            assert_eq!(Response::read_from(&mut &response.encode()[..]).unwrap(), response);
+        }
+        stream.write_all(&frame(vec![9])).unwrap();
+        assert!(Response::read_from(&mut stream).is_err());
+        assert_eq!(server.get("user:2"), Ok("bob".to_owned()));
+    }
+}
@@ -0,0 +1,170 @@
+//! A cache of recently read values in their decompressed form, so hot keys skip decompression.
+//!
+//! Each value is cached with the version of the entry it was decoded from and is only returned for
+//! that same version, so a stale value is never served even if an invalidation is missed. Writes
+//! and deletes still drop the key's value to free its memory early.
+//!
+//! The cache is split into shards by key hash, each with an equal share of the size and its own
+//! least-recently-used order, so reads of unrelated keys rarely wait for each other.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::entry::ENTRY_OVERHEAD;
+use crate::ring;
+use crate::stats::CacheMetrics;
+
+/// How many shards a read cache is split into.
+const READ_CACHE_SHARDS: usize = 16;
+
+#[derive(Debug)]
+pub(crate) struct ReadCache {
+    shards: Box<[Mutex<CacheShard>]>,
+    /// The size each shard is kept under.
+    shard_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CacheShard {
+    bytes: usize,
+    clock: u64,
+    /// Keys ordered by the tick of their last use, oldest first.
+    order: BTreeMap<u64, Vec<u8>>,
+    values: HashMap<Vec<u8>, CachedValue>,
+}
+
+#[derive(Debug)]
+struct CachedValue {
+    /// The tick of the key's last use.
+    tick: u64,
+    /// The version of the entry the value was decoded from.
+    version: u64,
+    value: Arc<[u8]>,
+}
+
+impl CacheShard {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.values.remove(key) {
+            self.order.remove(&cached.tick);
+            self.bytes -= cached_size(key, &cached.value);
+        }
+    }
+}
+
+/// Returns the memory counted for a cached value.
+fn cached_size(key: &[u8], value: &[u8]) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+impl ReadCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            shards: (0..READ_CACHE_SHARDS).map(|_| Mutex::new(CacheShard::default())).collect(),
+            shard_bytes: max_bytes / READ_CACHE_SHARDS,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the value cached for the version of the key, making it the most recently used.
+    pub(crate) fn get(&self, key: &[u8], version: u64) -> Option<Arc<[u8]>> {
+        let mut shard = self.shard(key);
+        shard.clock += 1;
+        let clock = shard.clock;
+        let found = shard.values.get_mut(key).filter(|cached| cached.version == version).map(|cached| {
+            let tick = std::mem::replace(&mut cached.tick, clock);
+            (tick, Arc::clone(&cached.value))
+        });
+        let Some((tick, value)) = found else {
+            drop(shard);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        shard.order.remove(&tick);
+        shard.order.insert(clock, key.to_vec());
+        drop(shard);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Caches the value decoded from the version of the key, dropping the least recently used
+    /// values until the shard is back under its size. Values bigger than a whole shard aren't cached.
+    pub(crate) fn insert(&self, key: &[u8], version: u64, value: Arc<[u8]>) {
+        let size = cached_size(key, &value);
+        let mut shard = self.shard(key);
+        shard.remove(key);
+        if size > self.shard_bytes {
+            return;
+        }
+        while shard.bytes + size > self.shard_bytes {
+            let Some((_, oldest)) = shard.order.pop_first() else { break };
+            if let Some(cached) = shard.values.remove(&oldest) {
+                shard.bytes -= cached_size(&oldest, &cached.value);
+            }
+        }
+        shard.clock += 1;
+        let clock = shard.clock;
+        shard.order.insert(clock, key.to_vec());
+        shard.values.insert(key.to_vec(), CachedValue { tick: clock, version, value });
+        shard.bytes += size;
+    }
+
+    /// Drops the value cached for the key, if any.
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        self.shard(key).remove(key);
+    }
+
+    pub(crate) fn metrics(&self) -> CacheMetrics {
+        let mut metrics = CacheMetrics { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed), ..CacheMetrics::default() };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            metrics.values += shard.values.len();
+            metrics.bytes += shard.bytes as u64;
+        }
+        metrics
+    }
+
+    /// Locks the key's shard. The shards hold nothing that a panicking reader could leave
+    /// half-updated in a way that serves a wrong value, so a poisoned one is used as it is.
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, CacheShard> {
+        let mut hasher = DefaultHasher::new();
+        ring::hash_key(key, &mut hasher);
+        self.shards[hasher.finish() as usize % self.shards.len()].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_cache() {
+        let value = |byte: u8| Arc::from(vec![byte; 100]);
+        let cache = ReadCache::new(READ_CACHE_SHARDS * 1000);
+        cache.insert(b"key", 1, value(1));
+        assert_eq!(cache.get(b"key", 1), Some(value(1)));
+        // A value is only served for the version it was decoded from.
+        assert_eq!(cache.get(b"key", 2), None);
+        cache.invalidate(b"key");
+        assert_eq!(cache.get(b"key", 1), None);
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses, metrics.values, metrics.bytes), (1, 2, 0, 0));
+        assert_eq!(metrics.hit_rate(), 1.0 / 3.0);
+
+        // Each shard keeps its most recently used values within its share of the size.
+        let keys: Vec<_> = (0..200).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            cache.insert(key.as_bytes(), 1, value(0));
+        }
+        let metrics = cache.metrics();
+        assert!(metrics.bytes <= (READ_CACHE_SHARDS * 1000) as u64, "{} bytes cached", metrics.bytes);
+        assert!(metrics.values < keys.len());
+        assert!(cache.get(keys[199].as_bytes(), 1).is_some());
+        cache.insert(b"huge", 1, Arc::from(vec![0; 1000]));
+        assert_eq!(cache.get(b"huge", 1), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Bringing replicas up to date when a quorum read finds them behind.
+//!
+//! A quorum read compares the states of a key on several copies of its partition and returns the
+//! newest. The replicas it found behind are repaired by a background thread, so the read doesn't
+//! wait on them. A repair is skipped if the replica changed since it was read, so it never
+//! overwrites a newer write that reached it meanwhile, and if a rebalance or failover changed which
+//! partition owns the key or which copy leads it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use crate::entry::Entry;
+use crate::rebalance::Routing;
+use crate::replication::ReplicaOp;
+use crate::storage_server::Partition;
+
+/// A stale replica of a key found by a quorum read.
+pub(crate) struct Repair {
+    pub(crate) partition: usize,
+    pub(crate) replica: Arc<RwLock<Partition>>,
+    pub(crate) key: Vec<u8>,
+    /// The newest state of the key, or None if it was deleted.
+    pub(crate) newest: Option<Entry>,
+    /// The version the replica held when it was read, or None if it didn't hold the key.
+    pub(crate) seen: Option<u64>,
+}
+
+impl Repair {
+    /// Writes the newest state to the replica, returning whether it had to.
+    fn apply(self, routing: &Routing) -> bool {
+        let _routing = routing.enter();
+        let topology = routing.current();
+        let still_replica = topology.ring.partition(&self.key) == self.partition
+            && topology.leadership.copies(self.partition).iter().skip(1).any(|copy| Arc::ptr_eq(copy, &self.replica));
+        if !still_replica {
+            return false;
+        }
+        let Ok(mut replica) = self.replica.write() else {
+            return false;
+        };
+        if replica.down {
+            return false;
+        }
+        let current = replica.data.get(&self.key).map(|entry| entry.meta.version);
+        let op = match self.newest {
+            Some(entry) if current.is_none_or(|version| version < entry.meta.version) => ReplicaOp::Store(self.key, entry),
+            None if current.is_some() && current == self.seen => ReplicaOp::Remove(self.key),
+            _ => return false,
+        };
+        op.apply(&mut replica);
+        true
+    }
+}
+
+/// A background thread that applies the repairs found by quorum reads, counting the replicas it
+/// repaired. It finishes the repairs already scheduled and exits when stopped or dropped.
+pub(crate) struct ReadRepairer {
+    sender: Mutex<Option<Sender<Repair>>>,
+    repaired: Arc<AtomicU64>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ReadRepairer {
+    pub(crate) fn spawn(routing: Arc<Routing>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Repair>();
+        let repaired = Arc::new(AtomicU64::new(0));
+        let handle = {
+            let repaired = Arc::clone(&repaired);
+            thread::spawn(move || {
+                for repair in receiver {
+                    if repair.apply(&routing) {
+                        repaired.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        };
+        Self { sender: Mutex::new(Some(sender)), repaired, handle: Mutex::new(Some(handle)) }
+    }
+
+    /// Queues the repair, unless the repairer has been stopped.
+    pub(crate) fn schedule(&self, repair: Repair) {
+        if let Some(sender) = &*self.sender.lock().unwrap_or_else(PoisonError::into_inner) {
+            let _ = sender.send(repair);
+        }
+    }
+
+    /// Returns how many replicas have been repaired.
+    pub(crate) fn repaired(&self) -> u64 {
+        self.repaired.load(Ordering::Relaxed)
+    }
+
+    /// Applies every repair scheduled so far, then stops the thread.
+    pub(crate) fn stop(&self) {
+        // Closing the channel stops the thread once it has applied every repair sent.
+        drop(self.sender.lock().unwrap_or_else(PoisonError::into_inner).take());
+        let handle = self.handle.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ReadRepairer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
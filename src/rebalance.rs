@@ -0,0 +1,211 @@
+//! Changing the number of partitions while the server keeps serving reads and writes.
+//!
+//! The partitions a rebalance adds are filled while the old ones keep serving: the keys whose
+//! position on the new ring falls to a new partition are copied to it, and every write made to
+//! such a key meanwhile is recorded in a migration journal as it is applied, then replayed on the
+//! key's new owner. Routing is then switched in a short pause: operations in flight finish, the
+//! rest of the journal is replayed, keys moving to partitions that were already serving are copied,
+//! the moved keys are removed from their old partitions, and the new ring takes over.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard};
+use crate::entry::Entry;
+use crate::leadership::Leadership;
+use crate::replication::ReplicaOp;
+use crate::ring::HashRing;
+use crate::storage_server::{release_replicas, Partition};
+
+/// The partitions of a server and the ring that routes keys to them.
+pub(crate) struct Topology {
+    pub(crate) ring: HashRing,
+    /// The leader of each partition.
+    pub(crate) partitions: Vec<Arc<RwLock<Partition>>>,
+    /// Every copy of each partition, the leader first, for serving reads from replicas and failing over.
+    pub(crate) leadership: Leadership,
+}
+
+impl Topology {
+    pub(crate) fn new(ring: HashRing, leadership: Leadership) -> Self {
+        let partitions = (0..leadership.partition_count()).map(|partition| Arc::clone(&leadership.copies(partition)[0])).collect();
+        Self { ring, partitions, leadership }
+    }
+
+    /// Returns the same partitions routed by another ring.
+    pub(crate) fn with_ring(&self, ring: HashRing) -> Self {
+        Self::new(ring, self.leadership.resized(self.copies()))
+    }
+
+    fn copies(&self) -> Vec<Vec<Arc<RwLock<Partition>>>> {
+        (0..self.partitions.len()).map(|partition| self.leadership.copies(partition).to_vec()).collect()
+    }
+}
+
+/// The current topology of a server.
+///
+/// Operations enter the routing before looking up a key's partition and leave once they are done
+/// with it, so a rebalance can switch the topology without an operation writing to a partition
+/// that no longer owns its key.
+pub(crate) struct Routing {
+    topology: RwLock<Arc<Topology>>,
+    /// Held shared by operations, and exclusively while the topology is switched.
+    gate: RwLock<()>,
+}
+
+thread_local! {
+    /// The routings the current thread has entered, by address.
+    static ENTERED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Holds off switching the topology until it is dropped.
+pub(crate) struct Entered<'a> {
+    routing: usize,
+    gate: Option<RwLockReadGuard<'a, ()>>,
+}
+
+impl Drop for Entered<'_> {
+    fn drop(&mut self) {
+        if self.gate.take().is_some() {
+            ENTERED.with(|entered| entered.borrow_mut().retain(|&routing| routing != self.routing));
+        }
+    }
+}
+
+impl Routing {
+    pub(crate) fn new(topology: Topology) -> Self {
+        Self { topology: RwLock::new(Arc::new(topology)), gate: RwLock::new(()) }
+    }
+
+    pub(crate) fn current(&self) -> Arc<Topology> {
+        Arc::clone(&self.topology.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Keeps the current topology in place until the returned guard is dropped.
+    ///
+    /// Entering again on a thread that already entered does nothing, so operations can call each
+    /// other without waiting on a switch that waits on them.
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        let routing = self as *const Self as usize;
+        let nested = ENTERED.with(|entered| entered.borrow().contains(&routing));
+        if nested {
+            return Entered { routing, gate: None };
+        }
+        let gate = self.gate.read().unwrap_or_else(PoisonError::into_inner);
+        ENTERED.with(|entered| entered.borrow_mut().push(routing));
+        Entered { routing, gate: Some(gate) }
+    }
+}
+
+/// The writes made to moving keys while a rebalance copies them, each with the partition that
+/// owns the key once the rebalance is done.
+#[derive(Debug)]
+pub(crate) struct MigrationJournal {
+    ring: HashRing,
+    /// The number of partitions serving before the rebalance. Keys moving to one of them are
+    /// copied during the switch instead, since partitions that serve can't hold keys they don't own.
+    serving: usize,
+    ops: Mutex<Vec<(usize, ReplicaOp)>>,
+}
+
+impl MigrationJournal {
+    /// Records the mutation the partition just applied, for the keys it affects that move to a
+    /// partition that isn't serving yet.
+    pub(crate) fn record(&self, partition: usize, op: &ReplicaOp) {
+        let moving = |key: &[u8]| Some(self.ring.partition(key)).filter(|&owner| owner != partition && owner >= self.serving);
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+        match op {
+            ReplicaOp::Store(key, _) | ReplicaOp::Remove(key) => ops.extend(moving(key).map(|owner| (owner, op.clone()))),
+            ReplicaOp::Extend(entries) => {
+                let mut groups: BTreeMap<usize, Vec<(Vec<u8>, Entry)>> = BTreeMap::new();
+                for (key, entry) in entries {
+                    if let Some(owner) = moving(key) {
+                        groups.entry(owner).or_default().push((key.clone(), entry.clone()));
+                    }
+                }
+                ops.extend(groups.into_iter().map(|(owner, entries)| (owner, ReplicaOp::Extend(entries))));
+            }
+            // Expired entries read as missing wherever they are, and the new owner sweeps its own.
+            ReplicaOp::RemoveExpired(_) => {}
+        }
+    }
+
+    fn take(&self) -> Vec<(usize, ReplicaOp)> {
+        mem::take(&mut self.ops.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Moves the server to the partitions of the ring, creating the copies of each new partition with
+/// `create`, and returns how many keys moved.
+///
+/// Partitions below both counts are kept, so a key only moves if the new ring routes it elsewhere.
+pub(crate) fn rebalance(routing: &Routing, ring: HashRing, mut create: impl FnMut(usize) -> Vec<Arc<RwLock<Partition>>>) -> usize {
+    let old = routing.current();
+    let serving = old.partitions.len();
+    let num_partitions = ring.partitions();
+    let mut copies = old.copies();
+    copies.truncate(num_partitions);
+    copies.extend((serving..num_partitions).map(&mut create));
+    let new = Topology::new(ring, old.leadership.resized(copies));
+
+    let journal = Arc::new(MigrationJournal { ring: new.ring.clone(), serving, ops: Mutex::new(Vec::new()) });
+    for (index, partition) in old.partitions.iter().enumerate() {
+        partition.write().unwrap_or_else(PoisonError::into_inner).migration = Some((index, Arc::clone(&journal)));
+    }
+
+    // Copy the keys moving to new partitions while the old ones keep serving them.
+    for (index, partition) in old.partitions.iter().enumerate() {
+        let moving = moving_entries(&partition.read().unwrap_or_else(PoisonError::into_inner), &new.ring, index);
+        for (owner, entries) in moving.into_iter().filter(|&(owner, _)| owner >= serving) {
+            new.partitions[owner].write().unwrap_or_else(PoisonError::into_inner).apply(ReplicaOp::Extend(entries));
+        }
+        // Catch up on the writes made meanwhile, so the switch has little left to replay.
+        replay(&new, journal.take());
+    }
+
+    // Switch once the operations in flight are done, holding off new ones until then.
+    let gate = routing.gate.write().unwrap_or_else(PoisonError::into_inner);
+    replay(&new, journal.take());
+    let mut moved = 0;
+    let mut deferred = Vec::new();
+    for (index, partition) in old.partitions.iter().enumerate() {
+        let mut partition_guard = partition.write().unwrap_or_else(PoisonError::into_inner);
+        partition_guard.migration = None;
+        for (owner, entries) in moving_entries(&partition_guard, &new.ring, index) {
+            moved += entries.len();
+            for (key, _) in &entries {
+                partition_guard.apply(ReplicaOp::Remove(key.clone()));
+            }
+            if owner < serving {
+                deferred.push((owner, ReplicaOp::Extend(entries)));
+            }
+        }
+    }
+    replay(&new, deferred);
+    *routing.topology.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(new);
+    drop(gate);
+
+    // Partitions that were removed are empty now, so break their replica cycles to free them.
+    for partition in old.partitions.iter().skip(num_partitions) {
+        release_replicas(partition);
+    }
+    moved
+}
+
+/// Returns the entries of the partition that the ring routes to another partition, by new owner.
+fn moving_entries(partition: &Partition, ring: &HashRing, index: usize) -> BTreeMap<usize, Vec<(Vec<u8>, Entry)>> {
+    let mut moving: BTreeMap<usize, Vec<(Vec<u8>, Entry)>> = BTreeMap::new();
+    for (key, entry) in partition.data.iter() {
+        let owner = ring.partition(&*key);
+        if owner != index {
+            moving.entry(owner).or_default().push((key.into_owned(), entry.into_owned()));
+        }
+    }
+    moving
+}
+
+fn replay(topology: &Topology, ops: Vec<(usize, ReplicaOp)>) {
+    for (owner, op) in ops {
+        topology.partitions[owner].write().unwrap_or_else(PoisonError::into_inner).apply(op);
+    }
+}
@@ -0,0 +1,274 @@
+//! Replicating a server's writes to a peer cluster in another region, to keep a warm standby there.
+//!
+//! The source server logs its records with the time they were made (see
+//! `StorageServer::with_logged_write_times`), and a `RemoteCluster` ships its transaction log to
+//! the peer over the `LogShipper` protocol, reconnecting whenever the connection fails. On the
+//! peer, a `ClusterFollower` applies the records it receives to a server, which routes each key
+//! with its own partitions, so the two clusters don't need the same layout. A record that conflicts
+//! with a write made on the peer is resolved by the follower's `ConflictPolicy`.
+//!
+//! Records applied by a follower are not logged again, so two clusters can replicate to each other
+//! without their writes bouncing back.
+
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use log::warn;
+use crate::error::FlowDbError;
+use crate::shipping::{LogFollower, LogShipper};
+use crate::storage_server::StorageServer;
+use crate::transaction_log::{LogPosition, LogRecord};
+
+/// How a follower resolves a remote write to a key that was also written locally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// The write made last wins, by the time it was made; a remote write made at the same time as
+    /// the local one wins. Records logged without a time always win. A delete only wins against
+    /// remote writes made before it if the follower's server keeps tombstones.
+    #[default]
+    LastWriteWins,
+    /// Every remote write is applied, replacing whatever was written locally.
+    RemoteWins,
+}
+
+impl ConflictPolicy {
+    /// Returns whether a remote write made at `at` replaces a local state last modified at `local`.
+    fn applies(self, at: Option<SystemTime>, local: Option<SystemTime>) -> bool {
+        match (self, at, local) {
+            (ConflictPolicy::LastWriteWins, Some(at), Some(local)) => at >= local,
+            _ => true,
+        }
+    }
+}
+
+/// A background thread that ships a transaction log to a peer cluster's `ClusterFollower`.
+///
+/// It connects to the peer, which sends the position to resume from, and streams records from
+/// there until the connection fails, then reconnects after the retry interval. The thread is
+/// stopped when the replication is stopped or dropped.
+pub struct RemoteCluster {
+    stop: Arc<AtomicBool>,
+    connection: Arc<Mutex<Option<TcpStream>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RemoteCluster {
+    /// Starts shipping the log in the given directory to the peer listening at the address.
+    pub fn start(log_dir: impl AsRef<Path>, peer: SocketAddr, retry_interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let connection = Arc::new(Mutex::new(None));
+        let handle = {
+            let (stop, connection) = (Arc::clone(&stop), Arc::clone(&connection));
+            let dir: PathBuf = log_dir.as_ref().to_owned();
+            thread::spawn(move || {
+                let shipper = LogShipper::new(&dir, 8192);
+                while !stop.load(Ordering::Acquire) {
+                    match TcpStream::connect_timeout(&peer, retry_interval.max(Duration::from_millis(1))) {
+                        Ok(stream) => {
+                            *connection.lock().unwrap_or_else(PoisonError::into_inner) = stream.try_clone().ok();
+                            // Stopping shuts down the stored connection, unless it was stored after.
+                            if stop.load(Ordering::Acquire) {
+                                break;
+                            }
+                            if let Err(e) = shipper.serve(stream) {
+                                warn!("Replication to cluster {} stopped: {}", peer, e);
+                            }
+                            connection.lock().unwrap_or_else(PoisonError::into_inner).take();
+                        }
+                        Err(e) => warn!("Couldn't connect to cluster {}: {}", peer, e),
+                    }
+                    thread::park_timeout(retry_interval);
+                }
+            })
+        };
+        Self { stop, connection, handle: Some(handle) }
+    }
+
+    /// Stops shipping and waits for the thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            if let Some(stream) = self.connection.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for RemoteCluster {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Applies the log a `RemoteCluster` ships to a server, remembering how far it got so a new
+/// connection resumes there.
+pub struct ClusterFollower {
+    server: Arc<StorageServer>,
+    policy: ConflictPolicy,
+    window: u32,
+    position: Mutex<LogPosition>,
+    /// Held while a connection is served.
+    serving: Mutex<()>,
+}
+
+impl ClusterFollower {
+    /// Creates a follower that applies the remote log from its start with `LastWriteWins`.
+    pub fn new(server: Arc<StorageServer>) -> Self {
+        Self { server, policy: ConflictPolicy::default(), window: 1024, position: Mutex::new(LogPosition::START), serving: Mutex::new(()) }
+    }
+
+    /// Sets how conflicting writes are resolved.
+    pub fn with_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets the position in the remote log to resume from, such as one saved from `position`.
+    pub fn with_position(self, position: LogPosition) -> Self {
+        *self.position.lock().unwrap_or_else(PoisonError::into_inner) = position;
+        self
+    }
+
+    /// Returns the position in the remote log past every record applied so far.
+    pub fn position(&self) -> LogPosition {
+        *self.position.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies the records shipped over the connection from a `RemoteCluster` until it fails.
+    ///
+    /// Connections are served one at a time, as each resumes from where the last one stopped.
+    pub fn serve<S: Read + Write>(&self, stream: S) -> Result<()> {
+        let _serving = self.serving.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut follower = LogFollower::connect(stream, self.position(), self.window)?;
+        loop {
+            let record = follower.next_record()?;
+            if self.server.apply_remote(&record, None, self.policy).is_err() {
+                warn!("Couldn't apply a record from the remote cluster at {:?}", follower.position());
+            }
+            *self.position.lock().unwrap_or_else(PoisonError::into_inner) = follower.position();
+        }
+    }
+}
+
+impl StorageServer {
+    /// Applies a record from a remote cluster's log without logging it, skipping writes that lose
+    /// to the key's local state under the policy. Each write of a commit is resolved on its own.
+    pub(crate) fn apply_remote(&self, record: &LogRecord, at: Option<SystemTime>, policy: ConflictPolicy) -> std::result::Result<(), FlowDbError> {
+        match record {
+            LogRecord::Timed { at, record } => self.apply_remote(record, Some(*at), policy),
+            LogRecord::Put { key, value } => {
+                let _routing = self.enter();
+                let _key_lock = self.key_locks.lock(key);
+                let partition = self.get_partition(key);
+                let mut partition_guard = partition.write()?;
+                let local = partition_guard.data.get(key).map(|entry| entry.meta.modified_at);
+                if policy.applies(at, local) {
+                    let entry = self.new_entry(value)?;
+                    self.store_entry_at(&mut partition_guard, key, entry, at.unwrap_or_else(SystemTime::now));
+                }
+                Ok(())
+            }
+            LogRecord::Delete { key } => {
+                let _routing = self.enter();
+                let _key_lock = self.key_locks.lock(key);
+                let partition = self.get_partition(key);
+                let mut partition_guard = partition.write()?;
+                let local = partition_guard.data.get(key).map(|entry| entry.meta.modified_at);
+                if policy.applies(at, local) {
+                    self.remove_entry_at(&mut partition_guard, key, at.unwrap_or_else(SystemTime::now));
+                }
+                Ok(())
+            }
+            LogRecord::Commit { records } => records.iter().try_for_each(|record| self.apply_remote(record, at, policy)),
+            // Merge operands combine with the local value in any order, so they always apply.
+            LogRecord::Merge { .. } => self.replay(record),
+            // So do expiries and collection commands, which apply to whatever the key holds.
+            LogRecord::Expire { .. } | LogRecord::Collection { .. } => self.replay(record),
+            // The remote cluster's checkpoints say nothing about what this one has persisted, and
+            // its transactions arrive as commits.
+            LogRecord::Checkpoint { .. } | LogRecord::BeginTxn | LogRecord::CommitTxn => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use crate::transaction_log::TransactionLog;
+
+    #[test]
+    fn test_remote_cluster() {
+        let dir = "logs/test_remote_cluster";
+        let _ = fs::remove_dir_all(dir);
+        let log = TransactionLog::new(dir, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let source = StorageServer::new(4, 2).with_transaction_log(Arc::new(Mutex::new(log))).with_logged_write_times();
+
+        // The peer has its own layout, and writes keys of its own before the source's arrive.
+        let peer = Arc::new(StorageServer::new(3, 1));
+        peer.put("early", "peer").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        {
+            let follower = ClusterFollower::new(Arc::clone(&peer));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let _ = follower.serve(stream.unwrap());
+                }
+            });
+        }
+        thread::sleep(Duration::from_millis(2));
+        for i in 0..20 {
+            source.put(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        source.put("early", "source").unwrap();
+        source.delete("key0").unwrap();
+        let replication = RemoteCluster::start(dir, address, Duration::from_millis(10));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while peer.get("key19").is_err() || peer.get("key0").is_ok() || peer.get("early") != Ok("source".to_owned()) {
+            assert!(Instant::now() < deadline, "peer did not catch up");
+            thread::sleep(Duration::from_millis(5));
+        }
+        for i in 1..20 {
+            assert_eq!(peer.get(format!("key{}", i)), Ok(format!("value{}", i)));
+        }
+        replication.stop();
+    }
+
+    #[test]
+    fn test_last_write_wins() {
+        let server = StorageServer::new(2, 1);
+        let timed = |seconds_ago: u64, record: LogRecord| LogRecord::Timed { at: SystemTime::now() - Duration::from_secs(seconds_ago), record: Box::new(record) };
+        let put = |value: &str| LogRecord::Put { key: b"key".to_vec(), value: value.as_bytes().to_vec() };
+
+        // A remote write made before the local one loses, and one made after it wins.
+        server.put("key", "local").unwrap();
+        server.apply_remote(&timed(60, put("older")), None, ConflictPolicy::LastWriteWins).unwrap();
+        assert_eq!(server.get("key"), Ok("local".to_owned()));
+        let newer = LogRecord::Timed { at: SystemTime::now() + Duration::from_secs(60), record: Box::new(put("newer")) };
+        server.apply_remote(&newer, None, ConflictPolicy::LastWriteWins).unwrap();
+        assert_eq!(server.get("key"), Ok("newer".to_owned()));
+        server.apply_remote(&timed(60, LogRecord::Delete { key: b"key".to_vec() }), None, ConflictPolicy::LastWriteWins).unwrap();
+        assert_eq!(server.get("key"), Ok("newer".to_owned()));
+
+        // Remote writes replace local ones regardless of time when the remote side wins.
+        server.apply_remote(&timed(60, put("remote")), None, ConflictPolicy::RemoteWins).unwrap();
+        assert_eq!(server.get("key"), Ok("remote".to_owned()));
+        server.apply_remote(&timed(60, LogRecord::Delete { key: b"key".to_vec() }), None, ConflictPolicy::RemoteWins).unwrap();
+        assert_eq!(server.get("key"), Err(FlowDbError::NotFound));
+    }
+}
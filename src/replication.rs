@@ -0,0 +1,470 @@
+//! How writes reach a partition's replicas, and the background replicator that updates the
+//! replicas a write doesn't wait for.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::entry::Entry;
+use crate::storage_server::Partition;
+
+/// How many copies of a partition a write updates before it returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplicationMode {
+    /// Every replica is updated before the write returns.
+    #[default]
+    Sync,
+    /// The write returns once this many copies, counting the primary, have applied it; the other
+    /// replicas are updated in the background, in the order the writes were made.
+    Quorum(usize),
+    /// The write returns once the primary has applied it, and every replica is updated in the
+    /// background. The same as `Quorum(1)`.
+    Async,
+}
+
+/// Why a replica couldn't apply a write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplicaError {
+    /// A thread panicked while holding the replica's lock, so its data may be half-updated. The
+    /// replica is skipped until it is rebuilt.
+    Poisoned,
+    /// The replica was marked down and takes no writes until it is marked up again.
+    Unavailable,
+}
+
+impl fmt::Display for ReplicaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaError::Poisoned => write!(f, "replica lock is poisoned"),
+            ReplicaError::Unavailable => write!(f, "replica is down"),
+        }
+    }
+}
+
+impl std::error::Error for ReplicaError {}
+
+/// Which copies of a partition a write reached, by replica index. Index 0 is the primary, which
+/// always applies the write.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationReport {
+    /// The copies that applied the write before it returned, the primary first.
+    pub applied: Vec<usize>,
+    /// The replicas the write was queued for, to be updated in the background.
+    pub queued: Vec<usize>,
+    /// The replicas that couldn't apply the write.
+    pub failed: Vec<(usize, ReplicaError)>,
+    /// The failed replicas the write was kept for as a hint, to be handed off once they recover.
+    pub hinted: Vec<usize>,
+}
+
+impl ReplicationReport {
+    /// Returns whether every replica applied the write or has it queued.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// A mutation of a partition's data, applied to each of its replicas.
+#[derive(Debug, Clone)]
+pub(crate) enum ReplicaOp {
+    Store(Vec<u8>, Entry),
+    Remove(Vec<u8>),
+    Extend(Vec<(Vec<u8>, Entry)>),
+    RemoveExpired(SystemTime),
+}
+
+impl ReplicaOp {
+    pub(crate) fn apply(&self, replica: &mut Partition) {
+        match self {
+            ReplicaOp::Store(key, entry) => replica.data.insert(key.clone(), entry.clone()),
+            ReplicaOp::Remove(key) => {
+                replica.data.remove(key);
+            }
+            ReplicaOp::Extend(entries) => replica.data.extend(entries.iter().cloned()),
+            ReplicaOp::RemoveExpired(now) => {
+                replica.data.remove_expired(*now);
+            }
+        }
+    }
+}
+
+/// Which copy of a partition serves reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadPreference {
+    /// Every read goes to the primary, so reads always see the latest write.
+    #[default]
+    Primary,
+    /// Reads take turns across all copies of the partition, primary included.
+    RoundRobin,
+    /// Reads go to the calling thread's own copy of the partition, or to the first other copy
+    /// that isn't locked by a writer, so threads spread out and avoid waiting on writes.
+    Nearest,
+}
+
+/// How many copies of a partition a single read or write has to reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Consistency {
+    /// One copy: a read goes to the copy chosen by the server's read preference, and a write
+    /// returns once the primary has applied it.
+    #[default]
+    One,
+    /// A majority of the copies.
+    Quorum,
+    /// Every copy.
+    All,
+    /// The primary alone, so a read sees every write already made and a write returns once the
+    /// primary has applied it.
+    LocalPrimary,
+}
+
+impl Consistency {
+    /// Returns how many of the given number of copies the level has to reach.
+    pub(crate) fn copies(self, copies: usize) -> usize {
+        match self {
+            Consistency::One | Consistency::LocalPrimary => 1,
+            Consistency::Quorum => copies / 2 + 1,
+            Consistency::All => copies,
+        }
+    }
+}
+
+impl ReplicationMode {
+    /// Returns how many copies, counting the primary, a write updates synchronously.
+    pub(crate) fn sync_copies(self) -> usize {
+        match self {
+            ReplicationMode::Sync => usize::MAX,
+            ReplicationMode::Quorum(n) => n.max(1),
+            ReplicationMode::Async => 1,
+        }
+    }
+}
+
+/// How a primary partition replicates its writes: the number of copies updated synchronously,
+/// where updates for the rest are queued, and where hints for failed replicas are kept.
+#[derive(Debug, Clone)]
+pub(crate) struct Replication {
+    pub(crate) sync_copies: usize,
+    pub(crate) queue: Option<(usize, Arc<ReplicationQueue>)>,
+    pub(crate) hints: Option<Arc<HintStore>>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self { sync_copies: usize::MAX, queue: None, hints: None }
+    }
+}
+
+/// Identifies a write made by a partition's primary: its place in the order of the primary's
+/// writes, starting at 1, and when it was made.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stamp {
+    pub(crate) sequence: u64,
+    pub(crate) made_at: Instant,
+}
+
+/// Applies the update to the replica. If the replica can't take it, the update is kept in the
+/// hint store if there is one, and the error is returned with whether a hint was kept.
+pub(crate) fn apply_to(replica: &Arc<RwLock<Partition>>, op: &ReplicaOp, stamp: Stamp, hints: Option<&HintStore>) -> Result<(), (ReplicaError, bool)> {
+    let (mut guard, error) = match replica.write() {
+        Ok(mut guard) if !guard.down => {
+            op.apply(&mut guard);
+            guard.applied = guard.applied.max(stamp.sequence);
+            return Ok(());
+        }
+        Ok(guard) => (guard, ReplicaError::Unavailable),
+        Err(poisoned) => (poisoned.into_inner(), ReplicaError::Poisoned),
+    };
+    guard.applied = guard.applied.max(stamp.sequence);
+    guard.missed += 1;
+    guard.missed_since.get_or_insert(stamp.made_at);
+    // The hint is kept while the replica is still locked, so a handoff can't replay past it.
+    let hinted = hints.map(|hints| hints.push(replica, op.clone())).is_some();
+    drop(guard);
+    Err((error, hinted))
+}
+
+/// Records that the replica was made identical to its primary, whose lock is held, so it has
+/// every write the primary made.
+pub(crate) fn record_caught_up(replica: &mut Partition) {
+    replica.applied = replica.written.load(Ordering::Acquire);
+    replica.missed = 0;
+    replica.missed_since = None;
+}
+
+/// Updates that replicas couldn't take when they were made, kept until the replicas recover.
+#[derive(Debug)]
+pub(crate) struct HintStore {
+    ttl: Duration,
+    hints: Mutex<Vec<Hint>>,
+}
+
+#[derive(Debug)]
+struct Hint {
+    replica: Arc<RwLock<Partition>>,
+    op: ReplicaOp,
+    expires_at: Instant,
+}
+
+impl HintStore {
+    /// Creates a store whose hints are dropped once they are older than the TTL. Replicas that
+    /// miss hints this way are left for anti-entropy to repair.
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self { ttl, hints: Mutex::new(Vec::new()) }
+    }
+
+    fn push(&self, replica: &Arc<RwLock<Partition>>, op: ReplicaOp) {
+        let hint = Hint { replica: Arc::clone(replica), op, expires_at: Instant::now() + self.ttl };
+        self.hints.lock().unwrap_or_else(PoisonError::into_inner).push(hint);
+    }
+
+    /// Returns how many hints are waiting to be handed off.
+    pub(crate) fn len(&self) -> usize {
+        self.hints.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Drops expired hints and hands off the rest to every replica that can take writes again,
+    /// returning how many hints were replayed. Replicas locked by someone else are tried next time.
+    pub(crate) fn hand_off(&self) -> usize {
+        let mut replicas: Vec<Arc<RwLock<Partition>>> = Vec::new();
+        {
+            let now = Instant::now();
+            let mut hints = self.hints.lock().unwrap_or_else(PoisonError::into_inner);
+            hints.retain(|hint| hint.expires_at > now);
+            for hint in hints.iter() {
+                if !replicas.iter().any(|replica| Arc::ptr_eq(replica, &hint.replica)) {
+                    replicas.push(Arc::clone(&hint.replica));
+                }
+            }
+        }
+        let mut replayed = 0;
+        for replica in &replicas {
+            if let Ok(mut guard) = replica.try_write() {
+                if !guard.down {
+                    replayed += self.replay(replica, &mut guard);
+                }
+            }
+        }
+        replayed
+    }
+
+    /// Applies the unexpired hints kept for the replica, in the order they were made. The caller
+    /// holds the replica's write lock, so no hints for it are added meanwhile.
+    pub(crate) fn replay(&self, replica: &Arc<RwLock<Partition>>, guard: &mut Partition) -> usize {
+        let hints = {
+            let mut hints = self.hints.lock().unwrap_or_else(PoisonError::into_inner);
+            let (replica_hints, rest) = mem::take(&mut *hints).into_iter().partition::<Vec<_>, _>(|hint| Arc::ptr_eq(&hint.replica, replica));
+            *hints = rest;
+            replica_hints
+        };
+        let now = Instant::now();
+        let mut replayed = 0;
+        for hint in hints.into_iter().filter(|hint| hint.expires_at > now) {
+            hint.op.apply(guard);
+            guard.missed = guard.missed.saturating_sub(1);
+            replayed += 1;
+        }
+        if guard.missed == 0 {
+            guard.missed_since = None;
+        }
+        replayed
+    }
+}
+
+/// A background thread that periodically hands off hints to the replicas of a StorageServer that
+/// recovered.
+///
+/// The thread is stopped when the worker is stopped or dropped.
+pub struct HintedHandoff {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HintedHandoff {
+    pub(crate) fn spawn(hints: Arc<HintStore>, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Acquire) {
+                hints.hand_off();
+                thread::park_timeout(interval);
+            }
+        });
+        Self { stop, handle: Some(handle) }
+    }
+
+    /// Stops the handoff thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for HintedHandoff {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// An update taken off a partition's queue: the write it comes from, the replicas it is for, where
+/// to keep hints for the ones that can't take it, and the update itself.
+type Update = (Stamp, Vec<Arc<RwLock<Partition>>>, Option<Arc<HintStore>>, ReplicaOp);
+
+/// Replica updates waiting for the replicator, with a queue per partition so a burst of writes
+/// to one partition doesn't hold up replicating the others.
+#[derive(Debug)]
+pub(crate) struct ReplicationQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    stop: AtomicBool,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    /// Each partition's updates, oldest first, with the writes they come from and the replicas
+    /// each is for.
+    pending: Vec<VecDeque<Update>>,
+    /// The partition whose queue is taken from next, so partitions take turns.
+    next: usize,
+    /// The partition and write of the update the replicator is applying, after taking it off the
+    /// queue.
+    applying: Option<(usize, Stamp)>,
+}
+
+impl QueueState {
+    fn pop(&mut self) -> Option<(usize, Update)> {
+        let partitions = self.pending.len();
+        let partition = (0..partitions).map(|i| (self.next + i) % partitions).find(|&i| !self.pending[i].is_empty())?;
+        self.next = (partition + 1) % partitions;
+        Some((partition, self.pending[partition].pop_front()?))
+    }
+}
+
+impl ReplicationQueue {
+    fn new(partitions: usize) -> Self {
+        let state = QueueState { pending: (0..partitions).map(|_| VecDeque::new()).collect(), next: 0, applying: None };
+        Self { state: Mutex::new(state), changed: Condvar::new(), stop: AtomicBool::new(false) }
+    }
+
+    /// Makes room for the updates of partitions added by a rebalance.
+    pub(crate) fn grow(&self, partitions: usize) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.pending.len() < partitions {
+            state.pending.resize_with(partitions, VecDeque::new);
+        }
+    }
+
+    /// Queues the update for the partition's replicas. The caller holds the primary's write lock,
+    /// so updates to the same partition are queued in the order they were applied to it.
+    pub(crate) fn push(&self, partition: usize, replicas: Vec<Arc<RwLock<Partition>>>, hints: Option<Arc<HintStore>>, stamp: Stamp, op: ReplicaOp) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).pending[partition].push_back((stamp, replicas, hints, op));
+        self.changed.notify_all();
+    }
+
+    /// Returns how far the partition's queued replicas are behind its primary: how long ago the
+    /// oldest update not yet applied to them was queued, or zero if they are up to date.
+    pub(crate) fn lag(&self, partition: usize) -> Duration {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let applying = state.applying.filter(|(applying, _)| *applying == partition).map(|(_, stamp)| stamp);
+        applying.or_else(|| state.pending[partition].front().map(|(stamp, ..)| *stamp)).map_or(Duration::ZERO, |stamp| stamp.made_at.elapsed())
+    }
+
+    /// Returns how many updates are waiting to be applied.
+    pub(crate) fn len(&self) -> usize {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.pending.iter().map(VecDeque::len).sum::<usize>() + usize::from(state.applying.is_some())
+    }
+
+    /// Blocks until the updates queued for the partition's writes up to `sequence` have been applied.
+    pub(crate) fn wait_until_applied(&self, partition: usize, sequence: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let applying = state.applying.filter(|(applying, _)| *applying == partition).map(|(_, stamp)| stamp);
+            let oldest = applying.or_else(|| state.pending.get(partition)?.front().map(|(stamp, ..)| *stamp));
+            if oldest.is_none_or(|stamp| stamp.sequence > sequence) {
+                return;
+            }
+            state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Blocks until every update queued so far has been applied.
+    pub(crate) fn wait_until_empty(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while state.pending.iter().any(|pending| !pending.is_empty()) || state.applying.is_some() {
+            state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// A background thread that applies queued updates to replicas. It drains the queue and exits
+/// when stopped or dropped.
+#[derive(Debug)]
+pub(crate) struct Replicator {
+    queue: Arc<ReplicationQueue>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Replicator {
+    /// Starts a replicator for a server with the given number of partitions.
+    pub(crate) fn spawn(partitions: usize) -> Self {
+        let queue = Arc::new(ReplicationQueue::new(partitions));
+        let handle = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || loop {
+                let mut state = queue.state.lock().unwrap_or_else(PoisonError::into_inner);
+                state.applying = None;
+                queue.changed.notify_all();
+                let (stamp, replicas, hints, op) = loop {
+                    if let Some((partition, (stamp, replicas, hints, op))) = state.pop() {
+                        state.applying = Some((partition, stamp));
+                        break (stamp, replicas, hints, op);
+                    }
+                    if queue.stop.load(Ordering::Acquire) {
+                        return;
+                    }
+                    state = queue.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+                };
+                drop(state);
+                for replica in &replicas {
+                    if let Err((error, _)) = apply_to(replica, &op, stamp, hints.as_deref()) {
+                        warn!("Skipping a queued update for a replica: {}", error);
+                    }
+                }
+            })
+        };
+        Self { queue, handle: Mutex::new(Some(handle)) }
+    }
+
+    pub(crate) fn queue(&self) -> &Arc<ReplicationQueue> {
+        &self.queue
+    }
+
+    /// Applies every update queued so far, then stops the thread. Updates queued after it has
+    /// stopped are never applied.
+    pub(crate) fn stop(&self) {
+        self.queue.stop.store(true, Ordering::Release);
+        self.queue.changed.notify_all();
+        let handle = self.handle.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Replicator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,414 @@
+//! A subset of the Redis serialization protocol (RESP2), so Redis clients and tools such as
+//! redis-cli can talk to a StorageServer.
+//!
+//! Supported commands:
+//!
+//! - `GET key` and `MGET key [key ...]`
+//! - `SET key value [EX seconds | PX milliseconds]` and `MSET key value [key value ...]`
+//! - `DEL key [key ...]` and `EXISTS key [key ...]`
+//! - `EXPIRE key seconds`, where a TTL of zero or less deletes the key as in Redis
+//! - `SCAN cursor [MATCH pattern] [COUNT count]`, with `*`, `?`, `[...]` and `\` in patterns
+//! - `PING [message]`, `ECHO message` and `QUIT`
+//!
+//! Commands may be sent as arrays of bulk strings or inline, and may be pipelined. Keys and values
+//! are binary safe. SCAN cursors are only valid on the connection that returned
+//! them, and only the most recent ones are kept, so a client should finish a scan on one
+//! connection.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use log::info;
+use crate::error::FlowDbError;
+use crate::scan::Cursor;
+use crate::storage_server::StorageServer;
+use crate::tls::{self, TlsConfig};
+
+/// The longest bulk string accepted in a command.
+const MAX_BULK_LEN: usize = 64 << 20;
+/// The most arguments accepted in a command.
+const MAX_ARGS: usize = 1 << 20;
+/// The most SCAN cursors kept for a connection; the oldest is forgotten when another is returned.
+const MAX_CURSORS: usize = 64;
+/// The number of keys a SCAN page looks at unless COUNT says otherwise, as in Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string for None.
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(message) => out.extend_from_slice(format!("-{}\r\n", message).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(replies) => {
+                out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                replies.iter().for_each(|reply| reply.encode(out));
+            }
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Reply::Error(format!("ERR {}", message.into()))
+    }
+}
+
+/// The state a connection keeps between commands.
+#[derive(Default)]
+struct Session {
+    cursors: HashMap<u64, Cursor>,
+    last_cursor: u64,
+}
+
+impl Session {
+    /// Stores the cursor and returns the number handed to the client for it.
+    fn save(&mut self, cursor: Cursor) -> u64 {
+        if self.cursors.len() >= MAX_CURSORS {
+            let oldest = *self.cursors.keys().min().expect("cursors are stored");
+            self.cursors.remove(&oldest);
+        }
+        self.last_cursor += 1;
+        self.cursors.insert(self.last_cursor, cursor);
+        self.last_cursor
+    }
+}
+
+impl StorageServer {
+    /// Serves RESP commands on the connection until the client closes it or sends QUIT, or the
+    /// connection fails or carries a malformed command.
+    pub fn serve_resp<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut session = Session::default();
+        let mut out = Vec::new();
+        loop {
+            let quit = match read_command(&mut reader) {
+                Ok(Some(args)) if args.is_empty() => continue,
+                Ok(Some(args)) => {
+                    self.command(&mut session, &args).encode(&mut out);
+                    args[0].eq_ignore_ascii_case(b"QUIT")
+                }
+                Ok(None) => true,
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    Reply::error(format!("Protocol error: {}", e)).encode(&mut out);
+                    true
+                }
+                Err(e) => return Err(e),
+            };
+            // Replies to pipelined commands are sent together once every buffered command is handled.
+            if quit || reader.buffer().is_empty() {
+                reader.get_mut().write_all(&out)?;
+                reader.get_mut().flush()?;
+                out.clear();
+            }
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(&self, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let args = &args[1..];
+        let arity = |valid: bool| if valid { Ok(()) } else { Err(Reply::error(format!("wrong number of arguments for '{}' command", name))) };
+        let result = match name.as_str() {
+            "ping" => arity(args.len() <= 1).map(|()| match args.first() {
+                Some(message) => Reply::Bulk(Some(message.clone())),
+                None => Reply::Status("PONG"),
+            }),
+            "echo" => arity(args.len() == 1).map(|()| Reply::Bulk(Some(args[0].clone()))),
+            "quit" => Ok(Reply::Status("OK")),
+            "get" => arity(args.len() == 1).and_then(|()| match self.get_bytes(&args[0]) {
+                Ok(value) => Ok(Reply::Bulk(Some(value))),
+                Err(FlowDbError::NotFound) => Ok(Reply::Bulk(None)),
+                Err(e) => Err(error_reply(e)),
+            }),
+            // As in Redis, keys that don't hold a plain value are returned as nil.
+            "mget" => arity(!args.is_empty()).map(|()| Reply::Array(self.multi_get_bytes(args).into_iter().map(|value| Reply::Bulk(value.ok())).collect())),
+            "set" => arity(args.len() >= 2).and_then(|()| self.set(&args[0], &args[1], &args[2..])),
+            "mset" => arity(!args.is_empty() && args.len().is_multiple_of(2)).and_then(|()| {
+                let pairs: Vec<_> = args.chunks(2).map(|pair| (pair[0].as_slice(), pair[1].as_slice())).collect();
+                self.multi_put(&pairs).map(|()| Reply::Status("OK")).map_err(error_reply)
+            }),
+            "del" => arity(!args.is_empty()).and_then(|()| {
+                let mut deleted = 0;
+                for arg in args {
+                    deleted += i64::from(self.delete(arg).map_err(error_reply)?);
+                }
+                Ok(Reply::Integer(deleted))
+            }),
+            "exists" => arity(!args.is_empty()).map(|()| Reply::Integer(args.iter().filter(|key| self.contains_key(key)).count() as i64)),
+            "expire" => arity(args.len() == 2).and_then(|()| {
+                let key = &args[0];
+                let seconds = integer(&args[1])?;
+                let existed = match u64::try_from(seconds) {
+                    Ok(seconds) if seconds > 0 => self.expire(key, Duration::from_secs(seconds)),
+                    _ => self.delete(key),
+                };
+                existed.map(|existed| Reply::Integer(i64::from(existed))).map_err(error_reply)
+            }),
+            "scan" => arity(!args.is_empty()).and_then(|()| self.scan_command(session, args)),
+            _ => Err(Reply::error(format!("unknown command '{}'", name))),
+        };
+        result.unwrap_or_else(|reply| reply)
+    }
+
+    fn set(&self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let ttl = match options {
+            [] => None,
+            [unit, amount] if unit.eq_ignore_ascii_case(b"EX") || unit.eq_ignore_ascii_case(b"PX") => {
+                let amount = u64::try_from(integer(amount)?).ok().filter(|&amount| amount > 0).ok_or_else(|| Reply::error("invalid expire time in 'set' command"))?;
+                Some(if unit.eq_ignore_ascii_case(b"EX") { Duration::from_secs(amount) } else { Duration::from_millis(amount) })
+            }
+            _ => return Err(Reply::error("syntax error")),
+        };
+        let stored = match ttl {
+            Some(ttl) => self.put_with_ttl(key, value, ttl),
+            None => self.put(key, value),
+        };
+        stored.map(|_| Reply::Status("OK")).map_err(error_reply)
+    }
+
+    fn scan_command(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let cursor = match integer(&args[0])? {
+            0 => None,
+            n => Some(u64::try_from(n).ok().and_then(|n| session.cursors.remove(&n)).ok_or_else(|| Reply::error("invalid cursor"))?),
+        };
+        let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+        for option in args[1..].chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value.as_slice()),
+                [name, value] if name.eq_ignore_ascii_case(b"COUNT") => count = usize::try_from(integer(value)?).ok().filter(|&count| count > 0).ok_or_else(|| Reply::error("syntax error"))?,
+                _ => return Err(Reply::error("syntax error")),
+            }
+        }
+        // As in Redis, the pattern is applied to the keys of a page, so a page may come back empty.
+        let (entries, next) = self.scan_page(cursor.as_ref(), count);
+        let keys = entries.into_iter().map(|(key, _)| key).filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)));
+        let next = next.map_or(0, |next| session.save(next));
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(keys.map(|key| Reply::Bulk(Some(key))).collect()),
+        ]))
+    }
+}
+
+fn integer(arg: &[u8]) -> Result<i64, Reply> {
+    str::from_utf8(arg).ok().and_then(|arg| arg.parse().ok()).ok_or_else(|| Reply::error("value is not an integer or out of range"))
+}
+
+fn error_reply(error: FlowDbError) -> Reply {
+    match error {
+        FlowDbError::InvalidValue => Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_owned()),
+        e => Reply::error(e.to_string()),
+    }
+}
+
+/// Returns whether the text matches the Redis glob pattern.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, text_rest)) = text.split_first() else { return false };
+            let (negated, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    // An unclosed class matches what it listed.
+                    [] => break,
+                    [b']', after @ ..] => {
+                        class = after;
+                        break;
+                    }
+                    [b'\\', literal, after @ ..] => {
+                        matched |= *literal == c;
+                        class = after;
+                    }
+                    [low, b'-', high, after @ ..] if *high != b']' => {
+                        matched |= (*low.min(high)..=*low.max(high)).contains(&c);
+                        class = after;
+                    }
+                    [literal, after @ ..] => {
+                        matched |= *literal == c;
+                        class = after;
+                    }
+                }
+            }
+            matched != negated && glob_match(class, text_rest)
+        }
+        Some((b'\\', [literal, rest @ ..])) => text.first() == Some(literal) && glob_match(rest, &text[1..]),
+        Some((literal, rest)) => text.first() == Some(literal) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Reads the next command's arguments, or None if the client closed the connection before sending
+/// one. A blank inline command has no arguments.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(Some(line.split(|byte| byte.is_ascii_whitespace()).filter(|arg| !arg.is_empty()).map(<[u8]>::to_vec).collect()));
+    };
+    let count = length(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        let len = line.strip_prefix(b"$").ok_or_else(|| invalid("expected a bulk string"))?;
+        let mut arg = vec![0; length(len, MAX_BULK_LEN)? + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("bulk string is not terminated"));
+        }
+        arg.truncate(arg.len() - 2);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Parses the length at the start of a line, up to `max`.
+fn length(line: &[u8], max: usize) -> io::Result<usize> {
+    let digits = line.strip_suffix(b"\r\n").ok_or_else(|| invalid("line is not terminated"))?;
+    str::from_utf8(digits).ok().and_then(|digits| digits.parse().ok()).filter(|&len| len <= max).ok_or_else(|| invalid("invalid length"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Accepts Redis clients on the address and serves each on its own thread, over TLS if
+/// configured, until accepting fails.
+pub fn listen(server: Arc<StorageServer>, address: impl ToSocketAddrs, tls: Option<&TlsConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving RESP on {}", listener.local_addr()?);
+    tls::serve(listener, tls, "RESP", move |connection| server.serve_resp(connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"key[0-9]", b"key7"));
+        assert!(!glob_match(b"key[0-9]", b"keyx"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+    }
+
+    /// Reads one reply off the connection.
+    fn read_reply(reader: &mut impl BufRead) -> Reply {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).unwrap();
+        let text = String::from_utf8_lossy(&line[1..line.len() - 2]).into_owned();
+        match line[0] {
+            b'+' => Reply::Status(match text.as_str() {
+                "OK" => "OK",
+                "PONG" => "PONG",
+                other => panic!("unexpected status {}", other),
+            }),
+            b'-' => Reply::Error(text),
+            b':' => Reply::Integer(text.parse().unwrap()),
+            b'$' if text == "-1" => Reply::Bulk(None),
+            b'$' => {
+                let mut data = vec![0; text.parse::<usize>().unwrap() + 2];
+                reader.read_exact(&mut data).unwrap();
+                data.truncate(data.len() - 2);
+                Reply::Bulk(Some(data))
+            }
+            b'*' => Reply::Array((0..text.parse().unwrap()).map(|_| read_reply(reader)).collect()),
+            other => panic!("unexpected reply type {}", other),
+        }
+    }
+
+    fn bulk(data: &[u8]) -> Reply {
+        Reply::Bulk(Some(data.to_vec()))
+    }
+
+    #[test]
+    fn test_resp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_resp(listener.accept().unwrap().0));
+        }
+        let mut reader = BufReader::new(TcpStream::connect(address).unwrap());
+        let mut call = |request: &[u8], replies: usize| -> Vec<Reply> {
+            reader.get_mut().write_all(request).unwrap();
+            (0..replies).map(|_| read_reply(&mut reader)).collect()
+        };
+        let error = |message: &str| Reply::Error(format!("ERR {}", message));
+
+        assert_eq!(call(b"*1\r\n$4\r\nPING\r\n", 1), vec![Reply::Status("PONG")]);
+        assert_eq!(call(b"*3\r\n$3\r\nSET\r\n$6\r\nuser:1\r\n$5\r\nalice\r\n", 1), vec![Reply::Status("OK")]);
+        assert_eq!(call(b"*2\r\n$3\r\nget\r\n$6\r\nuser:1\r\n", 1), vec![bulk(b"alice")]);
+        assert_eq!(call(b"GET missing\r\n", 1), vec![Reply::Bulk(None)]);
+
+        // Pipelined commands are answered in order.
+        let replies = call(b"*5\r\n$4\r\nMSET\r\n$6\r\nuser:2\r\n$3\r\nbob\r\n$3\r\nraw\r\n$2\r\n\x00\xff\r\nMGET user:1 missing raw\r\nEXISTS user:1 user:2 missing\r\n", 3);
+        assert_eq!(replies, vec![Reply::Status("OK"), Reply::Array(vec![bulk(b"alice"), Reply::Bulk(None), bulk(b"\x00\xff")]), Reply::Integer(2)]);
+        assert_eq!(server.get_bytes("raw"), Ok(vec![0, 255]));
+        let replies = call(b"SET temp value EX 60\r\nEXPIRE temp 0\r\nEXISTS temp\r\nEXPIRE missing 10\r\n", 4);
+        assert_eq!(replies, vec![Reply::Status("OK"), Reply::Integer(1), Reply::Integer(0), Reply::Integer(0)]);
+        assert_eq!(call(b"SET key value NX\r\n", 1), vec![error("syntax error")]);
+        assert_eq!(call(b"GET\r\n", 1), vec![error("wrong number of arguments for 'get' command")]);
+        assert_eq!(call(b"FLUSHALL\r\n", 1), vec![error("unknown command 'flushall'")]);
+
+        // Scanning with a small count takes several pages, and a pattern filters their keys.
+        let mut keys = Vec::new();
+        let mut cursor = b"0".to_vec();
+        loop {
+            let request = format!("SCAN {} MATCH user:* COUNT 1\r\n", String::from_utf8_lossy(&cursor));
+            let Reply::Array(reply) = call(request.as_bytes(), 1).remove(0) else { panic!("SCAN returns an array") };
+            let [Reply::Bulk(Some(next)), Reply::Array(page)] = <[Reply; 2]>::try_from(reply).unwrap() else { panic!("SCAN returns a cursor and keys") };
+            keys.extend(page.into_iter().map(|key| match key {
+                Reply::Bulk(Some(key)) => key,
+                other => panic!("unexpected key {:?}", other),
+            }));
+            cursor = next;
+            if cursor == b"0" {
+                break;
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+        assert_eq!(call(b"SCAN 12345\r\n", 1), vec![error("invalid cursor")]);
+
+        assert_eq!(call(b"DEL user:1 user:2 missing\r\n", 1), vec![Reply::Integer(2)]);
+        assert_eq!(call(b"QUIT\r\n", 1), vec![Reply::Status("OK")]);
+    }
+}
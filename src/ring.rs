@@ -0,0 +1,318 @@
+//! Routes keys to partitions with a consistent-hash ring, so changing the number of partitions only
+//! moves the keys of the partitions that were added or removed.
+//!
+//! Keys and tokens are hashed with the ring's `Partitioner`. The default, xxHash64, gives the same
+//! hashes on every platform and Rust version, so partition assignments that outlive the process,
+//! like the partition directories of LSM trees or a node serving another's partitions, stay valid.
+//!
+//! Servers used to hash with the standard library's `DefaultHasher`, which is what
+//! `Partitioner::SipHash` still does. LSM trees written by those servers must be reopened with
+//! `SipHash` to find their keys; data persisted with `open` and snapshots are rerouted key by key
+//! when loaded and can move to xxHash64 by loading them into a server using it.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// The number of virtual nodes each partition gets unless configured otherwise.
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// The hash function a ring places keys and tokens with. Every server routing the same keys,
+/// including nodes serving each other's partitions and servers reopening the same LSM trees, must
+/// use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Partitioner {
+    /// xxHash64 with seed 0, which is fast and never changes.
+    #[default]
+    XxHash64,
+    /// The standard library's `DefaultHasher`, which servers used before partitioners could be
+    /// chosen. Its hashes aren't guaranteed to stay the same across Rust versions, so it is only
+    /// meant for reading data partitioned with it.
+    SipHash,
+}
+
+impl Partitioner {
+    /// Returns the hash of the key.
+    pub fn hash_key(self, key: &[u8]) -> u64 {
+        match self {
+            Partitioner::XxHash64 => xxhash64(key, 0),
+            Partitioner::SipHash => {
+                let mut hasher = DefaultHasher::new();
+                hash_key(key, &mut hasher);
+                hasher.finish()
+            }
+        }
+    }
+
+    /// Returns the position of the partition's token number `node` on the ring.
+    fn token(self, partition: usize, node: usize) -> u64 {
+        match self {
+            Partitioner::XxHash64 => {
+                let mut bytes = [0; 16];
+                bytes[..8].copy_from_slice(&(partition as u64).to_le_bytes());
+                bytes[8..].copy_from_slice(&(node as u64).to_le_bytes());
+                xxhash64(&bytes, 0)
+            }
+            Partitioner::SipHash => {
+                let mut hasher = DefaultHasher::new();
+                (partition, node).hash(&mut hasher);
+                hasher.finish()
+            }
+        }
+    }
+}
+
+/// A consistent-hash ring that maps keys to partitions.
+///
+/// Each partition is placed on the ring at `virtual_nodes` points, or tokens, derived from its
+/// index. A key belongs to the partition owning the first token at or after the key's hash, wrapping
+/// around at the end. Adding a partition only takes over the ranges just before its own tokens, so
+/// going from N to N + 1 partitions moves about 1/(N + 1) of the keys, all to the new partition.
+/// More virtual nodes spread the keys more evenly at the cost of a larger ring.
+///
+/// Splitting or merging partitions hands tokens from one partition to another, so after that
+/// partitions no longer have `virtual_nodes` tokens each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRing {
+    /// Every token with the partition that owns it, sorted by token.
+    tokens: Vec<(u64, usize)>,
+    partitions: usize,
+    virtual_nodes: usize,
+    partitioner: Partitioner,
+}
+
+impl HashRing {
+    /// Creates a ring of `partitions` partitions with `virtual_nodes` tokens each, at least one,
+    /// hashed with the default partitioner.
+    pub fn new(partitions: usize, virtual_nodes: usize) -> Self {
+        Self::with_partitioner(partitions, virtual_nodes, Partitioner::default())
+    }
+
+    /// Creates a ring like `new` whose keys and tokens are hashed with the partitioner.
+    pub fn with_partitioner(partitions: usize, virtual_nodes: usize, partitioner: Partitioner) -> Self {
+        let virtual_nodes = virtual_nodes.max(1);
+        let mut tokens: Vec<_> = (0..partitions)
+            .flat_map(|partition| (0..virtual_nodes).map(move |node| (partitioner.token(partition, node), partition)))
+            .collect();
+        tokens.sort_unstable();
+        Self { tokens, partitions, virtual_nodes, partitioner }
+    }
+
+    /// Returns the number of partitions on the ring.
+    pub fn partitions(&self) -> usize {
+        self.partitions
+    }
+
+    /// Returns the number of tokens each partition was given when the ring was created.
+    pub fn virtual_nodes(&self) -> usize {
+        self.virtual_nodes
+    }
+
+    /// Returns the partitioner keys and tokens are hashed with.
+    pub fn partitioner(&self) -> Partitioner {
+        self.partitioner
+    }
+
+    /// Returns every token on the ring with the partition that owns it, in ring order.
+    pub fn tokens(&self) -> &[(u64, usize)] {
+        &self.tokens
+    }
+
+    /// Returns the ring with a new partition, numbered after the others, that takes over every
+    /// other token of `partition`, so it gets about half of that partition's keys and no others.
+    pub fn split(&self, partition: usize) -> Self {
+        let new = self.partitions;
+        let mut take = false;
+        let tokens = self
+            .tokens
+            .iter()
+            .map(|&(token, owner)| match owner == partition {
+                true => {
+                    take = !take;
+                    (token, if take { owner } else { new })
+                }
+                false => (token, owner),
+            })
+            .collect();
+        Self { tokens, partitions: self.partitions + 1, virtual_nodes: self.virtual_nodes, partitioner: self.partitioner }
+    }
+
+    /// Returns the ring with the tokens of partition `from` handed to `into`, and the last partition
+    /// renumbered to take the place of `from` so partitions stay numbered from 0.
+    pub fn merge(&self, from: usize, into: usize) -> Self {
+        let last = self.partitions - 1;
+        let tokens = self
+            .tokens
+            .iter()
+            .map(|&(token, owner)| {
+                let owner = if owner == from { into } else { owner };
+                (token, if owner == last { from } else { owner })
+            })
+            .collect();
+        Self { tokens, partitions: last, virtual_nodes: self.virtual_nodes, partitioner: self.partitioner }
+    }
+
+    /// Returns the partition the key belongs to. Panics if the ring has no partitions.
+    pub fn partition(&self, key: impl AsRef<[u8]>) -> usize {
+        self.partition_for_hash(self.partitioner.hash_key(key.as_ref()))
+    }
+
+    /// Returns the partition owning the first token at or after the hash.
+    pub fn partition_for_hash(&self, hash: u64) -> usize {
+        let index = self.tokens.partition_point(|&(token, _)| token < hash);
+        self.tokens[index % self.tokens.len()].1
+    }
+
+    /// Returns the fraction of the hash space each partition owns, by partition index.
+    pub fn ownership(&self) -> Vec<f64> {
+        let mut owned = vec![0u64; self.partitions];
+        let Some(&(last, _)) = self.tokens.last() else {
+            return Vec::new();
+        };
+        let mut previous = last;
+        for &(token, partition) in &self.tokens {
+            // Each token owns the range from the previous token, wrapping around for the first.
+            owned[partition] = owned[partition].wrapping_add(token.wrapping_sub(previous));
+            previous = token;
+        }
+        owned.into_iter().map(|owned| owned as f64 / u64::MAX as f64).collect()
+    }
+}
+
+/// Feeds the key to the hasher the way a `str` of the same bytes is hashed, so keys route and
+/// filter the same as they did when keys were strings.
+pub(crate) fn hash_key(key: &[u8], hasher: &mut impl Hasher) {
+    hasher.write(key);
+    hasher.write_u8(0xff);
+}
+
+/// Returns the xxHash64 hash of the data.
+fn xxhash64(data: &[u8], seed: u64) -> u64 {
+    let mut chunks = data.chunks_exact(32);
+    let mut hash = if data.len() >= 32 {
+        let mut lanes = [seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2), seed.wrapping_add(PRIME_2), seed, seed.wrapping_sub(PRIME_1)];
+        for chunk in &mut chunks {
+            for (lane, word) in lanes.iter_mut().zip(chunk.chunks_exact(8)) {
+                *lane = xxhash_round(*lane, read_u64(word));
+            }
+        }
+        let [a, b, c, d] = lanes;
+        let hash = a.rotate_left(1).wrapping_add(b.rotate_left(7)).wrapping_add(c.rotate_left(12)).wrapping_add(d.rotate_left(18));
+        lanes.iter().fold(hash, |hash, &lane| (hash ^ xxhash_round(0, lane)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4))
+    } else {
+        seed.wrapping_add(PRIME_5)
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    let mut rest = chunks.remainder();
+    while rest.len() >= 8 {
+        hash ^= xxhash_round(0, read_u64(rest));
+        hash = hash.rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        hash ^= u64::from(u32::from_le_bytes(rest[..4].try_into().unwrap())).wrapping_mul(PRIME_1);
+        hash = hash.rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        hash ^= u64::from(byte).wrapping_mul(PRIME_5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME_3);
+    hash ^ (hash >> 32)
+}
+
+fn xxhash_round(lane: u64, word: u64) -> u64 {
+    lane.wrapping_add(word.wrapping_mul(PRIME_2)).rotate_left(31).wrapping_mul(PRIME_1)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_moves_few_keys() {
+        let before = HashRing::new(8, DEFAULT_VIRTUAL_NODES);
+        let after = HashRing::new(9, DEFAULT_VIRTUAL_NODES);
+        let keys: Vec<_> = (0..10_000).map(|i| format!("key{}", i)).collect();
+        let moved: Vec<_> = keys.iter().filter(|key| before.partition(key) != after.partition(key)).collect();
+
+        // About 1/9 of the keys move, and only to the new partition.
+        assert!(moved.len() < 1_600, "{} keys moved", moved.len());
+        assert!(moved.iter().all(|key| after.partition(key) == 8));
+
+        let ownership = after.ownership();
+        assert_eq!(ownership.len(), 9);
+        assert!((ownership.iter().sum::<f64>() - 1.0).abs() < 1e-6);
+        assert!(ownership.iter().all(|&share| share > 0.05 && share < 0.2), "{:?}", ownership);
+        assert_eq!(after.tokens().len(), 9 * DEFAULT_VIRTUAL_NODES);
+    }
+
+    #[test]
+    fn test_partitioner() {
+        assert_eq!(xxhash64(b"", 0), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxhash64(b"a", 0), 0xD24E_C4F1_A98C_6E5B);
+        assert_eq!(xxhash64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+        assert_eq!(xxhash64(b"Nobody inspects the spammish repetition", 0), 0xFBCE_A83C_8A37_8BF1);
+
+        // The legacy partitioner routes keys exactly as rings did before partitioners existed.
+        let legacy = HashRing::with_partitioner(8, DEFAULT_VIRTUAL_NODES, Partitioner::SipHash);
+        let mut hasher = DefaultHasher::new();
+        hash_key(b"key", &mut hasher);
+        assert_eq!(legacy.partition("key"), legacy.partition_for_hash(hasher.finish()));
+        let mut hasher = DefaultHasher::new();
+        (3usize, 5usize).hash(&mut hasher);
+        assert!(legacy.tokens().contains(&(hasher.finish(), 3)));
+
+        // Both spread keys evenly, and splitting keeps the ring's partitioner.
+        let ring = HashRing::new(8, DEFAULT_VIRTUAL_NODES);
+        assert_eq!(ring.partitioner(), Partitioner::XxHash64);
+        assert_ne!(ring, legacy);
+        for ring in [&ring, &legacy] {
+            assert!(ring.ownership().iter().all(|&share| share > 0.08 && share < 0.17), "{:?}", ring.ownership());
+        }
+        assert_eq!(legacy.split(2).partitioner(), Partitioner::SipHash);
+    }
+
+    #[test]
+    fn test_ring_split_and_merge() {
+        let ring = HashRing::new(4, DEFAULT_VIRTUAL_NODES);
+        let keys: Vec<_> = (0..10_000).map(|i| format!("key{}", i)).collect();
+
+        // Splitting moves about half of the partition's keys, all to the new partition.
+        let split = ring.split(1);
+        assert_eq!(split.partitions(), 5);
+        let moved: Vec<_> = keys.iter().filter(|key| ring.partition(key) != split.partition(key)).collect();
+        assert!(moved.iter().all(|key| ring.partition(key) == 1 && split.partition(key) == 4));
+        let ownership = split.ownership();
+        assert!((ownership[1] + ownership[4] - ring.ownership()[1]).abs() < 1e-6);
+        assert!(ownership[4] > ownership[1] / 3.0 && ownership[4] < ownership[1] * 3.0, "{:?}", ownership);
+
+        // Merging hands one partition's keys to another and renumbers the last in its place.
+        let merged = ring.merge(0, 2);
+        assert_eq!(merged.partitions(), 3);
+        for key in &keys {
+            let expected = match ring.partition(key) {
+                0 | 2 => 2,
+                3 => 0,
+                partition => partition,
+            };
+            assert_eq!(merged.partition(key), expected);
+        }
+        assert_eq!(ring.split(3).merge(4, 3), ring);
+    }
+}
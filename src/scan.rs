@@ -0,0 +1,195 @@
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, RwLock};
+use std::vec::IntoIter;
+use crate::codec::Codecs;
+use crate::storage_server::Partition;
+
+/// Entries copied out of a partition as their keys, stored values and the codecs the values are
+/// in, consumed in order.
+type Entries = IntoIter<(Vec<u8>, Arc<[u8]>, u8)>;
+
+/// A page of key-value pairs, and the cursor for the next page if there is one.
+pub type Page = (Vec<(Vec<u8>, Vec<u8>)>, Option<Cursor>);
+
+/// An iterator over the key-value pairs of every partition of a StorageServer.
+///
+/// Partitions are visited in order. Each partition is read-locked only long enough to copy out its
+/// keys and share its stored values, so a scan never holds more than one partition lock and
+/// never blocks writers for longer than that copy. Values are decompressed lazily as the iterator advances.
+///
+/// A scan can be restricted to keys starting with a prefix, in which case only matching entries
+/// are copied out of each partition. Keys holding collections are skipped, and so are partitions
+/// whose lock is poisoned, since they may be half-updated.
+pub struct Scan {
+    partitions: Vec<Arc<RwLock<Partition>>>,
+    prefix: Option<Vec<u8>>,
+    codecs: Codecs,
+    next_partition: usize,
+    entries: Entries,
+}
+
+impl Scan {
+    pub(crate) fn new(partitions: Vec<Arc<RwLock<Partition>>>, prefix: Option<Vec<u8>>, codecs: Codecs) -> Self {
+        Self { partitions, prefix, codecs, next_partition: 0, entries: Vec::new().into_iter() }
+    }
+
+    /// Copies the entries of the next partition into the buffer, returning false once all partitions have been visited.
+    fn load_next_partition(&mut self) -> bool {
+        let Some(partition) = self.partitions.get(self.next_partition) else {
+            return false;
+        };
+        self.next_partition += 1;
+        let Ok(partition_guard) = partition.read() else {
+            self.entries = Vec::new().into_iter();
+            return true;
+        };
+        let prefix = self.prefix.as_deref().unwrap_or_default();
+        let entries: Vec<_> = partition_guard
+            .data
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && entry.is_live() && !entry.is_collection())
+            .map(|(key, entry)| (key.into_owned(), entry.value.clone(), entry.codec))
+            .collect();
+        self.entries = entries.into_iter();
+        true
+    }
+}
+
+impl Iterator for Scan {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for (key, stored, codec) in self.entries.by_ref() {
+                // Skip entries whose values can't be decoded rather than ending the scan.
+                if let Ok(value) = self.codecs.decode(&stored, codec) {
+                    return Some((key, value));
+                }
+            }
+            if !self.load_next_partition() {
+                return None;
+            }
+        }
+    }
+}
+
+/// An iterator over the key-value pairs within a key range, in ascending key order across all partitions.
+///
+/// Each partition's matching entries are copied out under its read lock, one partition at a time,
+/// and then merged lazily. Partitions whose lock is poisoned are skipped, as with `Scan`.
+pub struct RangeScan {
+    sources: Vec<Peekable<Entries>>,
+    codecs: Codecs,
+}
+
+impl RangeScan {
+    pub(crate) fn new<K: AsRef<[u8]>>(partitions: &[Arc<RwLock<Partition>>], range: impl RangeBounds<K>, codecs: Codecs) -> Self {
+        let bounds = (range.start_bound().map(K::as_ref), range.end_bound().map(K::as_ref));
+        let sources = partitions
+            .iter()
+            .filter_map(|partition| partition.read().ok())
+            .map(|partition_guard| partition_guard.data.range(bounds, usize::MAX).into_iter().peekable())
+            .collect();
+        Self { sources, codecs }
+    }
+}
+
+impl Iterator for RangeScan {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Keys are unique across partitions, so the smallest head among the sources is next.
+            let source = self
+                .sources
+                .iter_mut()
+                .filter_map(|source| source.peek().map(|(key, ..)| key.clone()).map(|key| (key, source)))
+                .min_by(|a, b| a.0.cmp(&b.0))
+                .map(|(_, source)| source)?;
+            let (key, stored, codec) = source.next()?;
+            if let Ok(value) = self.codecs.decode(&stored, codec) {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+/// A position in a paginated scan, returned by `StorageServer::scan_page` to resume from where the previous page ended.
+///
+/// Pages walk partitions in index order and keys in ascending order within each partition. A cursor
+/// can be turned into a string with `encode` to hand it to a client and parsed back with `decode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    partition: usize,
+    after: Option<Vec<u8>>,
+}
+
+impl Cursor {
+    /// Encodes the cursor as an opaque string, with the key it resumes after in hex so any key
+    /// bytes survive being passed around as text.
+    pub fn encode(&self) -> String {
+        match &self.after {
+            Some(key) => format!("{}:{}", self.partition, key.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+            None => self.partition.to_string(),
+        }
+    }
+
+    /// Parses a string produced by `encode`, returning None if it is malformed.
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let (partition, after) = match encoded.split_once(':') {
+            Some((partition, key)) => (partition, Some(decode_hex(key)?)),
+            None => (encoded, None),
+        };
+        Some(Self { partition: partition.parse().ok()?, after })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Returns up to `limit` decoded key-value pairs whose keys start with the prefix, starting at the
+/// cursor, plus the cursor for the next page.
+///
+/// Only one partition is locked at a time, and only the entries that make it into the page are
+/// copied. Partitions whose lock is poisoned are skipped, as with `Scan`.
+pub(crate) fn scan_page(
+    partitions: &[Arc<RwLock<Partition>>],
+    prefix: &[u8],
+    cursor: Option<&Cursor>,
+    limit: usize,
+    codecs: &Codecs,
+) -> Page {
+    let mut cursor = cursor.cloned().unwrap_or(Cursor { partition: 0, after: None });
+    let mut page = Vec::new();
+    while cursor.partition < partitions.len() && page.len() < limit {
+        let lower = match cursor.after.as_deref() {
+            Some(after) => Bound::Excluded(after),
+            None if prefix.is_empty() => Bound::Unbounded,
+            None => Bound::Included(prefix),
+        };
+        let wanted = limit - page.len();
+        let entries = match partitions[cursor.partition].read() {
+            Ok(partition_guard) => partition_guard.data.range((lower, Bound::Unbounded), wanted),
+            Err(_) => Vec::new(),
+        };
+
+        // Move on to the next partition once this one has no more matching entries to give.
+        if entries.len() < wanted || entries.last().is_some_and(|(last_key, ..)| !last_key.starts_with(prefix)) {
+            cursor = Cursor { partition: cursor.partition + 1, after: None };
+        } else if let Some((last_key, ..)) = entries.last() {
+            cursor.after = Some(last_key.clone());
+        }
+        for (key, stored, codec) in entries.into_iter().filter(|(key, ..)| key.starts_with(prefix)) {
+            if let Ok(value) = codecs.decode(&stored, codec) {
+                page.push((key, value));
+            }
+        }
+    }
+    let next = (cursor.partition < partitions.len()).then_some(cursor);
+    (page, next)
+}
@@ -0,0 +1,225 @@
+//! A transaction log made of independent shards, so writers to different keys don't queue behind
+//! one mutex.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::ring;
+use crate::transaction_log::{self, LogMetrics, LogRecord, Loggable, TransactionLog};
+
+/// A transaction log that can be cloned into every writer and written to concurrently.
+///
+/// Records are spread over `shards` ordinary transaction logs in subdirectories `shard-000`,
+/// `shard-001`, and so on, chosen by hashing the record's key; a commit goes to the shard of its
+/// first key. Only writers that land on the same shard wait for each other. Every record is tagged
+/// with a sequence number taken while its shard is locked, and reading merges the shards by
+/// sequence, so a record is replayed after everything logged before it returned, whichever shard
+/// that went to.
+#[derive(Clone)]
+pub struct SharedTransactionLog {
+    inner: Arc<Shards>,
+}
+
+struct Shards {
+    dir: PathBuf,
+    logs: Vec<Mutex<TransactionLog<Sequenced>>>,
+    sequence: AtomicU64,
+}
+
+/// A record tagged with its sequence number, as stored in a shard.
+struct Sequenced {
+    sequence: u64,
+    record: LogRecord,
+}
+
+impl Loggable for Sequenced {
+    fn encode(&self) -> Vec<u8> {
+        encode_sequenced(self.sequence, &self.record)
+    }
+
+    fn decode(frame: &[u8]) -> Option<Self> {
+        let (payload, []) = transaction_log::split_frame(frame)? else {
+            return None;
+        };
+        let (sequence, record) = payload.split_at_checked(8)?;
+        Some(Self { sequence: u64::from_be_bytes(sequence.try_into().ok()?), record: LogRecord::decode(record)? })
+    }
+}
+
+fn encode_sequenced(sequence: u64, record: &LogRecord) -> Vec<u8> {
+    let mut payload = sequence.to_be_bytes().to_vec();
+    payload.extend_from_slice(&record.encode());
+    transaction_log::frame(&payload)
+}
+
+impl SharedTransactionLog {
+    /// Opens the sharded log in the given directory, each shard rotating and retaining segments
+    /// like a `TransactionLog` with the same settings.
+    ///
+    /// The number of shards must stay the same across restarts.
+    pub fn open(path: impl AsRef<Path>, shards: usize, max_size: u64, max_files: u32, read_buffer_size: usize) -> Result<Self> {
+        let dir = path.as_ref().to_owned();
+        let mut logs = Vec::new();
+        let mut last = 0;
+        for shard in 0..shards.max(1) {
+            let shard_dir = shard_path(&dir, shard);
+            for entry in TransactionLog::<Sequenced>::iter_dir(&shard_dir, read_buffer_size)? {
+                last = last.max(entry?.1.sequence);
+            }
+            let shard_dir = shard_dir.to_str().ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "log path is not UTF-8"))?;
+            logs.push(Mutex::new(TransactionLog::new(shard_dir, max_size, max_files, read_buffer_size, Box::new(|data| data.to_vec()))?));
+        }
+        Ok(Self { inner: Arc::new(Shards { dir, logs, sequence: AtomicU64::new(last) }) })
+    }
+
+    /// Returns whether the directory holds a sharded log rather than a plain `TransactionLog`.
+    pub fn is_shared(path: impl AsRef<Path>) -> bool {
+        shard_path(path.as_ref(), 0).is_dir()
+    }
+
+    /// Reads every record from the shards of the log in the given directory, in the order they were
+    /// written. A missing directory holds no records.
+    pub fn read_all(path: impl AsRef<Path>, read_buffer_size: usize) -> Result<Vec<LogRecord>> {
+        let mut sequenced = Vec::new();
+        for shard in 0.. {
+            let shard_dir = shard_path(path.as_ref(), shard);
+            if !shard_dir.is_dir() {
+                break;
+            }
+            for entry in TransactionLog::<Sequenced>::iter_dir(&shard_dir, read_buffer_size)? {
+                sequenced.push(entry?.1);
+            }
+        }
+        sequenced.sort_unstable_by_key(|entry| entry.sequence);
+        Ok(sequenced.into_iter().map(|entry| entry.record).collect())
+    }
+
+    /// Returns the directory the log's shards are in.
+    pub fn path(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    /// Returns how many shards the log has.
+    pub fn shards(&self) -> usize {
+        self.inner.logs.len()
+    }
+
+    /// Writes the record to its shard and returns its sequence number, which is greater than that of
+    /// every record written before the call.
+    pub fn write_record(&self, record: &LogRecord) -> Result<u64> {
+        let mut log = lock(&self.inner.logs[self.shard(record)])?;
+        let sequence = self.inner.sequence.fetch_add(1, Ordering::AcqRel) + 1;
+        log.write(&encode_sequenced(sequence, record))?;
+        Ok(sequence)
+    }
+
+    /// Discards every record written so far in every shard. Shards are locked one at a time, so
+    /// records written concurrently may or may not survive.
+    pub fn truncate(&self) -> Result<()> {
+        for log in &self.inner.logs {
+            lock(log)?.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs every shard to disk like `TransactionLog::sync`, one at a time.
+    pub fn sync(&self) -> Result<()> {
+        for log in &self.inner.logs {
+            lock(log)?.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the counters of every shard added together.
+    pub fn metrics(&self) -> LogMetrics {
+        let mut metrics = LogMetrics::default();
+        for log in &self.inner.logs {
+            metrics.add(log.lock().unwrap_or_else(PoisonError::into_inner).metrics());
+        }
+        metrics
+    }
+
+    fn shard(&self, record: &LogRecord) -> usize {
+        let key = match record.untimed() {
+            LogRecord::Commit { records } => records.first().and_then(LogRecord::key),
+            record => record.key(),
+        };
+        let mut hasher = DefaultHasher::new();
+        if let Some(key) = key {
+            ring::hash_key(key, &mut hasher);
+        }
+        hasher.finish() as usize % self.inner.logs.len()
+    }
+}
+
+fn shard_path(dir: &Path, shard: usize) -> PathBuf {
+    dir.join(format!("shard-{:03}", shard))
+}
+
+/// Locks a shard, failing if a writer panicked while holding it, since its segment may end in a
+/// partly written record.
+fn lock(log: &Mutex<TransactionLog<Sequenced>>) -> Result<MutexGuard<'_, TransactionLog<Sequenced>>> {
+    log.lock().map_err(|_| Error::other("transaction log shard lock is poisoned"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn test_shared_log() {
+        let dir = "logs/test_shared_log";
+        let _ = fs::remove_dir_all(dir);
+        let log = SharedTransactionLog::open(dir, 4, 1024, 5, 8192).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let log = log.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        log.write_record(&LogRecord::Put { key: format!("key{}-{}", t, i).into_bytes(), value: b"value".to_vec() }).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(SharedTransactionLog::is_shared(dir));
+        assert!(!SharedTransactionLog::is_shared("logs/test_shared_log_missing"));
+
+        // Each writer's records come back in the order it wrote them, whichever shards they went to.
+        let records = SharedTransactionLog::read_all(dir, 8192).unwrap();
+        assert_eq!(records.len(), 200);
+        for t in 0..4 {
+            let prefix = format!("key{}-", t);
+            let keys: Vec<_> = records.iter().filter_map(LogRecord::key).filter(|key| key.starts_with(prefix.as_bytes())).map(<[u8]>::to_vec).collect();
+            assert_eq!(keys, (0..50).map(|i| format!("key{}-{}", t, i).into_bytes()).collect::<Vec<_>>());
+        }
+        assert_eq!(log.metrics().records_written, 200);
+
+        // Sequence numbers continue after a restart.
+        drop(log);
+        let log = SharedTransactionLog::open(dir, 4, 1024, 5, 8192).unwrap();
+        assert_eq!(log.write_record(&LogRecord::Delete { key: b"key0-0".to_vec() }).unwrap(), 201);
+        assert_eq!(SharedTransactionLog::read_all(dir, 8192).unwrap().last(), Some(&LogRecord::Delete { key: b"key0-0".to_vec() }));
+        log.truncate().unwrap();
+        assert_eq!(SharedTransactionLog::read_all(dir, 8192).unwrap(), vec![]);
+
+        // A shard poisoned by a panicking writer fails writes to it instead of panicking.
+        let record = LogRecord::Put { key: b"key".to_vec(), value: b"value".to_vec() };
+        let (poisoned, shard) = (log.clone(), log.shard(&record));
+        let _ = thread::spawn(move || {
+            let _guard = poisoned.inner.logs[shard].lock().unwrap();
+            panic!("poisoning the shard");
+        })
+        .join();
+        assert!(log.write_record(&record).is_err());
+        assert!(log.sync().is_err());
+        assert_eq!(log.metrics().records_written, 1);
+    }
+}
@@ -0,0 +1,234 @@
+//! Streams transaction log records to followers so a warm standby can replay the log continuously.
+//!
+//! A follower connects and sends the `LogPosition` to start from. The shipper sends every record
+//! from there on, then keeps following the log as it grows. The follower acknowledges records as it
+//! consumes them, and the shipper never has more than its window of records unacknowledged, so a
+//! slow follower holds the shipper back instead of being flooded. While there is nothing to send,
+//! the shipper sends heartbeats carrying its position, which also notice a follower that has gone
+//! away. A follower that reconnects from its last position resumes exactly where it stopped.
+//!
+//! Messages from the shipper start with a tag byte. A record is the tag, the segment and offset of
+//! the position just past it as big-endian u64s, and its frame; a heartbeat is the tag and the
+//! position. A transaction is shipped as one commit once it has been committed, so a follower
+//! never resumes partway into one. Messages from the follower are the start position, then
+//! acknowledgements, each a big-endian u32 count.
+//!
+//! Positions are byte offsets, so a follower must not resume into a segment that was rewritten by
+//! `TransactionLog::compact` since, and once its segment has been deleted it has to be reseeded
+//! from a snapshot.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use log::info;
+use crate::transaction_log::{LogPosition, LogRecord, TransactionLog};
+
+const TAG_RECORD: u8 = 0;
+const TAG_HEARTBEAT: u8 = 1;
+
+/// Serves the records of a transaction log directory to followers.
+pub struct LogShipper {
+    dir: PathBuf,
+    read_buffer_size: usize,
+    window: u32,
+    poll_interval: Duration,
+}
+
+impl LogShipper {
+    /// Creates a shipper for the log in the given directory, allowing 1024 unacknowledged records
+    /// and checking for new records every 100 milliseconds.
+    pub fn new(path: impl AsRef<Path>, read_buffer_size: usize) -> Self {
+        Self { dir: path.as_ref().to_owned(), read_buffer_size, window: 1024, poll_interval: Duration::from_millis(100) }
+    }
+
+    /// Sets how many records can be sent before the follower acknowledges them, at least 1.
+    pub fn with_window(mut self, window: u32) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Sets how long to wait for new records once the follower has caught up, which is also how
+    /// often heartbeats are sent.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Serves one follower until the connection fails or the follower's position can no longer be
+    /// read from the log.
+    pub fn serve<S: Read + Write>(&self, mut stream: S) -> Result<()> {
+        let mut position = read_position(&mut stream)?;
+        info!("Shipping transaction log to a follower from segment {} offset {}", position.segment, position.offset);
+        let mut unacknowledged = 0;
+        loop {
+            let mut iter = TransactionLog::<LogRecord>::iter_from(&self.dir, position, self.read_buffer_size)?;
+            while let Some(record) = iter.next() {
+                let record = match record {
+                    Ok((_, record)) => record,
+                    // The record being written right now; it is complete by the next poll.
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                };
+                while unacknowledged >= self.window {
+                    unacknowledged = unacknowledged.saturating_sub(read_u32(&mut stream)?);
+                }
+                let past = iter.position();
+                let mut message = vec![TAG_RECORD];
+                message.extend_from_slice(&past.segment.to_be_bytes());
+                message.extend_from_slice(&past.offset.to_be_bytes());
+                message.extend_from_slice(&record.encode());
+                stream.write_all(&message)?;
+                unacknowledged += 1;
+            }
+            // Only complete records advance the iterator's position, so a torn one is read again.
+            position = iter.position();
+            let mut message = vec![TAG_HEARTBEAT];
+            message.extend_from_slice(&position.segment.to_be_bytes());
+            message.extend_from_slice(&position.offset.to_be_bytes());
+            stream.write_all(&message)?;
+            stream.flush()?;
+            thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+/// Receives transaction log records from a `LogShipper`.
+pub struct LogFollower<S: Read + Write> {
+    stream: S,
+    position: LogPosition,
+    pending: u32,
+    ack_every: u32,
+}
+
+impl<S: Read + Write> LogFollower<S> {
+    /// Starts following the log from the position, on a connection to a shipper whose window is
+    /// `window` records.
+    pub fn connect(mut stream: S, from: LogPosition, window: u32) -> Result<Self> {
+        let mut request = from.segment.to_be_bytes().to_vec();
+        request.extend_from_slice(&from.offset.to_be_bytes());
+        stream.write_all(&request)?;
+        stream.flush()?;
+        Ok(Self { stream, position: from, pending: 0, ack_every: window.max(1).div_ceil(2) })
+    }
+
+    /// Returns the position to resume from: past every record returned so far.
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+
+    /// Waits for the next record, acknowledging the ones returned before it.
+    pub fn next_record(&mut self) -> Result<LogRecord> {
+        if self.pending >= self.ack_every {
+            self.stream.write_all(&self.pending.to_be_bytes())?;
+            self.stream.flush()?;
+            self.pending = 0;
+        }
+        loop {
+            let mut tag = [0; 1];
+            self.stream.read_exact(&mut tag)?;
+            let position = read_position(&mut self.stream)?;
+            match tag[0] {
+                TAG_RECORD => {
+                    let mut header = [0; 8];
+                    self.stream.read_exact(&mut header)?;
+                    let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+                    let mut frame = header.to_vec();
+                    if (&mut self.stream).take(len).read_to_end(&mut frame)? < len as usize {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated record"));
+                    }
+                    let record = LogRecord::decode(&frame).ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid record"))?;
+                    self.position = position;
+                    self.pending += 1;
+                    return Ok(record);
+                }
+                TAG_HEARTBEAT => self.position = position,
+                tag => return Err(Error::new(ErrorKind::InvalidData, format!("unknown message tag {}", tag))),
+            }
+        }
+    }
+}
+
+fn read_position(stream: &mut impl Read) -> Result<LogPosition> {
+    let mut bytes = [0; 16];
+    stream.read_exact(&mut bytes)?;
+    Ok(LogPosition { segment: u64::from_be_bytes(bytes[..8].try_into().unwrap()), offset: u64::from_be_bytes(bytes[8..].try_into().unwrap()) })
+}
+
+fn read_u32(stream: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+
+    fn put(key: &str) -> LogRecord {
+        LogRecord::Put { key: key.into(), value: b"value".to_vec() }
+    }
+
+    #[test]
+    fn test_log_shipping() {
+        let dir = "logs/test_log_shipping";
+        let _ = fs::remove_dir_all(dir);
+        let mut log = TransactionLog::new(dir, 64, 10, 8192, Box::new(|data| data.to_vec())).unwrap();
+        for i in 0..3 {
+            log.write_record(&put(&format!("key{}", i))).unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let shipper = LogShipper::new(dir, 8192).with_window(2).with_poll_interval(Duration::from_millis(10));
+            for stream in listener.incoming() {
+                let _ = shipper.serve(stream.unwrap());
+            }
+        });
+
+        let mut follower = LogFollower::connect(TcpStream::connect(address).unwrap(), LogPosition::START, 2).unwrap();
+        for i in 0..3 {
+            assert_eq!(follower.next_record().unwrap(), put(&format!("key{}", i)));
+        }
+
+        // Records written later are streamed as the log grows, across segment rotations.
+        for i in 3..6 {
+            log.write_record(&put(&format!("key{}", i))).unwrap();
+        }
+        for i in 3..5 {
+            assert_eq!(follower.next_record().unwrap(), put(&format!("key{}", i)));
+        }
+
+        // A follower that reconnects from its position resumes after the last record it received.
+        let position = follower.position();
+        drop(follower);
+        let mut follower = LogFollower::connect(TcpStream::connect(address).unwrap(), position, 2).unwrap();
+        assert_eq!(follower.next_record().unwrap(), put("key5"));
+        log.write_record(&put("key6")).unwrap();
+        assert_eq!(follower.next_record().unwrap(), put("key6"));
+
+        // A transaction arrives as one commit, and resuming after it starts past its end.
+        log.write_transaction(&[put("key7"), put("key8")]).unwrap();
+        assert_eq!(follower.next_record().unwrap(), LogRecord::Commit { records: vec![put("key7"), put("key8")] });
+        let position = follower.position();
+        drop(follower);
+        let mut follower = LogFollower::connect(TcpStream::connect(address).unwrap(), position, 2).unwrap();
+        log.write_record(&put("key9")).unwrap();
+        assert_eq!(follower.next_record().unwrap(), put("key9"));
+    }
+
+    #[test]
+    fn test_log_shipping_removed_segment() {
+        let dir = "logs/test_log_shipping_removed_segment";
+        let _ = fs::remove_dir_all(dir);
+        let mut log = TransactionLog::new(dir, 1, 2, 8192, Box::new(|data| data.to_vec())).unwrap();
+        for i in 0..5 {
+            log.write_record(&put(&format!("key{}", i))).unwrap();
+        }
+        let from = LogPosition { segment: 1, offset: 0 };
+        assert_eq!(TransactionLog::<LogRecord>::iter_from(dir, from, 8192).err().unwrap().kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,217 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use crate::engine::{EngineIter, StorageEngine};
+use crate::entry::Entry;
+
+/// A StorageEngine that keeps entries in memory up to a byte budget and spills the least recently
+/// used ones to an overflow file beyond it.
+///
+/// Spilled entries stay readable: lookups and scans read them back from the file. Writing a key
+/// brings it back into memory. The overflow file only extends memory and is cleared on open, so
+/// its contents do not survive a restart.
+#[derive(Debug)]
+pub(crate) struct SpillEngine {
+    hot: HashMap<Vec<u8>, HotEntry>,
+    hot_bytes: usize,
+    budget: usize,
+    clock: AtomicU64,
+    overflow: Overflow,
+}
+
+#[derive(Debug)]
+struct HotEntry {
+    entry: Entry,
+    last_access: AtomicU64,
+}
+
+impl SpillEngine {
+    /// Creates an engine that spills to the file at the path once its entries take more than `budget` bytes.
+    pub(crate) fn open(path: &Path, budget: usize) -> io::Result<Self> {
+        Ok(Self { hot: HashMap::new(), hot_bytes: 0, budget, clock: AtomicU64::new(0), overflow: Overflow::create(path)? })
+    }
+
+    /// Returns how many entries currently live in the overflow file.
+    #[cfg(test)]
+    fn spilled_len(&self) -> usize {
+        self.overflow.index.len()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Moves the least recently used entries to the overflow file until memory use drops to three
+    /// quarters of the budget, so spills happen in batches rather than on every write.
+    fn spill(&mut self) -> io::Result<()> {
+        if self.hot_bytes <= self.budget {
+            return Ok(());
+        }
+        let mut by_age: Vec<_> = self.hot.iter().map(|(key, hot)| (hot.last_access.load(Ordering::Relaxed), key.clone())).collect();
+        by_age.sort_unstable();
+        let target = self.budget / 4 * 3;
+        for (_, key) in by_age {
+            if self.hot_bytes <= target {
+                break;
+            }
+            let Some(hot) = self.hot.remove(&key) else { continue };
+            self.hot_bytes -= key.len() + hot.entry.footprint();
+            self.overflow.write(key, &hot.entry)?;
+        }
+        self.overflow.compact_if_sparse()
+    }
+}
+
+impl StorageEngine for SpillEngine {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, Entry>> {
+        if let Some(hot) = self.hot.get(key) {
+            hot.last_access.store(self.tick(), Ordering::Relaxed);
+            return Some(Cow::Borrowed(&hot.entry));
+        }
+        match self.overflow.read(key) {
+            Ok(entry) => entry.map(Cow::Owned),
+            Err(e) => {
+                log::error!("Failed to read spilled entry {}: {}", String::from_utf8_lossy(key), e);
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        self.overflow.remove(&key);
+        self.hot_bytes += key.len() + entry.footprint();
+        let hot = HotEntry { entry, last_access: AtomicU64::new(self.tick()) };
+        if let Some(previous) = self.hot.insert(key.clone(), hot) {
+            self.hot_bytes -= key.len() + previous.entry.footprint();
+        }
+        if let Err(e) = self.spill() {
+            log::error!("Failed to spill entries to {}: {}", self.overflow.path.display(), e);
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        if let Some(hot) = self.hot.remove(key) {
+            self.hot_bytes -= key.len() + hot.entry.footprint();
+            return Some(hot.entry);
+        }
+        let removed = self.overflow.read(key).ok().flatten();
+        self.overflow.remove(key);
+        removed
+    }
+
+    fn scan(&self) -> EngineIter<'_> {
+        let hot = self.hot.iter().map(|(key, hot)| (Cow::Borrowed(key), Cow::Borrowed(&hot.entry)));
+        let spilled = self.overflow.index.keys().filter_map(|key| match self.overflow.read(key) {
+            Ok(entry) => entry.map(|entry| (Cow::Borrowed(key), Cow::Owned(entry))),
+            Err(e) => {
+                log::error!("Failed to read spilled entry {}: {}", String::from_utf8_lossy(key), e);
+                None
+            }
+        });
+        Box::new(hot.chain(spilled))
+    }
+
+    fn memory_bytes(&self) -> Option<usize> {
+        Some(self.hot_bytes)
+    }
+}
+
+/// An append-only file of bincode-encoded entries, indexed in memory by key.
+///
+/// Overwritten and removed entries leave garbage behind, which is reclaimed by rewriting the file
+/// once it makes up most of it.
+#[derive(Debug)]
+struct Overflow {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: HashMap<Vec<u8>, (u64, u64)>,
+    len: u64,
+    garbage: u64,
+}
+
+impl Overflow {
+    fn create(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        Ok(Self { path: path.to_owned(), file: Mutex::new(file), index: HashMap::new(), len: 0, garbage: 0 })
+    }
+
+    fn read(&self, key: &[u8]) -> io::Result<Option<Entry>> {
+        let Some(&(offset, len)) = self.index.get(key) else {
+            return Ok(None);
+        };
+        let mut data = vec![0; len as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        bincode::deserialize(&data).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write(&mut self, key: Vec<u8>, entry: &Entry) -> io::Result<()> {
+        let data = bincode::serialize(entry).map_err(io::Error::other)?;
+        {
+            let file = self.file.get_mut().unwrap();
+            file.seek(SeekFrom::Start(self.len))?;
+            file.write_all(&data)?;
+        }
+        if let Some((_, previous)) = self.index.insert(key, (self.len, data.len() as u64)) {
+            self.garbage += previous;
+        }
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, len)) = self.index.remove(key) {
+            self.garbage += len;
+        }
+    }
+
+    /// Rewrites the file without garbage once garbage is more than half of it.
+    fn compact_if_sparse(&mut self) -> io::Result<()> {
+        if self.garbage * 2 <= self.len {
+            return Ok(());
+        }
+        let mut live = Vec::with_capacity(self.index.len());
+        for key in self.index.keys() {
+            if let Some(entry) = self.read(key)? {
+                live.push((key.clone(), entry));
+            }
+        }
+        *self = Self::create(&self.path)?;
+        for (key, entry) in live {
+            self.write(key, &entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_engine() {
+        let mut engine = SpillEngine::open(Path::new("logs/test_spill_engine.spill"), 1024).unwrap();
+        for i in 0..100 {
+            engine.put(format!("key{}", i).into_bytes(), Entry::new(vec![b'x'; 32]));
+        }
+        assert!(engine.hot_bytes <= 1024);
+        assert!(engine.spilled_len() > 0);
+        assert_eq!(engine.hot.len() + engine.spilled_len(), 100);
+
+        // Spilled entries are read back transparently and can be overwritten or deleted.
+        assert_eq!(engine.get(b"key0").map(|entry| entry.value.to_vec()), Some(vec![b'x'; 32]));
+        engine.put(b"key0".to_vec(), Entry::new(b"new".to_vec()));
+        assert_eq!(engine.get(b"key0").map(|entry| entry.value.to_vec()), Some(b"new".to_vec()));
+        assert!(engine.delete(b"key1").is_some());
+        assert!(engine.get(b"key1").is_none());
+        assert_eq!(engine.scan().count(), 99);
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+use crate::transaction_log::LogMetrics;
+
+/// A point-in-time summary of a single partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionStats {
+    /// The index of the partition, as used for routing keys.
+    pub index: usize,
+    /// The number of live keys stored in the partition.
+    pub keys: usize,
+    /// The total size of the stored (possibly compressed) values, in bytes.
+    pub compressed_bytes: u64,
+    /// The total size the values would have once decompressed, in bytes.
+    pub uncompressed_bytes: u64,
+    /// About how much memory the partition's copies hold entries in, in bytes, counting each
+    /// entry's key, stored value and a fixed overhead. Copies stored on disk count only what they
+    /// keep in memory, and poisoned replicas aren't counted.
+    pub memory_bytes: u64,
+    /// The number of replicas holding a copy of the partition, including the primary.
+    pub replicas: usize,
+    /// How far each replica other than the primary is behind it.
+    pub replica_lag: Vec<ReplicaLag>,
+}
+
+/// How far a replica is behind its partition's primary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicaLag {
+    /// The replica's index among the partition's copies; the primary is 0.
+    pub replica: usize,
+    /// The number of the primary's writes the replica hasn't applied.
+    pub records: u64,
+    /// How long ago the oldest write the replica hasn't applied was made.
+    pub time: Duration,
+    /// Whether the replica is up and within the server's lag thresholds, so it serves reads.
+    pub healthy: bool,
+}
+
+/// A point-in-time summary of every partition of a StorageServer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStats {
+    pub partitions: Vec<PartitionStats>,
+    /// The transaction log's counters, if the server has one.
+    pub log: Option<LogMetrics>,
+    /// The read cache's counters, if the server has one.
+    pub cache: Option<CacheMetrics>,
+}
+
+/// Counters describing how well a server's read cache of decompressed values is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheMetrics {
+    /// How many reads of a compressed value were served from the cache.
+    pub hits: u64,
+    /// How many reads of a compressed value had to decompress it.
+    pub misses: u64,
+    /// How many values are cached.
+    pub values: usize,
+    /// The memory counted for the cached keys and values, in bytes.
+    pub bytes: u64,
+}
+
+impl CacheMetrics {
+    /// Returns the share of reads served from the cache, or 0 before any reads.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+impl ServerStats {
+    /// Returns the number of live keys across all partitions.
+    pub fn total_keys(&self) -> usize {
+        self.partitions.iter().map(|partition| partition.keys).sum()
+    }
+
+    /// Returns the total stored size of all values, in bytes.
+    pub fn total_compressed_bytes(&self) -> u64 {
+        self.partitions.iter().map(|partition| partition.compressed_bytes).sum()
+    }
+
+    /// Returns the total decompressed size of all values, in bytes.
+    pub fn total_uncompressed_bytes(&self) -> u64 {
+        self.partitions.iter().map(|partition| partition.uncompressed_bytes).sum()
+    }
+
+    /// Returns the memory counted for every partition and replica, in bytes.
+    pub fn total_memory_bytes(&self) -> u64 {
+        self.partitions.iter().map(|partition| partition.memory_bytes).sum()
+    }
+}
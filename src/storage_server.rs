@@ -0,0 +1,4511 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::RangeBounds;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::anti_entropy::{self, AntiEntropy};
+use crate::backend::{Backend, MapVersion, PartitionData};
+use crate::bulk::{self, DumpFormat};
+use crate::checkpoint::{CheckpointReport, Checkpointer};
+use crate::cluster::RemoteNode;
+use crate::coalescer::{WriteCoalescer, WRITE_QUEUES};
+use crate::codec::{Codec, Codecs, Snappy, NO_COMPRESSION_ID};
+use crate::encoding::Encoding;
+use crate::eviction::{EvictionPolicy, EvictionTracker, MemoryLimit, MemoryPolicy};
+use crate::engine::StorageEngine;
+use crate::entry::{Entry, ValueMeta};
+use crate::error::FlowDbError;
+use crate::key_locks::{KeyLocks, KEY_LOCK_STRIPES};
+use crate::leadership::{HealthChecker, Leader, Leadership};
+use crate::lsm::{CompactionStats, LsmOptions, LsmTree};
+use crate::mvcc::{History, Pins, SnapshotView};
+use crate::namespace::NamespaceOptions;
+use crate::persistence;
+use crate::placement::{NodeLabels, Placement, PlacementPolicy};
+use crate::read_cache::ReadCache;
+use crate::read_repair::{ReadRepairer, Repair};
+use crate::rebalance::{self, Entered, MigrationJournal, Routing, Topology};
+use crate::replication::{self, HintStore, HintedHandoff, Consistency, ReadPreference, ReplicaOp, Replication, ReplicationMode, ReplicationReport, Replicator, Stamp};
+use crate::ring::{HashRing, Partitioner, DEFAULT_VIRTUAL_NODES};
+use crate::scan::{self, Cursor, Page, RangeScan, Scan};
+use crate::shared_log::SharedTransactionLog;
+use crate::shipping::LogFollower;
+use crate::spill::SpillEngine;
+use crate::stats::{PartitionStats, ReplicaLag, ServerStats};
+use crate::transaction_log::{BackgroundLog, LogRecord, Lsn, TransactionLog, WriteHandle};
+use crate::ttl::TtlSweeper;
+use crate::txn::Txn;
+use crate::watch::{ChangeEvent, Watchers};
+
+/// The number of records `import` commits together as one transaction.
+const IMPORT_BATCH_SIZE: usize = 10_000;
+/// The size in bytes below which values are stored uncompressed, by default.
+pub(crate) const MIN_COMPRESS_SIZE: usize = 64;
+
+/// A partitioned, replicated in-memory key-value store.
+///
+/// A thread that panics while holding a partition's lock poisons it, since the partition may be
+/// half-updated, but requests never panic because of one. Reads and writes of a partition whose
+/// primary is poisoned fail with `LockPoisoned` until `check_health` promotes one of its replicas,
+/// and poisoned replicas are passed over by reads and treated as unavailable by replication.
+/// Upkeep that counts, prunes or moves keys, such as `len`, `stats`, expiry sweeps and resharding,
+/// goes ahead on poisoned partitions.
+///
+/// Writes to a key also hold its key lock, one of a fixed set of stripes shared by unrelated keys.
+/// Read-modify-write operations such as `append`, `update` and `compare_and_swap` read, compute and
+/// encode under the key lock alone, so writes to other keys of the partition proceed meanwhile.
+/// Every write is logged under its key locks too, and takes the partition's write lock only to
+/// store its result, so reads wait for in-memory updates but never for the transaction log.
+///
+/// Concurrent puts to the same partition are applied in batches: one of them logs the whole batch
+/// as a single commit and stores it under one acquisition of the partition's write lock, while
+/// the others wait for their results.
+pub struct StorageServer {
+    routing: Arc<Routing>,
+    /// The backend new partitions store their entries in, unless they use an engine.
+    backend: Option<Backend>,
+    /// Held while the server is rebalanced, so rebalances take turns.
+    rebalancing: Mutex<()>,
+    /// Held by every write for the keys it writes, outside the partition lock.
+    pub(crate) key_locks: KeyLocks,
+    /// Batches concurrent puts by partition.
+    puts: WriteCoalescer<QueuedPut, PutResult>,
+    replicas: usize,
+    encoding: Encoding,
+    /// The codec new values are compressed with, and those values already stored may be in.
+    codecs: Codecs,
+    /// Where the codecs keep what they need to read stored values, such as trained dictionaries,
+    /// if the server's values outlive it.
+    codec_dir: Option<PathBuf>,
+    /// The size below which values are stored uncompressed whatever the codec.
+    min_compress_size: usize,
+    default_ttl: Option<Duration>,
+    namespaces: RwLock<HashMap<String, Arc<StorageServer>>>,
+    log: Option<LogSink>,
+    /// Whether records are logged with the time they were made.
+    log_write_times: bool,
+    watchers: Watchers,
+    version: AtomicU64,
+    merge_operator: Option<MergeFn>,
+    data_dir: Option<PathBuf>,
+    eviction: Option<Vec<EvictionTracker>>,
+    memory_limit: Option<MemoryLimit>,
+    read_cache: Option<ReadCache>,
+    tombstone_grace: Option<Duration>,
+    pins: Pins,
+    replication_mode: ReplicationMode,
+    replicator: Option<Replicator>,
+    read_preference: ReadPreference,
+    max_staleness: Option<Duration>,
+    max_lag_records: Option<u64>,
+    /// How many copies of a partition `get` and `get_with_meta` compare.
+    read_quorum: usize,
+    read_repairer: OnceLock<ReadRepairer>,
+    next_read: AtomicUsize,
+    hints: Option<Arc<HintStore>>,
+    /// The nodes owning the partitions this server doesn't hold itself, by partition index.
+    remotes: HashMap<usize, Arc<RemoteNode>>,
+    /// The labeled nodes the copies of each partition are placed on, if any.
+    pub(crate) placement: Option<RwLock<Placement>>,
+    /// Set by `shutdown`, after which writes are refused.
+    shutting_down: AtomicBool,
+}
+
+/// Where a server writes its transaction log records.
+enum LogSink {
+    /// Written and flushed before the mutation is applied, under the key locks of the keys it writes.
+    Direct(Arc<Mutex<TransactionLog>>),
+    /// Queued under the key locks of the keys it writes, in order, and written by the log's own thread.
+    Background(Arc<BackgroundLog>),
+    /// Written to one of several shards, under only that shard's lock.
+    Shared(SharedTransactionLog),
+}
+
+/// A put waiting to be applied in a batch.
+struct QueuedPut {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    entry: Entry,
+}
+
+/// The commit LSN of a put and the copies it reached.
+type PutResult = Result<(Option<Lsn>, ReplicationReport), FlowDbError>;
+
+/// Combines a key's existing value (None if missing) with a merge operand into its new value.
+pub type MergeFn = Box<dyn Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync>;
+
+impl Drop for StorageServer {
+    fn drop(&mut self) {
+        // Finish updating replicas first, so flushing writes them in full.
+        drop(self.replicator.take());
+        drop(self.read_repairer.take());
+        let _ = self.flush();
+        for partition in &self.topology().partitions {
+            release_replicas(partition);
+        }
+    }
+}
+
+/// Breaks the cycle between the partition and its replicas, each of which holds every copy
+/// including itself, so they can be freed.
+pub(crate) fn release_replicas(partition: &Arc<RwLock<Partition>>) {
+    let replicas = std::mem::take(&mut partition.write().unwrap_or_else(PoisonError::into_inner).replicas);
+    for replica in replicas.iter().skip(1) {
+        replica.write().unwrap_or_else(PoisonError::into_inner).replicas.clear();
+    }
+}
+
+/// Creates the copies of a partition, the primary first, each holding every copy, with the data
+/// built by `data(replica)`.
+fn new_copies(num_replicas: usize, mut data: impl FnMut(usize) -> Result<PartitionData, FlowDbError>) -> Result<Vec<Arc<RwLock<Partition>>>, FlowDbError> {
+    let mut replicas = Vec::with_capacity(num_replicas);
+    let written = Arc::new(AtomicU64::new(0));
+    for replica_index in 0..num_replicas {
+        replicas.push(Arc::new(RwLock::new(Partition {
+            data: data(replica_index)?,
+            replicas: Vec::with_capacity(num_replicas),
+            history: History::default(),
+            replication: Replication::default(),
+            down: false,
+            migration: None,
+            written: Arc::clone(&written),
+            applied: 0,
+            missed: 0,
+            missed_since: None,
+            checkpointed: AtomicU64::new(u64::MAX),
+        })));
+    }
+    for replica in replicas.iter() {
+        let mut replica_guard = match replica.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        replica_guard.replicas = replicas.clone();
+    }
+    Ok(replicas)
+}
+
+#[derive(Debug)]
+pub(crate) struct Partition {
+    pub(crate) data: PartitionData,
+    pub(crate) replicas: Vec<Arc<RwLock<Partition>>>,
+    /// Earlier states of overwritten keys, kept on the primary while snapshot views need them.
+    pub(crate) history: History,
+    /// How the primary's writes reach its replicas.
+    pub(crate) replication: Replication,
+    /// Whether this replica was marked down, so it takes no writes or reads.
+    pub(crate) down: bool,
+    /// The journal of a rebalance moving keys out of this primary, with the partition's index.
+    pub(crate) migration: Option<(usize, Arc<MigrationJournal>)>,
+    /// How many writes the partition's primary has replicated, shared by every copy.
+    pub(crate) written: Arc<AtomicU64>,
+    /// The sequence number of the latest of the primary's writes that reached this replica,
+    /// whether it applied the write or missed it.
+    pub(crate) applied: u64,
+    /// How many of the writes that reached this replica it missed and hasn't recovered since.
+    pub(crate) missed: u64,
+    /// When the oldest write this replica missed was made, until it recovers every missed write.
+    pub(crate) missed_since: Option<Instant>,
+    /// The primary's `written` count when it was last checkpointed, or u64::MAX if it never was.
+    pub(crate) checkpointed: AtomicU64,
+}
+
+impl StorageServer {
+    /// Creates a new storage server with the given number of partitions, each replicated `num_replicas` times.
+    pub fn new(num_partitions: usize, num_replicas: usize) -> Self {
+        Self::with_backend(num_partitions, num_replicas, Backend::default())
+    }
+
+    /// Creates a new storage server whose partitions store their entries using the given backend.
+    pub fn with_backend(num_partitions: usize, num_replicas: usize, backend: Backend) -> Self {
+        let mut server = Self::with_partition_data(num_partitions, num_replicas, |_, _| Ok(PartitionData::new(backend))).unwrap();
+        server.backend = Some(backend);
+        server
+    }
+
+    /// Creates a new storage server whose partitions store their entries in LSM trees under the given directory.
+    ///
+    /// Each partition and replica gets its own tree in `partition-<index>/replica-<index>`, holding
+    /// at most `LsmOptions::memtable_size` bytes of recent writes in memory before they are flushed to
+    /// sorted SSTable files in the background. Data left in the directory by a previous server is reopened;
+    /// writes that were still in memory when the process died are lost unless a transaction log is
+    /// replayed with `recover`.
+    pub fn with_lsm(path: impl AsRef<Path>, num_partitions: usize, num_replicas: usize, options: LsmOptions) -> Result<Self, FlowDbError> {
+        let path = path.as_ref();
+        let mut server = Self::with_partition_data(num_partitions, num_replicas, |partition, replica| {
+            let dir = path.join(format!("partition-{}", partition)).join(format!("replica-{}", replica));
+            LsmTree::open(&dir, options).map(|tree| PartitionData::with_engine(Box::new(tree))).map_err(FlowDbError::from)
+        })?;
+        server.store_codecs_in(path.to_owned())?;
+        Ok(server)
+    }
+
+    /// Creates a new storage server that holds at most `budget` bytes of entries in memory, spilling
+    /// the least recently used ones to overflow files in the given directory beyond that.
+    ///
+    /// The budget is split evenly across every partition and replica, each of which spills to its own
+    /// `partition-<index>-replica-<index>.spill` file. Spilled entries are read back transparently
+    /// on access. Overflow files are scratch space and are cleared when the server is created.
+    pub fn with_memory_budget(path: impl AsRef<Path>, num_partitions: usize, num_replicas: usize, budget: usize) -> Result<Self, FlowDbError> {
+        let path = path.as_ref();
+        let engine_budget = budget / (num_partitions * num_replicas).max(1);
+        Self::with_partition_data(num_partitions, num_replicas, |partition, replica| {
+            let file = path.join(format!("partition-{}-replica-{}.spill", partition, replica));
+            SpillEngine::open(&file, engine_budget).map(|engine| PartitionData::with_engine(Box::new(engine))).map_err(FlowDbError::from)
+        })
+    }
+
+    /// Creates a new storage server whose partitions and replicas each store their entries in the
+    /// engine returned by `engine(partition_index, replica_index)`.
+    pub fn with_engine(num_partitions: usize, num_replicas: usize, mut engine: impl FnMut(usize, usize) -> Box<dyn StorageEngine>) -> Self {
+        Self::with_partition_data(num_partitions, num_replicas, |partition, replica| {
+            Ok(PartitionData::with_engine(engine(partition, replica)))
+        })
+        .unwrap()
+    }
+
+    /// Creates a new storage server whose partition and replica data is built by `data(partition, replica)`.
+    fn with_partition_data(
+        num_partitions: usize,
+        num_replicas: usize,
+        mut data: impl FnMut(usize, usize) -> Result<PartitionData, FlowDbError>,
+    ) -> Result<Self, FlowDbError> {
+        let mut copies = Vec::with_capacity(num_partitions);
+        for partition_index in 0..num_partitions {
+            copies.push(new_copies(num_replicas, |replica_index| data(partition_index, replica_index))?);
+        }
+        let topology = Topology::new(HashRing::new(num_partitions, DEFAULT_VIRTUAL_NODES), Leadership::new(copies));
+        Ok(Self {
+            routing: Arc::new(Routing::new(topology)),
+            backend: None,
+            rebalancing: Mutex::new(()),
+            key_locks: KeyLocks::new(KEY_LOCK_STRIPES),
+            puts: WriteCoalescer::new(WRITE_QUEUES),
+            replicas: num_replicas,
+            encoding: Encoding::default(),
+            codecs: Codecs::new(Arc::new(Snappy)),
+            codec_dir: None,
+            min_compress_size: MIN_COMPRESS_SIZE,
+            default_ttl: None,
+            namespaces: RwLock::new(HashMap::new()),
+            log: None,
+            log_write_times: false,
+            watchers: Watchers::default(),
+            version: AtomicU64::new(0),
+            merge_operator: None,
+            data_dir: None,
+            eviction: None,
+            memory_limit: None,
+            read_cache: None,
+            tombstone_grace: None,
+            pins: Pins::default(),
+            replication_mode: ReplicationMode::default(),
+            replicator: None,
+            read_preference: ReadPreference::default(),
+            max_staleness: None,
+            max_lag_records: None,
+            read_quorum: 1,
+            read_repairer: OnceLock::new(),
+            next_read: AtomicUsize::new(0),
+            hints: None,
+            remotes: HashMap::new(),
+            placement: None,
+            shutting_down: AtomicBool::new(false),
+        })
+    }
+
+    /// Changes the number of partitions to `num_partitions` while the server keeps serving reads
+    /// and writes, returning how many keys moved to another partition.
+    ///
+    /// Only the keys the new ring routes elsewhere move, about the share owned by the partitions
+    /// added or removed. They are copied while their old partitions keep serving them, and writes
+    /// made to them meanwhile reach both copies. Routing then switches to the new partitions at
+    /// once, after a pause that waits for operations in flight and copies the writes made since.
+    /// Only servers created with `new` or `with_backend` can be rebalanced, and not while they cap
+    /// partition sizes, have remote partitions, or a snapshot view is alive. Iterators created
+    /// before the switch keep scanning the old partitions.
+    pub fn rebalance(&self, num_partitions: usize) -> Result<usize, FlowDbError> {
+        if num_partitions == 0 {
+            return Err(FlowDbError::InvalidArgument);
+        }
+        self.reshape(|ring| Ok(HashRing::with_partitioner(num_partitions, ring.virtual_nodes(), ring.partitioner())))
+    }
+
+    /// Splits the partition in two while the server keeps serving, like `rebalance`, returning how
+    /// many keys moved.
+    ///
+    /// A new partition, numbered after the others, takes over half of the partition's ring tokens
+    /// and so about half of its keys, while every other partition keeps its keys. Fails if the
+    /// partition has fewer than two tokens.
+    pub fn split_partition(&self, index: usize) -> Result<usize, FlowDbError> {
+        self.reshape(|ring| match ring.tokens().iter().filter(|&&(_, owner)| owner == index).count() {
+            0 | 1 => Err(FlowDbError::InvalidArgument),
+            _ => Ok(ring.split(index)),
+        })
+    }
+
+    /// Merges partition `from` into partition `into` while the server keeps serving, like
+    /// `rebalance`, returning how many keys moved.
+    ///
+    /// The keys of `from` move to `into`, and the last partition is renumbered to take the place of
+    /// `from`, which moves its keys too unless it is one of the two.
+    pub fn merge_partitions(&self, from: usize, into: usize) -> Result<usize, FlowDbError> {
+        self.reshape(|ring| match from != into && from.max(into) < ring.partitions() {
+            true => Ok(ring.merge(from, into)),
+            false => Err(FlowDbError::InvalidArgument),
+        })
+    }
+
+    /// Moves the server's keys to the partitions of the ring `ring` builds from the current one.
+    fn reshape(&self, ring: impl FnOnce(&HashRing) -> Result<HashRing, FlowDbError>) -> Result<usize, FlowDbError> {
+        let backend = self.backend.ok_or(FlowDbError::Unsupported)?;
+        if self.eviction.is_some() || self.memory_limit.is_some() || self.pins.any() || !self.remotes.is_empty() {
+            return Err(FlowDbError::Unsupported);
+        }
+        let _rebalancing = self.rebalancing.lock().unwrap_or_else(PoisonError::into_inner);
+        self.check_accepting_writes()?;
+        let ring = ring(&self.topology().ring)?;
+        if let Some(replicator) = &self.replicator {
+            replicator.queue().grow(ring.partitions());
+        }
+        let num_partitions = ring.partitions();
+        let moved = rebalance::rebalance(&self.routing, ring, |index| {
+            let copies = new_copies(self.replicas, |_| Ok(PartitionData::new(backend))).unwrap();
+            copies[0].write().unwrap_or_else(PoisonError::into_inner).replication = Replication {
+                sync_copies: self.replication_mode.sync_copies(),
+                queue: self.replicator.as_ref().map(|replicator| (index, Arc::clone(replicator.queue()))),
+                hints: self.hints.clone(),
+            };
+            copies
+        });
+        if let Some(placement) = &self.placement {
+            placement.write().unwrap_or_else(PoisonError::into_inner).place(num_partitions, self.replicas);
+        }
+        Ok(moved)
+    }
+
+    /// Opens a storage server whose partition data is persisted in the given directory.
+    ///
+    /// Data flushed by a previous server is reloaded, even if it used a different number of
+    /// partitions. The data is written back by `flush`, `checkpoint` and whenever the server is dropped. Namespaces
+    /// are not persisted.
+    pub fn open(path: impl AsRef<Path>, num_partitions: usize, num_replicas: usize) -> Result<Self, FlowDbError> {
+        Self::new(num_partitions, num_replicas).with_data_dir(path)
+    }
+
+    /// Reloads the data flushed to the directory and persists the partitions there from now on, as
+    /// `open` does.
+    pub(crate) fn with_data_dir(mut self, path: impl AsRef<Path>) -> Result<Self, FlowDbError> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        self.store_codecs_in(path.to_owned())?;
+        let mut max_version = 0;
+        for file in persistence::partition_files(path)? {
+            for (key, entry) in persistence::load_partition(&file)? {
+                max_version = max_version.max(entry.meta.version);
+                self.get_partition(&key).write()?.store(&key, entry);
+            }
+        }
+        self.version = AtomicU64::new(max_version);
+        self.data_dir = Some(path.to_owned());
+        Ok(self)
+    }
+
+    /// Writes the data of every partition to disk.
+    ///
+    /// Servers created with `with_lsm` write their memtables to SSTables; servers created with `open`
+    /// write each partition to the data directory and then record a checkpoint, as `checkpoint`
+    /// does, so that `recover` doesn't replay what the files already hold. Every key lock is held
+    /// meanwhile. Does nothing for purely in-memory servers.
+    pub fn flush(&self) -> Result<(), FlowDbError> {
+        let _routing = self.enter();
+        let _key_locks = self.key_locks.lock_every();
+        self.flush_locked()
+    }
+
+    /// Flushes like `flush`, with every key lock already held.
+    fn flush_locked(&self) -> Result<(), FlowDbError> {
+        let topology = self.topology();
+        for partition in &topology.partitions {
+            for replica in partition.read()?.replicas.iter() {
+                // Index 0 is the partition itself, which is already locked.
+                if Arc::ptr_eq(replica, partition) {
+                    continue;
+                }
+                // A poisoned replica may be half-updated, so it is not written out.
+                match replica.write() {
+                    Ok(mut replica) => replica.data.flush()?,
+                    Err(_) => log::warn!("Not flushing a replica whose lock is poisoned"),
+                }
+            }
+            partition.write()?.data.flush()?;
+        }
+        if let Some(dir) = &self.data_dir {
+            self.write_checkpoint(dir, true)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every partition changed since the last checkpoint to the data directory, then records
+    /// a checkpoint in the transaction log, so `recover` only replays the records logged after it.
+    ///
+    /// Every key lock is held and all partitions are read-locked for the duration, as with
+    /// `snapshot`, so the files hold exactly the writes logged before the checkpoint; reads go
+    /// ahead meanwhile but writes wait. The log is synced once the checkpoint is recorded. Fails
+    /// with `Unsupported` if the server has no data directory.
+    pub fn checkpoint(&self) -> Result<CheckpointReport, FlowDbError> {
+        let dir = self.data_dir.as_ref().ok_or(FlowDbError::Unsupported)?;
+        let _routing = self.enter();
+        let _key_locks = self.key_locks.lock_every();
+        self.write_checkpoint(dir, false)
+    }
+
+    /// Writes the partitions to the data directory, every one or only those changed since the last
+    /// checkpoint, and records a checkpoint in the transaction log. Every key lock must be held.
+    fn write_checkpoint(&self, dir: &Path, every: bool) -> Result<CheckpointReport, FlowDbError> {
+        let topology = self.topology();
+        let guards: Vec<_> = topology.partitions.iter().map(|partition| partition.read()).collect::<Result<_, _>>()?;
+        let mut partitions = 0;
+        for (index, partition_guard) in guards.iter().enumerate() {
+            let written = partition_guard.written.load(Ordering::Acquire);
+            if !every && partition_guard.checkpointed.load(Ordering::Acquire) == written {
+                continue;
+            }
+            persistence::save_partition(&persistence::partition_path(dir, index), &partition_guard.data)?;
+            partition_guard.checkpointed.store(written, Ordering::Release);
+            partitions += 1;
+        }
+        persistence::remove_stale_partitions(dir, guards.len())?;
+
+        let record = LogRecord::Checkpoint { version: self.version.load(Ordering::SeqCst) };
+        let lsn = match &self.log {
+            Some(LogSink::Direct(log)) => {
+                let mut log = log.lock().map_err(|_| FlowDbError::LockPoisoned)?;
+                log.write_record(&record)?;
+                Some(log.sync()?)
+            }
+            Some(LogSink::Background(log)) => {
+                log.write_record(&record).and_then(WriteHandle::wait)?;
+                Some(log.sync()?)
+            }
+            Some(LogSink::Shared(log)) => {
+                let sequence = log.write_record(&record)?;
+                log.sync()?;
+                Some(sequence)
+            }
+            None => None,
+        };
+        Ok(CheckpointReport { partitions, lsn })
+    }
+
+    /// Starts a background thread that calls `checkpoint` every `interval` until the returned
+    /// checkpointer is stopped or dropped. The thread keeps the server alive until then.
+    pub fn start_checkpointer(self: &Arc<Self>, interval: Duration) -> Checkpointer {
+        Checkpointer::spawn(Arc::clone(self), interval)
+    }
+
+    /// Stops the server cleanly, so that every write it acknowledged survives it.
+    ///
+    /// Writes are refused with `ShuttingDown` from the start of the call, while writes already
+    /// under way and a rebalance in progress are waited for. Then queued replica updates and read
+    /// repairs are applied and their threads stopped, the transaction log is written out and synced
+    /// to disk whatever its sync policy, and the data is flushed like `flush`. The same is done for
+    /// every namespace. Reads keep being served, and calling it again only flushes again.
+    pub fn shutdown(&self) -> Result<(), FlowDbError> {
+        self.shutting_down.store(true, Ordering::Release);
+        drop(self.rebalancing.lock().unwrap_or_else(PoisonError::into_inner));
+        // Writes check for shutdown under their key locks and hold them until they are applied, so
+        // once every key lock is taken, the writes that got past the check have finished. Holding
+        // them until the data is flushed keeps any other write from being applied after the flush.
+        let _routing = self.enter();
+        let _key_locks = self.key_locks.lock_every();
+        if let Some(replicator) = &self.replicator {
+            replicator.stop();
+        }
+        if let Some(repairer) = self.read_repairer.get() {
+            repairer.stop();
+        }
+        match &self.log {
+            Some(LogSink::Direct(log)) => {
+                log.lock().map_err(|_| FlowDbError::LockPoisoned)?.sync()?;
+            }
+            Some(LogSink::Background(log)) => {
+                log.sync()?;
+            }
+            Some(LogSink::Shared(log)) => log.sync()?,
+            None => {}
+        }
+        self.flush_locked()?;
+        let namespaces: Vec<_> = self.namespaces.read().unwrap_or_else(PoisonError::into_inner).values().cloned().collect();
+        for namespace in namespaces {
+            namespace.shutdown()?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether `shutdown` has been called.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Acquire)
+    }
+
+    /// Fails with `ShuttingDown` once `shutdown` has been called. Writes check it under their key
+    /// locks, which is what lets `shutdown` wait for the ones that got past it.
+    pub(crate) fn check_accepting_writes(&self) -> Result<(), FlowDbError> {
+        match self.is_shutting_down() {
+            true => Err(FlowDbError::ShuttingDown),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns the compaction counters summed over every partition and replica, or None if the
+    /// server's engines do not compact.
+    pub fn compaction_stats(&self) -> Option<CompactionStats> {
+        let mut total = None;
+        for partition in &self.topology().partitions {
+            let partition_guard = partition.read().unwrap_or_else(PoisonError::into_inner);
+            let mut add = |stats: Option<CompactionStats>| {
+                if let Some(stats) = stats {
+                    total.get_or_insert_with(CompactionStats::default).add(stats);
+                }
+            };
+            add(partition_guard.data.compaction_stats());
+            for replica in partition_guard.replicas.iter().filter(|replica| !Arc::ptr_eq(replica, partition)) {
+                add(replica.read().unwrap_or_else(PoisonError::into_inner).data.compaction_stats());
+            }
+        }
+        total
+    }
+
+    /// Stops background compaction on every partition and replica until `resume_compaction` is called,
+    /// for example to keep disk I/O free during a bulk load. Compactions already running finish.
+    pub fn pause_compaction(&self) {
+        self.set_compaction_paused(true);
+    }
+
+    /// Resumes background compaction after `pause_compaction`.
+    pub fn resume_compaction(&self) {
+        self.set_compaction_paused(false);
+    }
+
+    fn set_compaction_paused(&self, paused: bool) {
+        for partition in &self.topology().partitions {
+            let partition_guard = partition.read().unwrap_or_else(PoisonError::into_inner);
+            partition_guard.data.set_compaction_paused(paused);
+            for replica in partition_guard.replicas.iter().filter(|replica| !Arc::ptr_eq(replica, partition)) {
+                replica.read().unwrap_or_else(PoisonError::into_inner).data.set_compaction_paused(paused);
+            }
+        }
+    }
+
+    /// Sets the serialization format used by `put_typed` and `get_typed`.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the size in bytes below which values are stored uncompressed, 64 by default. Snappy
+    /// makes tiny values bigger, so compressing them only costs CPU. Values already stored keep the
+    /// form they were stored in.
+    pub fn with_min_compress_size(mut self, bytes: usize) -> Self {
+        self.min_compress_size = bytes;
+        self
+    }
+
+    /// Sets the codec new values are compressed with, Snappy by default. Values already stored stay
+    /// readable as long as they are in a built-in codec or one the server was set to before, since
+    /// each entry records the codec it is in.
+    ///
+    /// On a server whose values outlive it, such as one created with `open` or `with_lsm`, a codec
+    /// that trains dictionaries keeps them in the server's directory and reloads those kept there.
+    /// If they can't be reloaded the error is logged; `StorageServerBuilder::build` fails instead.
+    pub fn with_codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        if let Err(e) = self.set_codec(codec) {
+            log::error!("Codec can't keep its dictionaries with the server: {}", e);
+        }
+        self
+    }
+
+    /// Sets the codec new values are compressed with, and the TTL of values put without one.
+    pub(crate) fn with_value_options(mut self, codec: Arc<dyn Codec>, default_ttl: Option<Duration>) -> Result<Self, FlowDbError> {
+        self.set_codec(codec)?;
+        self.default_ttl = default_ttl;
+        Ok(self)
+    }
+
+    fn set_codec(&mut self, codec: Arc<dyn Codec>) -> Result<(), FlowDbError> {
+        if let Some(dir) = &self.codec_dir {
+            codec.store_in(dir)?;
+        }
+        self.codecs.set(codec);
+        Ok(())
+    }
+
+    /// Keeps what the codecs need to read stored values in the directory from now on, reloading
+    /// what they kept there before any value is read.
+    fn store_codecs_in(&mut self, dir: PathBuf) -> Result<(), FlowDbError> {
+        self.codecs.store_in(&dir)?;
+        self.codec_dir = Some(dir);
+        Ok(())
+    }
+
+    /// Sets the transaction log that every mutation is written to before it is applied.
+    ///
+    /// Puts, deletes, merges, transactions, and collection commands are logged, along with when
+    /// keys with a TTL expire.
+    pub fn with_transaction_log(mut self, log: Arc<Mutex<TransactionLog>>) -> Self {
+        self.log = Some(LogSink::Direct(log));
+        self
+    }
+
+    /// Sets a transaction log written by a background thread, so writers no longer wait for file I/O
+    /// while holding the partition lock.
+    ///
+    /// Records are queued in the order mutations are applied. A mutation may be applied before its
+    /// record reaches the log; call `BackgroundLog::flush` to wait for every queued record, for
+    /// example before acknowledging a batch of writes.
+    pub fn with_background_log(mut self, log: Arc<BackgroundLog>) -> Self {
+        self.log = Some(LogSink::Background(log));
+        self
+    }
+
+    /// Sets a sharded transaction log, so writers to different keys log concurrently instead of
+    /// taking turns on one mutex. `recover` and `snapshot` handle it like a single log.
+    pub fn with_shared_log(mut self, log: SharedTransactionLog) -> Self {
+        self.log = Some(LogSink::Shared(log));
+        self
+    }
+
+    /// Logs every record with the time it was made, so a remote cluster following the log with a
+    /// `ClusterFollower` can order its writes against the ones made there.
+    pub fn with_logged_write_times(mut self) -> Self {
+        self.log_write_times = true;
+        self
+    }
+
+    /// Sets how many copies of a partition each write updates before it returns.
+    ///
+    /// With `ReplicationMode::Quorum(n)`, the primary and the first `n - 1` replicas are updated
+    /// synchronously and a background replicator brings the rest up to date, so reads of those
+    /// replicas may briefly miss recent writes. A quorum of at least the replica count is the same
+    /// as `Sync`, and a quorum of 0 is treated as 1. With `ReplicationMode::Async`, only the
+    /// primary is updated before a write returns, which keeps replication out of write latency.
+    pub fn with_replication_mode(mut self, mode: ReplicationMode) -> Self {
+        let sync_copies = mode.sync_copies();
+        let topology = self.topology();
+        if sync_copies < self.replicas && self.replicator.is_none() {
+            self.replicator = Some(Replicator::spawn(topology.partitions.len()));
+        }
+        for (index, partition) in topology.partitions.iter().enumerate() {
+            let queue = self.replicator.as_ref().map(|replicator| (index, Arc::clone(replicator.queue())));
+            let mut partition_guard = partition.write().unwrap_or_else(PoisonError::into_inner);
+            partition_guard.replication.sync_copies = sync_copies;
+            partition_guard.replication.queue = queue;
+        }
+        self.replication_mode = mode;
+        self
+    }
+
+    /// Returns how writes reach each partition's replicas.
+    pub fn replication_mode(&self) -> ReplicationMode {
+        self.replication_mode
+    }
+
+    /// Sets which copy of a partition serves `get`, `get_with_meta`, `contains_key` and `multi_get`.
+    ///
+    /// Reading from replicas spreads read-heavy workloads over every copy. With synchronous
+    /// replication every copy holds every write; with quorum or async replication, replicas can
+    /// lag behind the primary, within the bound set by `with_max_staleness`.
+    pub fn with_read_preference(mut self, preference: ReadPreference) -> Self {
+        self.read_preference = preference;
+        self
+    }
+
+    /// Sets how far behind the primary a replica may be and still serve reads. A replica whose
+    /// oldest unapplied write was made longer ago than this is reported unhealthy by `stats` and
+    /// passed over for the primary.
+    pub fn with_max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    /// Sets how many of the primary's writes a replica may be missing and still serve reads. A
+    /// replica further behind is reported unhealthy by `stats` and passed over for the primary.
+    pub fn with_max_lag_records(mut self, max_records: u64) -> Self {
+        self.max_lag_records = Some(max_records);
+        self
+    }
+
+    /// Sets how many copies of a partition `get` and `get_with_meta` read, 1 by default, which
+    /// reads the copy chosen by the read preference alone.
+    ///
+    /// A quorum read reads the primary and the first replicas that are up, and returns the newest
+    /// state of the key among them by version, so it sees every write that reached one of them. It
+    /// fails with `Unavailable` if fewer copies are up. Replicas found behind are brought up to
+    /// date in the background, counted by `read_repairs`. Without tombstones a deleted key leaves
+    /// no version behind, so the primary decides whether a key is deleted and replicas still
+    /// holding it are repaired by removing it.
+    pub fn with_read_quorum(mut self, copies: usize) -> Self {
+        self.read_quorum = copies.clamp(1, self.replicas);
+        self
+    }
+
+    /// Returns how many replicas quorum reads found behind and repaired.
+    pub fn read_repairs(&self) -> u64 {
+        self.read_repairer.get().map_or(0, ReadRepairer::repaired)
+    }
+
+    /// Sets how many points each partition has on the consistent-hash ring that routes keys, 128 by
+    /// default. Keys are routed by the ring they were written with, so this must be set before the
+    /// server holds any keys.
+    pub fn with_virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        let topology = self.topology();
+        let ring = HashRing::with_partitioner(topology.partitions.len(), virtual_nodes, topology.ring.partitioner());
+        self.routing = Arc::new(Routing::new(topology.with_ring(ring)));
+        self
+    }
+
+    /// Sets the hash function that routes keys to partitions, xxHash64 by default. Like
+    /// `with_virtual_nodes`, this must be set before the server holds any keys. LSM trees written
+    /// by servers that hashed keys with `DefaultHasher` must be reopened with `Partitioner::SipHash`.
+    pub fn with_partitioner(mut self, partitioner: Partitioner) -> Self {
+        let topology = self.topology();
+        let ring = HashRing::with_partitioner(topology.partitions.len(), topology.ring.virtual_nodes(), partitioner);
+        self.routing = Arc::new(Routing::new(topology.with_ring(ring)));
+        self
+    }
+
+    /// Marks the partition as owned by another node, so `get`, `put` and `delete` of its keys are
+    /// sent to that node, which serves them with `serve_node`. The node must use the same number
+    /// of partitions, virtual nodes and partitioner, so it routes the keys to the same partition.
+    ///
+    /// Other operations, such as scans, batches and transactions, only see the partitions held
+    /// locally.
+    pub fn with_remote_partition(mut self, partition: usize, node: Arc<RemoteNode>) -> Self {
+        self.remotes.insert(partition, node);
+        self
+    }
+
+    /// Places the copies of every partition on the labeled nodes, spreading each partition's copies
+    /// over distinct failure domains up to the policy's, as far as there are enough of them.
+    /// Partitions added by a rebalance are placed too.
+    ///
+    /// The copies stay in this process; the placement says which node each stands for, so
+    /// `mark_node_down` can take down every copy a failed node held. Does nothing without nodes.
+    pub fn with_placement(mut self, nodes: Vec<NodeLabels>, policy: PlacementPolicy) -> Self {
+        if !nodes.is_empty() {
+            self.placement = Some(RwLock::new(Placement::new(nodes, policy, self.partition_count(), self.replicas)));
+        }
+        self
+    }
+
+    /// Keeps writes that a replica can't take as hints for up to `ttl`, to hand them off once it
+    /// recovers, instead of leaving the replica diverged until anti-entropy repairs it.
+    pub fn with_hinted_handoff(mut self, ttl: Duration) -> Self {
+        let hints = Arc::new(HintStore::new(ttl));
+        for partition in &self.topology().partitions {
+            partition.write().unwrap_or_else(PoisonError::into_inner).replication.hints = Some(Arc::clone(&hints));
+        }
+        self.hints = Some(hints);
+        self
+    }
+
+    /// Marks a replica of the partition down, so it takes no writes and serves no reads until it is
+    /// marked up again. Writes it misses meanwhile are kept as hints if the server keeps them.
+    /// Fails for the partition's leader and for indexes out of range.
+    pub fn mark_replica_down(&self, partition: usize, replica: usize) -> Result<(), FlowDbError> {
+        let copy = self.replica_copy(partition, replica)?;
+        copy.write().unwrap_or_else(PoisonError::into_inner).down = true;
+        Ok(())
+    }
+
+    /// Marks a replica of the partition up, after it was marked down or its lock was poisoned, and
+    /// hands off the hints kept for it. Returns how many hints were replayed; writes whose hints
+    /// expired are left for `repair_replicas`.
+    pub fn mark_replica_up(&self, partition: usize, replica: usize) -> Result<usize, FlowDbError> {
+        let copy = self.replica_copy(partition, replica)?;
+        let mut copy_guard = copy.write().unwrap_or_else(PoisonError::into_inner);
+        copy.clear_poison();
+        copy_guard.down = false;
+        Ok(self.hints.as_ref().map_or(0, |hints| hints.replay(&copy, &mut copy_guard)))
+    }
+
+    fn replica_copy(&self, partition: usize, replica: usize) -> Result<Arc<RwLock<Partition>>, FlowDbError> {
+        match replica {
+            0 => Err(FlowDbError::InvalidArgument),
+            replica => self.topology().leadership.copies(partition).get(replica).cloned().ok_or(FlowDbError::InvalidArgument),
+        }
+    }
+
+    /// Returns how many hints are waiting to be handed off to replicas.
+    pub fn pending_hints(&self) -> usize {
+        self.hints.as_ref().map_or(0, |hints| hints.len())
+    }
+
+    /// Hands off the hints kept for replicas that can take writes again, and drops expired ones.
+    /// Returns how many hints were replayed.
+    pub fn hand_off_hints(&self) -> usize {
+        self.hints.as_ref().map_or(0, |hints| hints.hand_off())
+    }
+
+    /// Starts a background thread that calls `hand_off_hints` every `interval` until the returned
+    /// worker is stopped or dropped. Returns None if the server keeps no hints.
+    pub fn start_hinted_handoff(&self, interval: Duration) -> Option<HintedHandoff> {
+        self.hints.as_ref().map(|hints| HintedHandoff::spawn(Arc::clone(hints), interval))
+    }
+
+    /// Returns how many replica updates are waiting for the background replicator.
+    pub fn replication_backlog(&self) -> usize {
+        self.replicator.as_ref().map_or(0, |replicator| replicator.queue().len())
+    }
+
+    /// Blocks until every replica update queued so far has been applied, so every replica holds
+    /// every write made before the call.
+    pub fn wait_for_replication(&self) {
+        if let Some(replicator) = &self.replicator {
+            replicator.queue().wait_until_empty();
+        }
+    }
+
+    /// Registers the operator used by `merge` to combine operands with existing values.
+    pub fn with_merge_operator(mut self, operator: MergeFn) -> Self {
+        self.merge_operator = Some(operator);
+        self
+    }
+
+    /// Caps the size of each partition, evicting keys chosen by the policy once a write takes a
+    /// partition over `max_bytes_per_partition`.
+    ///
+    /// Sizes count the key and the stored (compressed) value. Evicted keys are removed from every
+    /// replica and reported to watchers as deletes, but are not written to the transaction log, which
+    /// suits cache-style deployments.
+    pub fn with_eviction(mut self, max_bytes_per_partition: usize, policy: EvictionPolicy) -> Self {
+        self.eviction = Some(self.topology().partitions.iter().map(|_| EvictionTracker::new(max_bytes_per_partition, policy)).collect());
+        self
+    }
+
+    /// Limits the memory taken by the server's partitions and their replicas to about `max_bytes`,
+    /// handling writes past it as the policy says: rejecting them with `Backpressure`, or evicting
+    /// keys of the written partition in the order of the server's eviction policy, least recently
+    /// used first unless `with_eviction` set another.
+    ///
+    /// Memory is counted as the key, the stored (compressed) value and a fixed overhead for every
+    /// entry a copy holds in memory, which `stats` reports per partition. Deletes are always applied.
+    /// Keys are evicted like with `with_eviction`, and only keys written since the limit was set
+    /// can be. Namespaces aren't counted, and the server can't be rebalanced.
+    pub fn with_memory_limit(mut self, max_bytes: usize, policy: MemoryPolicy) -> Self {
+        let limit = MemoryLimit::new(max_bytes, policy);
+        let topology = self.topology();
+        for index in 0..topology.partitions.len() {
+            for copy in topology.leadership.copies(index) {
+                copy.write().unwrap_or_else(PoisonError::into_inner).data.track_memory(Arc::clone(&limit.usage));
+            }
+        }
+        if policy == MemoryPolicy::Evict && self.eviction.is_none() {
+            self = self.with_eviction(usize::MAX, EvictionPolicy::Lru);
+        }
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Fails with `Backpressure` if the server rejects writes that store more while it is over
+    /// its memory limit, and is.
+    pub(crate) fn check_memory(&self) -> Result<(), FlowDbError> {
+        match &self.memory_limit {
+            Some(limit) if limit.policy == MemoryPolicy::Reject && limit.excess() > 0 => Err(FlowDbError::Backpressure),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keeps up to `max_bytes` of recently read values decompressed, so repeated reads of a hot key
+    /// skip decompression.
+    ///
+    /// Values are cached with the version they were read at and only served for it, and writes and
+    /// deletes drop the key's cached value. Values stored uncompressed are already read without
+    /// decoding, so they aren't cached. Hits and misses are counted in `stats`.
+    pub fn with_read_cache(mut self, max_bytes: usize) -> Self {
+        self.read_cache = Some(ReadCache::new(max_bytes));
+        self
+    }
+
+    /// Makes deletes leave a tombstone on the partition and its replicas instead of removing the key.
+    ///
+    /// A tombstone carries the version of the delete, so replicas and replayed writes can tell a key
+    /// that was deleted from one that was never written. Tombstones read as missing and are purged by
+    /// `sweep_expired` and the TTL sweeper once `grace` has elapsed.
+    pub fn with_tombstones(mut self, grace: Duration) -> Self {
+        self.tombstone_grace = Some(grace);
+        self
+    }
+
+    /// Returns the eviction tracker of the key's partition, if the server caps partition sizes.
+    fn eviction_tracker(&self, key: &[u8]) -> Option<&EvictionTracker> {
+        self.eviction.as_ref().map(|trackers| &trackers[self.partition_index(key)])
+    }
+
+    /// Records a read of the key for LRU eviction.
+    fn record_read(&self, key: &[u8]) {
+        if let Some(tracker) = self.eviction_tracker(key) {
+            tracker.record_read(key);
+        }
+    }
+
+    /// Records a write of the entry for eviction, then evicts keys from the partition until it is back under its cap.
+    fn record_write(&self, partition: &mut Partition, key: &[u8], entry: &Entry) {
+        let Some(tracker) = self.eviction_tracker(key) else {
+            return;
+        };
+        for victim in tracker.record_write(key, key.len() + entry.footprint()) {
+            self.evict(partition, victim);
+        }
+    }
+
+    /// Evicts keys of the partition, other than the key just stored, until the memory they are
+    /// estimated to free on every copy brings the server back under its memory limit, if it evicts
+    /// to stay under one. The estimate doesn't wait for replicas updated in the background.
+    fn enforce_memory_limit(&self, partition: &mut Partition, key: &[u8]) {
+        let (Some(limit), Some(tracker)) = (&self.memory_limit, self.eviction_tracker(key)) else {
+            return;
+        };
+        if limit.policy != MemoryPolicy::Evict {
+            return;
+        }
+        let mut excess = limit.excess();
+        while excess > 0 {
+            let Some((victim, size)) = tracker.pop_oldest(key) else { break };
+            excess = excess.saturating_sub(size * partition.replicas.len().max(1));
+            self.evict(partition, victim);
+        }
+    }
+
+    /// Removes the key from the partition and its replicas without logging it, reporting it to
+    /// watchers as deleted.
+    fn evict(&self, partition: &mut Partition, victim: Vec<u8>) {
+        self.invalidate_cached(&victim);
+        if partition.remove(&victim).is_some() && self.watchers.is_watched(&victim) {
+            self.watchers.notify(ChangeEvent::Delete { key: victim });
+        }
+    }
+
+    /// Writes the record to the transaction log, if the server has one, returning its LSN.
+    ///
+    /// A background log's LSNs aren't known until the writer thread gets to the record, so there is
+    /// none to return; `BackgroundLog::flush` returns the LSN past every record queued.
+    ///
+    /// Fails with `ShuttingDown` once the server is shutting down, so every write that logs goes
+    /// through here first.
+    pub(crate) fn log_record(&self, record: &LogRecord) -> Result<Option<Lsn>, FlowDbError> {
+        self.check_accepting_writes()?;
+        if stores_more(record) {
+            self.check_memory()?;
+        }
+        if self.log_write_times && self.log.is_some() {
+            return self.log_untimed(&LogRecord::Timed { at: SystemTime::now(), record: Box::new(record.clone()) });
+        }
+        self.log_untimed(record)
+    }
+
+    /// A commit goes to a single log as a transaction bracketed by `BeginTxn` and `CommitTxn`; a
+    /// shared log keeps it as one record, since it shards and sequences records one at a time.
+    fn log_untimed(&self, record: &LogRecord) -> Result<Option<Lsn>, FlowDbError> {
+        match (&self.log, record) {
+            (Some(LogSink::Direct(log)), LogRecord::Commit { records }) => {
+                Ok(Some(log.lock().map_err(|_| FlowDbError::LockPoisoned)?.write_transaction(records)?))
+            }
+            (Some(LogSink::Direct(log)), record) => Ok(Some(log.lock().map_err(|_| FlowDbError::LockPoisoned)?.write_record(record)?)),
+            // The writer thread reports failures; the caller doesn't wait for the write.
+            (Some(LogSink::Background(log)), LogRecord::Commit { records }) => {
+                log.write_transaction(records).map(|_| None).map_err(FlowDbError::from)
+            }
+            (Some(LogSink::Background(log)), record) => log.write_record(record).map(|_| None).map_err(FlowDbError::from),
+            (Some(LogSink::Shared(log)), record) => log.write_record(record).map(Some).map_err(FlowDbError::from),
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// Logs a write to the partition before it is applied, under the key locks of the keys it
+    /// writes but not the partition lock, so reads of the partition don't wait for the log. Fails
+    /// without logging if the partition is poisoned, since the write couldn't then be applied.
+    pub(crate) fn log_write(&self, partition: &RwLock<Partition>, record: &LogRecord) -> Result<Option<Lsn>, FlowDbError> {
+        if partition.is_poisoned() {
+            return Err(FlowDbError::LockPoisoned);
+        }
+        self.log_record(record)
+    }
+
+    /// Writes a consistent snapshot of every partition's compressed values and metadata to a file.
+    ///
+    /// Every key lock is held and all partitions are read-locked for the duration, so the snapshot
+    /// reflects a single point in time and includes every write already logged. If the server has a
+    /// transaction log, it is truncated before the locks are released, since the snapshot now
+    /// covers every logged write; restarting then only needs `load_snapshot` followed by `recover`
+    /// for the writes made after the snapshot. Returns how many keys were written.
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<usize, FlowDbError> {
+        let _routing = self.enter();
+        let _key_locks = self.key_locks.lock_every();
+        let topology = self.topology();
+        let guards: Vec<_> = topology.partitions.iter().map(|partition| partition.read()).collect::<Result<_, _>>()?;
+        let count = persistence::save_snapshot(path.as_ref(), guards.iter().map(|guard| &guard.data))?;
+        match &self.log {
+            Some(LogSink::Direct(log)) => log.lock().map_err(|_| FlowDbError::LockPoisoned)?.truncate()?,
+            Some(LogSink::Background(log)) => {
+                log.truncate().and_then(WriteHandle::wait)?;
+            }
+            Some(LogSink::Shared(log)) => log.truncate()?,
+            None => {}
+        }
+        Ok(count)
+    }
+
+    /// Loads every key from a snapshot written by `snapshot`, replacing existing values of the same keys.
+    ///
+    /// Keys are routed by this server's partitioning, so the snapshot can come from a server with a
+    /// different number of partitions or compression setting. Loaded keys are not written to the
+    /// transaction log. Returns how many keys were loaded.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> Result<usize, FlowDbError> {
+        let mut poisoned = false;
+        let count = persistence::load_snapshot(path.as_ref(), |key, entry| {
+            self.version.fetch_max(entry.meta.version, Ordering::SeqCst);
+            let _routing = self.enter();
+            let _key_lock = self.key_locks.lock(&key);
+            let partition = self.get_partition(&key);
+            let Ok(mut partition_guard) = partition.write() else {
+                poisoned = true;
+                return;
+            };
+            self.notify_put(&key, &entry);
+            self.record_write(&mut partition_guard, &key, &entry);
+            partition_guard.store(&key, entry);
+        })
+        ?;
+        if poisoned {
+            return Err(FlowDbError::LockPoisoned);
+        }
+        Ok(count)
+    }
+
+    /// Rebuilds the server's contents by replaying the transaction log in the given directory, one
+    /// segment after another. The directory may hold a `TransactionLog` or a `SharedTransactionLog`.
+    ///
+    /// Meant to be called on startup before the server accepts traffic. Replayed records are not
+    /// written to the server's own transaction log. A missing log directory is treated as empty; a log
+    /// containing an invalid record is rejected before anything is applied. Returns how many
+    /// records were replayed, counting each commit as one and checkpoints as none.
+    ///
+    /// A server with a data directory only replays the records after the log's last checkpoint,
+    /// since its files already hold the ones before.
+    pub fn recover(&self, log_path: impl AsRef<Path>) -> Result<usize, FlowDbError> {
+        let mut records = match SharedTransactionLog::is_shared(&log_path) {
+            true => SharedTransactionLog::read_all(log_path, 8192),
+            false => TransactionLog::read_all(log_path, 8192),
+        }
+        ?;
+        let last_checkpoint = records.iter().rposition(|record| matches!(record, LogRecord::Checkpoint { .. }));
+        if let (Some(last_checkpoint), Some(_)) = (last_checkpoint, &self.data_dir) {
+            records.drain(..=last_checkpoint);
+        }
+        records.retain(|record| !matches!(record, LogRecord::Checkpoint { .. }));
+        for record in &records {
+            self.replay(record)?;
+        }
+        Ok(records.len())
+    }
+
+    /// Replays records from a log shipper as they arrive, keeping this server a warm standby of the
+    /// log's owner. Returns once the connection fails; reconnecting from the follower's position
+    /// carries on where it stopped.
+    pub fn follow<S: Read + Write>(&self, follower: &mut LogFollower<S>) -> Result<(), FlowDbError> {
+        loop {
+            let record = follower.next_record().map_err(|e| {
+                log::error!("Log shipping stopped at {:?}: {}", follower.position(), e);
+                FlowDbError::from(e)
+            })?;
+            self.replay(&record)?;
+        }
+    }
+
+    /// Applies a logged record to the partitions without logging it again.
+    pub(crate) fn replay(&self, record: &LogRecord) -> Result<(), FlowDbError> {
+        match record {
+            LogRecord::Put { key, value } => {
+                let _routing = self.enter();
+                let _key_lock = self.key_locks.lock(key);
+                let partition = self.get_partition(key);
+                let mut partition_guard = partition.write()?;
+                self.store_entry(&mut partition_guard, key, self.new_entry(value)?);
+            }
+            LogRecord::Delete { key } => {
+                let _routing = self.enter();
+                let _key_lock = self.key_locks.lock(key);
+                let partition = self.get_partition(key);
+                let mut partition_guard = partition.write()?;
+                self.remove_entry(&mut partition_guard, key);
+            }
+            LogRecord::Merge { key, operand } => {
+                let operator = self.merge_operator.as_ref().ok_or(FlowDbError::Unsupported)?;
+                self.read_modify_write(key, |existing| Ok((Some(operator(existing, operand)), None)))?;
+            }
+            LogRecord::Commit { records } => {
+                for record in records {
+                    self.replay(record)?;
+                }
+            }
+            LogRecord::Timed { record, .. } => self.replay(record)?,
+            // Reading a log folds a transaction's markers into a commit; alone they do nothing.
+            LogRecord::Checkpoint { .. } | LogRecord::BeginTxn | LogRecord::CommitTxn => {}
+            LogRecord::Expire { key, at } => {
+                let _routing = self.enter();
+                let _key_lock = self.key_locks.lock(key);
+                let partition = self.get_partition(key);
+                if let Some(mut entry) = self.live_entry(&partition, key)? {
+                    entry.expires_at = Some(*at);
+                    let mut partition_guard = partition.write()?;
+                    self.store_entry(&mut partition_guard, key, entry);
+                }
+            }
+            LogRecord::Collection { key, op } => {
+                self.modify_collection(key, op, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a channel that receives an event every time the key is put or deleted.
+    ///
+    /// Events are sent while the key's partition is locked, so they arrive in the order the writes
+    /// were applied. Expiry of a TTL is not reported. Dropping the receiver unsubscribes.
+    pub fn watch(&self, key: impl AsRef<[u8]>) -> Receiver<ChangeEvent> {
+        self.watchers.subscribe_key(key.as_ref())
+    }
+
+    /// Returns a channel that receives an event every time a key starting with the prefix is put or deleted.
+    pub fn watch_prefix(&self, prefix: impl AsRef<[u8]>) -> Receiver<ChangeEvent> {
+        self.watchers.subscribe_prefix(prefix.as_ref())
+    }
+
+    /// Stores the entry on the partition and its replicas, notifying any watchers of the key.
+    /// Returns which replicas the entry reached.
+    pub(crate) fn store_entry(&self, partition: &mut Partition, key: &[u8], entry: Entry) -> ReplicationReport {
+        self.store_entry_at(partition, key, entry, SystemTime::now())
+    }
+
+    /// Stores the entry like `store_entry`, as written at the given time.
+    pub(crate) fn store_entry_at(&self, partition: &mut Partition, key: &[u8], mut entry: Entry, at: SystemTime) -> ReplicationReport {
+        self.stamp(partition, key, &mut entry, at);
+        self.preserve(partition, key, entry.meta.version);
+        self.notify_put(key, &entry);
+        self.record_write(partition, key, &entry);
+        self.invalidate_cached(key);
+        let report = partition.store(key, entry);
+        self.enforce_memory_limit(partition, key);
+        report
+    }
+
+    /// Removes the key from the partition and its replicas, notifying any watchers if it existed.
+    ///
+    /// If the server keeps tombstones, the key is replaced by one rather than removed.
+    pub(crate) fn remove_entry(&self, partition: &mut Partition, key: &[u8]) -> Option<Entry> {
+        self.remove_entry_at(partition, key, SystemTime::now())
+    }
+
+    /// Removes the key like `remove_entry`, as deleted at the given time.
+    pub(crate) fn remove_entry_at(&self, partition: &mut Partition, key: &[u8], at: SystemTime) -> Option<Entry> {
+        let removed = match self.tombstone_grace {
+            Some(grace) => {
+                let previous = partition.data.get(key).map(Cow::into_owned).filter(|entry| !entry.is_tombstone());
+                if previous.is_some() {
+                    let mut tombstone = Entry::tombstone(grace);
+                    self.stamp(partition, key, &mut tombstone, at);
+                    self.preserve(partition, key, tombstone.meta.version);
+                    partition.store(key, tombstone);
+                }
+                previous
+            }
+            None => {
+                if partition.data.get(key).is_some() {
+                    let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+                    self.preserve(partition, key, version);
+                }
+                partition.remove(key)
+            }
+        };
+        if let Some(tracker) = self.eviction_tracker(key) {
+            tracker.record_remove(key);
+        }
+        self.invalidate_cached(key);
+        if removed.is_some() && self.watchers.is_watched(key) {
+            self.watchers.notify(ChangeEvent::Delete { key: key.to_vec() });
+        }
+        removed
+    }
+
+    /// Keeps the key's current state in the partition's history if a snapshot view may need it once
+    /// the write at `version` replaces it.
+    fn preserve(&self, partition: &mut Partition, key: &[u8], version: u64) {
+        if self.pins.any() {
+            let previous = partition.data.get(key).map(Cow::into_owned);
+            partition.history.record(key, version, previous);
+        }
+    }
+
+    /// Returns a read-only view of the server as of now, unaffected by later writes.
+    ///
+    /// Overwritten and deleted values are kept in memory while any view is alive.
+    pub fn snapshot_view(&self) -> SnapshotView<'_> {
+        SnapshotView::new(self, self.pins.pin(&self.version))
+    }
+
+    /// Releases a snapshot view's pin and drops the history no remaining view needs.
+    pub(crate) fn unpin(&self, version: u64) {
+        let oldest = self.pins.unpin(version);
+        for partition in &self.topology().partitions {
+            partition.write().unwrap_or_else(PoisonError::into_inner).history.prune(oldest);
+        }
+    }
+
+    /// Assigns the entry the next version and its timestamps, keeping the creation time of a live entry it replaces.
+    fn stamp(&self, partition: &Partition, key: &[u8], entry: &mut Entry, at: SystemTime) {
+        let created_at = partition.data.get_live(key).map_or(at, |existing| existing.meta.created_at);
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        entry.meta = ValueMeta { version, created_at, modified_at: at };
+    }
+
+    /// Recovers the plain value of an entry, failing for entries that hold a collection and for
+    /// values that can't be decompressed or don't match their checksum.
+    pub(crate) fn decode_entry(&self, entry: &Entry) -> Result<Vec<u8>, FlowDbError> {
+        if entry.is_collection() {
+            return Err(FlowDbError::InvalidValue);
+        }
+        let value = self.codecs.decode(&entry.value, entry.codec)?;
+        verified(entry, value)
+    }
+
+    /// Returns the value of the entry like `decode_entry`, sharing the stored bytes rather than
+    /// copying them when the value isn't compressed.
+    fn decode_entry_shared(&self, entry: &Entry) -> Result<Arc<[u8]>, FlowDbError> {
+        if entry.is_collection() {
+            return Err(FlowDbError::InvalidValue);
+        }
+        let value = match entry.codec {
+            NO_COMPRESSION_ID => Arc::clone(&entry.value),
+            codec => self.codecs.decode(&entry.value, codec)?.into(),
+        };
+        verified(entry, value)
+    }
+
+    /// Returns the value of the key's entry like `decode_entry_shared`, from the read cache if it
+    /// holds the entry's version, and caching it otherwise.
+    fn decode_cached(&self, key: &[u8], entry: &Entry) -> Result<Arc<[u8]>, FlowDbError> {
+        // Unstamped entries all have version 0, so only stamped ones are told apart by version.
+        let cached = entry.codec != NO_COMPRESSION_ID && entry.meta.version != 0 && !entry.is_collection();
+        let Some(cache) = self.read_cache.as_ref().filter(|_| cached) else {
+            return self.decode_entry_shared(entry);
+        };
+        if let Some(value) = cache.get(key, entry.meta.version) {
+            return Ok(value);
+        }
+        let value = self.decode_entry_shared(entry)?;
+        cache.insert(key, entry.meta.version, Arc::clone(&value));
+        Ok(value)
+    }
+
+    /// Drops the key's value from the read cache, if the server has one.
+    fn invalidate_cached(&self, key: &[u8]) {
+        if let Some(cache) = &self.read_cache {
+            cache.invalidate(key);
+        }
+    }
+
+    fn notify_put(&self, key: &[u8], entry: &Entry) {
+        if self.watchers.is_watched(key) {
+            if let Ok(value) = self.decode_entry(entry) {
+                self.watchers.notify(ChangeEvent::Put { key: key.to_vec(), value });
+            }
+        }
+    }
+
+    /// Starts a transaction whose writes are applied atomically on commit.
+    pub fn begin(&self) -> Txn<'_> {
+        Txn::new(self)
+    }
+
+    /// Creates a logically separate keyspace with its own partitions, codec, and TTL default.
+    ///
+    /// The returned handle is a StorageServer scoped to the namespace, so all of the usual operations
+    /// (get, put, delete, scans, ...) work on it. Returns an error if the namespace already exists.
+    pub fn create_namespace(&self, name: &str, options: NamespaceOptions) -> Result<Arc<StorageServer>, FlowDbError> {
+        let mut namespaces = self.namespaces.write().unwrap_or_else(PoisonError::into_inner);
+        if namespaces.contains_key(name) {
+            return Err(FlowDbError::InvalidArgument);
+        }
+        let mut namespace = StorageServer::with_backend(options.num_partitions, options.num_replicas, options.backend)
+            .with_encoding(self.encoding)
+            .with_min_compress_size(self.min_compress_size);
+        // A namespace's dictionaries are kept with the server's, in a directory of its own, so its
+        // name must not lead anywhere else.
+        if let Some(dir) = &self.codec_dir {
+            if !matches!(Path::new(name).components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+                return Err(FlowDbError::InvalidArgument);
+            }
+            namespace.store_codecs_in(dir.join("namespaces").join(name))?;
+        }
+        let namespace = Arc::new(namespace.with_value_options(options.codec.0, options.default_ttl)?);
+        namespaces.insert(name.to_owned(), Arc::clone(&namespace));
+        Ok(namespace)
+    }
+
+    /// Trains the server's codec on up to `max_samples` of its values, so values like them that are
+    /// written from now on compress better, and returns how many values it was trained on. Fails
+    /// with `Unsupported` unless the codec can be trained, as `Zstd` can.
+    ///
+    /// To train the dictionary of a namespace, call this on the namespace's handle. The dictionary
+    /// is written to the server's directory, if it has one, before any value is compressed with it.
+    pub fn train_dictionary(&self, max_samples: usize) -> Result<usize, FlowDbError> {
+        let samples: Vec<Vec<u8>> = self.scan().take(max_samples).map(|(_, value)| value).collect();
+        self.codecs.current().train(&samples)?;
+        Ok(samples.len())
+    }
+
+    /// Returns a handle to the namespace with the given name, if it exists.
+    pub fn namespace(&self, name: &str) -> Option<Arc<StorageServer>> {
+        self.namespaces.read().unwrap_or_else(PoisonError::into_inner).get(name).cloned()
+    }
+
+    /// Removes the namespace and all of its data, returning whether it existed.
+    ///
+    /// Outstanding handles keep working but are no longer reachable through this server.
+    pub fn drop_namespace(&self, name: &str) -> bool {
+        self.namespaces.write().unwrap_or_else(PoisonError::into_inner).remove(name).is_some()
+    }
+
+    /// Returns the number of replicas kept for each partition.
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
+
+    pub(crate) fn partition_count(&self) -> usize {
+        self.topology().partitions.len()
+    }
+
+    pub(crate) fn partition(&self, index: usize) -> Arc<RwLock<Partition>> {
+        Arc::clone(&self.topology().partitions[index])
+    }
+
+    pub(crate) fn partition_index(&self, key: &[u8]) -> usize {
+        self.topology().ring.partition(key)
+    }
+
+    /// Returns the consistent-hash ring that currently routes keys to partitions.
+    pub fn ring(&self) -> HashRing {
+        self.topology().ring.clone()
+    }
+
+    pub(crate) fn get_partition(&self, key: &[u8]) -> Arc<RwLock<Partition>> {
+        let topology = self.topology();
+        Arc::clone(&topology.partitions[topology.ring.partition(key)])
+    }
+
+    /// Returns the node owning the key's partition, if another node owns it.
+    fn remote_node(&self, key: &[u8]) -> Option<&RemoteNode> {
+        if self.remotes.is_empty() {
+            return None;
+        }
+        self.remotes.get(&self.partition_index(key)).map(|node| &**node)
+    }
+
+    /// Returns the partitions and the ring routing keys to them.
+    ///
+    /// A rebalance may replace them unless the routing is entered first.
+    pub(crate) fn topology(&self) -> Arc<Topology> {
+        self.routing.current()
+    }
+
+    /// Holds off rebalancing from switching the topology until the returned guard is dropped.
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        self.routing.enter()
+    }
+
+    /// Read-locks the copy of the partition that serves reads under the server's read preference.
+    fn read_copy<'a>(&self, topology: &'a Topology, index: usize) -> Result<RwLockReadGuard<'a, Partition>, FlowDbError> {
+        let copies = topology.leadership.copies(index);
+        let home = match self.read_preference {
+            ReadPreference::Primary => 0,
+            ReadPreference::RoundRobin => self.next_read.fetch_add(1, Ordering::Relaxed) % copies.len(),
+            ReadPreference::Nearest => {
+                let mut hasher = DefaultHasher::new();
+                thread::current().id().hash(&mut hasher);
+                hasher.finish() as usize % copies.len()
+            }
+        };
+        // Replicas behind on queued writes are passed over before waiting for their lock, which
+        // the replicator may be holding.
+        let fresh = |copy: usize| copy < self.replication_mode.sync_copies() || self.is_fresh(index);
+        let serves = |copy: usize, guard: &Partition| copy == 0 || self.replica_lag(index, copy, guard).healthy;
+        if self.read_preference == ReadPreference::Nearest {
+            for copy in (0..copies.len()).map(|i| (home + i) % copies.len()).filter(|&copy| fresh(copy)) {
+                if let Ok(guard) = copies[copy].try_read() {
+                    if serves(copy, &guard) {
+                        return Ok(guard);
+                    }
+                }
+            }
+        }
+        // A poisoned replica may be half-updated and an unhealthy one misses writes, so neither serves reads.
+        if fresh(home) {
+            if let Ok(guard) = copies[home].read() {
+                if serves(home, &guard) {
+                    return Ok(guard);
+                }
+            }
+        }
+        Ok(topology.partitions[index].read()?)
+    }
+
+    /// Calls `read` with the key's live entry, read from as many copies as the consistency level
+    /// asks for, or as the server's read quorum if there is none.
+    fn read_entry<R>(&self, key: &[u8], consistency: Option<Consistency>, read: impl FnOnce(&Entry) -> Result<R, FlowDbError>) -> Result<R, FlowDbError> {
+        let topology = self.topology();
+        let index = topology.ring.partition(key);
+        let found = |entry: &Entry| {
+            self.record_read(key);
+            read(entry)
+        };
+        let copies = consistency.map_or(self.read_quorum, |consistency| consistency.copies(self.replicas));
+        let primary = consistency == Some(Consistency::LocalPrimary) || (copies == 1 && self.read_preference == ReadPreference::Primary);
+        if let Some(entries) = self.published_primary(&topology, index).filter(|_| primary) {
+            // The primary's latest version is read without its lock, so neither writers nor their
+            // replication hold up the read.
+            entries.get_live(key).ok_or(FlowDbError::NotFound).and_then(found)
+        } else if consistency == Some(Consistency::LocalPrimary) {
+            let partition_guard = topology.partitions[index].read()?;
+            partition_guard.data.get_live(key).ok_or(FlowDbError::NotFound).and_then(|entry| found(&entry))
+        } else if copies > 1 {
+            self.quorum_read(&topology, index, key, copies)?.filter(Entry::is_live).ok_or(FlowDbError::NotFound).and_then(|entry| found(&entry))
+        } else {
+            // Look up the key in the copy that serves reads, treating expired entries as missing.
+            let partition_guard = self.read_copy(&topology, index)?;
+            partition_guard.data.get_live(key).ok_or(FlowDbError::NotFound).and_then(|entry| found(&entry))
+        }
+    }
+
+    /// Returns the latest version of the primary's entries that writers published, unless they are
+    /// stored in an engine or the primary is poisoned, since it may then be half-updated.
+    fn published_primary(&self, topology: &Topology, index: usize) -> Option<Arc<MapVersion>> {
+        match topology.partitions[index].is_poisoned() {
+            true => None,
+            false => topology.leadership.published(index),
+        }
+    }
+
+    /// Reads the key from `required` copies of its partition and returns its newest state among
+    /// them, scheduling a repair of each replica that was behind it. Fails if fewer copies are up.
+    fn quorum_read(&self, topology: &Topology, index: usize, key: &[u8], required: usize) -> Result<Option<Entry>, FlowDbError> {
+        let copies = topology.leadership.copies(index);
+        let mut read = Vec::with_capacity(required);
+        for (copy, partition) in copies.iter().enumerate() {
+            if read.len() == required {
+                break;
+            }
+            // Poisoned copies may be half-updated, so they are neither read nor repaired.
+            if let Ok(guard) = partition.read() {
+                if !guard.down {
+                    read.push((copy, guard.data.get(key).map(Cow::into_owned)));
+                }
+            }
+        }
+        if read.len() < required {
+            return Err(FlowDbError::Unavailable);
+        }
+        let primary_deleted = self.tombstone_grace.is_none() && read.first().is_some_and(|(copy, entry)| *copy == 0 && entry.is_none());
+        let newest = match primary_deleted {
+            true => None,
+            false => read.iter().filter_map(|(_, entry)| entry.clone()).max_by_key(|entry| entry.meta.version),
+        };
+        let newest_version = newest.as_ref().map(|entry| entry.meta.version);
+        for (copy, entry) in read.into_iter().filter(|&(copy, _)| copy > 0) {
+            let seen = entry.map(|entry| entry.meta.version);
+            if seen != newest_version {
+                let repairer = self.read_repairer.get_or_init(|| ReadRepairer::spawn(Arc::clone(&self.routing)));
+                repairer.schedule(Repair { partition: index, replica: Arc::clone(&copies[copy]), key: key.to_vec(), newest: newest.clone(), seen });
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Returns whether the partition's asynchronously updated replicas are within the staleness bound.
+    fn is_fresh(&self, index: usize) -> bool {
+        match (&self.replicator, self.max_staleness) {
+            (Some(replicator), Some(max_staleness)) => replicator.queue().lag(index) <= max_staleness,
+            _ => true,
+        }
+    }
+
+    /// Returns how far the replica, read-locked by the caller, is behind the partition's primary.
+    fn replica_lag(&self, index: usize, replica: usize, copy: &Partition) -> ReplicaLag {
+        let queued = copy.written.load(Ordering::Acquire).saturating_sub(copy.applied);
+        let records = queued + copy.missed;
+        let time = match (copy.missed_since, &self.replicator) {
+            (Some(missed_since), _) => missed_since.elapsed(),
+            // Otherwise the replica is only waiting for the writes still queued for the partition.
+            (None, Some(replicator)) if queued > 0 => replicator.queue().lag(index),
+            _ => Duration::ZERO,
+        };
+        let healthy = !copy.down
+            && self.max_lag_records.is_none_or(|max_records| records <= max_records)
+            && self.max_staleness.is_none_or(|max_staleness| time <= max_staleness);
+        ReplicaLag { replica, records, time, healthy }
+    }
+
+    /// Groups the given items by the index of the partition their key belongs to.
+    fn group_by_partition<'a, T>(&self, topology: &Topology, items: &'a [T], key: impl Fn(&T) -> &[u8]) -> HashMap<usize, Vec<(usize, &'a T)>> {
+        let mut groups: HashMap<usize, Vec<(usize, &T)>> = HashMap::new();
+        for (position, item) in items.iter().enumerate() {
+            groups.entry(topology.ring.partition(key(item))).or_default().push((position, item));
+        }
+        groups
+    }
+
+    /// Returns the value associated with the given key as a UTF-8 string, or an error if the key is
+    /// not found or its value isn't valid UTF-8.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<String, FlowDbError> {
+        self.get_bytes(key.as_ref()).and_then(into_string)
+    }
+
+    /// Returns the raw bytes of the value associated with the given key, or an error if the key is
+    /// not found or its value fails its checksum.
+    ///
+    /// Reads of a partition's primary held in the built-in maps don't take its lock: they see the
+    /// version its writers last published, so a write in progress, or waiting on a replica, never
+    /// holds them up. A write can be seen as soon as the primary has applied it.
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Vec<u8>, FlowDbError> {
+        self.get_bytes_at(key.as_ref(), None)
+    }
+
+    /// Returns the value associated with the given key like `get_bytes`, without copying it out of
+    /// the partition if values aren't compressed, or decompressed into a new buffer if they are.
+    ///
+    /// The returned bytes are shared with the stored entry, so holding on to them keeps the value
+    /// in memory after it is overwritten or deleted.
+    pub fn get_shared(&self, key: impl AsRef<[u8]>) -> Result<Arc<[u8]>, FlowDbError> {
+        let key = key.as_ref();
+        let _routing = self.enter();
+        if let Some(node) = self.remote_node(key) {
+            return node.get(key).map(Arc::from);
+        }
+        self.read_entry(key, None, |entry| self.decode_cached(key, entry))
+    }
+
+    /// Returns the value associated with the given key like `get`, read from as many copies as the
+    /// consistency level asks for instead of the server's own read settings.
+    ///
+    /// `One` reads the copy chosen by the read preference, `LocalPrimary` the primary, and `Quorum`
+    /// and `All` compare copies like a server with `with_read_quorum` does, failing with
+    /// `Unavailable` if too few are up. Keys of remote partitions are read by their node as usual.
+    pub fn get_with_consistency(&self, key: impl AsRef<[u8]>, consistency: Consistency) -> Result<String, FlowDbError> {
+        self.get_bytes_at(key.as_ref(), Some(consistency)).and_then(into_string)
+    }
+
+    fn get_bytes_at(&self, key: &[u8], consistency: Option<Consistency>) -> Result<Vec<u8>, FlowDbError> {
+        // Read the key from the copies of its partition that serve reads, or ask the partition's
+        // node if it is remote.
+        let _routing = self.enter();
+        if let Some(node) = self.remote_node(key) {
+            return node.get(key);
+        }
+        self.read_entry(key, consistency, |entry| self.decode_cached(key, entry).map(|value| value.to_vec()))
+    }
+
+    /// Returns the raw value associated with the given key together with its version and timestamps.
+    pub fn get_with_meta(&self, key: impl AsRef<[u8]>) -> Result<(Vec<u8>, ValueMeta), FlowDbError> {
+        let key = key.as_ref();
+        let _routing = self.enter();
+        self.read_entry(key, None, |entry| Ok((self.decode_cached(key, entry)?.to_vec(), entry.meta)))
+    }
+
+    /// Returns the value associated with the given key, deserialized with the server's encoding.
+    pub fn get_typed<T: DeserializeOwned>(&self, key: impl AsRef<[u8]>) -> Result<T, FlowDbError> {
+        let key = key.as_ref();
+        let data = self.get_bytes(key)?;
+        self.encoding.deserialize(&data).map_err(|_| FlowDbError::InvalidValue)
+    }
+
+    /// Returns whether the key is present, without decompressing its value. Keys of a partition
+    /// that can't be read because its primary is poisoned are reported as missing.
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        let key = key.as_ref();
+        let _routing = self.enter();
+        let topology = self.topology();
+        let is_present = self.read_copy(&topology, topology.ring.partition(key)).is_ok_and(|guard| guard.data.get_live(key).is_some());
+        is_present
+    }
+
+    /// Returns the number of keys stored across all partitions.
+    ///
+    /// Partitions are counted one at a time, so the total may be slightly stale under concurrent writes.
+    pub fn len(&self) -> usize {
+        let _routing = self.enter();
+        self.topology().partitions.iter().map(|partition| partition.read().unwrap_or_else(PoisonError::into_inner).data.live_len()).sum()
+    }
+
+    /// Returns whether no keys are stored in any partition.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns per-partition key counts and sizes, which is useful for spotting skew in the key distribution.
+    pub fn stats(&self) -> ServerStats {
+        let _routing = self.enter();
+        let topology = self.topology();
+        let partitions = topology
+            .partitions
+            .iter()
+            .enumerate()
+            .map(|(index, partition)| {
+                let partition_guard = partition.read().unwrap_or_else(PoisonError::into_inner);
+                let mut stats = PartitionStats {
+                    index,
+                    keys: 0,
+                    compressed_bytes: 0,
+                    uncompressed_bytes: 0,
+                    memory_bytes: partition_guard.data.memory_bytes() as u64,
+                    replicas: partition_guard.replicas.len(),
+                    replica_lag: Vec::new(),
+                };
+                for (_, entry) in partition_guard.data.iter().filter(|(_, entry)| entry.is_live()) {
+                    stats.keys += 1;
+                    stats.compressed_bytes += entry.value.len() as u64;
+                    stats.uncompressed_bytes += self.codecs.decoded_len(&entry.value, entry.codec) as u64;
+                }
+                drop(partition_guard);
+                for (replica, copy) in topology.leadership.copies(index).iter().enumerate().skip(1) {
+                    // A poisoned replica serves no reads, however far behind it is.
+                    let lag = match copy.read() {
+                        Ok(guard) => {
+                            stats.memory_bytes += guard.data.memory_bytes() as u64;
+                            self.replica_lag(index, replica, &guard)
+                        }
+                        Err(poisoned) => ReplicaLag { healthy: false, ..self.replica_lag(index, replica, &poisoned.into_inner()) },
+                    };
+                    stats.replica_lag.push(lag);
+                }
+                stats
+            })
+            .collect();
+        let log = match &self.log {
+            Some(LogSink::Direct(log)) => Some(log.lock().unwrap_or_else(PoisonError::into_inner).metrics()),
+            Some(LogSink::Background(log)) => Some(log.metrics()),
+            Some(LogSink::Shared(log)) => Some(log.metrics()),
+            None => None,
+        };
+        let cache = self.read_cache.as_ref().map(ReadCache::metrics);
+        ServerStats { partitions, log, cache }
+    }
+
+    /// Returns the values for all given keys as UTF-8 strings, in the same order as the keys.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Result<String, FlowDbError>> {
+        self.multi_get_bytes(keys).into_iter().map(|result| result.and_then(into_string)).collect()
+    }
+
+    /// Returns the raw values for all given keys, in the same order as the keys.
+    ///
+    /// Each partition is read-locked once for all of the keys that belong to it.
+    pub fn multi_get_bytes<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Result<Vec<u8>, FlowDbError>> {
+        let _routing = self.enter();
+        let topology = self.topology();
+        let mut results = vec![Err(FlowDbError::NotFound); keys.len()];
+        for (partition_index, group) in self.group_by_partition(&topology, keys, |key| key.as_ref()) {
+            let partition_guard = match self.read_copy(&topology, partition_index) {
+                Ok(partition_guard) => partition_guard,
+                Err(e) => {
+                    for (position, _) in group {
+                        results[position] = Err(e.clone());
+                    }
+                    continue;
+                }
+            };
+            for (position, key) in group {
+                if let Some(entry) = partition_guard.data.get_live(key.as_ref()) {
+                    self.record_read(key.as_ref());
+                    results[position] = self.decode_cached(key.as_ref(), &entry).map(|value| value.to_vec());
+                }
+            }
+        }
+        results
+    }
+
+    /// Returns an iterator over every key-value pair stored on the server, one partition at a time.
+    pub fn scan(&self) -> Scan {
+        Scan::new(self.topology().partitions.clone(), None, self.codecs.clone())
+    }
+
+    /// Returns an iterator over every key-value pair whose key starts with the given prefix.
+    ///
+    /// Keys are hash-partitioned, so this fans out over all partitions; results are streamed one
+    /// partition at a time rather than collected up front.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan {
+        Scan::new(self.topology().partitions.clone(), Some(prefix.as_ref().to_vec()), self.codecs.clone())
+    }
+
+    /// Returns a page of at most `limit` key-value pairs starting at the cursor (or the beginning if None),
+    /// together with the cursor for the next page, or None once every partition has been scanned.
+    ///
+    /// Unlike `scan`, no state is kept between calls, so iteration can be resumed by a different client
+    /// or after a restart. Keys written behind the cursor during pagination are not returned.
+    pub fn scan_page(&self, cursor: Option<&Cursor>, limit: usize) -> Page {
+        self.scan_prefix_page(b"", cursor, limit)
+    }
+
+    /// Returns a page of at most `limit` key-value pairs whose keys start with the prefix, as with
+    /// `scan_page`. The same prefix must be passed for every page of a scan.
+    pub fn scan_prefix_page(&self, prefix: impl AsRef<[u8]>, cursor: Option<&Cursor>, limit: usize) -> Page {
+        let _routing = self.enter();
+        scan::scan_page(&self.topology().partitions, prefix.as_ref(), cursor, limit, &self.codecs)
+    }
+
+    /// Returns an iterator over the key-value pairs whose keys fall within the range, in key order.
+    ///
+    /// Works with any backend, but the Ordered backend avoids sorting each partition's keys.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> RangeScan {
+        RangeScan::new(&self.topology().partitions, range, self.codecs.clone())
+    }
+
+    /// Inserts a key-value pair into the partition and its replicas.
+    ///
+    /// Accepts any byte-like value, so both strings and binary data can be stored. Returns the LSN
+    /// of the logged put, or None if the server has no transaction log or logs in the background.
+    pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Lsn>, FlowDbError> {
+        self.put_with_report(key.as_ref(), value).map(|(lsn, _)| lsn)
+    }
+
+    /// Inserts the key-value pair like `put`, also returning which replicas applied it.
+    ///
+    /// Replication is best-effort: a replica that can't apply the put is skipped and listed in the
+    /// report's failures, while the put still succeeds on the primary and the other replicas.
+    pub fn put_with_report(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(Option<Lsn>, ReplicationReport), FlowDbError> {
+        self.put_value(key.as_ref(), value.as_ref(), None)
+    }
+
+    /// Inserts the key-value pair like `put_with_report`, returning once as many copies as the
+    /// consistency level asks for have applied it.
+    ///
+    /// A write never reaches fewer copies synchronously than the server's replication mode updates.
+    /// A level asking for more waits for the background replicator to apply the write to replicas
+    /// it was queued for, which then count as applied in the report if they hold it and every
+    /// earlier write. Fails with `ReplicaFailure` if too few copies applied it, though the copies
+    /// that did keep it. Writes to keys of remote partitions are replicated by their node as usual.
+    pub fn put_with_consistency(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, consistency: Consistency) -> Result<(Option<Lsn>, ReplicationReport), FlowDbError> {
+        let key = key.as_ref();
+        let _routing = self.enter();
+        let (lsn, mut report) = self.put_value(key, value.as_ref(), None)?;
+        let required = consistency.copies(self.replicas);
+        if report.applied.len() < required && self.remote_node(key).is_none() {
+            self.wait_for_queued(key, &mut report);
+        }
+        if report.applied.len() < required {
+            return Err(FlowDbError::ReplicaFailure { required, applied: report.applied.len() });
+        }
+        Ok((lsn, report))
+    }
+
+    /// Waits for the replicator to apply the latest write made to the key's partition, moving the
+    /// queued replicas of the report that hold it and every earlier write to its applied copies.
+    fn wait_for_queued(&self, key: &[u8], report: &mut ReplicationReport) {
+        let Some(replicator) = &self.replicator else {
+            return;
+        };
+        let topology = self.topology();
+        let index = topology.ring.partition(key);
+        let sequence = topology.partitions[index].read().unwrap_or_else(PoisonError::into_inner).written.load(Ordering::Acquire);
+        replicator.queue().wait_until_applied(index, sequence);
+        let copies = topology.leadership.copies(index);
+        let caught_up = |replica: &usize| {
+            let copy = copies.get(*replica).and_then(|copy| copy.read().ok());
+            copy.is_some_and(|copy| !copy.down && copy.missed == 0 && copy.applied >= sequence)
+        };
+        let (applied, queued): (Vec<usize>, Vec<usize>) = report.queued.iter().partition(|replica| caught_up(replica));
+        report.applied.extend(applied);
+        report.queued = queued;
+    }
+
+    /// Serializes the value with the server's encoding and inserts it into the partition and its replicas.
+    pub fn put_typed<T: Serialize>(&self, key: impl AsRef<[u8]>, value: &T) -> Result<Option<Lsn>, FlowDbError> {
+        let key = key.as_ref();
+        let data = self.encoding.serialize(value)?;
+        self.put(key, data)
+    }
+
+    /// Inserts a key-value pair that expires once `ttl` has elapsed.
+    ///
+    /// Expired entries are treated as missing immediately and are physically removed by `sweep_expired`
+    /// or a background TtlSweeper.
+    pub fn put_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> Result<Option<Lsn>, FlowDbError> {
+        self.put_value(key.as_ref(), value.as_ref(), Some(ttl)).map(|(lsn, _)| lsn)
+    }
+
+    /// Makes the key expire once `ttl` has elapsed, keeping its value, and returns whether the key
+    /// was present. Only the expiry is logged; the value is replicated along with its new TTL.
+    pub fn expire(&self, key: impl AsRef<[u8]>, ttl: Duration) -> Result<bool, FlowDbError> {
+        let key = key.as_ref();
+        let _routing = self.enter();
+        if let Some(node) = self.remote_node(key) {
+            return match node.get(key) {
+                Ok(value) => node.put(key, &value, Some(ttl)).map(|_| true),
+                Err(FlowDbError::NotFound) => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+        let _key_lock = self.key_locks.lock(key);
+        let partition = self.get_partition(key);
+        let Some(mut entry) = self.live_entry(&partition, key)? else {
+            return Ok(false);
+        };
+        let at = SystemTime::now() + ttl;
+        entry.expires_at = Some(at);
+        self.log_write(&partition, &LogRecord::Expire { key: key.to_vec(), at })?;
+        let mut partition_guard = partition.write()?;
+        self.store_entry(&mut partition_guard, key, entry);
+        Ok(true)
+    }
+
+    /// Inserts the value with the TTL if given, or the server's default TTL otherwise.
+    pub(crate) fn put_value(&self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<(Option<Lsn>, ReplicationReport), FlowDbError> {
+        let entry = self.new_entry_with_ttl(value, ttl)?;
+        self.put_entry(key, value, entry)
+    }
+
+    /// Builds the entry stored for the value with the TTL if given, or the server's default TTL otherwise.
+    fn new_entry_with_ttl(&self, value: &[u8], ttl: Option<Duration>) -> Result<Entry, FlowDbError> {
+        match ttl {
+            Some(ttl) => self.with_encoded_value(Entry::with_ttl(Vec::new(), ttl), value),
+            None => self.new_entry(value),
+        }
+    }
+
+    /// Builds the entry stored for a newly written value, applying the server's compression and TTL default.
+    pub(crate) fn new_entry(&self, value: &[u8]) -> Result<Entry, FlowDbError> {
+        let entry = match self.default_ttl {
+            Some(ttl) => Entry::with_ttl(Vec::new(), ttl),
+            None => Entry::new(Vec::new()),
+        };
+        self.with_encoded_value(entry, value)
+    }
+
+    /// Stores the value in the entry, compressed with the server's codec if it is at least
+    /// `min_compress_size` bytes.
+    fn with_encoded_value(&self, entry: Entry, value: &[u8]) -> Result<Entry, FlowDbError> {
+        let (stored, codec) = self.codecs.encode(value, self.min_compress_size)?;
+        Ok(entry.with_value(stored, codec, value))
+    }
+
+    fn put_entry(&self, key: &[u8], value: &[u8], entry: Entry) -> Result<(Option<Lsn>, ReplicationReport), FlowDbError> {
+        // Determine which partition the key belongs to, and hand the put to its node if it is remote.
+        let _routing = self.enter();
+        if let Some(node) = self.remote_node(key) {
+            let ttl = entry.expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default());
+            return node.put(key, value, ttl).map(|report| (None, report));
+        }
+        // Queue the put with the others to its partition, holding the key lock until it is applied
+        // so that puts to the same key are never in one batch.
+        let _key_lock = self.key_locks.lock(key);
+        let put = QueuedPut { key: key.to_vec(), value: value.to_vec(), entry };
+        self.puts.submit(self.partition_index(key), put, |puts| self.apply_puts(puts)).unwrap_or(Err(FlowDbError::LockPoisoned))
+    }
+
+    /// Applies a batch of puts, returning their results in order. Each partition's puts are logged as
+    /// one record, a commit if there are several, and stored under one acquisition of its write lock.
+    fn apply_puts(&self, puts: Vec<QueuedPut>) -> Vec<PutResult> {
+        let mut results = vec![Err(FlowDbError::LockPoisoned); puts.len()];
+        let topology = self.topology();
+        for (index, group) in self.group_by_partition(&topology, &puts, |put| &put.key) {
+            let partition = &topology.partitions[index];
+
+            // Write the puts to the transaction log before applying them.
+            let records = group.iter().flat_map(|(_, put)| put_records(&put.key, put.value.clone(), &put.entry)).collect();
+            let record = single_record(records);
+            let lsn = match self.log_write(partition, &record) {
+                Ok(lsn) => lsn,
+                Err(e) => {
+                    for (position, _) in group {
+                        results[position] = Err(e.clone());
+                    }
+                    continue;
+                }
+            };
+
+            // Insert the key-value pairs into the primary and replica partitions.
+            let Ok(mut partition_guard) = partition.write() else {
+                continue;
+            };
+            for (position, put) in group {
+                results[position] = Ok((lsn, self.store_entry(&mut partition_guard, &put.key, put.entry.clone())));
+            }
+        }
+        results
+    }
+
+    /// Removes the key from the partition and its replicas, returning whether the key existed.
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<bool, FlowDbError> {
+        self.delete_with_lsn(key.as_ref()).map(|(existed, _)| existed)
+    }
+
+    /// Removes the key like `delete`, also returning the LSN of the logged delete. Deleting a missing
+    /// key logs nothing, so there is no LSN either.
+    pub fn delete_with_lsn(&self, key: impl AsRef<[u8]>) -> Result<(bool, Option<Lsn>), FlowDbError> {
+        let key = key.as_ref();
+        // Determine which partition the key belongs to, and hand the delete to its node if it is remote.
+        let _routing = self.enter();
+        if let Some(node) = self.remote_node(key) {
+            return node.delete(key).map(|existed| (existed, None));
+        }
+        let _key_lock = self.key_locks.lock(key);
+        let partition = self.get_partition(key);
+
+        // Write the delete to the transaction log before applying it.
+        let mut lsn = None;
+        if partition.read()?.data.get(key).is_some_and(|entry| !entry.is_tombstone()) {
+            lsn = self.log_write(&partition, &LogRecord::Delete { key: key.to_vec() })?;
+        }
+
+        // Acquire a lock on the partition to ensure exclusive access.
+        let mut partition_guard = partition.write()?;
+
+        // Remove the key from the primary and replica partitions.
+        let existed = self.remove_entry(&mut partition_guard, key).is_some_and(|entry| entry.is_live());
+
+        Ok((existed, lsn))
+    }
+
+    /// Inserts all given key-value pairs into their partitions and replicas.
+    ///
+    /// Each partition and replica is write-locked once for all of the pairs that belong to it, and
+    /// each partition's pairs are written to the transaction log as a single commit.
+    pub fn multi_put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, pairs: &[(K, V)]) -> Result<(), FlowDbError> {
+        let _routing = self.enter();
+        let topology = self.topology();
+        for (partition_index, group) in self.group_by_partition(&topology, pairs, |(key, _)| key.as_ref()) {
+            let mut records = Vec::new();
+            let mut entries: Vec<(Vec<u8>, Entry)> = Vec::new();
+            for (_, (key, value)) in group {
+                let entry = self.new_entry(value.as_ref())?;
+                records.extend(put_records(key.as_ref(), value.as_ref().to_vec(), &entry));
+                entries.push((key.as_ref().to_vec(), entry));
+            }
+
+            // Log the group, then apply it to the primary partition under a single lock.
+            let _key_locks = self.key_locks.lock_all(entries.iter().map(|(key, _)| key));
+            let partition = &topology.partitions[partition_index];
+            self.log_write(partition, &LogRecord::Commit { records })?;
+            let mut partition_guard = partition.write()?;
+            for (key, entry) in &mut entries {
+                self.stamp(&partition_guard, key, entry, SystemTime::now());
+                self.preserve(&mut partition_guard, key, entry.meta.version);
+                self.notify_put(key, entry);
+                self.record_write(&mut partition_guard, key, entry);
+                self.invalidate_cached(key);
+            }
+            partition_guard.data.extend(entries.iter().cloned());
+            let last_key = entries.last().map(|(key, _)| key.clone());
+
+            // Apply the whole group to each replica partition under a single lock.
+            replicate(&partition_guard, ReplicaOp::Extend(entries));
+
+            // The keys were recorded in order, so evicting for the last one evicts what storing
+            // them one at a time would have.
+            if let Some(key) = last_key {
+                self.enforce_memory_limit(&mut partition_guard, &key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads key-value records from the reader, returning how many were imported.
+    ///
+    /// Records are streamed and committed in batches of `IMPORT_BATCH_SIZE`: each batch locks every
+    /// partition it touches once and is written to the transaction log as a single commit record.
+    /// A malformed record aborts the import; batches committed before it remain applied.
+    pub fn import<R: Read>(&self, reader: R, format: DumpFormat) -> Result<usize, FlowDbError> {
+        let mut reader = BufReader::new(reader);
+        let mut imported = 0;
+        loop {
+            let mut txn = self.begin();
+            let mut batch_len = 0;
+            while batch_len < IMPORT_BATCH_SIZE {
+                match bulk::read_record(&mut reader, format)? {
+                    Some((key, value)) => txn.put(&key, value),
+                    None => break,
+                }
+                batch_len += 1;
+            }
+            txn.commit()?;
+            imported += batch_len;
+            if batch_len < IMPORT_BATCH_SIZE {
+                return Ok(imported);
+            }
+        }
+    }
+
+    /// Writes every key-value pair (or only those whose key starts with `prefix`) to the writer in a
+    /// format that `import` can reload, returning how many records were exported.
+    ///
+    /// Each partition is snapshotted under its read lock in turn, so every partition's contents are
+    /// internally consistent while writers are only ever blocked on one partition. To export a
+    /// namespace, call this on the namespace's handle.
+    pub fn export<W: Write>(&self, writer: W, format: DumpFormat, prefix: Option<&[u8]>) -> Result<usize, FlowDbError> {
+        let mut writer = BufWriter::new(writer);
+        let mut exported = 0;
+        for (key, value) in self.scan_prefix(prefix.unwrap_or_default()) {
+            bulk::write_record(&mut writer, format, &key, &value)?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Atomically replaces the value of the key with `new` if its current value equals `expected`.
+    ///
+    /// Passing `None` as `expected` means the key must not exist yet. On mismatch, the inner
+    /// result holds the actual current value (`None` if the key is missing). Failing to read,
+    /// decode or store the value is returned as an error.
+    pub fn compare_and_swap(&self, key: impl AsRef<[u8]>, expected: Option<&[u8]>, new: &[u8]) -> Result<Result<(), Option<Vec<u8>>>, FlowDbError> {
+        let key = key.as_ref();
+        // Determine which partition the key belongs to.
+        let _routing = self.enter();
+        let partition = self.get_partition(key);
+
+        // Hold the key lock across the comparison and the swap so no other writer can interleave.
+        let _key_lock = self.key_locks.lock(key);
+        let current = self.live_entry(&partition, key)?.map(|entry| self.decode_entry(&entry)).transpose()?;
+        if current.as_deref() != expected {
+            return Ok(Err(current));
+        }
+        let entry = self.new_entry(new)?;
+        self.log_write(&partition, &put_record(key, new.to_vec(), &entry))?;
+        let mut partition_guard = partition.write()?;
+
+        // Replace the value on the primary and replica partitions.
+        self.store_entry(&mut partition_guard, key, entry);
+        Ok(Ok(()))
+    }
+
+    /// Appends the bytes to the key's current value, creating the key if it is missing.
+    ///
+    /// The value is decompressed, extended, and recompressed under the key lock, so concurrent
+    /// appends never lose each other's data. An existing TTL is preserved.
+    pub fn append(&self, key: impl AsRef<[u8]>, bytes: &[u8]) -> Result<(), FlowDbError> {
+        let key = key.as_ref();
+        // Determine which partition the key belongs to.
+        let _routing = self.enter();
+        let partition = self.get_partition(key);
+
+        // Hold the key lock across the read and the write back.
+        let _key_lock = self.key_locks.lock(key);
+        let (entry, value) = match self.live_entry(&partition, key)? {
+            Some(existing) => {
+                let mut data = self.decode_entry(&existing)?;
+                data.extend_from_slice(bytes);
+                (self.with_encoded_value(existing, &data)?, data)
+            }
+            None => (self.new_entry(bytes)?, bytes.to_vec()),
+        };
+
+        // Store the combined value on the primary and replica partitions.
+        self.log_write(&partition, &put_record(key, value, &entry))?;
+        let mut partition_guard = partition.write()?;
+        self.store_entry(&mut partition_guard, key, entry);
+        Ok(())
+    }
+
+    /// Inserts the key-value pair only if the key is missing, returning the existing value otherwise.
+    ///
+    /// The check and the insert happen under the same key lock, so exactly one of several concurrent
+    /// callers wins, which makes this suitable for locks and leases. The existing value is returned
+    /// in the inner result; failing to read, decode or store a value is returned as an error.
+    pub fn put_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Result<(), Vec<u8>>, FlowDbError> {
+        let key = key.as_ref();
+        // Determine which partition the key belongs to.
+        let _routing = self.enter();
+        let partition = self.get_partition(key);
+
+        // Hold the key lock across the existence check and the insert.
+        let _key_lock = self.key_locks.lock(key);
+        if let Some(existing) = self.live_entry(&partition, key)? {
+            return Ok(Err(self.decode_entry(&existing)?));
+        }
+        let entry = self.new_entry(value.as_ref())?;
+        self.log_write(&partition, &put_record(key, value.as_ref().to_vec(), &entry))?;
+        let mut partition_guard = partition.write()?;
+        self.store_entry(&mut partition_guard, key, entry);
+        Ok(Ok(()))
+    }
+
+    /// Inserts the key-value pair with the TTL if given, only if the key is present when `present`
+    /// is set or missing otherwise, and returns whether it was inserted.
+    ///
+    /// The check and the insert happen under the same key lock, as with `put_if_absent`.
+    pub(crate) fn put_if_present(&self, key: &[u8], value: &[u8], ttl: Option<Duration>, present: bool) -> Result<bool, FlowDbError> {
+        let _routing = self.enter();
+        let _key_lock = self.key_locks.lock(key);
+        let partition = self.get_partition(key);
+        if self.live_entry(&partition, key)?.is_some() != present {
+            return Ok(false);
+        }
+        let entry = self.new_entry_with_ttl(value, ttl)?;
+        self.log_write(&partition, &put_record(key, value.to_vec(), &entry))?;
+        let mut partition_guard = partition.write()?;
+        self.store_entry(&mut partition_guard, key, entry);
+        Ok(true)
+    }
+
+    /// Atomically replaces the key's value with the result of `f`, returning the new value.
+    ///
+    /// `f` receives the current value (or None if the key is missing) and runs while the key lock
+    /// is held, so no other write to the key can interleave, though other keys of its partition can
+    /// be written meanwhile. Returning None deletes the key. An existing TTL is preserved.
+    pub fn update<F>(&self, key: impl AsRef<[u8]>, f: F) -> Result<Option<Vec<u8>>, FlowDbError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let key = key.as_ref();
+        self.read_modify_write(key, |old| {
+            let new = f(old);
+            let record = match &new {
+                Some(value) => LogRecord::Put { key: key.to_vec(), value: value.clone() },
+                None => LogRecord::Delete { key: key.to_vec() },
+            };
+            Ok((new, Some(record)))
+        })
+    }
+
+    /// Combines the operand with the key's current value using the registered merge operator.
+    ///
+    /// The merge is recorded in the transaction log as the operand rather than the resulting value,
+    /// and the result is replicated like a put. Returns an error if no merge operator is registered.
+    pub fn merge(&self, key: impl AsRef<[u8]>, operand: impl AsRef<[u8]>) -> Result<(), FlowDbError> {
+        let key = key.as_ref();
+        let operator = self.merge_operator.as_ref().ok_or(FlowDbError::Unsupported)?;
+        let operand = operand.as_ref();
+        self.read_modify_write(key, |existing| {
+            let record = LogRecord::Merge { key: key.to_vec(), operand: operand.to_vec() };
+            Ok((Some(operator(existing, operand)), Some(record)))
+        })?;
+        Ok(())
+    }
+
+    /// Runs `f` on the key's current value under the key lock and writes back its result, logging
+    /// the record `f` returns with it if there is one.
+    ///
+    /// If `f` fails, nothing is written.
+    fn read_modify_write<F>(&self, key: &[u8], f: F) -> Result<Option<Vec<u8>>, FlowDbError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<(Option<Vec<u8>>, Option<LogRecord>), FlowDbError>,
+    {
+        // Determine which partition the key belongs to.
+        let _routing = self.enter();
+        let partition = self.get_partition(key);
+
+        // Hold the key lock while the closure runs and its result is written back, taking the
+        // partition's write lock only for the write.
+        let _key_lock = self.key_locks.lock(key);
+        let existing = self.live_entry(&partition, key)?;
+        let old = match &existing {
+            Some(entry) => Some(self.decode_entry(entry)?),
+            None => None,
+        };
+        let (new, record) = f(old.as_deref())?;
+        let entry = match (&new, existing) {
+            (Some(value), Some(existing)) => Some(self.with_encoded_value(existing, value)?),
+            (Some(value), None) => Some(self.new_entry(value)?),
+            (None, _) => None,
+        };
+
+        // Write back or delete on the primary and replica partitions, logging when a kept TTL expires.
+        let record = match (record, &entry) {
+            (Some(LogRecord::Put { key, value }), Some(entry)) => Some(put_record(&key, value, entry)),
+            (record, _) => record,
+        };
+        if let Some(record) = &record {
+            self.log_write(&partition, record)?;
+        }
+        let mut partition_guard = partition.write()?;
+        match entry {
+            Some(entry) => {
+                self.store_entry(&mut partition_guard, key, entry);
+            }
+            None => {
+                self.remove_entry(&mut partition_guard, key);
+            }
+        }
+        Ok(new)
+    }
+
+    /// Returns a copy of the key's live entry on its primary, holding the partition's read lock
+    /// only while it is copied.
+    pub(crate) fn live_entry(&self, partition: &RwLock<Partition>, key: &[u8]) -> Result<Option<Entry>, FlowDbError> {
+        Ok(partition.read()?.data.get_live(key).map(Cow::into_owned))
+    }
+
+    /// Removes every expired entry from all partitions and their replicas, returning how many keys were evicted.
+    ///
+    /// Tombstones whose grace period has elapsed are purged too, and counted.
+    pub fn sweep_expired(&self) -> usize {
+        let _routing = self.enter();
+        sweep_expired_partitions(&self.topology().partitions)
+    }
+
+    /// Starts a background thread that calls `sweep_expired` every `interval` until the returned sweeper is stopped or dropped.
+    pub fn start_ttl_sweeper(&self, interval: Duration) -> TtlSweeper {
+        TtlSweeper::spawn(Arc::clone(&self.routing), interval)
+    }
+
+    /// Returns the current leader of every partition, by partition index.
+    pub fn leaders(&self) -> Vec<Leader> {
+        self.topology().leadership.leaders()
+    }
+
+    /// Promotes a healthy replica of every partition whose leader is unavailable, returning the
+    /// newly elected leaders. A leader is unavailable once a thread panicked while holding its lock.
+    ///
+    /// The promoted replica takes over the leader's place, so reads and writes of the partition reach
+    /// it as soon as this returns. Writes the failed leader applied but never replicated are lost.
+    pub fn check_health(&self) -> Vec<Leader> {
+        let _routing = self.enter();
+        self.topology().leadership.check(self.replicator.as_ref().map(|replicator| &**replicator.queue()))
+    }
+
+    /// Starts a background thread that calls `check_health` every `interval` until the returned checker is stopped or dropped.
+    pub fn start_health_checker(&self, interval: Duration) -> HealthChecker {
+        let queue = self.replicator.as_ref().map(|replicator| Arc::clone(replicator.queue()));
+        HealthChecker::spawn(Arc::clone(&self.routing), queue, interval)
+    }
+
+    /// Compares every replica with its partition's leader using Merkle trees, and copies the keys of
+    /// the ranges that differ from the leader. Returns how many keys were repaired.
+    ///
+    /// Replicas only diverge when a write fails to reach them, such as one skipped while the replica
+    /// was unavailable. With asynchronous replication, replicas that are merely behind are brought up
+    /// to date too.
+    pub fn repair_replicas(&self) -> usize {
+        let _routing = self.enter();
+        anti_entropy::repair_replicas(&self.topology().leadership)
+    }
+
+    /// Starts a background thread that calls `repair_replicas` every `interval` until the returned service is stopped or dropped.
+    pub fn start_anti_entropy(&self, interval: Duration) -> AntiEntropy {
+        AntiEntropy::spawn(Arc::clone(&self.routing), interval)
+    }
+}
+
+/// Removes expired entries from the given primary partitions and their replicas.
+pub(crate) fn sweep_expired_partitions(partitions: &[Arc<RwLock<Partition>>]) -> usize {
+    let now = SystemTime::now();
+    let mut evicted = 0;
+    for partition in partitions {
+        // Sweep one partition at a time so readers and writers of other partitions aren't blocked.
+        let mut partition_guard = partition.write().unwrap_or_else(PoisonError::into_inner);
+        evicted += partition_guard.data.remove_expired(now).len();
+        replicate(&partition_guard, ReplicaOp::RemoveExpired(now));
+    }
+    evicted
+}
+
+impl Partition {
+    /// Inserts the entry into this primary partition and all of its replicas.
+    fn store(&mut self, key: &[u8], entry: Entry) -> ReplicationReport {
+        self.data.insert(key.to_vec(), entry.clone());
+        replicate(self, ReplicaOp::Store(key.to_vec(), entry))
+    }
+
+    /// Removes the key from this primary partition and all of its replicas, returning the primary's entry.
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let removed = self.data.remove(key);
+        replicate(self, ReplicaOp::Remove(key.to_vec()));
+        removed
+    }
+
+    /// Applies the mutation to this primary partition and all of its replicas.
+    pub(crate) fn apply(&mut self, op: ReplicaOp) {
+        op.apply(self);
+        replicate(self, op);
+    }
+}
+
+/// Applies the given mutation to every replica of the partition except the primary itself, or
+/// queues it for the replicas past the partition's synchronous copies.
+///
+/// The caller must hold the primary's write lock so replicas observe mutations in the same order.
+/// While a rebalance moves keys out of the partition, mutations of them are journaled as well.
+/// A replica that is down or whose lock is poisoned is skipped rather than failing the mutation,
+/// and gets the mutation as a hint if the server keeps them.
+fn replicate(partition: &Partition, op: ReplicaOp) -> ReplicationReport {
+    let mut report = ReplicationReport { applied: vec![0], ..ReplicationReport::default() };
+    let sync = partition.replication.sync_copies.min(partition.replicas.len());
+    let hints = partition.replication.hints.as_deref();
+    let stamp = Stamp { sequence: partition.written.fetch_add(1, Ordering::AcqRel) + 1, made_at: Instant::now() };
+    for (index, replica) in partition.replicas.iter().enumerate().take(sync).skip(1) {
+        match replication::apply_to(replica, &op, stamp, hints) {
+            Ok(()) => report.applied.push(index),
+            Err((error, hinted)) => {
+                log::warn!("Skipping replica {}: {}", index, error);
+                report.failed.push((index, error));
+                if hinted {
+                    report.hinted.push(index);
+                }
+            }
+        }
+    }
+    if let Some((index, journal)) = &partition.migration {
+        journal.record(*index, &op);
+    }
+    if let Some((index, queue)) = &partition.replication.queue {
+        if sync < partition.replicas.len() {
+            report.queued.extend(sync..partition.replicas.len());
+            queue.push(*index, partition.replicas[sync..].to_vec(), partition.replication.hints.clone(), stamp, op);
+        }
+    }
+    report
+}
+
+/// Returns whether applying the record may store more than it removes, so a server over its
+/// memory limit rejects it.
+fn stores_more(record: &LogRecord) -> bool {
+    match record {
+        LogRecord::Put { .. } | LogRecord::Merge { .. } | LogRecord::Collection { .. } => true,
+        LogRecord::Delete { .. } | LogRecord::Checkpoint { .. } | LogRecord::Expire { .. } | LogRecord::BeginTxn | LogRecord::CommitTxn => false,
+        LogRecord::Commit { records } => records.iter().any(stores_more),
+        LogRecord::Timed { record, .. } => stores_more(record),
+    }
+}
+
+/// Returns the records logged for putting the entry holding the value: the put, then the entry's
+/// expiry if it has one, so the key expires again when the log is replayed.
+pub(crate) fn put_records(key: &[u8], value: Vec<u8>, entry: &Entry) -> Vec<LogRecord> {
+    let mut records = vec![LogRecord::Put { key: key.to_vec(), value }];
+    records.extend(entry.expires_at.map(|at| LogRecord::Expire { key: key.to_vec(), at }));
+    records
+}
+
+/// Returns the record logged for putting the entry holding the value, as `put_records` does.
+fn put_record(key: &[u8], value: Vec<u8>, entry: &Entry) -> LogRecord {
+    single_record(put_records(key, value, entry))
+}
+
+/// Returns the records as one record, a commit if there are several, so they are replayed all or nothing.
+fn single_record(mut records: Vec<LogRecord>) -> LogRecord {
+    match records.len() {
+        1 => records.remove(0),
+        _ => LogRecord::Commit { records },
+    }
+}
+
+/// Returns the decoded value if it matches the entry's checksum.
+fn verified<V: AsRef<[u8]>>(entry: &Entry, value: V) -> Result<V, FlowDbError> {
+    if !entry.verify(value.as_ref()) {
+        log::error!("Stored value version {} failed its checksum", entry.meta.version);
+        return Err(FlowDbError::CorruptValue);
+    }
+    Ok(value)
+}
+
+fn into_string(data: Vec<u8>) -> Result<String, FlowDbError> {
+    String::from_utf8(data).map_err(|_| FlowDbError::InvalidValue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{NoCompression, Zstd, SNAPPY_ID, ZSTD_ID, ZSTD_LEVEL};
+    use crate::entry::ENTRY_OVERHEAD;
+    use crate::replication::ReplicaError;
+
+    #[test]
+    fn test_new_storage_server() {
+        let num_partitions = 3;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.partition_count(), num_partitions);
+        assert_eq!(storage_server.replicas, num_replicas);
+    }
+
+    #[test]
+    fn test_get_partition() {
+        let num_partitions = 3;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let partition = storage_server.get_partition(b"test_key");
+        assert!(partition.read().unwrap().data.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_get() {
+        let num_partitions = 3;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "test_key";
+        let value = "test_value";
+        let compressed_value = Snappy.compress(value.as_bytes()).unwrap();
+        let partition = storage_server.get_partition(key.as_bytes());
+        let mut partition_guard = partition.write().unwrap();
+        partition_guard.data.insert(key.as_bytes().to_vec(), Entry { codec: SNAPPY_ID, ..Entry::new(compressed_value) });
+        drop(partition_guard); // Release the lock early.
+        let result = storage_server.get(key);
+        assert_eq!(result, Ok(value.to_owned()));
+    }
+
+    #[test]
+    fn test_put_replicas() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "test_key";
+        let value = "test_value";
+        let stored_value = value.as_bytes().to_vec();
+        let result = storage_server.put(key, value);
+        assert_eq!(result, Ok(None));
+        for i in 1..num_replicas {
+            let partition = storage_server.get_partition(key.as_bytes());
+            let replica = &partition.write().unwrap().replicas[i];
+            let replica_guard = replica.read().unwrap();
+            assert_eq!(replica_guard.data.get(key.as_bytes()).map(|entry| entry.value.to_vec()), Some(stored_value.clone()));
+        }
+    }
+
+    #[test]
+    fn test_put_existing_key() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "test_key";
+        let value1 = "test_value1";
+        let value2 = "test_value2";
+        let result = storage_server.put(key, value1);
+        assert_eq!(result, Ok(None));
+        let result = storage_server.put(key, value2);
+        assert_eq!(result, Ok(None));
+        let result = storage_server.get(key);
+        assert_eq!(result, Ok(value2.to_owned()));
+    }
+
+    #[test]
+    fn test_get_nonexistent_key() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "test_key".to_string();
+        let result = storage_server.get(key.clone());
+        assert_eq!(result, Err(FlowDbError::NotFound));
+    }
+
+    #[test]
+    fn test_delete() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "test_key";
+        storage_server.put(key, "test_value").unwrap();
+        assert_eq!(storage_server.delete(key), Ok(true));
+        assert_eq!(storage_server.get(key), Err(FlowDbError::NotFound));
+        let partition = storage_server.get_partition(key.as_bytes());
+        for replica in partition.read().unwrap().replicas.iter().skip(1) {
+            assert!(replica.read().unwrap().data.get(key.as_bytes()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_delete_nonexistent_key() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.delete("test_key"), Ok(false));
+    }
+
+    #[test]
+    fn test_multi_put_and_multi_get() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let pairs: Vec<(String, String)> = (0..20).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+        let pairs: Vec<(&str, &str)> = pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(storage_server.multi_put(&pairs), Ok(()));
+        for (key, value) in &pairs {
+            let partition = storage_server.get_partition(key.as_bytes());
+            for replica in partition.read().unwrap().replicas.iter() {
+                assert_eq!(replica.read().unwrap().data.get(key.as_bytes()).map(|entry| entry.value.to_vec()), Some(value.as_bytes().to_vec()));
+            }
+        }
+        let results = storage_server.multi_get(&["key3", "missing", "key0"]);
+        assert_eq!(results, vec![Ok("value3".to_owned()), Err(FlowDbError::NotFound), Ok("value0".to_owned())]);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "counter";
+        assert_eq!(storage_server.compare_and_swap(key, None, b"1"), Ok(Ok(())));
+        assert_eq!(storage_server.compare_and_swap(key, None, b"2"), Ok(Err(Some(b"1".to_vec()))));
+        assert_eq!(storage_server.compare_and_swap(key, Some(b"0"), b"2"), Ok(Err(Some(b"1".to_vec()))));
+        assert_eq!(storage_server.compare_and_swap(key, Some(b"1"), b"2"), Ok(Ok(())));
+        assert_eq!(storage_server.get(key), Ok("2".to_owned()));
+        let partition = storage_server.get_partition(key.as_bytes());
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(key.as_bytes()).map(|entry| entry.value.to_vec()), Some(b"2".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.scan().count(), 0);
+        for i in 0..10 {
+            storage_server.put(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = storage_server.scan().collect();
+        pairs.sort();
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = (0..10).map(|i| (format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())).collect();
+        expected.sort();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("user:1:name", "alice").unwrap();
+        storage_server.put("user:1:email", "alice@example.com").unwrap();
+        storage_server.put("user:2:name", "bob").unwrap();
+        storage_server.put("session:1", "token").unwrap();
+        let mut keys: Vec<Vec<u8>> = storage_server.scan_prefix("user:1:").map(|(key, _)| key).collect();
+        keys.sort();
+        assert_eq!(keys, vec![b"user:1:email".to_vec(), b"user:1:name".to_vec()]);
+        assert_eq!(storage_server.scan_prefix("missing:").count(), 0);
+    }
+
+    #[test]
+    fn test_range() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        for backend in [Backend::Hash, Backend::Ordered] {
+            let storage_server = StorageServer::with_backend(num_partitions, num_replicas, backend);
+            for key in ["a", "b", "c", "d", "e", "f"] {
+                storage_server.put(key, key.to_uppercase()).unwrap();
+            }
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = storage_server.range("b".."e").collect();
+            assert_eq!(pairs, vec![
+                (b"b".to_vec(), b"B".to_vec()),
+                (b"c".to_vec(), b"C".to_vec()),
+                (b"d".to_vec(), b"D".to_vec()),
+            ]);
+            let keys: Vec<Vec<u8>> = storage_server.range("d"..).map(|(key, _)| key).collect();
+            assert_eq!(keys, vec![b"d", b"e", b"f"]);
+        }
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let dir = "logs/test_binary_keys";
+        let _ = std::fs::remove_dir_all(dir);
+        let builder = || StorageServer::builder().partitions(4).replicas(2).backend(Backend::Ordered).wal(dir);
+        let key = |user: u32, seq: u64| [&user.to_be_bytes()[..], &seq.to_be_bytes()].concat();
+        {
+            let storage_server = builder().build().unwrap();
+            for seq in [3, 1, 256] {
+                storage_server.put(key(1, seq), seq.to_string()).unwrap();
+            }
+            storage_server.put(key(2, 1), "other").unwrap();
+            storage_server.put([0xff, 0x00], "not UTF-8").unwrap();
+        }
+
+        // Keys that aren't valid UTF-8 survive the log, and ranges come back in byte order.
+        let storage_server = builder().build().unwrap();
+        assert_eq!(storage_server.get(key(1, 256)), Ok("256".to_owned()));
+        assert_eq!(storage_server.get([0xff, 0x00]), Ok("not UTF-8".to_owned()));
+        let seqs: Vec<Vec<u8>> = storage_server.range(key(1, 0)..key(2, 0)).map(|(_, value)| value).collect();
+        assert_eq!(seqs, vec![b"1".to_vec(), b"3".to_vec(), b"256".to_vec()]);
+        assert_eq!(storage_server.scan_prefix(2u32.to_be_bytes()).count(), 1);
+
+        // Scan cursors hold the last key in a form that round-trips whatever its bytes.
+        let (page, next) = storage_server.scan_page(None, 1);
+        let next = Cursor::decode(&next.unwrap().encode()).unwrap();
+        let (rest, _) = storage_server.scan_page(Some(&next), 10);
+        assert_eq!(page.len() + rest.len(), 5);
+        assert!(!rest.contains(&page[0]));
+    }
+
+    #[test]
+    fn test_get_shared() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let uncompressed = StorageServer::new(num_partitions, num_replicas).with_codec(Arc::new(NoCompression));
+        uncompressed.put("key", "value").unwrap();
+        let value = uncompressed.get_shared("key").unwrap();
+        assert_eq!(&*value, b"value");
+
+        // Without compression the stored bytes are handed out as they are, and shared by every copy.
+        let partition = uncompressed.get_partition(b"key");
+        let partition_guard = partition.read().unwrap();
+        assert!(Arc::ptr_eq(&partition_guard.data.get(b"key").unwrap().value, &value));
+        for replica in partition_guard.replicas.iter() {
+            assert!(Arc::ptr_eq(&replica.read().unwrap().data.get(b"key").unwrap().value, &value));
+        }
+
+        let compressed = StorageServer::new(num_partitions, num_replicas);
+        compressed.put("key", [0, 255]).unwrap();
+        assert_eq!(compressed.get_shared("key").as_deref(), Ok(&[0, 255][..]));
+        assert_eq!(compressed.get_shared("missing"), Err(FlowDbError::NotFound));
+        compressed.sadd("set", &[b"member"]).unwrap();
+        assert_eq!(compressed.get_shared("set"), Err(FlowDbError::InvalidValue));
+    }
+
+    #[test]
+    fn test_min_compress_size() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let large = "value ".repeat(MIN_COMPRESS_SIZE);
+        storage_server.put("small", "value").unwrap();
+        storage_server.put("large", &large).unwrap();
+        let stored = |key: &str| storage_server.get_partition(key.as_bytes()).read().unwrap().data.get(key.as_bytes()).unwrap().into_owned();
+        assert_eq!(stored("small").codec, NO_COMPRESSION_ID);
+        assert_eq!(&*stored("small").value, b"value");
+        assert_eq!(stored("large").codec, SNAPPY_ID);
+        assert!(stored("large").value.len() < large.len());
+
+        // Both forms are read back, whether by key, by scan, or by range.
+        assert_eq!(storage_server.get("small"), Ok("value".to_owned()));
+        assert_eq!(storage_server.get("large"), Ok(large.clone()));
+        let mut pairs: Vec<_> = storage_server.scan().collect();
+        pairs.sort();
+        assert_eq!(pairs, vec![(b"large".to_vec(), large.clone().into_bytes()), (b"small".to_vec(), b"value".to_vec())]);
+        assert_eq!(storage_server.range("a"..).count(), 2);
+        assert_eq!(storage_server.stats().total_uncompressed_bytes(), (5 + large.len()) as u64);
+
+        // A value that grows past the threshold is compressed when it is rewritten.
+        storage_server.append("small", large.as_bytes()).unwrap();
+        assert_eq!(stored("small").codec, SNAPPY_ID);
+        assert_eq!(storage_server.get("small"), Ok(format!("value{}", large)));
+
+        let eager = StorageServer::new(num_partitions, num_replicas).with_min_compress_size(0);
+        eager.put("small", "value").unwrap();
+        assert_eq!(eager.get_partition(b"small").read().unwrap().data.get(b"small").unwrap().codec, SNAPPY_ID);
+        assert_eq!(eager.get("small"), Ok("value".to_owned()));
+    }
+
+    #[test]
+    fn test_codec_change() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let snapshot_path = "logs/test_codec_change.snap";
+        let snappy_value = "snappy ".repeat(MIN_COMPRESS_SIZE);
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("snappy", &snappy_value).unwrap();
+        assert_eq!(storage_server.snapshot(snapshot_path), Ok(1));
+
+        // Values written before the codec changed are read with the codec they were written with.
+        let restored = StorageServer::new(num_partitions, num_replicas).with_codec(Arc::new(NoCompression)).with_min_compress_size(0);
+        assert_eq!(restored.load_snapshot(snapshot_path), Ok(1));
+        restored.put("raw", "raw value").unwrap();
+        assert_eq!(restored.get_partition(b"raw").read().unwrap().data.get(b"raw").unwrap().codec, NO_COMPRESSION_ID);
+        assert_eq!(restored.get("snappy"), Ok(snappy_value.clone()));
+        assert_eq!(restored.scan().count(), 2);
+
+        let namespace = restored.create_namespace("snappy", NamespaceOptions::new(2, 1).codec(Arc::new(Snappy))).unwrap();
+        namespace.put("key", &snappy_value).unwrap();
+        assert_eq!(namespace.get_partition(b"key").read().unwrap().data.get(b"key").unwrap().codec, SNAPPY_ID);
+        assert_eq!(namespace.get("key"), Ok(snappy_value));
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_read_cache(1 << 20);
+        let cache = |storage_server: &StorageServer| storage_server.stats().cache.map(|metrics| (metrics.hits, metrics.misses, metrics.values));
+        let value = |i: usize| format!("value{} ", i).repeat(MIN_COMPRESS_SIZE);
+        storage_server.put("key", value(1)).unwrap();
+        assert_eq!(storage_server.get("key"), Ok(value(1)));
+        assert_eq!(storage_server.get_shared("key").as_deref(), Ok(value(1).as_bytes()));
+        assert_eq!(cache(&storage_server), Some((1, 1, 1)));
+
+        // Writes and deletes drop the cached value, so the new value is read.
+        storage_server.put("key", value(2)).unwrap();
+        assert_eq!(cache(&storage_server), Some((1, 1, 0)));
+        assert_eq!(storage_server.multi_get(&["key", "key"]), vec![Ok(value(2)), Ok(value(2))]);
+        assert_eq!(cache(&storage_server), Some((2, 2, 1)));
+        storage_server.delete("key").unwrap();
+        assert_eq!(storage_server.get("key"), Err(FlowDbError::NotFound));
+        assert_eq!(cache(&storage_server), Some((2, 2, 0)));
+        storage_server.put("key", value(3)).unwrap();
+        assert_eq!(storage_server.get("key"), Ok(value(3)));
+        storage_server.multi_put(&[("key", value(4))]).unwrap();
+        assert_eq!(cache(&storage_server), Some((2, 3, 0)));
+        assert_eq!(storage_server.get("key"), Ok(value(4)));
+        storage_server.delete("key").unwrap();
+
+        // Values stored uncompressed are read as they are, without going through the cache.
+        storage_server.put("small", "value").unwrap();
+        assert_eq!(storage_server.get("small"), Ok("value".to_owned()));
+        assert_eq!(cache(&storage_server), Some((2, 4, 0)));
+        assert_eq!(StorageServer::new(num_partitions, num_replicas).stats().cache, None);
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put_with_ttl("expired", "value", Duration::ZERO).unwrap();
+        storage_server.put_with_ttl("live", "value", Duration::from_secs(60)).unwrap();
+        storage_server.put("forever", "value").unwrap();
+        assert_eq!(storage_server.get("expired"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.get("live"), Ok("value".to_owned()));
+        assert_eq!(storage_server.scan().count(), 2);
+
+        // The expired key is still physically present until it is swept.
+        assert!(storage_server.get_partition(b"expired").read().unwrap().data.get(b"expired").is_some());
+        assert_eq!(storage_server.sweep_expired(), 1);
+        let partition = storage_server.get_partition(b"expired");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert!(replica.read().unwrap().data.get(b"expired").is_none());
+        }
+
+        // Expiring a key keeps its value until the TTL elapses.
+        assert_eq!(storage_server.expire("live", Duration::from_secs(1)), Ok(true));
+        assert_eq!(storage_server.get("live"), Ok("value".to_owned()));
+        assert_eq!(storage_server.expire("forever", Duration::ZERO), Ok(true));
+        assert_eq!(storage_server.get("forever"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.expire("expired", Duration::from_secs(60)), Ok(false));
+    }
+
+    #[test]
+    fn test_ttl_sweeper() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put_with_ttl("key", "value", Duration::from_millis(10)).unwrap();
+        let sweeper = storage_server.start_ttl_sweeper(Duration::from_millis(5));
+        std::thread::sleep(Duration::from_millis(100));
+        sweeper.stop();
+        assert!(storage_server.get_partition(b"key").read().unwrap().data.get(b"key").is_none());
+    }
+
+    #[test]
+    fn test_append() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "events";
+        storage_server.append(key, b"one\n").unwrap();
+        storage_server.append(key, b"two\n").unwrap();
+        assert_eq!(storage_server.get(key), Ok("one\ntwo\n".to_owned()));
+        let partition = storage_server.get_partition(key.as_bytes());
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(key.as_bytes()).map(|entry| entry.value.to_vec()), Some(b"one\ntwo\n".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "image";
+        let value: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe];
+        storage_server.put(key, &value).unwrap();
+        assert_eq!(storage_server.get_bytes(key), Ok(value.clone()));
+        assert_eq!(storage_server.get(key), Err(FlowDbError::InvalidValue));
+        assert_eq!(storage_server.multi_get_bytes(&[key]), vec![Ok(value)]);
+    }
+
+    #[test]
+    fn test_corrupt_value() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("key1", "value1").unwrap();
+        storage_server.put("key2", "value2").unwrap();
+
+        // Bytes that decompress cleanly but to the wrong value are caught by the checksum.
+        let partition = storage_server.get_partition(b"key1");
+        let mut entry = partition.read().unwrap().data.get(b"key1").unwrap().into_owned();
+        entry.value = Snappy.compress(b"garbage").unwrap().into();
+        entry.codec = SNAPPY_ID;
+        partition.write().unwrap().data.insert(b"key1".to_vec(), entry.clone());
+        assert_eq!(storage_server.get("key1"), Err(FlowDbError::CorruptValue));
+
+        // So are bytes that no longer decompress at all.
+        entry.value = vec![0xff; 4].into();
+        partition.write().unwrap().data.insert(b"key1".to_vec(), entry);
+        assert_eq!(storage_server.get_bytes("key1"), Err(FlowDbError::CorruptValue));
+        assert_eq!(storage_server.multi_get(&["key1", "key2"]), vec![Err(FlowDbError::CorruptValue), Ok("value2".to_owned())]);
+        assert_eq!(storage_server.compare_and_swap("key1", Some(b"value1"), b"value3"), Err(FlowDbError::CorruptValue));
+        assert_eq!(storage_server.put_if_absent("key1", "value3"), Err(FlowDbError::CorruptValue));
+
+        // Rewriting the key stores a fresh checksum.
+        storage_server.append("key2", b"!").unwrap();
+        assert_eq!(storage_server.get("key2"), Ok("value2!".to_owned()));
+    }
+
+    #[test]
+    fn test_typed_values() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct User {
+            name: String,
+            age: u32,
+        }
+
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let user = User { name: "alice".to_owned(), age: 42 };
+        for encoding in [Encoding::Json, Encoding::Bincode] {
+            let storage_server = StorageServer::new(num_partitions, num_replicas).with_encoding(encoding);
+            storage_server.put_typed("user:1", &user).unwrap();
+            assert_eq!(storage_server.get_typed::<User>("user:1"), Ok(User { name: "alice".to_owned(), age: 42 }));
+            assert_eq!(storage_server.get_typed::<User>("missing"), Err(FlowDbError::NotFound));
+        }
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put_typed("user:1", &user).unwrap();
+        assert_eq!(storage_server.get("user:1"), Ok(r#"{"name":"alice","age":42}"#.to_owned()));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let users = storage_server.create_namespace("users", NamespaceOptions::new(2, 1)).unwrap();
+        let cache = storage_server
+            .create_namespace("cache", NamespaceOptions::new(2, 1).compression(false).default_ttl(Duration::ZERO))
+            .unwrap();
+        assert!(storage_server.create_namespace("users", NamespaceOptions::new(2, 1)).is_err());
+
+        // Namespaces don't see each other's keys or the default keyspace.
+        users.put("key", "user").unwrap();
+        storage_server.put("key", "default").unwrap();
+        assert_eq!(users.get("key"), Ok("user".to_owned()));
+        assert_eq!(storage_server.get("key"), Ok("default".to_owned()));
+        assert_eq!(storage_server.namespace("users").unwrap().get("key"), Ok("user".to_owned()));
+
+        // The cache namespace stores values raw and expires them by default.
+        cache.put("key", "cached").unwrap();
+        assert_eq!(cache.get("key"), Err(FlowDbError::NotFound));
+        let partition = cache.get_partition(b"key");
+        assert_eq!(partition.read().unwrap().data.get(b"key").map(|entry| entry.value.to_vec()), Some(b"cached".to_vec()));
+
+        assert!(storage_server.drop_namespace("users"));
+        assert!(!storage_server.drop_namespace("users"));
+        assert!(storage_server.namespace("users").is_none());
+    }
+
+    #[test]
+    fn test_dictionary_compression() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let users = storage_server.create_namespace("users", NamespaceOptions::new(2, 1).dictionary_compression(200)).unwrap();
+        let document = |i: usize| format!(r#"{{"id":{},"name":"user{}","email":"user{}@example.com","active":true,"roles":["reader"]}}"#, i, i, i);
+        let stored_len = |server: &StorageServer, key: &str| server.get_partition(key.as_bytes()).read().unwrap().data.get(key.as_bytes()).unwrap().value.len();
+        for i in 0..200 {
+            users.put(format!("user:{}", i), document(i)).unwrap();
+        }
+
+        // Values put once the namespace has trained its dictionary are far smaller than with Snappy.
+        users.put("user:1000", document(1000)).unwrap();
+        storage_server.put("user:1000", document(1000)).unwrap();
+        assert_eq!(users.get_partition(b"user:1000").read().unwrap().data.get(b"user:1000").unwrap().codec, ZSTD_ID);
+        assert!(stored_len(&users, "user:1000") * 2 < stored_len(&storage_server, "user:1000"));
+        assert_eq!(users.get("user:1000"), Ok(document(1000)));
+        assert_eq!(users.get("user:0"), Ok(document(0)));
+
+        // Retraining on the namespace's values keeps every value readable.
+        assert_eq!(users.train_dictionary(150), Ok(150));
+        users.put("user:1001", document(1001)).unwrap();
+        assert_eq!(users.scan().count(), 202);
+        assert_eq!(users.get("user:1000"), Ok(document(1000)));
+        assert_eq!(storage_server.train_dictionary(10), Err(FlowDbError::Unsupported));
+    }
+
+    #[test]
+    fn test_dictionary_restart() {
+        let dir = "logs/test_dictionary_restart";
+        let _ = std::fs::remove_dir_all(dir);
+        let document = |i: usize| format!(r#"{{"id":{},"name":"user{}","email":"user{}@example.com","active":true,"roles":["reader"]}}"#, i, i, i);
+        let (data_dir, lsm_dir) = (format!("{}/data", dir), format!("{}/lsm", dir));
+        {
+            let flushed = StorageServer::open(&data_dir, 4, 1).unwrap().with_codec(Arc::new(Zstd::new(ZSTD_LEVEL)));
+            let lsm = StorageServer::with_lsm(&lsm_dir, 4, 1, LsmOptions::new().memtable_size(512)).unwrap().with_codec(Arc::new(Zstd::new(ZSTD_LEVEL)));
+            for server in [&flushed, &lsm] {
+                for i in 0..200 {
+                    server.put(format!("user:{}", i), document(i)).unwrap();
+                }
+                assert_eq!(server.train_dictionary(200), Ok(200));
+                server.put("user:1000", document(1000)).unwrap();
+                assert!(server.get_partition(b"user:1000").read().unwrap().data.get(b"user:1000").unwrap().value.len() < document(1000).len() / 2);
+                server.flush().unwrap();
+            }
+        }
+
+        // Reopened servers read the values compressed with the dictionary, whatever their codec.
+        let flushed = StorageServer::open(&data_dir, 4, 1).unwrap();
+        assert_eq!(flushed.get("user:1000"), Ok(document(1000)));
+        assert_eq!(flushed.get("user:0"), Ok(document(0)));
+        let lsm = StorageServer::with_lsm(&lsm_dir, 4, 1, LsmOptions::new()).unwrap().with_codec(Arc::new(Zstd::new(ZSTD_LEVEL)));
+        assert_eq!(lsm.get("user:1000"), Ok(document(1000)));
+
+        // A namespace keeps its dictionary in a directory of its own, and reloads it when recreated.
+        let users = flushed.create_namespace("users", NamespaceOptions::new(2, 1).dictionary_compression(100)).unwrap();
+        for i in 0..100 {
+            users.put(format!("user:{}", i), document(i)).unwrap();
+        }
+        let codec = Arc::new(Zstd::trained(ZSTD_LEVEL, 100));
+        let reopened = StorageServer::open(&data_dir, 4, 1).unwrap();
+        reopened.create_namespace("users", NamespaceOptions::new(2, 1).codec(Arc::clone(&codec) as Arc<dyn Codec>)).unwrap();
+        assert!(codec.dictionary().is_some());
+        assert!(matches!(reopened.create_namespace("../users", NamespaceOptions::new(2, 1)), Err(FlowDbError::InvalidArgument)));
+    }
+
+    #[test]
+    fn test_contains_key_and_len() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert!(storage_server.is_empty());
+        storage_server.put("a", "1").unwrap();
+        storage_server.put("b", "2").unwrap();
+        storage_server.put_with_ttl("expired", "3", Duration::ZERO).unwrap();
+        assert!(storage_server.contains_key("a"));
+        assert!(!storage_server.contains_key("expired"));
+        assert!(!storage_server.contains_key("missing"));
+        assert_eq!(storage_server.len(), 2);
+        assert!(!storage_server.is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let value = "x".repeat(1000);
+        for i in 0..10 {
+            storage_server.put(format!("key{}", i), &value).unwrap();
+        }
+        let stats = storage_server.stats();
+        assert_eq!(stats.partitions.len(), num_partitions);
+        assert!(stats.partitions.iter().all(|partition| partition.replicas == num_replicas));
+        assert_eq!(stats.total_keys(), 10);
+        assert_eq!(stats.total_uncompressed_bytes(), 10_000);
+        assert!(stats.total_compressed_bytes() < stats.total_uncompressed_bytes());
+        assert_eq!(stats.log, None);
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "lock";
+        assert_eq!(storage_server.put_if_absent(key, "owner1"), Ok(Ok(())));
+        assert_eq!(storage_server.put_if_absent(key, "owner2"), Ok(Err(b"owner1".to_vec())));
+        assert_eq!(storage_server.get(key), Ok("owner1".to_owned()));
+        let partition = storage_server.get_partition(key.as_bytes());
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(key.as_bytes()).map(|entry| entry.value.to_vec()), Some(b"owner1".to_vec()));
+        }
+
+        // An expired holder no longer blocks new writers.
+        storage_server.put_with_ttl("lease", "owner1", Duration::ZERO).unwrap();
+        assert_eq!(storage_server.put_if_absent("lease", "owner2"), Ok(Ok(())));
+    }
+
+    #[test]
+    fn test_update() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key = "counter";
+        let increment = |old: Option<&[u8]>| {
+            let count: u64 = old.map(|v| std::str::from_utf8(v).unwrap().parse().unwrap()).unwrap_or(0);
+            Some((count + 1).to_string().into_bytes())
+        };
+        assert_eq!(storage_server.update(key, increment), Ok(Some(b"1".to_vec())));
+        assert_eq!(storage_server.update(key, increment), Ok(Some(b"2".to_vec())));
+        let partition = storage_server.get_partition(key.as_bytes());
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(key.as_bytes()).map(|entry| entry.value.to_vec()), Some(b"2".to_vec()));
+        }
+        assert_eq!(storage_server.update(key, |_| None), Ok(None));
+        assert!(!storage_server.contains_key(key));
+    }
+
+    #[test]
+    fn test_update_concurrent() {
+        let num_partitions = 2;
+        let num_replicas = 2;
+        let storage_server = Arc::new(StorageServer::new(num_partitions, num_replicas));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let storage_server = Arc::clone(&storage_server);
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        storage_server
+                            .update("counter", |old| {
+                                let count: u64 = old.map(|v| std::str::from_utf8(v).unwrap().parse().unwrap()).unwrap_or(0);
+                                Some((count + 1).to_string().into_bytes())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(storage_server.get("counter"), Ok("400".to_owned()));
+    }
+
+    #[test]
+    fn test_update_other_keys() {
+        let storage_server = Arc::new(StorageServer::new(1, 2));
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let (release, wait_release) = std::sync::mpsc::channel::<()>();
+        let updater = {
+            let storage_server = Arc::clone(&storage_server);
+            std::thread::spawn(move || {
+                storage_server.update("slow", |_| {
+                    started.send(()).unwrap();
+                    wait_release.recv().unwrap();
+                    Some(b"done".to_vec())
+                })
+            })
+        };
+
+        // While the update of one key runs, other keys of its partition can still be written.
+        wait_started.recv().unwrap();
+        storage_server.put("fast", "value").unwrap();
+        storage_server.append("fast", b"2").unwrap();
+        assert_eq!(storage_server.get("fast"), Ok("value2".to_owned()));
+        assert_eq!(storage_server.get("slow"), Err(FlowDbError::NotFound));
+        release.send(()).unwrap();
+        assert_eq!(updater.join().unwrap(), Ok(Some(b"done".to_vec())));
+        assert_eq!(storage_server.get("slow"), Ok("done".to_owned()));
+    }
+
+    #[test]
+    fn test_batched_puts() {
+        let log_path = "logs/test_batched_puts";
+        let _ = fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let storage_server = Arc::new(StorageServer::new(1, 2).with_transaction_log(Arc::new(Mutex::new(log))));
+        let put = |key: &'static str| {
+            let storage_server = Arc::clone(&storage_server);
+            std::thread::spawn(move || storage_server.put(key, "value"))
+        };
+
+        // Puts queued while another is being applied are applied together, and logged as one commit.
+        let partition = storage_server.get_partition(b"first");
+        let partition_guard = partition.write().unwrap();
+        let first = put("first");
+        std::thread::sleep(Duration::from_millis(100));
+        let queued: Vec<_> = ["key1", "key2", "key3"].into_iter().map(put).collect();
+        std::thread::sleep(Duration::from_millis(100));
+        drop(partition_guard);
+        assert!(first.join().unwrap().is_ok());
+        for put in queued {
+            assert!(put.join().unwrap().is_ok());
+        }
+        let logged = TransactionLog::read_all(log_path, 8192).unwrap();
+        assert_eq!(logged.len(), 2);
+        assert!(matches!(&logged[1], LogRecord::Commit { records } if records.len() == 3));
+        for key in ["first", "key1", "key2", "key3"] {
+            assert_eq!(storage_server.get(key), Ok("value".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_reads_during_log_writes() {
+        let log_path = "logs/test_reads_during_log_writes";
+        let _ = fs::remove_dir_all(log_path);
+        let log = Arc::new(Mutex::new(TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap()));
+        let storage_server = Arc::new(StorageServer::new(1, 2).with_transaction_log(Arc::clone(&log)));
+        storage_server.put("key", "value1").unwrap();
+
+        // A put stuck writing its record doesn't hold up reads of its partition, and is applied once logged.
+        let log_guard = log.lock().unwrap();
+        let writer = {
+            let storage_server = Arc::clone(&storage_server);
+            std::thread::spawn(move || storage_server.put("key", "value2"))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(storage_server.get("key"), Ok("value1".to_owned()));
+        assert_eq!(storage_server.len(), 1);
+        drop(log_guard);
+        writer.join().unwrap().unwrap();
+        assert_eq!(storage_server.get("key"), Ok("value2".to_owned()));
+
+        // A snapshot covers every logged write, so the log can be truncated after it.
+        assert_eq!(storage_server.snapshot(format!("{}.snapshot", log_path)), Ok(1));
+        assert_eq!(TransactionLog::<LogRecord>::read_all(log_path, 8192).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_transaction_commit_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_transaction_log(Arc::new(Mutex::new(log)));
+        storage_server.put("alice", "100").unwrap();
+        storage_server.put("bob", "0").unwrap();
+
+        let mut txn = storage_server.begin();
+        txn.put("alice", "60");
+        txn.put("bob", "40");
+        txn.delete("temp");
+        assert_eq!(txn.get("alice"), Ok(b"60".to_vec()));
+        assert_eq!(storage_server.get("alice"), Ok("100".to_owned()));
+        assert_eq!(txn.commit(), Ok(()));
+        assert_eq!(storage_server.get("alice"), Ok("60".to_owned()));
+        assert_eq!(storage_server.get("bob"), Ok("40".to_owned()));
+
+        let logged: Vec<LogRecord> = TransactionLog::read_all(log_path, 8192).unwrap();
+        assert_eq!(logged[2..], [LogRecord::Commit {
+            records: vec![
+                LogRecord::Put { key: b"alice".to_vec(), value: b"60".to_vec() },
+                LogRecord::Put { key: b"bob".to_vec(), value: b"40".to_vec() },
+                LogRecord::Delete { key: b"temp".to_vec() },
+            ],
+        }]);
+    }
+
+    #[test]
+    fn test_transaction_conflict() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("alice", "100").unwrap();
+        storage_server.put("bob", "0").unwrap();
+        let balance = |txn: &mut Txn, key: &str| String::from_utf8(txn.get(key).unwrap()).unwrap().parse::<i64>().unwrap();
+
+        // Of two transactions that read the same balance, only the first to commit is applied.
+        let mut debit = storage_server.begin();
+        let mut credit = storage_server.begin();
+        let (alice, bob) = (balance(&mut debit, "alice"), balance(&mut credit, "bob"));
+        let alice_again = balance(&mut credit, "alice");
+        debit.put("alice", (alice - 30).to_string());
+        credit.put("alice", (alice_again + 10).to_string());
+        credit.put("bob", (bob - 10).to_string());
+        assert_eq!(debit.commit(), Ok(()));
+        assert_eq!(credit.commit(), Err(FlowDbError::Conflict));
+        assert_eq!(storage_server.get("alice"), Ok("70".to_owned()));
+        assert_eq!(storage_server.get("bob"), Ok("0".to_owned()));
+
+        // Concurrent transfers retried on conflict never lose an update.
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        loop {
+                            let mut txn = storage_server.begin();
+                            let (alice, bob) = (balance(&mut txn, "alice"), balance(&mut txn, "bob"));
+                            txn.put("alice", (alice - 1).to_string());
+                            txn.put("bob", (bob + 1).to_string());
+                            match txn.commit() {
+                                Ok(()) => break,
+                                Err(FlowDbError::Conflict) => continue,
+                                Err(e) => panic!("transfer failed: {}", e),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(storage_server.get("alice"), Ok("-30".to_owned()));
+        assert_eq!(storage_server.get("bob"), Ok("100".to_owned()));
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("alice", "100").unwrap();
+        let mut txn = storage_server.begin();
+        txn.put("alice", "0");
+        txn.rollback();
+        assert_eq!(storage_server.get("alice"), Ok("100".to_owned()));
+    }
+
+    #[test]
+    fn test_watch() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        let key_events = storage_server.watch("user:1");
+        let prefix_events = storage_server.watch_prefix("user:");
+        storage_server.put("user:1", "alice").unwrap();
+        storage_server.put("user:2", "bob").unwrap();
+        storage_server.put("session:1", "token").unwrap();
+        storage_server.delete("user:1").unwrap();
+        storage_server.delete("user:3").unwrap();
+
+        let key_events: Vec<ChangeEvent> = key_events.try_iter().collect();
+        assert_eq!(key_events, vec![
+            ChangeEvent::Put { key: b"user:1".to_vec(), value: b"alice".to_vec() },
+            ChangeEvent::Delete { key: b"user:1".to_vec() },
+        ]);
+        let prefix_keys: Vec<Vec<u8>> = prefix_events.try_iter().map(|event| event.key().to_vec()).collect();
+        assert_eq!(prefix_keys, vec![b"user:1", b"user:2", b"user:1"]);
+    }
+
+    #[test]
+    fn test_scan_page() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        for i in 0..25 {
+            storage_server.put(format!("key{:02}", i), format!("value{}", i)).unwrap();
+        }
+        let mut keys = Vec::new();
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let (page, next) = storage_server.scan_page(cursor.as_ref(), 7);
+            assert!(page.len() <= 7);
+            keys.extend(page.into_iter().map(|(key, _)| key));
+            match next {
+                // Cursors survive a round trip through their string form.
+                Some(next) => cursor = Some(Cursor::decode(&next.encode()).unwrap()),
+                None => break,
+            }
+        }
+        keys.sort();
+        let expected: Vec<Vec<u8>> = (0..25).map(|i| format!("key{:02}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(Cursor::decode("not a cursor"), None);
+
+        // Pages of a prefix scan only hold matching keys.
+        storage_server.put("kez", "value").unwrap();
+        let mut keys = Vec::new();
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let (page, next) = storage_server.scan_prefix_page("key1", cursor.as_ref(), 3);
+            keys.extend(page.into_iter().map(|(key, _)| key));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        keys.sort();
+        let expected: Vec<Vec<u8>> = (10..20).map(|i| format!("key{}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_get_with_meta() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("key", "v1").unwrap();
+        let (value, first) = storage_server.get_with_meta("key").unwrap();
+        assert_eq!(value, b"v1");
+        storage_server.multi_put(&[("other", "x")]).unwrap();
+        storage_server.put("key", "v2").unwrap();
+        let (value, second) = storage_server.get_with_meta("key").unwrap();
+        assert_eq!(value, b"v2");
+        assert!(second.version > first.version);
+        assert_eq!(second.created_at, first.created_at);
+        assert!(second.modified_at >= first.modified_at);
+        assert!(storage_server.get_with_meta("other").unwrap().1.version > first.version);
+
+        // Replicas carry the same metadata as the primary.
+        let partition = storage_server.get_partition(b"key");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(b"key").map(|entry| entry.meta), Some(second));
+        }
+
+        // Recreating a deleted key starts a new creation time.
+        storage_server.delete("key").unwrap();
+        storage_server.put("key", "v3").unwrap();
+        assert!(storage_server.get_with_meta("key").unwrap().1.created_at >= second.modified_at);
+        assert_eq!(storage_server.get_with_meta("missing"), Err(FlowDbError::NotFound));
+    }
+
+    #[test]
+    fn test_import() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let mut binary = Vec::new();
+        for i in 0..100 {
+            bulk::write_record(&mut binary, DumpFormat::Binary, format!("key{}", i).as_bytes(), &[i as u8, 0, 255]).unwrap();
+        }
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.import(binary.as_slice(), DumpFormat::Binary), Ok(100));
+        assert_eq!(storage_server.len(), 100);
+        assert_eq!(storage_server.get_bytes("key42"), Ok(vec![42, 0, 255]));
+
+        let ndjson = "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\n\n";
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.import(ndjson.as_bytes(), DumpFormat::Ndjson), Ok(2));
+        assert_eq!(storage_server.get("b"), Ok("2".to_owned()));
+        assert!(matches!(storage_server.import("not json\n".as_bytes(), DumpFormat::Ndjson), Err(FlowDbError::Io(_))));
+        assert!(matches!(storage_server.import([0u8, 0, 0, 9, b'k'].as_slice(), DumpFormat::Binary), Err(FlowDbError::Io(_))));
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        for i in 0..50 {
+            storage_server.put(format!("user:{}", i), format!("name{}", i)).unwrap();
+            storage_server.put(format!("session:{}", i), [i as u8, 0xff]).unwrap();
+        }
+        for format in [DumpFormat::Binary, DumpFormat::Ndjson] {
+            let mut dump = Vec::new();
+            assert_eq!(storage_server.export(&mut dump, format, Some("user:".as_bytes())), Ok(50));
+            let restored = StorageServer::new(num_partitions, num_replicas);
+            assert_eq!(restored.import(dump.as_slice(), format), Ok(50));
+            assert_eq!(restored.get("user:7"), Ok("name7".to_owned()));
+            assert!(!restored.contains_key("session:7"));
+        }
+
+        // Binary values can't be represented in NDJSON, but round-trip through the binary format.
+        assert!(matches!(storage_server.export(Vec::new(), DumpFormat::Ndjson, None), Err(FlowDbError::Io(_))));
+        let mut dump = Vec::new();
+        assert_eq!(storage_server.export(&mut dump, DumpFormat::Binary, None), Ok(100));
+        let restored = StorageServer::new(num_partitions, num_replicas);
+        restored.import(dump.as_slice(), DumpFormat::Binary).unwrap();
+        assert_eq!(restored.get_bytes("session:7"), Ok(vec![7, 0xff]));
+    }
+
+    #[test]
+    fn test_merge() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_merge_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let add: MergeFn = Box::new(|existing, operand| {
+            let parse = |bytes: &[u8]| std::str::from_utf8(bytes).unwrap().parse::<i64>().unwrap();
+            (existing.map_or(0, parse) + parse(operand)).to_string().into_bytes()
+        });
+        let storage_server = StorageServer::new(num_partitions, num_replicas)
+            .with_transaction_log(Arc::new(Mutex::new(log)))
+            .with_merge_operator(add);
+        storage_server.merge("counter", "5").unwrap();
+        storage_server.merge("counter", "-2").unwrap();
+        assert_eq!(storage_server.get("counter"), Ok("3".to_owned()));
+        let partition = storage_server.get_partition(b"counter");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(b"counter").map(|entry| entry.value.to_vec()), Some(b"3".to_vec()));
+        }
+        let logged: Vec<LogRecord> = TransactionLog::read_all(log_path, 8192).unwrap();
+        assert_eq!(logged, vec![
+            LogRecord::Merge { key: b"counter".to_vec(), operand: b"5".to_vec() },
+            LogRecord::Merge { key: b"counter".to_vec(), operand: b"-2".to_vec() },
+        ]);
+
+        let without_operator = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(without_operator.merge("counter", "1"), Err(FlowDbError::Unsupported));
+    }
+
+    #[test]
+    fn test_writes_are_logged() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_writes_are_logged_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_transaction_log(Arc::new(Mutex::new(log)));
+        storage_server.put("key1", "value1").unwrap();
+        storage_server.append("key1", b"!").unwrap();
+        storage_server.compare_and_swap("key2", None, b"value2").unwrap().unwrap();
+        storage_server.delete("key2").unwrap();
+        storage_server.delete("missing").unwrap();
+        storage_server.update("key1", |_| None).unwrap();
+        storage_server.multi_put(&[("key3", "value3")]).unwrap();
+        let logged: Vec<LogRecord> = TransactionLog::read_all(log_path, 8192).unwrap();
+        assert_eq!(logged, vec![
+            LogRecord::Put { key: b"key1".to_vec(), value: b"value1".to_vec() },
+            LogRecord::Put { key: b"key1".to_vec(), value: b"value1!".to_vec() },
+            LogRecord::Put { key: b"key2".to_vec(), value: b"value2".to_vec() },
+            LogRecord::Delete { key: b"key2".to_vec() },
+            LogRecord::Delete { key: b"key1".to_vec() },
+            LogRecord::Commit { records: vec![LogRecord::Put { key: b"key3".to_vec(), value: b"value3".to_vec() }] },
+        ]);
+    }
+
+    #[test]
+    fn test_recover() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_recover_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        {
+            let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+            let storage_server = StorageServer::new(num_partitions, num_replicas).with_transaction_log(Arc::new(Mutex::new(log)));
+            storage_server.put("key1", "value1").unwrap();
+            storage_server.put("key2", "value2").unwrap();
+            storage_server.delete("key1").unwrap();
+            let mut txn = storage_server.begin();
+            txn.put("key3", "value3");
+            txn.commit().unwrap();
+        }
+        // A transaction cut short by a crash is dropped as a whole.
+        let mut log: TransactionLog = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        log.write(&[LogRecord::BeginTxn.encode(), LogRecord::Put { key: b"key4".to_vec(), value: b"value4".to_vec() }.encode()].concat()).unwrap();
+        drop(log);
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.recover(log_path), Ok(4));
+        assert_eq!(storage_server.get("key4"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.get("key1"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.get("key2"), Ok("value2".to_owned()));
+        assert_eq!(storage_server.get("key3"), Ok("value3".to_owned()));
+        let partition = storage_server.get_partition(b"key2");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(b"key2").map(|entry| entry.value.to_vec()), Some(b"value2".to_vec()));
+        }
+        assert_eq!(storage_server.recover("logs/test_recover_missing.log"), Ok(0));
+    }
+
+    #[test]
+    fn test_recover_ttl() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_recover_ttl_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_transaction_log(Arc::new(Mutex::new(log)));
+        storage_server.put_with_ttl("expired", "value", Duration::ZERO).unwrap();
+        storage_server.put_with_ttl("lease", "owner", Duration::from_secs(60)).unwrap();
+        storage_server.append("lease", b"!").unwrap();
+        storage_server.put("session", "value").unwrap();
+        assert_eq!(storage_server.expire("session", Duration::ZERO), Ok(true));
+        storage_server.put("plain", "value").unwrap();
+        let expires_at = |server: &StorageServer, key: &str| server.live_entry(&server.get_partition(key.as_bytes()), key.as_bytes()).unwrap().and_then(|entry| entry.expires_at);
+        let lease_expires_at = expires_at(&storage_server, "lease");
+        assert!(lease_expires_at.is_some());
+
+        // Keys come back with the time they expire at, so expired ones stay gone.
+        let recovered = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(recovered.recover(log_path), Ok(6));
+        assert_eq!(recovered.get("expired"), Err(FlowDbError::NotFound));
+        assert_eq!(recovered.get("session"), Err(FlowDbError::NotFound));
+        assert_eq!(recovered.get("lease"), Ok("owner!".to_owned()));
+        assert_eq!(expires_at(&recovered, "lease"), lease_expires_at);
+        assert_eq!(recovered.get("plain"), Ok("value".to_owned()));
+        assert_eq!(expires_at(&recovered, "plain"), None);
+    }
+
+    #[test]
+    fn test_recover_collections() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_recover_collections_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        {
+            let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+            let storage_server = StorageServer::new(num_partitions, num_replicas).with_transaction_log(Arc::new(Mutex::new(log)));
+            storage_server.lpush("list", &[b"a", b"b"]).unwrap();
+            storage_server.lpush("list", &[b"c"]).unwrap();
+            storage_server.sadd("set", &[b"x", b"y"]).unwrap();
+            storage_server.hset("hash", b"name", b"alice").unwrap();
+            storage_server.hset("hash", b"name", b"bob").unwrap();
+            storage_server.hset("gone", b"field", b"value").unwrap();
+            storage_server.delete("gone").unwrap();
+        }
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.recover(log_path), Ok(7));
+        assert_eq!(storage_server.lrange("list", 0, -1), Ok(vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]));
+        assert_eq!(storage_server.smembers("set"), Ok(vec![b"x".to_vec(), b"y".to_vec()]));
+        assert_eq!(storage_server.hget("hash", b"name"), Ok(Some(b"bob".to_vec())));
+        assert!(!storage_server.contains_key("gone"));
+        let partition = storage_server.get_partition(b"set");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert!(replica.read().unwrap().data.get(b"set").unwrap().is_collection());
+        }
+    }
+
+    #[test]
+    fn test_lsm() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let data_dir = "logs/test_lsm";
+        let _ = std::fs::remove_dir_all(data_dir);
+        {
+            let storage_server = StorageServer::with_lsm(data_dir, num_partitions, num_replicas, LsmOptions::new().memtable_size(512)).unwrap();
+            for i in 0..50 {
+                storage_server.put(format!("key{:02}", i), format!("value{}", i)).unwrap();
+            }
+            assert!(storage_server.delete("key07").unwrap());
+        }
+        let storage_server = StorageServer::with_lsm(data_dir, num_partitions, num_replicas, LsmOptions::new().memtable_size(512)).unwrap();
+        assert_eq!(storage_server.len(), 49);
+        assert_eq!(storage_server.get("key42"), Ok("value42".to_owned()));
+        assert_eq!(storage_server.get("key07"), Err(FlowDbError::NotFound));
+        let partition = storage_server.get_partition(b"key42");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert_eq!(replica.read().unwrap().data.get(b"key42").map(|entry| entry.value.to_vec()), Some(b"value42".to_vec()));
+        }
+        let range: Vec<_> = storage_server.range("key10".."key13").map(|(key, _)| key).collect();
+        assert_eq!(range, vec![b"key10", b"key11", b"key12"]);
+    }
+
+    #[test]
+    fn test_partitioner() {
+        let data_dir = "logs/test_partitioner";
+        let _ = std::fs::remove_dir_all(data_dir);
+        let open = |partitioner| StorageServer::with_lsm(data_dir, 4, 1, LsmOptions::new()).unwrap().with_partitioner(partitioner);
+        {
+            let storage_server = open(Partitioner::SipHash);
+            for i in 0..50 {
+                storage_server.put(format!("key{:02}", i), format!("value{}", i)).unwrap();
+            }
+            storage_server.flush().unwrap();
+        }
+
+        // Trees partitioned with the legacy hash only find their keys when reopened with it.
+        let found = |storage_server: &StorageServer| (0..50).filter(|i| storage_server.get(format!("key{:02}", i)).is_ok()).count();
+        assert!(found(&open(Partitioner::XxHash64)) < 50);
+        let storage_server = open(Partitioner::SipHash);
+        assert_eq!(found(&storage_server), 50);
+        assert_eq!(storage_server.with_virtual_nodes(16).ring(), HashRing::with_partitioner(4, 16, Partitioner::SipHash));
+        assert_eq!(StorageServer::new(4, 1).ring().partitioner(), Partitioner::XxHash64);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let snapshot_path = "logs/test_snapshot.snap";
+        let log_path = "logs/test_snapshot_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_transaction_log(Arc::new(Mutex::new(log)));
+        storage_server.put("key1", "value1").unwrap();
+        storage_server.put_with_ttl("key2", "value2", Duration::from_secs(60)).unwrap();
+        let meta = storage_server.get_with_meta("key1").unwrap().1;
+        assert_eq!(storage_server.snapshot(snapshot_path), Ok(2));
+        assert_eq!(TransactionLog::<LogRecord>::read_all(log_path, 8192).unwrap(), vec![]);
+        storage_server.put("key3", "value3").unwrap();
+
+        // Restart from the snapshot plus the writes logged after it.
+        let restored = StorageServer::new(2, num_replicas);
+        assert_eq!(restored.load_snapshot(snapshot_path), Ok(2));
+        assert_eq!(restored.recover(log_path), Ok(1));
+        assert_eq!(restored.get_with_meta("key1"), Ok((b"value1".to_vec(), meta)));
+        assert_eq!(restored.get("key3"), Ok("value3".to_owned()));
+        let partition = restored.get_partition(b"key2");
+        assert!(partition.read().unwrap().replicas.iter().all(|replica| replica.read().unwrap().data.get(b"key2").unwrap().expires_at.is_some()));
+    }
+
+    #[test]
+    fn test_with_engine() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::with_engine(num_partitions, num_replicas, |_, _| Box::new(std::collections::BTreeMap::new()));
+        storage_server.put("b", "2").unwrap();
+        storage_server.put("a", "1").unwrap();
+        storage_server.put("c", "3").unwrap();
+        assert!(storage_server.delete("c").unwrap());
+        assert_eq!(storage_server.get("a"), Ok("1".to_owned()));
+        let keys: Vec<_> = storage_server.range("a"..).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"a", b"b"]);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::with_memory_budget("logs/test_memory_budget", num_partitions, num_replicas, 16 * 1024).unwrap();
+        for i in 0..1000 {
+            storage_server.put(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        assert_eq!(storage_server.len(), 1000);
+        assert_eq!(storage_server.get("key0"), Ok("value0".to_owned()));
+        assert_eq!(storage_server.get("key999"), Ok("value999".to_owned()));
+        assert_eq!(storage_server.scan().count(), 1000);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let num_partitions = 1;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_eviction(500, EvictionPolicy::Lru);
+        for i in 0..6 {
+            storage_server.put(format!("key{}", i), [b'x'; 100]).unwrap();
+        }
+        assert_eq!(storage_server.len(), 6);
+        assert!(storage_server.get("key0").is_ok());
+        storage_server.put("key6", [b'x'; 100]).unwrap();
+        storage_server.put("key7", [b'x'; 100]).unwrap();
+        assert!(storage_server.len() < 8);
+        assert!(storage_server.contains_key("key0"));
+        assert!(storage_server.contains_key("key7"));
+        assert!(!storage_server.contains_key("key1"));
+        let partition = storage_server.get_partition(b"key1");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert!(replica.read().unwrap().data.get(b"key1").is_none());
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        // Each key counts its 4 bytes, its value stored as it is and the overhead, on both copies.
+        let per_key = 2 * (4 + 32 + ENTRY_OVERHEAD);
+        let storage_server = StorageServer::new(2, 2).with_memory_limit(5 * per_key, MemoryPolicy::Reject);
+        for i in 0..6 {
+            storage_server.put(format!("key{}", i), [b'x'; 32]).unwrap();
+        }
+        assert_eq!(storage_server.stats().total_memory_bytes(), 6 * per_key as u64);
+        assert_eq!(storage_server.put("key6", [b'x'; 32]), Err(FlowDbError::Backpressure));
+        assert_eq!(storage_server.lpush("list", &[b"a"]), Err(FlowDbError::Backpressure));
+
+        // Deletes are still applied and make room again.
+        assert!(storage_server.delete("key0").unwrap());
+        assert!(storage_server.delete("key1").unwrap());
+        storage_server.put("key6", [b'x'; 32]).unwrap();
+        assert_eq!(storage_server.stats().total_memory_bytes(), 5 * per_key as u64);
+
+        // A server that evicts keeps the keys written last within its limit.
+        let storage_server = StorageServer::new(1, 2).with_memory_limit(5 * per_key, MemoryPolicy::Evict);
+        for i in 0..10 {
+            storage_server.put(format!("key{}", i), [b'x'; 32]).unwrap();
+        }
+        assert_eq!(storage_server.stats().total_memory_bytes(), 5 * per_key as u64);
+        assert_eq!(storage_server.len(), 5);
+        assert!(storage_server.contains_key("key9") && !storage_server.contains_key("key4"));
+
+        // So does one writing many keys at once.
+        let pairs: Vec<_> = (10..20).map(|i| (format!("k{:03}", i), [b'x'; 32])).collect();
+        storage_server.multi_put(&pairs).unwrap();
+        assert_eq!(storage_server.stats().total_memory_bytes(), 5 * per_key as u64);
+        assert!(storage_server.contains_key("k019") && !storage_server.contains_key("k014") && !storage_server.contains_key("key9"));
+    }
+
+    #[test]
+    fn test_collections() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(storage_server.lpush("list", &[b"a", b"b"]), Ok(2));
+        assert_eq!(storage_server.lpush("list", &[b"c"]), Ok(3));
+        assert_eq!(storage_server.lrange("list", 0, -1), Ok(vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]));
+        assert_eq!(storage_server.lrange("list", -2, 10), Ok(vec![b"b".to_vec(), b"a".to_vec()]));
+        assert_eq!(storage_server.lrange("list", 2, 1), Ok(vec![]));
+        assert_eq!(storage_server.lrange("missing", 0, -1), Ok(vec![]));
+
+        assert_eq!(storage_server.sadd("set", &[b"x", b"y", b"x"]), Ok(2));
+        assert_eq!(storage_server.sadd("set", &[b"y", b"z"]), Ok(1));
+        assert_eq!(storage_server.smembers("set"), Ok(vec![b"x".to_vec(), b"y".to_vec(), b"z".to_vec()]));
+
+        assert_eq!(storage_server.hset("hash", b"name", b"alice"), Ok(true));
+        assert_eq!(storage_server.hset("hash", b"name", b"bob"), Ok(false));
+        assert_eq!(storage_server.hget("hash", b"name"), Ok(Some(b"bob".to_vec())));
+        assert_eq!(storage_server.hget("hash", b"age"), Ok(None));
+
+        // Collections are replicated, and type mismatches are rejected.
+        let partition = storage_server.get_partition(b"set");
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert!(replica.read().unwrap().data.get(b"set").unwrap().is_collection());
+        }
+        storage_server.put("plain", "value").unwrap();
+        assert_eq!(storage_server.lpush("plain", &[b"a"]), Err(FlowDbError::InvalidValue));
+        assert_eq!(storage_server.smembers("list"), Err(FlowDbError::InvalidValue));
+        assert_eq!(storage_server.get("list"), Err(FlowDbError::InvalidValue));
+        assert_eq!(storage_server.scan().count(), 1);
+        assert!(storage_server.delete("list").unwrap());
+    }
+
+    #[test]
+    fn test_open_and_flush() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let data_dir = "logs/test_open_and_flush";
+        let _ = std::fs::remove_dir_all(data_dir);
+        {
+            let storage_server = StorageServer::open(data_dir, num_partitions, num_replicas).unwrap();
+            storage_server.put("key1", "value1").unwrap();
+            storage_server.put("key2", "value2").unwrap();
+            storage_server.sadd("set", &[b"a"]).unwrap();
+            storage_server.flush().unwrap();
+            storage_server.delete("key2").unwrap();
+        }
+        let storage_server = StorageServer::open(data_dir, num_partitions, num_replicas).unwrap();
+        assert_eq!(storage_server.get("key1"), Ok("value1".to_owned()));
+        assert_eq!(storage_server.get("key2"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.smembers("set"), Ok(vec![b"a".to_vec()]));
+        let version = storage_server.get_with_meta("key1").unwrap().1.version;
+        storage_server.put("key3", "value3").unwrap();
+        assert!(storage_server.get_with_meta("key3").unwrap().1.version > version);
+        drop(storage_server);
+
+        // Reopening with fewer partitions reroutes the keys and removes the unused files.
+        let storage_server = StorageServer::open(data_dir, 2, num_replicas).unwrap();
+        assert_eq!(storage_server.get("key1"), Ok("value1".to_owned()));
+        assert_eq!(storage_server.get("key3"), Ok("value3".to_owned()));
+        storage_server.flush().unwrap();
+        assert!(!std::path::Path::new(data_dir).join("partition-3.bin").exists());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = "logs/test_checkpoint";
+        let (data_dir, log_path) = (format!("{}/data", dir), format!("{}/wal", dir));
+        let _ = std::fs::remove_dir_all(dir);
+        let open = || {
+            let log = TransactionLog::new(&log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+            StorageServer::open(&data_dir, 4, 2).unwrap().with_transaction_log(Arc::new(Mutex::new(log)))
+        };
+        {
+            let storage_server = open();
+            for i in 0..20 {
+                storage_server.put(format!("key{}", i), "value").unwrap();
+            }
+            let report = storage_server.checkpoint().unwrap();
+            assert_eq!(report.partitions, 4);
+            assert!(report.lsn.is_some());
+            // Only the partitions written since the last checkpoint are written again.
+            assert_eq!(storage_server.checkpoint().unwrap().partitions, 0);
+            storage_server.put("key0", "updated").unwrap();
+            assert_eq!(storage_server.checkpoint().unwrap().partitions, 1);
+            storage_server.put("tail", "value").unwrap();
+            storage_server.delete("key1").unwrap();
+            // Forgetting the server skips the flush on drop, as a crash would.
+            std::mem::forget(storage_server);
+        }
+
+        // Recovery only replays the records logged after the last checkpoint.
+        let storage_server = StorageServer::open(&data_dir, 4, 2).unwrap();
+        assert_eq!(storage_server.recover(&log_path), Ok(2));
+        assert_eq!(storage_server.get("key0"), Ok("updated".to_owned()));
+        assert_eq!(storage_server.get("key1"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.get("key2"), Ok("value".to_owned()));
+        assert_eq!(storage_server.get("tail"), Ok("value".to_owned()));
+        drop(storage_server);
+
+        // A server without a data directory can't checkpoint, and replays the whole log.
+        let in_memory = StorageServer::new(4, 2);
+        assert_eq!(in_memory.checkpoint(), Err(FlowDbError::Unsupported));
+        assert_eq!(in_memory.recover(&log_path), Ok(23));
+
+        let storage_server = Arc::new(open());
+        storage_server.put("background", "value").unwrap();
+        let checkpointer = storage_server.start_checkpointer(Duration::from_millis(10));
+        let started = Instant::now();
+        while !matches!(TransactionLog::read_all(&log_path, 8192).unwrap().last(), Some(LogRecord::Checkpoint { .. })) {
+            assert!(started.elapsed() < Duration::from_secs(5), "no checkpoint was recorded");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        checkpointer.stop();
+    }
+
+    #[test]
+    fn test_checkpoint_on_flush() {
+        let dir = "logs/test_checkpoint_on_flush";
+        let (data_dir, log_path) = (format!("{}/data", dir), format!("{}/wal", dir));
+        let _ = std::fs::remove_dir_all(dir);
+        let open = || {
+            let log = TransactionLog::new(&log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+            let add: MergeFn = Box::new(|existing, operand| {
+                let parse = |bytes: &[u8]| std::str::from_utf8(bytes).unwrap().parse::<i64>().unwrap();
+                (existing.map_or(0, parse) + parse(operand)).to_string().into_bytes()
+            });
+            let storage_server = StorageServer::open(&data_dir, 4, 2).unwrap().with_transaction_log(Arc::new(Mutex::new(log))).with_merge_operator(add);
+            let replayed = storage_server.recover(&log_path).unwrap();
+            (storage_server, replayed)
+        };
+        {
+            let (storage_server, _) = open();
+            storage_server.merge("counter", "5").unwrap();
+            storage_server.checkpoint().unwrap();
+            storage_server.merge("counter", "2").unwrap();
+        }
+
+        // Dropping the server flushes and records a checkpoint, so no merge is applied twice.
+        let (storage_server, replayed) = open();
+        assert_eq!(replayed, 0);
+        assert_eq!(storage_server.get("counter"), Ok("7".to_owned()));
+        storage_server.merge("counter", "1").unwrap();
+        storage_server.shutdown().unwrap();
+        drop(storage_server);
+        let (storage_server, replayed) = open();
+        assert_eq!(replayed, 0);
+        assert_eq!(storage_server.get("counter"), Ok("8".to_owned()));
+        storage_server.merge("counter", "1").unwrap();
+        storage_server.flush().unwrap();
+        std::mem::forget(storage_server);
+        assert_eq!(open().0.get("counter"), Ok("9".to_owned()));
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_tombstones(Duration::from_millis(50));
+        storage_server.put("key", "value").unwrap();
+        let put_version = storage_server.get_with_meta("key").unwrap().1.version;
+        assert_eq!(storage_server.delete("key"), Ok(true));
+        assert_eq!(storage_server.delete("key"), Ok(false));
+        assert_eq!(storage_server.get("key"), Err(FlowDbError::NotFound));
+        assert!(!storage_server.contains_key("key"));
+        assert_eq!(storage_server.len(), 0);
+
+        // Every replica holds the tombstone, versioned after the put it deletes.
+        let partition = storage_server.get_partition(b"key");
+        for replica in partition.read().unwrap().replicas.iter() {
+            let tombstone = replica.read().unwrap().data.get(b"key").unwrap().into_owned();
+            assert!(tombstone.is_tombstone());
+            assert!(tombstone.meta.version > put_version);
+        }
+
+        // The tombstone outlives a sweep during its grace period and is purged from every replica after.
+        assert_eq!(storage_server.sweep_expired(), 0);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(storage_server.sweep_expired(), 1);
+        for replica in partition.read().unwrap().replicas.iter() {
+            assert!(replica.read().unwrap().data.get(b"key").is_none());
+        }
+
+        // A key can be written again after being deleted.
+        storage_server.put("key", "again").unwrap();
+        assert_eq!(storage_server.get("key"), Ok("again".to_owned()));
+    }
+
+    #[test]
+    fn test_snapshot_view() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let storage_server = StorageServer::new(num_partitions, num_replicas);
+        storage_server.put("key1", "value1").unwrap();
+        storage_server.put("key2", "value2").unwrap();
+
+        let view = storage_server.snapshot_view();
+        storage_server.put("key1", "updated").unwrap();
+        storage_server.put("key1", "updated again").unwrap();
+        storage_server.delete("key2").unwrap();
+        storage_server.put("key3", "value3").unwrap();
+
+        // The view keeps seeing the state from when it was taken, while the server moves on.
+        assert_eq!(view.get("key1"), Ok("value1".to_owned()));
+        assert_eq!(view.get("key2"), Ok("value2".to_owned()));
+        assert_eq!(view.get("key3"), Err(FlowDbError::NotFound));
+        assert_eq!(storage_server.get("key1"), Ok("updated again".to_owned()));
+        assert_eq!(storage_server.get("key2"), Err(FlowDbError::NotFound));
+        let mut pairs: Vec<_> = view.scan().collect();
+        pairs.sort();
+        assert_eq!(pairs, vec![(b"key1".to_vec(), b"value1".to_vec()), (b"key2".to_vec(), b"value2".to_vec())]);
+
+        // A later view sees the later writes, and dropping every view frees the kept history.
+        let later = storage_server.snapshot_view();
+        assert_eq!(later.get("key1"), Ok("updated again".to_owned()));
+        drop(view);
+        drop(later);
+        assert!(storage_server.topology().partitions.iter().all(|partition| partition.read().unwrap().history.keys().next().is_none()));
+    }
+
+    #[test]
+    fn test_background_log() {
+        let num_partitions = 4;
+        let num_replicas = 2;
+        let log_path = "logs/test_background_log_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let log = Arc::new(BackgroundLog::spawn(log, 16));
+        let storage_server = StorageServer::new(num_partitions, num_replicas).with_background_log(Arc::clone(&log));
+        for i in 0..50 {
+            storage_server.put(format!("key{}", i), "value").unwrap();
+        }
+        storage_server.delete("key0").unwrap();
+        log.flush().unwrap();
+        assert_eq!(storage_server.stats().log.map(|metrics| metrics.records_written), Some(51));
+
+        let restored = StorageServer::new(num_partitions, num_replicas);
+        assert_eq!(restored.recover(log_path), Ok(51));
+        assert_eq!(restored.len(), 49);
+        assert_eq!(restored.get("key49"), Ok("value".to_owned()));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let data_dir = "logs/test_shutdown";
+        let log_path = "logs/test_shutdown_wal";
+        let _ = std::fs::remove_dir_all(data_dir);
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let log = Arc::new(BackgroundLog::spawn(log, 16));
+        let storage_server = StorageServer::open(data_dir, 4, 2).unwrap().with_replication_mode(ReplicationMode::Async).with_background_log(Arc::clone(&log));
+        let replica = Arc::clone(&storage_server.get_partition(b"key0").read().unwrap().replicas[1]);
+
+        // Shutting down waits for the replica that is busy to catch up.
+        let busy = replica.write().unwrap();
+        for i in 0..20 {
+            storage_server.put(format!("key{}", i), "value").unwrap();
+        }
+        thread::scope(|scope| {
+            let stopping = scope.spawn(|| storage_server.shutdown());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!stopping.is_finished());
+            drop(busy);
+            stopping.join().unwrap().unwrap();
+        });
+        assert_eq!(storage_server.replication_backlog(), 0);
+        assert!(replica.read().unwrap().data.get(b"key0").is_some());
+        assert_eq!(log.durable_lsn(), log.flush().unwrap());
+
+        // Writes are refused from then on, while reads are still served.
+        assert_eq!(storage_server.put("key0", "value2"), Err(FlowDbError::ShuttingDown));
+        assert_eq!(storage_server.lpush("list", &[b"a"]), Err(FlowDbError::ShuttingDown));
+        assert_eq!(storage_server.rebalance(8), Err(FlowDbError::ShuttingDown));
+        assert_eq!(storage_server.get("key0"), Ok("value".to_owned()));
+
+        // Both the data directory and the log hold every write.
+        assert_eq!(StorageServer::open(data_dir, 4, 2).unwrap().len(), 20);
+        assert_eq!(StorageServer::new(4, 2).recover(log_path), Ok(20));
+    }
+
+    #[test]
+    fn test_shutdown_races_writes() {
+        let data_dir = "logs/test_shutdown_races_writes";
+        let _ = std::fs::remove_dir_all(data_dir);
+        let storage_server = StorageServer::open(data_dir, 4, 1).unwrap();
+        let partition = storage_server.get_partition(b"key");
+        thread::scope(|scope| {
+            // The put gets past the shutdown check, then waits for its partition's write lock.
+            let reading = partition.read().unwrap();
+            let putting = scope.spawn(|| storage_server.put("key", "value"));
+            thread::sleep(Duration::from_millis(50));
+            let stopping = scope.spawn(|| storage_server.shutdown());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!stopping.is_finished());
+            drop(reading);
+            assert!(putting.join().unwrap().is_ok());
+            stopping.join().unwrap().unwrap();
+        });
+
+        // Shutdown waited for the acknowledged put before flushing.
+        assert_eq!(StorageServer::open(data_dir, 4, 1).unwrap().get("key"), Ok("value".to_owned()));
+    }
+
+    #[test]
+    fn test_read_during_write() {
+        let storage_server = StorageServer::new(1, 2);
+        storage_server.put("key", "value1").unwrap();
+        let partition = storage_server.get_partition(b"key");
+        let replica = Arc::clone(&partition.read().unwrap().replicas[1]);
+        thread::scope(|scope| {
+            // The put applies its value to the primary, then waits for the replica while it holds
+            // the primary's write lock.
+            let busy = replica.write().unwrap();
+            let putting = scope.spawn(|| storage_server.put("key", "value2"));
+            while partition.try_read().is_ok() {
+                thread::yield_now();
+            }
+            let reading = scope.spawn(|| storage_server.get("key"));
+            thread::sleep(Duration::from_millis(50));
+            assert!(reading.is_finished());
+            assert_eq!(reading.join().unwrap(), Ok("value2".to_owned()));
+            assert_eq!(storage_server.get_with_consistency("key", Consistency::LocalPrimary), Ok("value2".to_owned()));
+            assert!(!putting.is_finished());
+            drop(busy);
+            putting.join().unwrap().unwrap();
+        });
+        assert_eq!(replica.read().unwrap().data.get(b"key").map(|entry| entry.value.to_vec()), Some(b"value2".to_vec()));
+    }
+
+    #[test]
+    fn test_follow() {
+        let log_path = "logs/test_follow_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let primary = StorageServer::new(4, 2).with_transaction_log(Arc::new(Mutex::new(log)));
+        primary.put("key0", "value").unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let shipper = crate::LogShipper::new(log_path, 8192).with_poll_interval(Duration::from_millis(10));
+            let _ = shipper.serve(listener.accept().unwrap().0);
+        });
+        let standby = Arc::new(StorageServer::new(4, 2));
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        let mut follower = LogFollower::connect(stream, crate::transaction_log::LogPosition::START, 1024).unwrap();
+        let follower_standby = Arc::clone(&standby);
+        std::thread::spawn(move || follower_standby.follow(&mut follower));
+
+        primary.put("key1", "value").unwrap();
+        primary.delete("key0").unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while standby.get("key0").is_ok() || standby.get("key1").is_err() {
+            assert!(std::time::Instant::now() < deadline, "standby did not catch up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(standby.len(), 1);
+    }
+
+    #[test]
+    fn test_shared_log() {
+        let log_path = "logs/test_shared_log_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = SharedTransactionLog::open(log_path, 4, 1024 * 1024, 5, 8192).unwrap();
+        let storage_server = Arc::new(StorageServer::new(4, 2).with_shared_log(log));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let storage_server = Arc::clone(&storage_server);
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        storage_server.put(format!("key{}", i), format!("value{}", t)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        storage_server.delete("key0").unwrap();
+
+        // Replay follows the order the puts were applied in, so every key ends with the same value.
+        let restored = StorageServer::new(4, 2);
+        assert_eq!(restored.recover(log_path), Ok(101));
+        assert_eq!(restored.len(), 24);
+        for i in 1..25 {
+            let key = format!("key{}", i);
+            assert_eq!(restored.get(&key), storage_server.get(&key));
+        }
+    }
+
+    #[test]
+    fn test_commit_lsn() {
+        let log_path = "logs/test_commit_lsn_wal";
+        let _ = std::fs::remove_dir_all(log_path);
+        let log = Arc::new(Mutex::new(TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap()));
+        let storage_server = StorageServer::new(4, 2).with_transaction_log(Arc::clone(&log));
+        let put = storage_server.put("key", "value").unwrap().unwrap();
+        let (existed, delete) = storage_server.delete_with_lsn("key").unwrap();
+        assert!(existed);
+        assert!(delete.unwrap() > put);
+        assert_eq!(delete, Some(log.lock().unwrap().lsn()));
+        assert_eq!(storage_server.delete_with_lsn("key"), Ok((false, None)));
+
+        assert_eq!(StorageServer::new(4, 2).put("key", "value"), Ok(None));
+    }
+
+    #[test]
+    fn test_write_quorum() {
+        let storage_server = StorageServer::new(4, 3).with_replication_mode(ReplicationMode::Quorum(2));
+        assert_eq!(storage_server.replication_mode(), ReplicationMode::Quorum(2));
+        let replicas = storage_server.get_partition(b"key").read().unwrap().replicas.clone();
+        let has_key = |replica: &Arc<RwLock<Partition>>| replica.read().unwrap().data.get(b"key").is_some();
+
+        // The write returns once the first replica has it, while the last one is still busy.
+        let busy = replicas[2].write().unwrap();
+        storage_server.put("key", "value").unwrap();
+        assert!(has_key(&replicas[1]));
+        assert_eq!(storage_server.get("key"), Ok("value".to_owned()));
+        drop(busy);
+        storage_server.wait_for_replication();
+        assert!(has_key(&replicas[2]));
+        assert_eq!(storage_server.replication_backlog(), 0);
+
+        storage_server.delete("key").unwrap();
+        storage_server.wait_for_replication();
+        assert!(replicas.iter().all(|replica| !has_key(replica)));
+
+        // A quorum of every copy replicates synchronously.
+        let storage_server = StorageServer::new(4, 3).with_replication_mode(ReplicationMode::Quorum(3));
+        assert!(storage_server.replicator.is_none());
+    }
+
+    #[test]
+    fn test_async_replication() {
+        let storage_server = StorageServer::new(4, 2).with_replication_mode(ReplicationMode::Async);
+        let replica = Arc::clone(&storage_server.get_partition(b"key").read().unwrap().replicas[1]);
+
+        // Writes return while the replica is busy, and reach it in order once it is free.
+        let busy = replica.write().unwrap();
+        let (_, report) = storage_server.put_with_report("key", "value1").unwrap();
+        assert_eq!((report.applied, report.queued), (vec![0], vec![1]));
+        storage_server.put("key", "value2").unwrap();
+        assert_eq!(storage_server.replication_backlog(), 2);
+        drop(busy);
+        storage_server.wait_for_replication();
+        let value = replica.read().unwrap().data.get(b"key").map(|entry| entry.value.to_vec());
+        assert_eq!(value, Some(b"value2".to_vec()));
+    }
+
+    #[test]
+    fn test_read_preference() {
+        let storage_server = StorageServer::new(4, 2).with_read_preference(ReadPreference::RoundRobin);
+        storage_server.put("key", "primary").unwrap();
+        let replica = Arc::clone(&storage_server.topology().leadership.copies(storage_server.partition_index(b"key"))[1]);
+        replica.write().unwrap().data.insert(b"key".to_vec(), storage_server.new_entry(b"replica").unwrap());
+
+        // Round-robin reads alternate between the copies.
+        let mut values: Vec<_> = (0..4).map(|_| storage_server.get("key").unwrap()).collect();
+        values.sort();
+        assert_eq!(values, ["primary", "primary", "replica", "replica"]);
+
+        // Nearest reads pass over a copy that a writer holds.
+        let storage_server = storage_server.with_read_preference(ReadPreference::Nearest);
+        let busy = replica.write().unwrap();
+        assert_eq!(storage_server.get("key"), Ok("primary".to_owned()));
+        drop(busy);
+    }
+
+    #[test]
+    fn test_read_staleness() {
+        let storage_server = StorageServer::new(4, 2)
+            .with_replication_mode(ReplicationMode::Async)
+            .with_read_preference(ReadPreference::RoundRobin)
+            .with_max_staleness(Duration::from_millis(10));
+        let replica = Arc::clone(&storage_server.topology().leadership.copies(storage_server.partition_index(b"key"))[1]);
+
+        // Once the replica falls too far behind, reads go to the primary instead of waiting on it.
+        let busy = replica.write().unwrap();
+        storage_server.put("key", "value").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        for _ in 0..4 {
+            assert_eq!(storage_server.get("key"), Ok("value".to_owned()));
+        }
+        drop(busy);
+        storage_server.wait_for_replication();
+        assert_eq!(storage_server.get("key"), Ok("value".to_owned()));
+    }
+
+    #[test]
+    fn test_failover() {
+        let storage_server = StorageServer::new(4, 3);
+        storage_server.put("key", "value1").unwrap();
+        let index = storage_server.partition_index(b"key");
+        assert!(storage_server.leaders().iter().all(|leader| leader.replica == 0 && leader.term == 0));
+        assert_eq!(storage_server.check_health(), vec![]);
+
+        let primary = storage_server.get_partition(b"key");
+        let _ = std::thread::spawn(move || {
+            let _guard = primary.write().unwrap();
+            panic!("poisoning the primary");
+        })
+        .join();
+
+        // The first healthy replica takes over, and reads and writes reach it.
+        let leader = Leader { partition: index, replica: 1, term: 1 };
+        assert_eq!(storage_server.check_health(), vec![leader]);
+        assert_eq!(storage_server.leaders()[index], leader);
+        assert_eq!(storage_server.get("key"), Ok("value1".to_owned()));
+        storage_server.put("key", "value2").unwrap();
+        for copy in storage_server.topology().leadership.copies(index) {
+            assert_eq!(copy.read().unwrap().data.get(b"key").map(|entry| entry.value.to_vec()), Some(b"value2".to_vec()));
+        }
+        assert_eq!(storage_server.check_health(), vec![]);
+    }
+
+    #[test]
+    fn test_repair_replicas() {
+        let storage_server = StorageServer::new(4, 2);
+        for i in 0..20 {
+            storage_server.put(format!("key{}", i), "value").unwrap();
+        }
+        assert_eq!(storage_server.repair_replicas(), 0);
+
+        // A replica that missed a put and a delete is brought back in line with its leader.
+        let replica = Arc::clone(&storage_server.topology().leadership.copies(storage_server.partition_index(b"key1"))[1]);
+        replica.write().unwrap().data.remove(b"key1");
+        replica.write().unwrap().data.insert(b"gone".to_vec(), storage_server.new_entry(b"value").unwrap());
+        let anti_entropy = storage_server.start_anti_entropy(Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(100));
+        anti_entropy.stop();
+        assert!(replica.read().unwrap().data.get(b"key1").is_some());
+        assert_eq!(storage_server.repair_replicas(), 0);
+    }
+
+    #[test]
+    fn test_hinted_handoff() {
+        let storage_server = StorageServer::new(4, 2).with_hinted_handoff(Duration::from_secs(60));
+        let index = storage_server.partition_index(b"key");
+        let replica = Arc::clone(&storage_server.topology().leadership.copies(index)[1]);
+        let value = |replica: &Arc<RwLock<Partition>>| replica.read().unwrap().data.get(b"key").map(|entry| entry.value.to_vec());
+        assert_eq!(storage_server.mark_replica_down(index, 0), Err(FlowDbError::InvalidArgument));
+
+        // Writes the replica misses while it is down are kept as hints.
+        storage_server.mark_replica_down(index, 1).unwrap();
+        let (_, report) = storage_server.put_with_report("key", "value1").unwrap();
+        assert_eq!(report.failed, vec![(1, ReplicaError::Unavailable)]);
+        assert_eq!(report.hinted, vec![1]);
+        storage_server.put("key", "value2").unwrap();
+        assert_eq!(storage_server.pending_hints(), 2);
+        assert_eq!(storage_server.hand_off_hints(), 0);
+        assert_eq!(value(&replica), None);
+
+        // The hints are replayed in order once it is back.
+        assert_eq!(storage_server.mark_replica_up(index, 1), Ok(2));
+        assert_eq!(value(&replica), Some(b"value2".to_vec()));
+        assert_eq!(storage_server.pending_hints(), 0);
+
+        // A background worker hands off hints kept for a replica whose lock was poisoned, once the
+        // lock is healthy again.
+        let poisoned = Arc::clone(&replica);
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoned.write().unwrap();
+            panic!("poisoning the replica");
+        })
+        .join();
+        storage_server.put("key", "value3").unwrap();
+        let handoff = storage_server.start_hinted_handoff(Duration::from_millis(10)).unwrap();
+        replica.clear_poison();
+        std::thread::sleep(Duration::from_millis(100));
+        handoff.stop();
+        assert_eq!(value(&replica), Some(b"value3".to_vec()));
+        assert!(StorageServer::new(4, 2).start_hinted_handoff(Duration::from_millis(10)).is_none());
+    }
+
+    #[test]
+    fn test_poisoned_replica() {
+        let storage_server = StorageServer::new(4, 3);
+        let replica = Arc::clone(&storage_server.get_partition(b"key").read().unwrap().replicas[1]);
+        let _ = std::thread::spawn(move || {
+            let _guard = replica.write().unwrap();
+            panic!("poisoning the replica");
+        })
+        .join();
+
+        // The put skips the poisoned replica instead of panicking.
+        let (_, report) = storage_server.put_with_report("key", "value").unwrap();
+        assert_eq!(report.applied, vec![0, 2]);
+        assert_eq!(report.failed, vec![(1, ReplicaError::Poisoned)]);
+        assert!(!report.is_complete());
+        assert_eq!(storage_server.get("key"), Ok("value".to_owned()));
+    }
+
+    #[test]
+    fn test_poisoned_primary() {
+        let storage_server = StorageServer::new(1, 2);
+        storage_server.put("key", "value1").unwrap();
+        let primary = storage_server.get_partition(b"key");
+        let _ = std::thread::spawn(move || {
+            let _guard = primary.write().unwrap();
+            panic!("poisoning the primary");
+        })
+        .join();
+
+        // Requests fail instead of panicking, while upkeep carries on.
+        assert_eq!(storage_server.get("key"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.put("key", "value2"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.delete("key"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.compare_and_swap("key", Some(b"value1"), b"value2"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.put_if_absent("key", "value2"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.multi_get(&["key"]), vec![Err(FlowDbError::LockPoisoned)]);
+        assert!(!storage_server.contains_key("key"));
+        assert_eq!(storage_server.scan().count(), 0);
+        assert_eq!(storage_server.len(), 1);
+
+        // Promoting the replica brings the partition back.
+        assert_eq!(storage_server.check_health().len(), 1);
+        assert_eq!(storage_server.get("key"), Ok("value1".to_owned()));
+        storage_server.put("key", "value2").unwrap();
+        assert_eq!(storage_server.get("key"), Ok("value2".to_owned()));
+    }
+
+    #[test]
+    fn test_poisoned_locks() {
+        let log_path = "logs/test_poisoned_locks";
+        let _ = fs::remove_dir_all(log_path);
+        let log = Arc::new(Mutex::new(TransactionLog::new(log_path, 1024 * 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap()));
+        let storage_server = StorageServer::new(4, 2).with_transaction_log(Arc::clone(&log));
+        storage_server.put("key", "value1").unwrap();
+        let poison = |f: &dyn Fn()| {
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        };
+
+        // A poisoned namespace registry only holds names, so it keeps working.
+        poison(&|| {
+            let _guard = storage_server.namespaces.write().unwrap();
+            panic!("poisoning the namespaces");
+        });
+        let namespace = storage_server.create_namespace("ns", NamespaceOptions::new(2, 1)).unwrap();
+        namespace.put("key", "value").unwrap();
+        assert!(storage_server.namespace("ns").is_some());
+        assert!(storage_server.drop_namespace("ns"));
+
+        // A poisoned transaction log may end in a partial record, so writes fail without being
+        // applied, while reads and stats carry on.
+        let poisoned = Arc::clone(&log);
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoned.lock().unwrap();
+            panic!("poisoning the log");
+        })
+        .join();
+        assert_eq!(storage_server.put("key", "value2"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.append("key", b"2"), Err(FlowDbError::LockPoisoned));
+        assert_eq!(storage_server.get("key"), Ok("value1".to_owned()));
+        assert!(storage_server.stats().log.is_some());
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let storage_server = Arc::new(StorageServer::new(4, 2));
+        for i in 0..1000 {
+            storage_server.put(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+
+        // Keep writing while the partitions are added.
+        let writer = {
+            let storage_server = Arc::clone(&storage_server);
+            std::thread::spawn(move || {
+                for i in 0..1000 {
+                    storage_server.put(format!("key{}", i), format!("updated{}", i)).unwrap();
+                }
+            })
+        };
+        let moved = storage_server.rebalance(6).unwrap();
+        writer.join().unwrap();
+        assert!(moved > 0 && moved < 600, "{} keys moved", moved);
+        assert_eq!(storage_server.partition_count(), 6);
+        assert_eq!(storage_server.len(), 1000);
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            assert_eq!(storage_server.get(&key), Ok(format!("updated{}", i)));
+            let index = storage_server.partition_index(key.as_bytes());
+            for copy in storage_server.topology().leadership.copies(index) {
+                assert!(copy.read().unwrap().data.get_live(key.as_bytes()).is_some());
+            }
+        }
+
+        // Shrinking folds the removed partitions' keys into the remaining ones.
+        storage_server.rebalance(3).unwrap();
+        assert_eq!(storage_server.ring().partitions(), 3);
+        assert_eq!(storage_server.stats().partitions.iter().map(|partition| partition.keys).sum::<usize>(), 1000);
+        assert_eq!(storage_server.get("key7"), Ok("updated7".to_owned()));
+        assert!(storage_server.rebalance(0).is_err());
+    }
+
+    #[test]
+    fn test_split_and_merge_partitions() {
+        let storage_server = StorageServer::new(4, 2);
+        for i in 0..1000 {
+            storage_server.put(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        let keys = |storage_server: &StorageServer| storage_server.stats().partitions.iter().map(|partition| partition.keys).collect::<Vec<_>>();
+        let before = keys(&storage_server);
+
+        // Splitting moves about half of one partition's keys to the new partition.
+        let moved = storage_server.split_partition(1).unwrap();
+        let after = keys(&storage_server);
+        assert_eq!(after.len(), 5);
+        assert_eq!((after[0], after[2], after[3]), (before[0], before[2], before[3]));
+        assert_eq!((after[1] + after[4], after[4]), (before[1], moved));
+        assert!(moved > before[1] / 4 && moved < before[1] * 3 / 4, "{} of {} keys moved", moved, before[1]);
+
+        // Merging folds one partition into another, renumbering the last one in its place.
+        assert_eq!(storage_server.merge_partitions(4, 1).unwrap(), moved);
+        assert_eq!(keys(&storage_server), before);
+        storage_server.merge_partitions(0, 2).unwrap();
+        assert_eq!(keys(&storage_server), vec![before[3], before[1], before[0] + before[2]]);
+        for i in 0..1000 {
+            assert_eq!(storage_server.get(format!("key{}", i)), Ok(format!("value{}", i)));
+        }
+        assert!(storage_server.merge_partitions(1, 1).is_err());
+        assert!(storage_server.merge_partitions(0, 3).is_err());
+        assert!(storage_server.split_partition(3).is_err());
+    }
+
+    #[test]
+    fn test_replication_lag() {
+        let storage_server = StorageServer::new(1, 2).with_max_lag_records(1);
+        let lag = |storage_server: &StorageServer| storage_server.stats().partitions[0].replica_lag[0];
+        storage_server.put("key", "value1").unwrap();
+        assert_eq!(lag(&storage_server), ReplicaLag { replica: 1, records: 0, time: Duration::ZERO, healthy: true });
+
+        // Writes the replica missed while it was down count against it until it is repaired.
+        storage_server.mark_replica_down(0, 1).unwrap();
+        storage_server.put("key", "value2").unwrap();
+        storage_server.mark_replica_up(0, 1).unwrap();
+        let behind = lag(&storage_server);
+        assert_eq!((behind.records, behind.healthy), (1, true));
+        storage_server.mark_replica_down(0, 1).unwrap();
+        storage_server.put("key", "value3").unwrap();
+        storage_server.mark_replica_up(0, 1).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let behind = lag(&storage_server);
+        assert_eq!((behind.records, behind.healthy), (2, false));
+        assert!(behind.time >= Duration::from_millis(5));
+
+        // A replica too far behind serves no reads.
+        let storage_server = storage_server.with_read_preference(ReadPreference::RoundRobin);
+        for _ in 0..4 {
+            assert_eq!(storage_server.get("key"), Ok("value3".to_owned()));
+        }
+        assert_eq!(storage_server.repair_replicas(), 1);
+        assert_eq!(lag(&storage_server), ReplicaLag { replica: 1, records: 0, time: Duration::ZERO, healthy: true });
+    }
+
+    #[test]
+    fn test_read_repair() {
+        let storage_server = StorageServer::new(1, 3).with_read_quorum(3);
+        let replica = |copy: usize| Arc::clone(&storage_server.topology().leadership.copies(0)[copy]);
+        let wait_for_repairs = |repairs: u64| {
+            let start = Instant::now();
+            while storage_server.read_repairs() < repairs && start.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(storage_server.read_repairs(), repairs);
+        };
+
+        // A replica that missed a write is brought up to date by the next read.
+        storage_server.put("key", "value1").unwrap();
+        storage_server.mark_replica_down(0, 2).unwrap();
+        storage_server.put("key", "value2").unwrap();
+        storage_server.mark_replica_up(0, 2).unwrap();
+        assert_eq!(storage_server.get("key"), Ok("value2".to_owned()));
+        wait_for_repairs(1);
+        let repaired = replica(2).read().unwrap().data.get_live(b"key").map(|entry| storage_server.decode_entry(&entry));
+        assert_eq!(repaired, Some(Ok(b"value2".to_vec())));
+        assert_eq!(storage_server.get("key"), Ok("value2".to_owned()));
+        assert_eq!(storage_server.read_repairs(), 1);
+
+        // So is one that missed a delete.
+        storage_server.mark_replica_down(0, 1).unwrap();
+        storage_server.delete("key").unwrap();
+        storage_server.mark_replica_up(0, 1).unwrap();
+        assert_eq!(storage_server.get("key"), Err(FlowDbError::NotFound));
+        wait_for_repairs(2);
+        assert!(replica(1).read().unwrap().data.get(b"key").is_none());
+    }
+
+    #[test]
+    fn test_consistency_levels() {
+        let storage_server = StorageServer::new(1, 3).with_replication_mode(ReplicationMode::Async);
+        let (_, report) = storage_server.put_with_consistency("key", "value1", Consistency::One).unwrap();
+        assert_eq!((report.applied, report.queued), (vec![0], vec![1, 2]));
+        assert_eq!(storage_server.get_with_consistency("key", Consistency::LocalPrimary), Ok("value1".to_owned()));
+
+        // Stronger writes wait for the replicator to reach enough copies.
+        let (_, report) = storage_server.put_with_consistency("key", "value2", Consistency::All).unwrap();
+        assert_eq!((report.applied, report.queued), (vec![0, 1, 2], vec![]));
+        for copy in storage_server.topology().leadership.copies(0) {
+            let value = copy.read().unwrap().data.get_live(b"key").map(|entry| storage_server.decode_entry(&entry));
+            assert_eq!(value, Some(Ok(b"value2".to_vec())));
+        }
+
+        // Levels a partition can't reach fail, while weaker ones still succeed.
+        storage_server.mark_replica_down(0, 2).unwrap();
+        assert_eq!(storage_server.put_with_consistency("key", "value3", Consistency::All), Err(FlowDbError::ReplicaFailure { required: 3, applied: 2 }));
+        assert!(storage_server.put_with_consistency("key", "value4", Consistency::Quorum).is_ok());
+        assert_eq!(storage_server.get_with_consistency("key", Consistency::Quorum), Ok("value4".to_owned()));
+        assert_eq!(storage_server.get_with_consistency("key", Consistency::All), Err(FlowDbError::Unavailable));
+        storage_server.mark_replica_down(0, 1).unwrap();
+        assert_eq!(storage_server.put_with_consistency("key", "value5", Consistency::Quorum), Err(FlowDbError::ReplicaFailure { required: 2, applied: 1 }));
+        assert_eq!(storage_server.get_with_consistency("key", Consistency::One), Ok("value5".to_owned()));
+    }
+}
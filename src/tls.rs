@@ -0,0 +1,248 @@
+//! TLS for the server's listeners, with optional verification of client certificates.
+//!
+//! Every frontend's `listen` takes an optional `TlsConfig`; with one, each connection is a TLS
+//! session set up with the configured certificate before any request is read. With a client CA
+//! as well, clients must present a certificate signed by it, so only known services can connect.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use log::error;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+/// Where a listener's certificate and key are, and which clients it accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    cert_chain: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Serves the PEM certificate chain in `cert_chain`, leaf first, with the PEM private key in `key`.
+    pub fn new(cert_chain: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        Self { cert_chain: cert_chain.as_ref().to_owned(), key: key.as_ref().to_owned(), client_ca: None }
+    }
+
+    /// Requires clients to present a certificate signed by one of the PEM CA certificates in the file.
+    pub fn with_client_ca(mut self, client_ca: impl AsRef<Path>) -> Self {
+        self.client_ca = Some(client_ca.as_ref().to_owned());
+        self
+    }
+
+    /// Builds the server's TLS configuration, failing if a file is missing or doesn't hold what it
+    /// should, or if the key doesn't match the certificate.
+    pub(crate) fn acceptor(&self) -> io::Result<Arc<ServerConfig>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions().map_err(invalid)?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(cert).map_err(invalid)?;
+                }
+                builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build().map_err(invalid)?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(invalid)?;
+        let config = builder.with_single_cert(read_certs(&self.cert_chain)?, key).map_err(invalid)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// Reads every PEM certificate in the file, failing if it holds none.
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn invalid(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Runs the TLS handshake with the client, so a failed one is reported before any request is read.
+fn accept(config: Arc<ServerConfig>, mut stream: TcpStream) -> io::Result<StreamOwned<ServerConnection, TcpStream>> {
+    let mut connection = ServerConnection::new(config).map_err(invalid)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// A client connection, in plaintext or over TLS.
+pub(crate) enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Accepts clients on the listener and serves each on its own thread, over TLS if configured,
+/// until accepting fails. `name` is the frontend named in logged errors.
+pub(crate) fn serve<F>(listener: TcpListener, tls: Option<&TlsConfig>, name: &'static str, handle: F) -> io::Result<()>
+where
+    F: Fn(Connection) -> io::Result<()> + Send + Sync + 'static,
+{
+    let acceptor = tls.map(TlsConfig::acceptor).transpose()?;
+    let handle = Arc::new(handle);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        stream.set_nodelay(true)?;
+        let (acceptor, handle) = (acceptor.clone(), Arc::clone(&handle));
+        thread::spawn(move || {
+            let connection = match acceptor {
+                Some(acceptor) => match accept(acceptor, stream) {
+                    Ok(stream) => Connection::Tls(Box::new(stream)),
+                    Err(e) => {
+                        error!("TLS handshake with {} client failed: {}", name, e);
+                        return;
+                    }
+                },
+                None => Connection::Plain(stream),
+            };
+            if let Err(e) = handle(connection) {
+                error!("Error serving {} client: {}", name, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509Name, X509};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection};
+    use crate::protocol::{Request, Response};
+    use crate::storage_server::StorageServer;
+
+    /// Creates a certificate for the name, signed by the issuer or, without one, by itself as a CA.
+    fn certificate(name: &str, serial: u32, issuer: Option<&(X509, PKey<Private>)>) -> (X509, PKey<Private>) {
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(issuer.map_or(&subject, |(cert, _)| cert.subject_name())).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        match issuer {
+            Some((issuer_cert, _)) => {
+                let san = SubjectAlternativeName::new().dns(name).build(&builder.x509v3_context(Some(issuer_cert), None)).unwrap();
+                builder.append_extension(san).unwrap();
+            }
+            None => builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap(),
+        }
+        builder.sign(issuer.map_or(&key, |(_, issuer_key)| issuer_key), MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    fn write_pem(dir: &str, name: &str, (cert, key): &(X509, PKey<Private>)) {
+        fs::write(format!("{}/{}.pem", dir, name), cert.to_pem().unwrap()).unwrap();
+        fs::write(format!("{}/{}-key.pem", dir, name), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let dir = "logs/test_mutual_tls";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let ca = certificate("flowdb-test-ca", 1, None);
+        write_pem(dir, "ca", &ca);
+        write_pem(dir, "server", &certificate("localhost", 2, Some(&ca)));
+        write_pem(dir, "client", &certificate("client", 3, Some(&ca)));
+
+        let config = TlsConfig::new(format!("{}/server.pem", dir), format!("{}/server-key.pem", dir)).with_client_ca(format!("{}/ca.pem", dir));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        thread::spawn(move || serve(listener, Some(&config), "test", move |connection| server.serve_client(connection)));
+
+        let connect = |with_client_cert: bool| -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+            let mut roots = RootCertStore::empty();
+            roots.add(CertificateDer::from_pem_file(format!("{}/ca.pem", dir)).unwrap()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match with_client_cert {
+                true => {
+                    let certs = read_certs(Path::new(&format!("{}/client.pem", dir))).unwrap();
+                    let key = PrivateKeyDer::from_pem_file(format!("{}/client-key.pem", dir)).unwrap();
+                    builder.with_client_auth_cert(certs, key).unwrap()
+                }
+                false => builder.with_no_client_auth(),
+            };
+            let mut connection = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).map_err(invalid)?;
+            let mut stream = TcpStream::connect(address)?;
+            while connection.is_handshaking() {
+                connection.complete_io(&mut stream)?;
+            }
+            Ok(StreamOwned::new(connection, stream))
+        };
+        let put = Request::Put { key: b"key".to_vec(), value: b"value".to_vec() }.encode();
+
+        // A client with a certificate from the CA is served.
+        let mut stream = connect(true).unwrap();
+        stream.write_all(&put).unwrap();
+        assert_eq!(Response::read_from(&mut stream).unwrap(), Response::Stored);
+
+        // A client without one is turned away, during the handshake or when it sends a request.
+        let served = connect(false).is_ok_and(|mut stream| stream.write_all(&put).is_ok() && Response::read_from(&mut stream).is_ok());
+        assert!(!served);
+
+        // So is one that doesn't speak TLS.
+        let mut plain = TcpStream::connect(address).unwrap();
+        plain.write_all(&put).unwrap();
+        assert!(Response::read_from(&mut plain).is_err());
+
+        assert!(TlsConfig::new(format!("{}/missing.pem", dir), format!("{}/server-key.pem", dir)).acceptor().is_err());
+        assert!(TlsConfig::new(format!("{}/server.pem", dir), format!("{}/client-key.pem", dir)).acceptor().is_err());
+    }
+}
@@ -1,164 +1,1267 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Read, Result, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
 use std::net::TcpStream;
-use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use log::{error, info};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use log::{error, info, warn};
 use openssl::ssl::SslStream;
+use snap::read::FrameDecoder;
+use snap::write::FrameEncoder;
+use crate::archive::SegmentArchiver;
+use crate::collections::CollectionOp;
 
-/// A transaction log that writes data to a file.
-pub struct TransactionLog {
+/// A log sequence number, identifying the point in a log just past a record.
+///
+/// LSNs increase with every write, across restarts, truncation, and the deletion of old segments,
+/// so comparing them tells whether one write came before another; only writes lost because they
+/// were never synced can have their LSNs handed out again after a crash. A
+/// `TransactionLog`'s LSNs are its `LogPosition`s packed into a u64; see `LogPosition::lsn`.
+pub type Lsn = u64;
+
+/// How many low bits of an LSN hold the offset within its segment.
+const LSN_OFFSET_BITS: u32 = 40;
+
+/// A function applied to every record before it is written to the log.
+pub type FormatFn = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// When the transaction log forces its writes to disk with fsync.
+///
+/// Every write is handed to the operating system before it returns, so it survives the process
+/// crashing; only syncing makes it survive the machine crashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Sync after every write. The safest and slowest option.
+    Always,
+    /// Sync from a background thread at the given interval, so a machine crash loses at most that
+    /// much of the latest writes.
+    EveryN(Duration),
+    /// Leave syncing to the operating system, except when a segment is sealed.
+    #[default]
+    Never,
+}
+
+/// How sealed transaction log segments are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogCompression {
+    /// Sealed segments are left as written.
+    #[default]
+    None,
+    /// Sealed segments are compressed with snappy's framing format into `wal-NNNNNN.log.sz`.
+    Snappy,
+}
+
+/// The size of the header framing every record: the payload length and its CRC32, both big-endian.
+const FRAME_HEADER_LEN: usize = 8;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_MERGE: u8 = 3;
+const OP_COMMIT: u8 = 4;
+const OP_TIMED: u8 = 5;
+const OP_CHECKPOINT: u8 = 6;
+const OP_EXPIRE: u8 = 7;
+const OP_COLLECTION: u8 = 8;
+const OP_BEGIN_TXN: u8 = 9;
+const OP_COMMIT_TXN: u8 = 10;
+
+/// A single mutation recorded in the transaction log.
+///
+/// Records are encoded as frames: a header with the payload length and CRC32, then a payload of an
+/// op byte, the length-prefixed key, and the value. Keys and values can hold any bytes, including
+/// newlines. A commit's payload is the number of records in it followed by their frames, all of
+/// which must be applied together. A timed record's payload is its time in nanoseconds since the
+/// Unix epoch as a big-endian u64, followed by the frame of the record. A checkpoint's payload is
+/// its version as a big-endian u64. An expiry's value is its time in the same form, and a
+/// collection command's value is the bincode-encoded command.
+///
+/// A transaction written to a single log is bracketed instead: a begin record, the frames of its
+/// records, and a commit record, neither of which has anything after its op byte. Reading the log
+/// returns the bracket as one `Commit`, and drops a transaction that was never committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogRecord {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    Merge { key: Vec<u8>, operand: Vec<u8> },
+    Commit { records: Vec<LogRecord> },
+    /// A record together with when it was made, so a remote cluster applying it can order it
+    /// against its own writes.
+    Timed { at: SystemTime, record: Box<LogRecord> },
+    /// Marks that a checkpoint persisted every record before this one, holding the newest entry
+    /// version the checkpoint covers. Replaying it changes nothing.
+    Checkpoint { version: u64 },
+    /// Makes the key's current value expire at the given time. Written after the put of a value
+    /// with a TTL, so the key expires again once the log is replayed.
+    Expire { key: Vec<u8>, at: SystemTime },
+    /// A list, set, or hash command run on the collection stored at the key.
+    Collection { key: Vec<u8>, op: CollectionOp },
+    /// Starts a transaction, whose records up to the next `CommitTxn` are applied all or nothing.
+    BeginTxn,
+    /// Commits the transaction started by the last `BeginTxn`.
+    CommitTxn,
+}
+
+impl LogRecord {
+    /// Encodes the record as a frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            LogRecord::Put { key, value } => {
+                payload.push(OP_PUT);
+                push_key(&mut payload, key);
+                payload.extend_from_slice(value);
+            }
+            LogRecord::Delete { key } => {
+                payload.push(OP_DELETE);
+                push_key(&mut payload, key);
+            }
+            LogRecord::Merge { key, operand } => {
+                payload.push(OP_MERGE);
+                push_key(&mut payload, key);
+                payload.extend_from_slice(operand);
+            }
+            LogRecord::Commit { records } => {
+                payload.push(OP_COMMIT);
+                payload.extend_from_slice(&(records.len() as u32).to_be_bytes());
+                for record in records {
+                    payload.extend_from_slice(&record.encode());
+                }
+            }
+            LogRecord::Timed { at, record } => {
+                payload.push(OP_TIMED);
+                payload.extend_from_slice(&unix_nanos(*at).to_be_bytes());
+                payload.extend_from_slice(&record.encode());
+            }
+            LogRecord::Checkpoint { version } => {
+                payload.push(OP_CHECKPOINT);
+                payload.extend_from_slice(&version.to_be_bytes());
+            }
+            LogRecord::Expire { key, at } => {
+                payload.push(OP_EXPIRE);
+                push_key(&mut payload, key);
+                payload.extend_from_slice(&unix_nanos(*at).to_be_bytes());
+            }
+            LogRecord::Collection { key, op } => {
+                payload.push(OP_COLLECTION);
+                push_key(&mut payload, key);
+                payload.extend_from_slice(&bincode::serialize(op).expect("collection commands always serialize"));
+            }
+            LogRecord::BeginTxn => payload.push(OP_BEGIN_TXN),
+            LogRecord::CommitTxn => payload.push(OP_COMMIT_TXN),
+        }
+        frame(&payload)
+    }
+
+    /// Encodes the records as a transaction: a `BeginTxn` frame, theirs, and a `CommitTxn` frame.
+    pub fn encode_transaction(records: &[LogRecord]) -> Vec<u8> {
+        let mut data = LogRecord::BeginTxn.encode();
+        for record in records {
+            data.extend_from_slice(&record.encode());
+        }
+        data.extend_from_slice(&LogRecord::CommitTxn.encode());
+        data
+    }
+
+    /// Decodes every record in data produced by concatenating `encode` outputs, returning None if
+    /// any frame is truncated, fails its checksum, or is otherwise invalid.
+    pub fn decode_all(mut data: &[u8]) -> Option<Vec<Self>> {
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            let (record, rest) = Self::decode_frame(data)?;
+            records.push(record);
+            data = rest;
+        }
+        Some(records)
+    }
+
+    /// Decodes a single frame produced by `encode`, returning None if it is not a valid record.
+    pub fn decode(frame: &[u8]) -> Option<Self> {
+        match Self::decode_frame(frame)? {
+            (record, []) => Some(record),
+            _ => None,
+        }
+    }
+
+    /// Decodes the frame at the start of data, returning the record and the bytes after it.
+    fn decode_frame(data: &[u8]) -> Option<(Self, &[u8])> {
+        let (payload, rest) = split_frame(data)?;
+        let (&op, body) = payload.split_first()?;
+        let record = match op {
+            OP_COMMIT => {
+                let (count, mut frames) = body.split_at_checked(4)?;
+                let count = u32::from_be_bytes(count.try_into().ok()?);
+                let mut records = Vec::new();
+                for _ in 0..count {
+                    let (record, rest) = Self::decode_frame(frames)?;
+                    records.push(record);
+                    frames = rest;
+                }
+                if !frames.is_empty() {
+                    return None;
+                }
+                LogRecord::Commit { records }
+            }
+            OP_TIMED => {
+                let (nanos, frame) = body.split_at_checked(8)?;
+                let at = from_unix_nanos(nanos)?;
+                let (record, []) = Self::decode_frame(frame)? else {
+                    return None;
+                };
+                LogRecord::Timed { at, record: Box::new(record) }
+            }
+            OP_CHECKPOINT => LogRecord::Checkpoint { version: u64::from_be_bytes(body.try_into().ok()?) },
+            OP_BEGIN_TXN if body.is_empty() => LogRecord::BeginTxn,
+            OP_COMMIT_TXN if body.is_empty() => LogRecord::CommitTxn,
+            OP_BEGIN_TXN | OP_COMMIT_TXN => return None,
+            _ => {
+                let (key_len, body) = body.split_at_checked(4)?;
+                let (key, value) = body.split_at_checked(u32::from_be_bytes(key_len.try_into().ok()?) as usize)?;
+                let key = key.to_vec();
+                match op {
+                    OP_PUT => LogRecord::Put { key, value: value.to_vec() },
+                    OP_DELETE if value.is_empty() => LogRecord::Delete { key },
+                    OP_MERGE => LogRecord::Merge { key, operand: value.to_vec() },
+                    OP_EXPIRE => LogRecord::Expire { key, at: from_unix_nanos(value)? },
+                    OP_COLLECTION => LogRecord::Collection { key, op: bincode::deserialize(value).ok()? },
+                    _ => return None,
+                }
+            }
+        };
+        Some((record, rest))
+    }
+
+    /// Returns the key the record changes, or None for commits, markers and checkpoints.
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        match self {
+            LogRecord::Put { key, .. }
+            | LogRecord::Delete { key }
+            | LogRecord::Merge { key, .. }
+            | LogRecord::Expire { key, .. }
+            | LogRecord::Collection { key, .. } => Some(key),
+            LogRecord::Commit { .. } | LogRecord::BeginTxn | LogRecord::CommitTxn | LogRecord::Checkpoint { .. } => None,
+            LogRecord::Timed { record, .. } => record.key(),
+        }
+    }
+
+    /// Returns the record without the time it was made, if it has one.
+    pub(crate) fn untimed(&self) -> &LogRecord {
+        match self {
+            LogRecord::Timed { record, .. } => record,
+            record => record,
+        }
+    }
+
+    /// Parses a tab-separated text command as sent by clients: `PUT\tkey\tvalue`, `DEL\tkey`, or
+    /// `MRG\tkey\toperand`, optionally newline-terminated. Returns None if it is not a valid command.
+    pub fn parse_line(line: &[u8]) -> Option<Self> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let mut fields = line.splitn(3, |&b| b == b'\t');
+        let op = fields.next()?;
+        let key = fields.next()?.to_vec();
+        match (op, fields.next()) {
+            (b"PUT", Some(value)) => Some(LogRecord::Put { key, value: value.to_vec() }),
+            (b"DEL", None) => Some(LogRecord::Delete { key }),
+            (b"MRG", Some(operand)) => Some(LogRecord::Merge { key, operand: operand.to_vec() }),
+            _ => None,
+        }
+    }
+}
+
+/// A type the transaction log can hold, encoded as the payload of a checksummed frame.
+pub trait Loggable: Sized {
+    /// Encodes the value as a frame.
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decodes a single frame produced by `encode`, returning None if it is not a valid value.
+    fn decode(frame: &[u8]) -> Option<Self>;
+
+    /// Returns whether the entry begins or commits a transaction. Reading a log returns the entries
+    /// between the two as the one entry `transaction` combines them into, or none of them if the
+    /// transaction was never committed.
+    fn marker(&self) -> Option<TxnMarker> {
+        None
+    }
+
+    /// Combines the entries of a committed transaction into one, or returns None if the type has
+    /// no transactions.
+    fn transaction(entries: Vec<Self>) -> Option<Self> {
+        let _ = entries;
+        None
+    }
+}
+
+/// Where an entry stands in a transaction, as returned by `Loggable::marker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnMarker {
+    Begin,
+    Commit,
+}
+
+impl Loggable for LogRecord {
+    fn encode(&self) -> Vec<u8> {
+        LogRecord::encode(self)
+    }
+
+    fn decode(frame: &[u8]) -> Option<Self> {
+        LogRecord::decode(frame)
+    }
+
+    fn marker(&self) -> Option<TxnMarker> {
+        match self {
+            LogRecord::BeginTxn => Some(TxnMarker::Begin),
+            LogRecord::CommitTxn => Some(TxnMarker::Commit),
+            _ => None,
+        }
+    }
+
+    fn transaction(records: Vec<Self>) -> Option<Self> {
+        Some(LogRecord::Commit { records })
+    }
+}
+
+/// Frames the payload with its length and CRC32.
+pub(crate) fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Checks the frame at the start of data, returning its payload and the bytes after it, or None
+/// if the frame is truncated or fails its checksum.
+pub(crate) fn split_frame(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (header, rest) = data.split_at_checked(FRAME_HEADER_LEN)?;
+    let len = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+    let checksum = u32::from_be_bytes(header[4..].try_into().ok()?);
+    let (payload, rest) = rest.split_at_checked(len)?;
+    (crc32fast::hash(payload) == checksum).then_some((payload, rest))
+}
+
+/// Appends the key to the payload, prefixed with its length.
+fn push_key(payload: &mut Vec<u8>, key: &[u8]) {
+    payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    payload.extend_from_slice(key);
+}
+
+/// Returns the time in nanoseconds since the Unix epoch, or 0 for times before it.
+fn unix_nanos(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// Decodes a time written by `unix_nanos` as a big-endian u64.
+fn from_unix_nanos(bytes: &[u8]) -> Option<SystemTime> {
+    Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(bytes.try_into().ok()?)))
+}
+
+/// A transaction log that writes data to numbered segment files in a directory.
+///
+/// Records are appended to the newest segment, `wal-000001.log`, `wal-000002.log`, and so on. Once a
+/// segment reaches `max_size` bytes it is sealed and a new one is started, and the oldest segments
+/// are deleted whole once there are more than `max_files`. Reading the segments in order replays
+/// every record still retained.
+///
+/// The log holds `LogRecord`s unless it is opened for another `Loggable` entry type.
+pub struct TransactionLog<E = LogRecord> {
     file: BufWriter<File>,
-    path: PathBuf,
+    dir: PathBuf,
+    segment: u64,
+    segment_len: u64,
     max_size: u64,
     max_files: u32,
-    compact_threshold: f64,
     read_buffer_size: usize,
-    format: Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>,
+    format: FormatFn,
+    sync_policy: SyncPolicy,
+    syncer: Option<Syncer>,
+    compression: LogCompression,
+    archiver: Option<Box<dyn SegmentArchiver>>,
+    retain_for: Option<Duration>,
+    checkpoints: BTreeMap<u64, usize>,
+    counters: Arc<LogCounters>,
+    entries: PhantomData<fn(E) -> E>,
 }
 
-impl TransactionLog {
-    /// Creates a new transaction log that writes to the specified file path.
-    pub fn new(path: &str, max_size: u64, max_files: u32, compact_threshold: f64, read_buffer_size: usize, format: Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>) -> Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)?;
-        let file_size = file.metadata()?.len();
+impl<E: Loggable> TransactionLog<E> {
+    /// Opens the transaction log in the given directory, appending to its newest segment.
+    ///
+    /// A crash during a write can leave a partial record at the end of the newest segment, so it is
+    /// cut back to the end of its last complete, CRC-checked record before appending, or to the
+    /// start of a transaction that was never committed.
+    pub fn new(path: &str, max_size: u64, max_files: u32, read_buffer_size: usize, format: FormatFn) -> Result<Self> {
+        let dir = PathBuf::from(path);
+        fs::create_dir_all(&dir)?;
+        let segment = segment_numbers(&dir)?.last().copied().unwrap_or(1);
+        let file = Self::open_segment(&dir, segment)?;
+        let segment_len = Self::repair_tail(&dir, segment, &file, read_buffer_size)?;
         let file = BufWriter::with_capacity(8192, file);
-        let path = PathBuf::from(path);
-        Ok(Self { file, path, max_size, max_files, compact_threshold, read_buffer_size, format })
+        let counters = Arc::new(LogCounters::default());
+        counters.segment_size.store(segment_len, Ordering::Relaxed);
+        // Whatever survived until now is as durable as it will get.
+        counters.durable_lsn.store(LogPosition { segment, offset: segment_len }.lsn(), Ordering::Relaxed);
+        Ok(Self { file, dir, segment, segment_len, max_size, max_files, read_buffer_size, format, sync_policy: SyncPolicy::default(), syncer: None, compression: LogCompression::default(), archiver: None, retain_for: None, checkpoints: BTreeMap::new(), counters, entries: PhantomData })
+    }
+
+    /// Sets when writes are synced to disk. `SyncPolicy::EveryN` starts a background thread that
+    /// runs until the log is dropped.
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Result<Self> {
+        self.syncer = match policy {
+            SyncPolicy::EveryN(interval) => Some(Syncer::spawn(self.file.get_ref().try_clone()?, interval, Arc::clone(&self.counters))),
+            SyncPolicy::Always | SyncPolicy::Never => None,
+        };
+        self.sync_policy = policy;
+        Ok(self)
+    }
+
+    /// Sets how segments are stored once sealed. The segment being written is never compressed, and
+    /// segments sealed earlier are left as they are.
+    pub fn with_compression(mut self, compression: LogCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets where sealed segments are archived before they are deleted, whether by `cleanup`,
+    /// `truncate` or `compact`; compaction archives the segments it merges as they were.
+    pub fn with_archiver(mut self, archiver: impl SegmentArchiver + 'static) -> Self {
+        self.archiver = Some(Box::new(archiver));
+        self
+    }
+
+    /// Sets how long sealed segments are kept: older ones are deleted by `cleanup` however few
+    /// segments there are, on top of the `max_files` limit.
+    pub fn with_retention(mut self, retain_for: Duration) -> Self {
+        self.retain_for = Some(retain_for);
+        self
+    }
+
+    /// Marks the start of a checkpoint and returns its position. Until the checkpoint is finished,
+    /// retention never deletes the segment it starts in or any after it, however many or old they
+    /// are, since recovering from the checkpoint replays them.
+    pub fn begin_checkpoint(&mut self) -> Result<LogPosition> {
+        self.file.flush()?;
+        *self.checkpoints.entry(self.segment).or_default() += 1;
+        Ok(LogPosition { segment: self.segment, offset: self.segment_len })
     }
 
-    /// Writes the given data to the transaction log.
-    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+    /// Marks the checkpoint that started at the position as finished, deleting any segments that
+    /// were only kept for it.
+    pub fn finish_checkpoint(&mut self, start: LogPosition) -> Result<()> {
+        if let Some(count) = self.checkpoints.get_mut(&start.segment) {
+            *count -= 1;
+            if *count == 0 {
+                self.checkpoints.remove(&start.segment);
+            }
+        }
+        self.cleanup()
+    }
+
+    /// Returns the LSN just past the last write, which the next write's LSN will be greater than.
+    pub fn lsn(&self) -> Lsn {
+        LogPosition { segment: self.segment, offset: self.segment_len }.lsn()
+    }
+
+    /// Returns the LSN up to which every write is known to be synced to disk.
+    pub fn durable_lsn(&self) -> Lsn {
+        self.counters.durable_lsn.load(Ordering::Acquire)
+    }
+
+    /// Returns the log's counters since it was opened, along with the size of the current segment.
+    pub fn metrics(&self) -> LogMetrics {
+        self.counters.metrics()
+    }
+
+    /// Reads every record from the segments of the log in the given directory, oldest first.
+    ///
+    /// A missing directory holds no records.
+    pub fn read_all(path: impl AsRef<Path>, read_buffer_size: usize) -> Result<Vec<E>> {
+        Self::iter_dir(path, read_buffer_size)?.map(|record| record.map(|(_, record)| record)).collect()
+    }
+
+    /// Returns an iterator over the records of the log in the given directory and their positions,
+    /// oldest first, reading the segments that exist when it is called.
+    ///
+    /// A missing directory holds no records.
+    pub fn iter_dir(path: impl AsRef<Path>, read_buffer_size: usize) -> Result<LogIter<E>> {
+        let dir = path.as_ref().to_owned();
+        let segments = if dir.exists() { segment_numbers(&dir)? } else { Vec::new() };
+        Ok(LogIter::new(dir, segments, read_buffer_size))
+    }
+
+    /// Returns an iterator over the records of the log in the given directory starting at the
+    /// position, which must be where a record starts or where a segment ends.
+    ///
+    /// Fails with `ErrorKind::NotFound` if the position's segment has already been deleted, since the
+    /// records after it may be gone; `LogPosition::START` always starts at the oldest record retained.
+    pub fn iter_from(path: impl AsRef<Path>, from: LogPosition, read_buffer_size: usize) -> Result<LogIter<E>> {
+        let mut iter = Self::iter_dir(path, read_buffer_size)?;
+        let segments: Vec<u64> = iter.segments.as_slice().iter().copied().filter(|&segment| segment >= from.segment).collect();
+        if from != LogPosition::START && segments.first().is_some_and(|&oldest| oldest != from.segment) {
+            return Err(Error::new(ErrorKind::NotFound, format!("transaction log segment {} has been removed", from.segment)));
+        }
+        iter.segments = segments.into_iter();
+        iter.position = from;
+        Ok(iter)
+    }
+
+    /// Returns an iterator over this log's records and their positions, oldest first, including
+    /// every write made before the call.
+    pub fn iter(&mut self) -> Result<LogIter<E>> {
+        self.file.flush()?;
+        Self::iter_dir(&self.dir, self.read_buffer_size)
+    }
+
+    /// Reads every record from this log's segments, oldest first, including unflushed writes.
+    pub fn records(&mut self) -> Result<Vec<E>> {
+        self.iter()?.map(|record| record.map(|(_, record)| record)).collect()
+    }
+
+    /// Writes already-encoded record bytes to the transaction log, applying the log's format, and
+    /// returns the write's LSN.
+    ///
+    /// Whether the data has been synced to disk as well depends on the sync policy and on whether
+    /// the write sealed the segment; it has once `durable_lsn` reaches the returned LSN.
+    pub fn write(&mut self, data: &[u8]) -> Result<Lsn> {
         let formatted_data = (self.format)(data);
         self.file.write_all(&formatted_data)?;
-        if self.file.buffer().len() as u64 >= self.max_size {
+        self.segment_len += formatted_data.len() as u64;
+        self.file.flush()?;
+        self.counters.bytes_written.fetch_add(formatted_data.len() as u64, Ordering::Relaxed);
+        self.counters.records_written.fetch_add(1, Ordering::Relaxed);
+        self.counters.segment_size.store(self.segment_len, Ordering::Relaxed);
+        let lsn = self.lsn();
+        if self.segment_len >= self.max_size {
+            // Rotating syncs the segment first.
             self.rotate()?;
+            self.counters.durable_lsn.fetch_max(lsn, Ordering::AcqRel);
+        } else if self.sync_policy == SyncPolicy::Always {
+            self.counters.timed_sync(|| self.file.get_ref().sync_data())?;
+            self.counters.durable_lsn.fetch_max(lsn, Ordering::AcqRel);
+        } else if let Some(syncer) = &self.syncer {
+            syncer.pending.fetch_max(lsn, Ordering::AcqRel);
         }
+        Ok(lsn)
+    }
+
+    /// Writes out anything still buffered and syncs the current segment to disk, whatever the sync
+    /// policy, returning the LSN up to which the log is now durable.
+    pub fn sync(&mut self) -> Result<Lsn> {
         self.file.flush()?;
+        self.counters.timed_sync(|| self.file.get_ref().sync_data())?;
+        let lsn = self.lsn();
+        self.counters.durable_lsn.fetch_max(lsn, Ordering::AcqRel);
+        Ok(lsn)
+    }
+
+    /// Writes a single mutation record to the transaction log, returning its LSN.
+    pub fn write_record(&mut self, record: &E) -> Result<Lsn> {
+        self.write(&record.encode())
+    }
+
+    /// Discards every record written so far, for example once a snapshot covers them.
+    ///
+    /// All segments are deleted and writing continues in a new one.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.rotate()?;
+        for segment in segment_numbers(&self.dir)? {
+            if segment != self.segment {
+                self.archive(segment)?;
+                remove_segment(&self.dir, segment)?;
+            }
+        }
         Ok(())
     }
+}
 
-    /// Rotates the transaction log by renaming the current log file to .1 and creating a new one.
+impl TransactionLog<LogRecord> {
+    /// Writes the records as one transaction, bracketed by `BeginTxn` and `CommitTxn` in a single
+    /// write, returning its LSN. They are read back as one `Commit`.
+    pub fn write_transaction(&mut self, records: &[LogRecord]) -> Result<Lsn> {
+        self.write(&LogRecord::encode_transaction(records))
+    }
+
+    /// Rewrites the sealed segments into one that holds only what replay needs, returning how many
+    /// records were dropped.
+    ///
+    /// For each key, the last put or delete is kept along with any merges, expiries, and collection
+    /// commands after it; earlier records are dropped. Deletes are kept because the log may be replayed on top of a snapshot. Commits
+    /// are flattened, which is safe because the compacted segment is written in full before it
+    /// replaces anything. The result takes the place of the newest sealed segment and the older
+    /// ones are deleted afterwards; a crash in between leaves them to be replayed first, which
+    /// yields the same state.
+    ///
+    /// Records before the last checkpoint in the sealed segments are dropped outright, since the
+    /// checkpoint persisted them; the checkpoint itself is kept at the start of the result.
+    pub fn compact(&mut self) -> Result<usize> {
+        let sealed: Vec<u64> = segment_numbers(&self.dir)?.into_iter().filter(|&segment| segment != self.segment).collect();
+        let Some(&newest) = sealed.last() else {
+            return Ok(0);
+        };
+        let mut bytes_before = 0;
+        for &segment in &sealed {
+            self.archive(segment)?;
+            bytes_before += fs::metadata(existing_segment_path(&self.dir, segment))?.len();
+        }
+        let iter = LogIter::<LogRecord>::new(self.dir.clone(), sealed.clone(), self.read_buffer_size);
+        let mut records = Vec::new();
+        let mut checkpoint = None;
+        let mut checkpointed = 0;
+        for record in iter {
+            match record?.1 {
+                record @ LogRecord::Checkpoint { .. } => {
+                    checkpointed += records.len();
+                    records.clear();
+                    checkpoint = Some(record);
+                }
+                LogRecord::Commit { records: in_commit } => records.extend(in_commit),
+                LogRecord::Timed { at, record } => match *record {
+                    LogRecord::Commit { records: in_commit } => {
+                        records.extend(in_commit.into_iter().map(|record| LogRecord::Timed { at, record: Box::new(record) }))
+                    }
+                    record => records.push(LogRecord::Timed { at, record: Box::new(record) }),
+                },
+                record => records.push(record),
+            }
+        }
+
+        // Walk backwards so the first put or delete seen for a key is its last one.
+        let mut settled = HashSet::new();
+        let mut kept: Vec<&LogRecord> = Vec::new();
+        for record in records.iter().rev() {
+            let Some(key) = record.key() else { continue };
+            if settled.contains(key) {
+                continue;
+            }
+            if !matches!(record.untimed(), LogRecord::Merge { .. } | LogRecord::Expire { .. } | LogRecord::Collection { .. }) {
+                settled.insert(key);
+            }
+            kept.push(record);
+        }
+        kept.reverse();
+
+        let compressed = self.compression == LogCompression::Snappy;
+        let (path, other_path) = match compressed {
+            true => (compressed_segment_path(&self.dir, newest), segment_path(&self.dir, newest)),
+            false => (segment_path(&self.dir, newest), compressed_segment_path(&self.dir, newest)),
+        };
+        let kept_records = checkpoint.iter().chain(kept.iter().copied());
+        write_segment_file(&path, kept_records.map(|record| (self.format)(&record.encode())), compressed)?;
+        if other_path.exists() {
+            fs::remove_file(other_path)?;
+        }
+        for &segment in &sealed[..sealed.len() - 1] {
+            remove_segment(&self.dir, segment)?;
+        }
+        let dropped = checkpointed + records.len() - kept.len();
+        let bytes_after = fs::metadata(&path)?.len();
+        self.counters.compaction_records_dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        self.counters.compaction_bytes_saved.fetch_add(bytes_before.saturating_sub(bytes_after), Ordering::Relaxed);
+        info!("Compacted {} transaction log segments, dropping {} of {} records", sealed.len(), dropped, checkpointed + records.len());
+        Ok(dropped)
+    }
+}
+
+impl<E: Loggable> TransactionLog<E> {
+    /// Seals the current segment and starts writing to the next one.
     fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.counters.timed_sync(|| self.file.get_ref().sync_all())?;
+        if self.compression == LogCompression::Snappy {
+            self.compress_segment(self.segment)?;
+        }
+        self.segment += 1;
+        self.file = BufWriter::with_capacity(8192, Self::open_segment(&self.dir, self.segment)?);
+        self.segment_len = 0;
+        self.counters.segment_size.store(0, Ordering::Relaxed);
+        self.counters.rotations.fetch_add(1, Ordering::Relaxed);
+        if let Some(syncer) = &self.syncer {
+            *syncer.file.lock().unwrap_or_else(PoisonError::into_inner) = self.file.get_ref().try_clone()?;
+        }
+        self.cleanup()
+    }
 
-        // Create a backup path by cloning the current path and changing the extension to .1.
-        let mut backup_path = self.path.clone();
-        backup_path.set_extension("log.bak");
+    /// Replaces the sealed segment with a compressed copy.
+    fn compress_segment(&self, segment: u64) -> Result<()> {
+        let path = segment_path(&self.dir, segment);
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+        write_segment_file(&compressed_segment_path(&self.dir, segment), [data], true)?;
+        fs::remove_file(path)
+    }
 
-        // Rename the current log file to the backup path.
-        fs::rename(&self.path, &backup_path)?;
+    /// Applies retention: deletes the oldest segments until no more than `max_files` remain, counting
+    /// the current one, along with any sealed longer ago than the retention window. Segments needed
+    /// by an unfinished checkpoint are kept either way.
+    ///
+    /// This runs every time a segment is sealed, and can be called to apply the retention window
+    /// while the log is idle. A segment that fails to archive is kept, along with every newer one,
+    /// and retried next time.
+    pub fn cleanup(&self) -> Result<()> {
+        let segments = segment_numbers(&self.dir)?;
+        let mut expired = segments.len().saturating_sub(self.max_files.max(1) as usize);
+        if let Some(retain_for) = self.retain_for {
+            let cutoff = SystemTime::now().checked_sub(retain_for).unwrap_or(SystemTime::UNIX_EPOCH);
+            while expired < segments.len() && segments[expired] != self.segment && self.sealed_at(segments[expired])? < cutoff {
+                expired += 1;
+            }
+        }
+        let held = self.checkpoints.keys().next().copied().unwrap_or(u64::MAX);
+        for &segment in segments[..expired].iter().take_while(|&&segment| segment < held) {
+            if let Err(e) = self.archive(segment) {
+                error!("Failed to archive transaction log segment {}, keeping it: {}", segment, e);
+                break;
+            }
+            remove_segment(&self.dir, segment)?;
+            info!("Removed transaction log segment {}", segment);
+        }
+        Ok(())
+    }
 
-        // Create a new file with the same name as the original log file and open it for appending.
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)?;
+    /// Truncates the segment after its last valid record, or before a transaction left open after
+    /// it, returning its new length.
+    fn repair_tail(dir: &Path, segment: u64, file: &File, read_buffer_size: usize) -> Result<u64> {
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::with_capacity(read_buffer_size, File::open(segment_path(dir, segment))?);
+        let (mut read, mut valid) = (0, 0);
+        let mut in_transaction = false;
+        while let Ok(Some(frame)) = read_frame(&mut reader) {
+            if split_frame(&frame).is_none() {
+                break;
+            }
+            read += frame.len() as u64;
+            match E::decode(&frame).and_then(|entry| entry.marker()) {
+                Some(TxnMarker::Begin) => in_transaction = true,
+                Some(TxnMarker::Commit) => in_transaction = false,
+                None => {}
+            }
+            if !in_transaction {
+                valid = read;
+            }
+        }
+        if valid < len {
+            file.set_len(valid)?;
+            file.sync_all()?;
+            warn!("Discarded {} bytes of torn, corrupt, or uncommitted records from transaction log segment {}", len - valid, segment);
+        }
+        Ok(valid)
+    }
 
-        // Write a newline character to the new file.
-        file.write_all(b"\n")?;
+    /// Returns when the sealed segment was last written.
+    fn sealed_at(&self, segment: u64) -> Result<SystemTime> {
+        fs::metadata(existing_segment_path(&self.dir, segment))?.modified()
+    }
 
-        // Create a new buffered writer with a capacity of 8192 bytes and set it as the current file.
-        self.file = BufWriter::with_capacity(8192, file);
+    /// Copies the sealed segment to the archiver, if there is one.
+    fn archive(&self, segment: u64) -> Result<()> {
+        let Some(archiver) = &self.archiver else {
+            return Ok(());
+        };
+        let path = existing_segment_path(&self.dir, segment);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_owned();
+        archiver.archive(&name, &path)
+    }
 
-        // Clean up any old backup files.
-        self.cleanup()?;
-        self.compact()?;
+    fn open_segment(dir: &Path, segment: u64) -> Result<File> {
+        OpenOptions::new().create(true).append(true).open(segment_path(dir, segment))
+    }
+}
 
-        // Return Ok if everything succeeded.
-        Ok(())
+impl<E> Drop for TransactionLog<E> {
+    fn drop(&mut self) {
+        if let Some(syncer) = self.syncer.take() {
+            syncer.stop();
+        }
+        if self.sync_policy != SyncPolicy::Never {
+            let _ = self.file.flush().and_then(|_| self.file.get_ref().sync_data());
+        }
     }
+}
 
-    /// Cleans up old backup files by deleting any files that exceed the maximum number of allowed files.
-    fn cleanup(&self) -> Result<()> {
+/// Counters describing the work done by a transaction log, as returned by `TransactionLog::metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LogMetrics {
+    /// How many bytes have been written, after formatting.
+    pub bytes_written: u64,
+    /// How many records have been written.
+    pub records_written: u64,
+    /// How many segments have been sealed.
+    pub rotations: u64,
+    /// How many times the current segment has been synced to disk, by writes, rotations, or the
+    /// background syncer.
+    pub fsyncs: u64,
+    /// The total time spent in those syncs.
+    pub fsync_time: Duration,
+    /// The size of the segment being written, in bytes.
+    pub segment_size: u64,
+    /// How many records compaction has dropped.
+    pub compaction_records_dropped: u64,
+    /// How many bytes of segment files compaction has reclaimed.
+    pub compaction_bytes_saved: u64,
+}
 
-        // Read the directory containing the log file and filter out any non-file entries.
-        let mut files = fs::read_dir(self.path.parent().unwrap())?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
+impl LogMetrics {
+    /// Adds the counters of another log to these.
+    pub(crate) fn add(&mut self, other: LogMetrics) {
+        self.bytes_written += other.bytes_written;
+        self.records_written += other.records_written;
+        self.rotations += other.rotations;
+        self.fsyncs += other.fsyncs;
+        self.fsync_time += other.fsync_time;
+        self.segment_size += other.segment_size;
+        self.compaction_records_dropped += other.compaction_records_dropped;
+        self.compaction_bytes_saved += other.compaction_bytes_saved;
+    }
 
-                // Filter out any files that don't have the .log extension.
-                entry.file_type().ok().map(|ft| ft.is_file()).unwrap_or(false) &&
-                    entry.path().extension().map(|ext| ext == "log").unwrap_or(false) &&
+    /// Returns the average time a sync took, or None if there have been none.
+    pub fn mean_fsync_latency(&self) -> Option<Duration> {
+        (self.fsyncs > 0).then(|| self.fsync_time / self.fsyncs as u32)
+    }
+}
 
-                    // Filter out any files that don't have a numeric stem.
-                    entry.path().file_stem().and_then(|stem| stem.to_str().and_then(|s| s.parse::<u32>().ok())).is_some()
-            })
-            .collect::<Vec<_>>();
+/// The counters behind `LogMetrics`, shared with the threads that write or sync the log.
+#[derive(Default)]
+struct LogCounters {
+    bytes_written: AtomicU64,
+    records_written: AtomicU64,
+    rotations: AtomicU64,
+    fsyncs: AtomicU64,
+    fsync_nanos: AtomicU64,
+    segment_size: AtomicU64,
+    compaction_records_dropped: AtomicU64,
+    compaction_bytes_saved: AtomicU64,
+    durable_lsn: AtomicU64,
+}
+
+impl LogCounters {
+    /// Runs the sync, counting it and how long it took.
+    fn timed_sync(&self, sync: impl FnOnce() -> Result<()>) -> Result<()> {
+        let start = Instant::now();
+        let result = sync();
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        self.fsync_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
 
-        // Sort the files by path and remove the oldest files until the number of files is within the maximum allowed.
-        files.sort_by_key(|entry| entry.path());
-        while files.len() > self.max_files as usize {
-            let entry = files.remove(0);
-            fs::remove_file(entry.path())?;
+    fn metrics(&self) -> LogMetrics {
+        LogMetrics {
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            records_written: self.records_written.load(Ordering::Relaxed),
+            rotations: self.rotations.load(Ordering::Relaxed),
+            fsyncs: self.fsyncs.load(Ordering::Relaxed),
+            fsync_time: Duration::from_nanos(self.fsync_nanos.load(Ordering::Relaxed)),
+            segment_size: self.segment_size.load(Ordering::Relaxed),
+            compaction_records_dropped: self.compaction_records_dropped.load(Ordering::Relaxed),
+            compaction_bytes_saved: self.compaction_bytes_saved.load(Ordering::Relaxed),
         }
+    }
+}
 
-        // Return Ok if everything succeeded.
-        Ok(())
+/// The background thread that syncs the current segment for `SyncPolicy::EveryN`.
+struct Syncer {
+    file: Arc<Mutex<File>>,
+    /// The LSN of the last write not yet synced, or 0 if there is none.
+    pending: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Syncer {
+    fn spawn(file: File, interval: Duration, counters: Arc<LogCounters>) -> Self {
+        let file = Arc::new(Mutex::new(file));
+        let pending = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let (file, pending, stop) = (Arc::clone(&file), Arc::clone(&pending), Arc::clone(&stop));
+            thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    thread::park_timeout(interval);
+                    let lsn = pending.swap(0, Ordering::AcqRel);
+                    if lsn == 0 {
+                        continue;
+                    }
+                    match counters.timed_sync(|| file.lock().unwrap_or_else(PoisonError::into_inner).sync_data()) {
+                        Ok(()) => {
+                            counters.durable_lsn.fetch_max(lsn, Ordering::AcqRel);
+                        }
+                        Err(e) => error!("Failed to sync transaction log: {}", e),
+                    }
+                }
+            })
+        };
+        Self { file, pending, stop, handle }
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        self.handle.thread().unpark();
+        let _ = self.handle.join();
     }
+}
+
+/// A transaction log written by a dedicated thread, so callers only pay for queueing a record.
+///
+/// Writes are queued on a bounded channel in submission order; once `capacity` writes are waiting,
+/// submitting blocks until the writer catches up. Each submission returns a handle that can be
+/// waited on for the write's outcome. The thread drains the queue and exits when the log is dropped.
+pub struct BackgroundLog {
+    sender: Option<SyncSender<Submission>>,
+    handle: Option<JoinHandle<()>>,
+    counters: Arc<LogCounters>,
+}
 
-    // This function compacts the transaction log file by removing old entries.
-    fn compact(&mut self) -> Result<()> {
-        // Open the transaction log file for reading.
-        let mut reader = BufReader::with_capacity(self.read_buffer_size, File::open(&self.path)?);
+/// A request queued for the background writer, with the channel its outcome is sent back on.
+enum Submission {
+    Write(Vec<u8>, Sender<Result<Lsn>>),
+    Truncate(Sender<Result<Lsn>>),
+    Flush(Sender<Result<Lsn>>),
+    Sync(Sender<Result<Lsn>>),
+}
+
+/// The pending outcome of a write queued on a `BackgroundLog`.
+#[must_use = "dropping the handle discards the outcome of the write"]
+pub struct WriteHandle {
+    receiver: Receiver<Result<Lsn>>,
+}
+
+impl WriteHandle {
+    /// Waits for the write to reach the log, returning its LSN.
+    pub fn wait(self) -> Result<Lsn> {
+        self.receiver.recv().unwrap_or_else(|_| Err(Error::other("transaction log writer stopped")))
+    }
+}
 
-        // Open the transaction log file for writing.
-        let mut writer = BufWriter::new(File::create(&self.path)?);
+impl BackgroundLog {
+    /// Moves the log onto a writer thread that accepts up to `capacity` queued writes.
+    pub fn spawn(mut log: TransactionLog, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let counters = Arc::clone(&log.counters);
+        let handle = thread::spawn(move || {
+            for submission in receiver {
+                let (result, done) = match submission {
+                    Submission::Write(data, done) => (log.write(&data), done),
+                    Submission::Truncate(done) => (log.truncate().map(|_| log.lsn()), done),
+                    Submission::Flush(done) => (Ok(log.lsn()), done),
+                    Submission::Sync(done) => (log.sync(), done),
+                };
+                if let Err(e) = &result {
+                    error!("Transaction log write error: {}", e);
+                }
+                // The caller may not be waiting for the outcome.
+                let _ = done.send(result);
+            }
+        });
+        Self { sender: Some(sender), handle: Some(handle), counters }
+    }
 
-        // Create a buffer to hold the contents of each line.
-        let mut buffer = Vec::new();
+    /// Returns the counters of the log being written. Writes still waiting in the queue are not counted.
+    pub fn metrics(&self) -> LogMetrics {
+        self.counters.metrics()
+    }
 
-        // Keep track of the size of the compacted file.
-        let mut compacted_size = 0;
+    /// Queues already-encoded record bytes to be written, blocking while the queue is full.
+    pub fn write(&self, data: &[u8]) -> Result<WriteHandle> {
+        self.submit(|done| Submission::Write(data.to_vec(), done))
+    }
 
-        // Keep track of the total size of the original file.
-        let mut total_size = 0;
+    /// Queues a single mutation record to be written, blocking while the queue is full.
+    pub fn write_record(&self, record: &LogRecord) -> Result<WriteHandle> {
+        self.write(&record.encode())
+    }
 
-        // Read each line of the file.
-        while reader.read_until(b'\n', &mut buffer)? > 0 {
+    /// Queues the records to be written as one bracketed transaction, like `write_transaction`.
+    pub fn write_transaction(&self, records: &[LogRecord]) -> Result<WriteHandle> {
+        self.write(&LogRecord::encode_transaction(records))
+    }
 
-            // Add the length of the line to the total size.
-            total_size += buffer.len() as u64;
+    /// Queues the discarding of every record written so far, after the writes queued before it.
+    pub fn truncate(&self) -> Result<WriteHandle> {
+        self.submit(Submission::Truncate)
+    }
 
-            // If the total size is greater than or equal to the maximum size times the compact threshold...
-            if total_size as f64 >= self.max_size as f64 * self.compact_threshold {
+    /// Waits until every write queued so far has reached the log, returning the LSN just past them.
+    pub fn flush(&self) -> Result<Lsn> {
+        self.submit(Submission::Flush)?.wait()
+    }
 
-                // Write the contents of the buffer to the file.
-                writer.write_all(&buffer)?;
+    /// Waits until every write queued so far has reached the log, then syncs it to disk like
+    /// `TransactionLog::sync`, returning the LSN just past them.
+    pub fn sync(&self) -> Result<Lsn> {
+        self.submit(Submission::Sync)?.wait()
+    }
 
-                // Add the length of the buffer to the compacted size.
-                compacted_size += buffer.len() as u64;
+    /// Returns the LSN up to which every write is known to be synced to disk.
+    pub fn durable_lsn(&self) -> Lsn {
+        self.counters.durable_lsn.load(Ordering::Acquire)
+    }
 
-                // Clear the buffer.
-                buffer.clear();
+    fn submit(&self, submission: impl FnOnce(Sender<Result<Lsn>>) -> Submission) -> Result<WriteHandle> {
+        let (done, receiver) = mpsc::channel();
+        let sender = self.sender.as_ref().expect("sender is only taken on drop");
+        sender.send(submission(done)).map_err(|_| Error::other("transaction log writer stopped"))?;
+        Ok(WriteHandle { receiver })
+    }
+}
+
+impl Drop for BackgroundLog {
+    /// Writes everything still queued, then stops the writer thread.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Where a record starts in the log: its segment number and the byte offset of its frame in the segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogPosition {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+impl LogPosition {
+    /// A position before every segment, for reading a log from its oldest retained record.
+    pub const START: LogPosition = LogPosition { segment: 0, offset: 0 };
+
+    /// Returns the position as an LSN: the segment number in the high bits and the offset in the
+    /// low 40, which is enough for segments of up to a terabyte.
+    pub fn lsn(&self) -> Lsn {
+        (self.segment << LSN_OFFSET_BITS) | self.offset
+    }
+
+    /// Returns the position an LSN stands for, such as where to resume shipping after it.
+    pub fn from_lsn(lsn: Lsn) -> Self {
+        Self { segment: lsn >> LSN_OFFSET_BITS, offset: lsn & ((1 << LSN_OFFSET_BITS) - 1) }
+    }
+}
+
+/// An iterator over the records of a transaction log and their positions, returned by
+/// `TransactionLog::iter`.
+///
+/// Segments are streamed one frame at a time. A truncated or corrupt frame yields an error, after
+/// which the iterator ends. A committed transaction is yielded as one entry at the position of its
+/// begin marker, once its commit marker has been read; one still open at the end is not yielded.
+pub struct LogIter<R = LogRecord> {
+    dir: PathBuf,
+    segments: std::vec::IntoIter<u64>,
+    current: Option<(u64, u64, Box<dyn Read + Send>)>,
+    read_buffer_size: usize,
+    position: LogPosition,
+    transaction: Option<(LogPosition, Vec<R>)>,
+}
+
+impl<R> LogIter<R> {
+    fn new(dir: PathBuf, segments: Vec<u64>, read_buffer_size: usize) -> Self {
+        Self { dir, segments: segments.into_iter(), current: None, read_buffer_size, position: LogPosition::START, transaction: None }
+    }
+
+    /// Returns where the next record would be read from: just past the last record read, or the
+    /// start of the last segment opened. Iterating again from here picks up records written since,
+    /// including the rest of a transaction that was still open.
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+
+    /// Opens the segment for reading, skipping to the current position if it lies in this segment.
+    fn open(&self, segment: u64) -> Result<(u64, Box<dyn Read + Send>)> {
+        let mut reader = open_segment_reader(&self.dir, segment, self.read_buffer_size)?;
+        let offset = if segment == self.position.segment { self.position.offset } else { 0 };
+        if io::copy(&mut reader.as_mut().take(offset), &mut io::sink())? < offset {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("offset {} is past the end of transaction log segment {}", offset, segment)));
+        }
+        Ok((offset, reader))
+    }
+
+    /// Ends the iteration after an error.
+    fn fail(&mut self, e: Error) -> Error {
+        self.current = None;
+        self.segments = Vec::new().into_iter();
+        self.transaction = None;
+        e
+    }
+}
+
+impl<R: Loggable> Iterator for LogIter<R> {
+    type Item = Result<(LogPosition, R)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let Some(segment) = self.segments.next() else {
+                    self.transaction = None;
+                    return None;
+                };
+                let (offset, reader) = match self.open(segment) {
+                    Ok(opened) => opened,
+                    Err(e) => return Some(Err(self.fail(e))),
+                };
+                // A transaction can only be resumed from its start.
+                if self.transaction.is_none() {
+                    self.position = LogPosition { segment, offset };
+                }
+                self.current = Some((segment, offset, reader));
+            }
+            let (segment, offset, reader) = self.current.as_mut().unwrap();
+            let position = LogPosition { segment: *segment, offset: *offset };
+            let frame = match read_frame(reader.as_mut()) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.current = None;
+                    continue;
+                }
+                Err(e) => return Some(Err(self.fail(e))),
+            };
+            *offset += frame.len() as u64;
+            let end = LogPosition { segment: *segment, offset: *offset };
+            let Some(record) = R::decode(&frame) else {
+                let path = segment_path(&self.dir, position.segment);
+                let e = Error::new(ErrorKind::InvalidData, format!("invalid record in {} at offset {}", path.display(), position.offset));
+                return Some(Err(self.fail(e)));
+            };
+            match (record.marker(), self.transaction.take()) {
+                // A transaction begun before this one and never committed is dropped.
+                (Some(TxnMarker::Begin), _) => self.transaction = Some((position, Vec::new())),
+                (Some(TxnMarker::Commit), Some((start, records))) => {
+                    self.position = end;
+                    return match R::transaction(records) {
+                        Some(transaction) => Some(Ok((start, transaction))),
+                        None => {
+                            let path = segment_path(&self.dir, start.segment);
+                            let e = Error::new(ErrorKind::InvalidData, format!("unsupported transaction in {} at offset {}", path.display(), start.offset));
+                            Some(Err(self.fail(e)))
+                        }
+                    };
+                }
+                // A commit outside a transaction commits nothing.
+                (Some(TxnMarker::Commit), None) => self.position = end,
+                (None, Some((start, mut records))) => {
+                    records.push(record);
+                    self.transaction = Some((start, records));
+                }
+                (None, None) => {
+                    self.position = end;
+                    return Some(Ok((position, record)));
+                }
             }
         }
+    }
+}
 
-        // Write any remaining contents of the buffer to the file.
-        writer.write_all(&buffer)?;
+/// Reads the next frame from a segment, returning None at the end of the segment.
+fn read_frame(reader: &mut dyn Read) -> Result<Option<Vec<u8>>> {
+    let mut frame = vec![0; FRAME_HEADER_LEN];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_LEN {
+        match reader.read(&mut frame[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(Error::new(ErrorKind::UnexpectedEof, "truncated record header")),
+            read => filled += read,
+        }
+    }
+    let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    // A corrupt length could be huge, so grow the buffer as data arrives rather than up front.
+    if reader.take(len as u64).read_to_end(&mut frame)? < len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated record"));
+    }
+    Ok(Some(frame))
+}
+
+/// Returns the numbers of the segments in the directory, in ascending order.
+fn segment_numbers(dir: &Path) -> Result<Vec<u64>> {
+    let mut segments: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|file_type| file_type.is_file()).unwrap_or(false))
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let name = name.to_str()?.strip_prefix("wal-")?;
+            name.strip_suffix(".log").or_else(|| name.strip_suffix(".log.sz"))?.parse().ok()
+        })
+        .collect();
+    segments.sort_unstable();
+    segments.dedup();
+    Ok(segments)
+}
 
-        // Add the length of the buffer to the compacted size.
-        compacted_size += buffer.len() as u64;
+/// Returns the path of the numbered segment in the log directory.
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("wal-{:06}.log", segment))
+}
 
-        // Log the size of the original and compacted files.
-        info!("Compacted log file from {} bytes to {} bytes", total_size, compacted_size);
+/// Returns the path of the numbered segment once it has been sealed and compressed.
+fn compressed_segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("wal-{:06}.log.sz", segment))
+}
 
-        // Return success.
-        Ok(())
+/// Returns the path of the numbered segment in whichever form exists. Like reading, this prefers the
+/// uncompressed form if a crash left both behind.
+fn existing_segment_path(dir: &Path, segment: u64) -> PathBuf {
+    let path = segment_path(dir, segment);
+    if path.exists() { path } else { compressed_segment_path(dir, segment) }
+}
+
+/// Opens the numbered segment for reading, decompressing it if it was compressed.
+///
+/// If a crash left both forms behind, the uncompressed one is read; both hold the same records.
+fn open_segment_reader(dir: &Path, segment: u64, read_buffer_size: usize) -> Result<Box<dyn Read + Send>> {
+    match File::open(segment_path(dir, segment)) {
+        Ok(file) => Ok(Box::new(BufReader::with_capacity(read_buffer_size, file))),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let file = File::open(compressed_segment_path(dir, segment))?;
+            Ok(Box::new(FrameDecoder::new(BufReader::with_capacity(read_buffer_size, file))))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes the numbered segment in whichever forms exist.
+fn remove_segment(dir: &Path, segment: u64) -> Result<()> {
+    for path in [segment_path(dir, segment), compressed_segment_path(dir, segment)] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
     }
+    Ok(())
+}
+
+/// Writes the records as a complete segment file at the path, compressing them if asked, via a
+/// temporary file so the path only ever holds a whole segment.
+fn write_segment_file(path: &Path, data: impl IntoIterator<Item = Vec<u8>>, compressed: bool) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let file = File::create(&temp_path)?;
+    let file = if compressed {
+        let mut writer = FrameEncoder::new(BufWriter::new(file));
+        for chunk in data {
+            writer.write_all(&chunk)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.into_inner().map_err(|e| e.into_error())?
+    } else {
+        let mut writer = BufWriter::new(file);
+        for chunk in data {
+            writer.write_all(&chunk)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?
+    };
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
 }
 
+/// Reads text commands from a client, one per line, and writes each to the log as a record.
 pub fn handle_client(stream: SslStream<TcpStream>, log: Arc<Mutex<TransactionLog>>) -> Result<()> {
     let mut reader = BufReader::new(stream);
-    let mut buffer = String::new();
-    while reader.read_line(&mut buffer)? > 0 {
-        if let Err(e) = log.lock().unwrap().write(buffer.as_bytes()) {
+    let mut buffer = Vec::new();
+    while reader.read_until(b'\n', &mut buffer)? > 0 {
+        let Some(record) = LogRecord::parse_line(&buffer) else {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid transaction log command"));
+        };
+        let mut log = log.lock().map_err(|_| Error::other("transaction log lock is poisoned"))?;
+        if let Err(e) = log.write_record(&record) {
             error!("Transaction log write error: {}", e);
-            return Err(e.into());
+            return Err(e);
         }
         buffer.clear();
     }
@@ -168,25 +1271,428 @@ pub fn handle_client(stream: SslStream<TcpStream>, log: Arc<Mutex<TransactionLog
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::archive::DirectoryArchiver;
+
+    fn open(dir: &str, max_size: u64, max_files: u32) -> TransactionLog {
+        let _ = fs::remove_dir_all(dir);
+        TransactionLog::new(dir, max_size, max_files, 8192, Box::new(|data| data.to_vec())).unwrap()
+    }
 
     #[test]
     fn test_new() {
-        let log = TransactionLog::new("logs/test_new.log", 1024, 5, 0.5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let log = open("logs/test_new", 1024, 5);
         assert_eq!(log.max_size, 1024);
         assert_eq!(log.max_files, 5);
+        assert_eq!(log.segment, 1);
         assert_eq!(log.file.get_ref().metadata().unwrap().len(), 0);
     }
 
     #[test]
     fn test_write() {
-        let mut log = TransactionLog::new("logs/test_write.log", 15, 5, 0.5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        let mut log = open("logs/test_write", 15, 5);
         log.write(b"Hello, world!").unwrap();
-        println!("{}", log.file.get_ref().metadata().unwrap().len());
         assert_eq!(log.file.get_ref().metadata().unwrap().len(), 13);
 
         // Write more than max_size bytes to trigger rotation
         let data = vec![b'x'; 20];
         log.write(&data).unwrap();
+        assert_eq!(log.segment, 2);
         assert_eq!(log.file.get_ref().metadata().unwrap().len(), 0);
+        assert_eq!(fs::metadata("logs/test_write/wal-000001.log").unwrap().len(), 33);
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = "logs/test_segments";
+        let mut log = open(dir, 40, 3);
+        for i in 0..10 {
+            log.write_record(&LogRecord::Put { key: format!("key{}", i).into_bytes(), value: b"value".to_vec() }).unwrap();
+        }
+
+        // Every two records fill a segment, and only the newest three segments are kept.
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![4, 5, 6]);
+        let keys: Vec<_> = log
+            .records()
+            .unwrap()
+            .into_iter()
+            .map(|record| match record {
+                LogRecord::Put { key, .. } => key,
+                _ => panic!("unexpected record"),
+            })
+            .collect();
+        assert_eq!(keys, vec![b"key6", b"key7", b"key8", b"key9"]);
+
+        // Reopening continues in the newest segment.
+        drop(log);
+        let mut log: TransactionLog = TransactionLog::new(dir, 40, 3, 8192, Box::new(|data| data.to_vec())).unwrap();
+        assert_eq!(log.segment, 6);
+        log.truncate().unwrap();
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![7]);
+        assert_eq!(log.records().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let dir = "logs/test_torn_write";
+        let mut log = open(dir, 1024, 5);
+        let put = |key: &str| LogRecord::Put { key: key.into(), value: b"value".to_vec() };
+        log.write_record(&put("a")).unwrap();
+        log.write_record(&put("b")).unwrap();
+        let valid = log.segment_len;
+
+        // A crash halfway through a write leaves only part of the record behind.
+        let torn = put("c").encode();
+        log.write(&torn[..torn.len() / 2]).unwrap();
+        drop(log);
+
+        let mut log = TransactionLog::new(dir, 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        assert_eq!(log.segment_len, valid);
+        assert_eq!(fs::metadata(segment_path(Path::new(dir), 1)).unwrap().len(), valid);
+        log.write_record(&put("d")).unwrap();
+        assert_eq!(log.records().unwrap(), vec![put("a"), put("b"), put("d")]);
+
+        // A complete frame whose checksum doesn't match is discarded too.
+        let mut corrupt = put("e").encode();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        log.write(&corrupt).unwrap();
+        drop(log);
+        let mut log: TransactionLog = TransactionLog::new(dir, 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        assert_eq!(log.records().unwrap(), vec![put("a"), put("b"), put("d")]);
+    }
+
+    #[test]
+    fn test_lsn() {
+        let dir = "logs/test_lsn";
+        let mut log = open(dir, 40, 5);
+        let record = LogRecord::Delete { key: b"key".to_vec() };
+        let mut lsns = Vec::new();
+        for _ in 0..5 {
+            lsns.push(log.write_record(&record).unwrap());
+        }
+        drop(log);
+        let mut log = TransactionLog::new(dir, 40, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        lsns.push(log.write_record(&record).unwrap());
+        log.truncate().unwrap();
+        lsns.push(log.write_record(&record).unwrap());
+
+        // LSNs keep increasing across rotations, reopening, and truncation.
+        assert!(lsns.windows(2).all(|pair| pair[0] < pair[1]));
+        let position = LogPosition::from_lsn(lsns[0]);
+        assert_eq!(position, LogPosition { segment: 1, offset: record.encode().len() as u64 });
+        assert_eq!(position.lsn(), lsns[0]);
+    }
+
+    #[test]
+    fn test_rotation_under_max_files() {
+        for max_files in 1..=4 {
+            let dir = format!("logs/test_rotation_under_max_files_{}", max_files);
+            let mut log = open(&dir, 1, max_files);
+            for i in 0..10 {
+                log.write_record(&LogRecord::Delete { key: format!("key{}", i).into_bytes() }).unwrap();
+            }
+
+            // Every write seals its segment, so the newest segment is empty and the ones before it
+            // hold the latest records; older generations are deleted whole.
+            let expected: Vec<u64> = (12 - max_files as u64..=11).collect();
+            assert_eq!(segment_numbers(Path::new(&dir)).unwrap(), expected);
+            let kept: Vec<_> = (11 - max_files as usize..10).map(|i| LogRecord::Delete { key: format!("key{}", i).into_bytes() }).collect();
+            assert_eq!(log.records().unwrap(), kept);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compact() {
+        let dir = "logs/test_compact";
+        let mut log = open(dir, 20, 10);
+        let put = |key: &str, value: &str| LogRecord::Put { key: key.into(), value: value.as_bytes().to_vec() };
+        let merge = |key: &str, operand: &str| LogRecord::Merge { key: key.into(), operand: operand.as_bytes().to_vec() };
+        let delete = |key: &str| LogRecord::Delete { key: key.into() };
+        log.write_record(&put("a", "1")).unwrap();
+        log.write_record(&put("b", "1\n2")).unwrap();
+        log.write_record(&put("a", "2")).unwrap();
+        log.write_record(&LogRecord::Commit { records: vec![put("c", "1"), delete("b")] }).unwrap();
+        log.write_record(&merge("a", "+1")).unwrap();
+        log.write_record(&put("c", "2")).unwrap();
+        log.write_record(&merge("c", "+1")).unwrap();
+        log.write_record(&merge("c", "+2")).unwrap();
+        let sealed = segment_numbers(Path::new(dir)).unwrap();
+        assert!(sealed.len() > 2);
+
+        // The current segment is left alone.
+        log.write_record(&put("a", "3")).unwrap();
+        let current = log.segment;
+        assert_eq!(log.compact().unwrap(), 3);
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![current - 1, current]);
+        assert_eq!(log.records().unwrap(), vec![
+            put("a", "2"),
+            delete("b"),
+            merge("a", "+1"),
+            put("c", "2"),
+            merge("c", "+1"),
+            merge("c", "+2"),
+            put("a", "3"),
+        ]);
+        assert_eq!(log.compact().unwrap(), 0);
+
+        // Records before the last checkpoint are dropped, and the checkpoint is kept in their place.
+        log.write_record(&LogRecord::Checkpoint { version: 7 }).unwrap();
+        log.write_record(&merge("a", "+2")).unwrap();
+        log.write_record(&put("d", "1")).unwrap();
+        log.write_record(&put("e", "1")).unwrap();
+        assert_eq!(log.compact().unwrap(), 7);
+        assert_eq!(log.records().unwrap(), vec![LogRecord::Checkpoint { version: 7 }, merge("a", "+2"), put("d", "1"), put("e", "1")]);
+    }
+
+    #[test]
+    fn test_compressed_segments() {
+        let dir = "logs/test_compressed_segments";
+        let mut log = open(dir, 256, 10).with_compression(LogCompression::Snappy);
+        let records: Vec<_> = (0..20).map(|i| LogRecord::Put { key: format!("key{}", i).into_bytes(), value: vec![b'x'; 100] }).collect();
+        for record in &records {
+            log.write_record(record).unwrap();
+        }
+
+        // Sealed segments are replaced by much smaller compressed files and still read back in order.
+        let sealed = compressed_segment_path(Path::new(dir), 1);
+        assert!(!segment_path(Path::new(dir), 1).exists());
+        assert!(fs::metadata(&sealed).unwrap().len() < 128);
+        assert!(segment_path(Path::new(dir), log.segment).exists());
+        assert_eq!(log.records().unwrap(), records);
+
+        // Compaction keeps the compressed form. Rewriting key0 seals a segment, so its first put is dropped.
+        log.write_record(&records[0]).unwrap();
+        assert_eq!(log.compact().unwrap(), 1);
+        let newest_sealed = log.segment - 1;
+        assert!(compressed_segment_path(Path::new(dir), newest_sealed).exists());
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![newest_sealed, log.segment]);
+        assert_eq!(TransactionLog::<LogRecord>::read_all(dir, 8192).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_retention() {
+        let dir = "logs/test_retention";
+        let mut log = open(dir, 1, 100).with_retention(Duration::from_secs(3600));
+        for i in 0..3 {
+            log.write_record(&LogRecord::Delete { key: format!("key{}", i).into_bytes() }).unwrap();
+        }
+        log.cleanup().unwrap();
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![1, 2, 3, 4]);
+
+        // Segments sealed before the window are deleted though far fewer than max_files remain.
+        let sealed_at = SystemTime::now() - Duration::from_secs(7200);
+        for segment in [1, 2] {
+            File::options().write(true).open(segment_path(Path::new(dir), segment)).unwrap().set_modified(sealed_at).unwrap();
+        }
+        log.cleanup().unwrap();
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_checkpoint_retention() {
+        let dir = "logs/test_checkpoint_retention";
+        let mut log = open(dir, 1, 2);
+        log.write_record(&LogRecord::Delete { key: b"key0".to_vec() }).unwrap();
+        let checkpoint = log.begin_checkpoint().unwrap();
+        assert_eq!(checkpoint, LogPosition { segment: 2, offset: 0 });
+        for i in 1..5 {
+            log.write_record(&LogRecord::Delete { key: format!("key{}", i).into_bytes() }).unwrap();
+        }
+
+        // Only the segment from before the checkpoint can go while it is unfinished.
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![2, 3, 4, 5, 6]);
+        log.finish_checkpoint(checkpoint).unwrap();
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = "logs/test_archive";
+        let archive_dir = "logs/test_archive_archive";
+        let _ = fs::remove_dir_all(archive_dir);
+        let mut log = open(dir, 1, 2).with_compression(LogCompression::Snappy).with_archiver(DirectoryArchiver::new(archive_dir).unwrap());
+        for i in 0..5 {
+            log.write_record(&LogRecord::Delete { key: format!("key{}", i).into_bytes() }).unwrap();
+        }
+
+        // Segments deleted by cleanup were archived first, so the archive and the log together
+        // still hold every record.
+        assert_eq!(segment_numbers(Path::new(dir)).unwrap(), vec![5, 6]);
+        assert_eq!(segment_numbers(Path::new(archive_dir)).unwrap(), vec![1, 2, 3, 4]);
+        let mut records = TransactionLog::read_all(archive_dir, 8192).unwrap();
+        records.extend(log.records().unwrap());
+        let expected: Vec<_> = (0..5).map(|i| LogRecord::Delete { key: format!("key{}", i).into_bytes() }).collect();
+        assert_eq!(records, expected);
+
+        log.truncate().unwrap();
+        assert_eq!(TransactionLog::<LogRecord>::read_all(archive_dir, 8192).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let dir = "logs/test_metrics";
+        let mut log = open(dir, 40, 10).with_sync_policy(SyncPolicy::Always).unwrap();
+        let record = LogRecord::Put { key: b"key".to_vec(), value: b"value".to_vec() };
+        let len = record.encode().len() as u64;
+        for _ in 0..5 {
+            log.write_record(&record).unwrap();
+        }
+
+        // Every two records fill a segment, and every write is synced by the policy or the rotation.
+        let metrics = log.metrics();
+        assert_eq!(metrics.records_written, 5);
+        assert_eq!(metrics.bytes_written, 5 * len);
+        assert_eq!(metrics.rotations, 2);
+        assert_eq!(metrics.fsyncs, 5);
+        assert!(metrics.mean_fsync_latency().is_some());
+        assert_eq!(metrics.segment_size, len);
+
+        assert_eq!(log.compact().unwrap(), 3);
+        let metrics = log.metrics();
+        assert_eq!(metrics.compaction_records_dropped, 3);
+        assert_eq!(metrics.compaction_bytes_saved, 3 * len);
+    }
+
+    #[test]
+    fn test_background_log() {
+        let dir = "logs/test_background_log";
+        let log = open(dir, 1024, 5).with_sync_policy(SyncPolicy::Always).unwrap();
+        let log = BackgroundLog::spawn(log, 4);
+        let records: Vec<_> = (0..10).map(|i| LogRecord::Delete { key: format!("key{}", i).into_bytes() }).collect();
+        let handles: Vec<_> = records.iter().map(|record| log.write_record(record).unwrap()).collect();
+        let lsns: Vec<_> = handles.into_iter().map(|handle| handle.wait().unwrap()).collect();
+        assert!(lsns.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(log.durable_lsn(), lsns[9]);
+        assert_eq!(TransactionLog::<LogRecord>::read_all(dir, 8192).unwrap(), records);
+
+        // Writes whose handles are dropped still land, in order, before a flush returns.
+        log.write_record(&records[0]).unwrap().wait().unwrap();
+        let _ = log.write_record(&records[1]).unwrap();
+        log.flush().unwrap();
+        assert_eq!(TransactionLog::<LogRecord>::read_all(dir, 8192).unwrap()[10..], records[..2]);
+        log.truncate().unwrap().wait().unwrap();
+        assert_eq!(TransactionLog::<LogRecord>::read_all(dir, 8192).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_iter() {
+        let dir = "logs/test_iter";
+        let mut log = open(dir, 40, 5);
+        let records: Vec<_> = (0..5).map(|i| LogRecord::Put { key: format!("key{}", i).into_bytes(), value: b"value".to_vec() }).collect();
+        for record in &records {
+            log.write_record(record).unwrap();
+        }
+        let logged: Vec<_> = log.iter().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(logged.iter().map(|(_, record)| record.clone()).collect::<Vec<_>>(), records);
+
+        // Two records fit in each segment, so positions restart at every segment.
+        let frame_len = records[0].encode().len() as u64;
+        let positions: Vec<_> = logged.iter().map(|(position, _)| (position.segment, position.offset)).collect();
+        assert_eq!(positions, vec![(1, 0), (1, frame_len), (2, 0), (2, frame_len), (3, 0)]);
+
+        // A corrupt frame ends the iteration with an error.
+        log.write(b"garbage!").unwrap();
+        let results: Vec<_> = log.iter().unwrap().collect();
+        assert_eq!(results.len(), 6);
+        assert!(results[5].is_err());
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let mut log = open("logs/test_sync_policy_always", 1024, 5).with_sync_policy(SyncPolicy::Always).unwrap();
+        let lsn = log.write(b"data").unwrap();
+        assert_eq!(log.durable_lsn(), lsn);
+
+        let mut log = open("logs/test_sync_policy_never", 1024, 5);
+        assert!(log.durable_lsn() < log.write(b"data").unwrap());
+        // Sealing a segment always syncs it.
+        let lsn = log.write(&[b'x'; 1024]).unwrap();
+        assert_eq!(log.durable_lsn(), lsn);
+
+        let mut log = open("logs/test_sync_policy_every", 1024, 5).with_sync_policy(SyncPolicy::EveryN(Duration::from_millis(10))).unwrap();
+        let lsn = log.write(b"data").unwrap();
+        for _ in 0..100 {
+            if log.durable_lsn() == lsn {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(log.durable_lsn(), lsn);
+    }
+
+    #[test]
+    fn test_log_record_roundtrip() {
+        let put = LogRecord::Put { key: b"test_key".to_vec(), value: b"line one\nline\ttwo\n".to_vec() };
+        let delete = LogRecord::Delete { key: b"test_key".to_vec() };
+        assert_eq!(LogRecord::decode(&put.encode()), Some(put.clone()));
+        assert_eq!(LogRecord::decode(&delete.encode()), Some(delete));
+        assert_eq!(LogRecord::decode(b"GARBAGE\n"), None);
+
+        // A flipped bit fails the checksum, and a truncated frame is rejected.
+        let mut frame = put.encode();
+        *frame.last_mut().unwrap() ^= 1;
+        assert_eq!(LogRecord::decode(&frame), None);
+        let frame = put.encode();
+        assert_eq!(LogRecord::decode(&frame[..frame.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_log_record_commit_roundtrip() {
+        let put = LogRecord::Put { key: b"a".to_vec(), value: b"1".to_vec() };
+        let commit = LogRecord::Commit { records: vec![put.clone(), LogRecord::Delete { key: b"b".to_vec() }] };
+        let mut data = commit.encode();
+        data.extend_from_slice(&put.encode());
+        assert_eq!(LogRecord::decode_all(&data), Some(vec![commit.clone(), put]));
+        assert_eq!(LogRecord::decode_all(&data[..data.len() - 3]), None);
+
+        let timed = LogRecord::Timed { at: SystemTime::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789), record: Box::new(commit) };
+        assert_eq!(LogRecord::decode(&timed.encode()), Some(timed));
+        let checkpoint = LogRecord::Checkpoint { version: 42 };
+        assert_eq!(LogRecord::decode(&checkpoint.encode()), Some(checkpoint));
+        let expire = LogRecord::Expire { key: b"a".to_vec(), at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) };
+        assert_eq!(LogRecord::decode(&expire.encode()), Some(expire));
+        let collection = LogRecord::Collection { key: b"a".to_vec(), op: CollectionOp::HashSet { field: b"f".to_vec(), value: b"v".to_vec() } };
+        assert_eq!(LogRecord::decode(&collection.encode()), Some(collection));
+        assert_eq!(LogRecord::decode(&LogRecord::BeginTxn.encode()), Some(LogRecord::BeginTxn));
+        assert_eq!(LogRecord::decode(&LogRecord::CommitTxn.encode()), Some(LogRecord::CommitTxn));
+    }
+
+    #[test]
+    fn test_transactions() {
+        let dir = "logs/test_transactions";
+        let mut log = open(dir, 1024, 5);
+        let put = |key: &str| LogRecord::Put { key: key.into(), value: b"value".to_vec() };
+        log.write_record(&put("a")).unwrap();
+        log.write_transaction(&[put("b"), LogRecord::Delete { key: b"a".to_vec() }]).unwrap();
+        let (after, committed) = (log.lsn(), vec![put("c")]);
+        log.write_transaction(&committed).unwrap();
+
+        // A committed transaction is read as one commit at the position of its begin marker.
+        let logged: Vec<_> = log.iter().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(logged.len(), 3);
+        assert_eq!(logged[1].1, LogRecord::Commit { records: vec![put("b"), LogRecord::Delete { key: b"a".to_vec() }] });
+        assert_eq!(logged[2], (LogPosition::from_lsn(after), LogRecord::Commit { records: committed }));
+
+        // One that was never committed is not read, and the iterator stops short of it.
+        let end = log.lsn();
+        log.write(&[LogRecord::BeginTxn.encode(), put("d").encode()].concat()).unwrap();
+        let mut iter = log.iter().unwrap();
+        assert_eq!(iter.by_ref().count(), 3);
+        assert_eq!(iter.position(), LogPosition::from_lsn(end));
+
+        // Reopening the log cuts it off, so records written after it aren't taken into it.
+        drop(log);
+        let mut log: TransactionLog = TransactionLog::new(dir, 1024, 5, 8192, Box::new(|data| data.to_vec())).unwrap();
+        assert_eq!(log.lsn(), end);
+        log.write_record(&put("e")).unwrap();
+        let records = log.records().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3], put("e"));
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(LogRecord::parse_line(b"PUT\tkey\tvalue\n"), Some(LogRecord::Put { key: b"key".to_vec(), value: b"value".to_vec() }));
+        assert_eq!(LogRecord::parse_line(b"DEL\tkey"), Some(LogRecord::Delete { key: b"key".to_vec() }));
+        assert_eq!(LogRecord::parse_line(b"GARBAGE\n"), None);
+    }
+}
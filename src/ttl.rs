@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::rebalance::Routing;
+use crate::storage_server::sweep_expired_partitions;
+
+/// A background thread that periodically evicts expired entries from a StorageServer's partitions.
+///
+/// It also reaps tombstones left by deletes once their grace period has elapsed, on the primary and
+/// every replica.
+///
+/// The thread is stopped when the sweeper is stopped or dropped.
+pub struct TtlSweeper {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TtlSweeper {
+    pub(crate) fn spawn(routing: Arc<Routing>, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Acquire) {
+                sweep_expired_partitions(&routing.current().partitions);
+                thread::park_timeout(interval);
+            }
+        });
+        Self { stop, handle: Some(handle) }
+    }
+
+    /// Stops the sweeper thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for TtlSweeper {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::sync::RwLockWriteGuard;
+use crate::error::FlowDbError;
+use crate::storage_server::{put_records, Partition, StorageServer};
+use crate::transaction_log::LogRecord;
+
+/// A set of writes that is applied to a StorageServer atomically.
+///
+/// Writes are buffered until `commit`, and the version of every key the transaction reads is
+/// recorded. Committing locks the keys read and written, fails with `Conflict` if any key read has
+/// been written since, writes a single commit record to the server's transaction log, and then
+/// locks every partition involved in ascending index order (so concurrent transactions can't
+/// deadlock) and applies all writes before releasing any lock. Dropping a transaction without
+/// committing discards its writes.
+pub struct Txn<'a> {
+    server: &'a StorageServer,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// The version of each key when it was first read, or None if it was missing.
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+}
+
+impl<'a> Txn<'a> {
+    pub(crate) fn new(server: &'a StorageServer) -> Self {
+        Self { server, writes: BTreeMap::new(), reads: BTreeMap::new() }
+    }
+
+    /// Returns the value of the key as seen by this transaction, including its own uncommitted writes.
+    ///
+    /// Reading a key the transaction hasn't written adds it to the keys checked at commit.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Vec<u8>, FlowDbError> {
+        let key = key.as_ref();
+        match self.writes.get(key) {
+            Some(Some(value)) => Ok(value.clone()),
+            Some(None) => Err(FlowDbError::NotFound),
+            None => {
+                let _routing = self.server.enter();
+                let entry = self.server.live_entry(&self.server.get_partition(key), key)?;
+                self.reads.entry(key.to_vec()).or_insert(entry.as_ref().map(|entry| entry.meta.version));
+                match entry {
+                    Some(entry) => self.server.decode_entry(&entry),
+                    None => Err(FlowDbError::NotFound),
+                }
+            }
+        }
+    }
+
+    /// Buffers a put of the key-value pair.
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.writes.insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+    }
+
+    /// Buffers a delete of the key.
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) {
+        self.writes.insert(key.as_ref().to_vec(), None);
+    }
+
+    /// Discards all buffered writes.
+    pub fn rollback(self) {}
+
+    /// Atomically applies all buffered writes.
+    ///
+    /// If a key the transaction read has been written since, nothing is applied and `Conflict` is
+    /// returned, so the caller can retry the transaction. If the commit record can't be written to
+    /// the transaction log, nothing is applied and an error is returned.
+    pub fn commit(self) -> Result<(), FlowDbError> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        // Build every entry before anything is locked or logged, so a value that can't be stored fails
+        // the commit.
+        let entries = self
+            .writes
+            .iter()
+            .map(|(key, value)| Ok((key, value.as_deref().map(|value| self.server.new_entry(value)).transpose()?)))
+            .collect::<Result<Vec<_>, FlowDbError>>()?;
+
+        // Lock the keys read and written, so no other write to them can come between the check of
+        // the reads, the log and the partitions.
+        let _routing = self.server.enter();
+        let _key_locks = self.server.key_locks.lock_all(self.writes.keys().chain(self.reads.keys()));
+        let topology = self.server.topology();
+        for (key, version) in &self.reads {
+            let current = self.server.live_entry(&topology.partitions[topology.ring.partition(key)], key)?;
+            if current.map(|entry| entry.meta.version) != *version {
+                return Err(FlowDbError::Conflict);
+            }
+        }
+        let mut indexes: Vec<usize> = self.writes.keys().map(|key| topology.ring.partition(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        // Record the whole transaction before applying it, so it is replayed all or nothing.
+        if indexes.iter().any(|&index| topology.partitions[index].is_poisoned()) {
+            return Err(FlowDbError::LockPoisoned);
+        }
+        let records = self
+            .writes
+            .iter()
+            .zip(&entries)
+            .flat_map(|((key, value), (_, entry))| match (value, entry) {
+                (Some(value), Some(entry)) => put_records(key, value.clone(), entry),
+                _ => vec![LogRecord::Delete { key: key.clone() }],
+            })
+            .collect();
+        self.server.log_record(&LogRecord::Commit { records })?;
+
+        // Lock every involved partition in ascending index order to avoid deadlocks between transactions.
+        let mut guards: BTreeMap<usize, RwLockWriteGuard<Partition>> = BTreeMap::new();
+        for index in indexes {
+            guards.insert(index, topology.partitions[index].write()?);
+        }
+
+        // Apply every write while all partition locks are still held.
+        for (key, entry) in entries {
+            let partition_guard = guards.get_mut(&topology.ring.partition(key)).unwrap();
+            match entry {
+                Some(entry) => {
+                    self.server.store_entry(partition_guard, key, entry);
+                }
+                None => {
+                    self.server.remove_entry(partition_guard, key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{PoisonError, RwLock};
+
+/// A change to a watched key, delivered to subscribers created with `StorageServer::watch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// The key was written; `value` is the new, decompressed value.
+    Put { key: Vec<u8>, value: Vec<u8> },
+    /// The key was deleted.
+    Delete { key: Vec<u8> },
+}
+
+impl ChangeEvent {
+    /// Returns the key the event refers to.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            ChangeEvent::Put { key, .. } | ChangeEvent::Delete { key } => key,
+        }
+    }
+}
+
+enum Filter {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl Filter {
+    fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            Filter::Key(watched) => watched == key,
+            Filter::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// The subscribers interested in changes to a StorageServer's keys.
+///
+/// Subscribers whose receiver has been dropped are removed the next time an event would be sent to them.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    subscribers: RwLock<Vec<(Filter, Sender<ChangeEvent>)>>,
+}
+
+impl Watchers {
+    pub(crate) fn subscribe_key(&self, key: &[u8]) -> Receiver<ChangeEvent> {
+        self.subscribe(Filter::Key(key.to_vec()))
+    }
+
+    pub(crate) fn subscribe_prefix(&self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        self.subscribe(Filter::Prefix(prefix.to_vec()))
+    }
+
+    fn subscribe(&self, filter: Filter) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.write().unwrap_or_else(PoisonError::into_inner).push((filter, sender));
+        receiver
+    }
+
+    /// Returns whether any subscriber is interested in the key, so callers can skip building events nobody receives.
+    pub(crate) fn is_watched(&self, key: &[u8]) -> bool {
+        self.subscribers.read().unwrap_or_else(PoisonError::into_inner).iter().any(|(filter, _)| filter.matches(key))
+    }
+
+    /// Sends the event to every matching subscriber.
+    pub(crate) fn notify(&self, event: ChangeEvent) {
+        self.subscribers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(filter, sender)| !filter.matches(event.key()) || sender.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,177 @@
+//! A WebSocket feed of changes to the keys under a prefix, served on the HTTP frontend at
+//! `GET /v1/watch?prefix=`.
+//!
+//! After the upgrade, the server sends a text message for every put or delete of a matching key,
+//! in the order the writes were applied: `{"event": "put", "key": ..., "value": ...}` with the
+//! value in the same form as the HTTP API's entries, or `{"event": "delete", "key": ...}`. Writes
+//! made before the handshake completes aren't sent, and neither is expiry of a TTL.
+//!
+//! The feed only goes one way: frames from the client aren't read. The server pings the client
+//! when no change has been sent for a while, and the feed ends once a write to the client fails,
+//! so a client that goes away is noticed within two ping intervals.
+
+use std::io::{self, Write};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use openssl::base64;
+use openssl::sha::sha1;
+use serde_json::json;
+use crate::http::JsonEntry;
+use crate::storage_server::StorageServer;
+use crate::watch::ChangeEvent;
+
+/// Appended to the client's key before hashing it for the handshake, as RFC 6455 requires.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// How long the feed may be idle before the client is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_PING: u8 = 0x9;
+
+impl StorageServer {
+    /// Completes the WebSocket handshake for the client's `Sec-WebSocket-Key` and sends changes to
+    /// keys starting with the prefix until a write to the client fails.
+    pub(crate) fn serve_watch<S: Write>(&self, stream: &mut S, key: &str, prefix: &str) -> io::Result<()> {
+        // Subscribe before answering, so every write after the client sees the upgrade is sent.
+        let events = self.watch_prefix(prefix);
+        write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(key))?;
+        stream.flush()?;
+        let mut out = Vec::new();
+        loop {
+            match events.recv_timeout(PING_INTERVAL) {
+                Ok(event) => {
+                    // Changes that arrived together are sent together.
+                    for event in std::iter::once(event).chain(events.try_iter()) {
+                        encode_frame(OPCODE_TEXT, event_json(event).as_bytes(), &mut out);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => encode_frame(OPCODE_PING, &[], &mut out),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            stream.write_all(&out)?;
+            stream.flush()?;
+            out.clear();
+        }
+    }
+}
+
+/// Returns the `Sec-WebSocket-Accept` answering the client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+fn event_json(event: ChangeEvent) -> String {
+    match event {
+        ChangeEvent::Put { key, value } => {
+            let mut message = json!(JsonEntry::new(key, value));
+            message["event"] = json!("put");
+            message.to_string()
+        }
+        ChangeEvent::Delete { key } => {
+            let mut message = json!(JsonEntry::key(key));
+            message["event"] = json!("delete");
+            message.to_string()
+        }
+    }
+}
+
+/// Appends an unmasked, unfragmented frame, as servers send them.
+fn encode_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xffff => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Reads a frame sent by the server, returning its opcode and payload.
+    fn read_frame(stream: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[1] & 0x80, 0, "server frames are not masked");
+        let len = match header[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0; 8];
+                stream.read_exact(&mut len).unwrap();
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    fn read_event(stream: &mut impl Read) -> serde_json::Value {
+        let (opcode, payload) = read_frame(stream);
+        assert_eq!(opcode, 0x80 | OPCODE_TEXT);
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_websocket_watch() {
+        // The example handshake from RFC 6455.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(StorageServer::new(4, 2));
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let server = Arc::clone(&server);
+                    thread::spawn(move || server.serve_http(stream.unwrap()));
+                }
+            });
+        }
+
+        let mut reader = BufReader::new(TcpStream::connect(address).unwrap());
+        write!(reader.get_mut(), "GET /v1/watch?prefix=user%3A HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut handshake = String::new();
+        while !handshake.ends_with("\r\n\r\n") {
+            reader.read_line(&mut handshake).unwrap();
+        }
+        assert!(handshake.starts_with("HTTP/1.1 101 "));
+        assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        // Only changes to keys under the prefix are sent, in order.
+        server.put("user:1", "alice").unwrap();
+        server.put("order:1", "ignored").unwrap();
+        server.put("user:2", [0, 255]).unwrap();
+        server.delete("user:1").unwrap();
+        let long = "x".repeat(70_000);
+        server.put("user:3", &long).unwrap();
+        assert_eq!(read_event(&mut reader), json!({ "event": "put", "key": "user:1", "value": "alice" }));
+        assert_eq!(read_event(&mut reader), json!({ "event": "put", "key": "user:2", "value_bytes": [0, 255] }));
+        assert_eq!(read_event(&mut reader), json!({ "event": "delete", "key": "user:1" }));
+        assert_eq!(read_event(&mut reader), json!({ "event": "put", "key": "user:3", "value": long }));
+
+        // A plain request for the feed is refused.
+        let mut plain = TcpStream::connect(address).unwrap();
+        write!(plain, "GET /v1/watch HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        plain.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "));
+    }
+}